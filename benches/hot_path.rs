@@ -0,0 +1,139 @@
+//! Benchmarks of the allocation hot path and of report rendering, to catch overhead regressions.
+//! Run with `cargo bench --bench hot_path`.
+//!
+//! The profilers here are not the global allocator, so each benchmark calls into one directly and measures only
+//! that profiler's work on top of the System allocator.
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, BuildHasherDefault};
+use std::time::{Duration, Instant};
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use dashmap::DashMap;
+use ying_profiler::{PointerHasher, YingProfiler};
+
+const GIANT_ALLOC_LIMIT: usize = 64 * 1024 * 1024 * 1024;
+
+// Samples every allocation
+static SAMPLE_ALL: YingProfiler = YingProfiler::new(1, GIANT_ALLOC_LIMIT);
+// Effectively never samples
+static SAMPLE_NONE: YingProfiler = YingProfiler::new(u32::MAX, GIANT_ALLOC_LIMIT);
+static REPORTING: YingProfiler = YingProfiler::new(500, GIANT_ALLOC_LIMIT);
+
+fn layout() -> Layout {
+    Layout::from_size_align(256, 8).unwrap()
+}
+
+// Times `iters` allocations, freeing them afterwards outside of the timed section
+fn time_allocs(profiler: &YingProfiler, iters: u64) -> Duration {
+    let layout = layout();
+    let mut ptrs = Vec::with_capacity(iters as usize);
+    let start = Instant::now();
+    for _ in 0..iters {
+        ptrs.push(unsafe { profiler.alloc(black_box(layout)) });
+    }
+    let elapsed = start.elapsed();
+    for ptr in ptrs {
+        unsafe { profiler.dealloc(ptr, layout) };
+    }
+    elapsed
+}
+
+// Times `iters` frees of allocations made beforehand, outside of the timed section
+fn time_deallocs(profiler: &YingProfiler, iters: u64) -> Duration {
+    let layout = layout();
+    let ptrs: Vec<_> = (0..iters)
+        .map(|_| unsafe { profiler.alloc(layout) })
+        .collect();
+    let start = Instant::now();
+    for ptr in ptrs {
+        unsafe { profiler.dealloc(black_box(ptr), layout) };
+    }
+    start.elapsed()
+}
+
+fn alloc_benchmarks(c: &mut Criterion) {
+    c.bench_function("sampled_alloc", |b| {
+        b.iter_custom(|iters| time_allocs(&SAMPLE_ALL, iters))
+    });
+    c.bench_function("unsampled_alloc", |b| {
+        b.iter_custom(|iters| time_allocs(&SAMPLE_NONE, iters))
+    });
+    c.bench_function("sampled_dealloc", |b| {
+        b.iter_custom(|iters| time_deallocs(&SAMPLE_ALL, iters))
+    });
+    c.bench_function("unsampled_dealloc", |b| {
+        b.iter_custom(|iters| time_deallocs(&SAMPLE_NONE, iters))
+    });
+}
+
+// Inserts then removes each pointer, as a sampled allocation and its free do in outstanding_allocs
+fn insert_remove_all<S: BuildHasher + Clone>(map: &DashMap<u64, u64, S>, ptrs: &[u64]) {
+    for &ptr in ptrs {
+        map.insert(black_box(ptr), 256);
+    }
+    for &ptr in ptrs {
+        black_box(map.remove(&ptr));
+    }
+}
+
+fn hasher_benchmarks(c: &mut Criterion) {
+    // Real allocation addresses, with their alignment and clustering
+    let layout = layout();
+    let allocs: Vec<_> = (0..10_000)
+        .map(|_| unsafe { System.alloc(layout) })
+        .collect();
+    let ptrs: Vec<u64> = allocs.iter().map(|&ptr| ptr as u64).collect();
+
+    let mut group = c.benchmark_group("outstanding_allocs_hasher");
+    let by_pointer = DashMap::with_hasher(BuildHasherDefault::<PointerHasher>::default());
+    group.bench_function("PointerHasher", |b| {
+        b.iter(|| insert_remove_all(&by_pointer, &ptrs))
+    });
+    let by_sip = DashMap::with_hasher(RandomState::new());
+    group.bench_function("RandomState", |b| {
+        b.iter(|| insert_remove_all(&by_sip, &ptrs))
+    });
+    group.finish();
+
+    for ptr in allocs {
+        unsafe { System.dealloc(ptr, layout) };
+    }
+}
+
+fn report_benchmarks(c: &mut Criterion) {
+    let mut group = c.benchmark_group("rich_report");
+    for num_stacks in [10usize, 100, 1000] {
+        REPORTING.reset_state_for_testing_only();
+        for n in 0..num_stacks {
+            let leaf = format!("my_app::module_{}::allocate", n);
+            REPORTING.inject_synthetic_stack(
+                &[&leaf, "my_app::handler::process", "my_app::main"],
+                (n as u64 + 1) * 1000,
+                (n as u64 + 1) * 100,
+            );
+        }
+        group.bench_with_input(
+            BenchmarkId::from_parameter(num_stacks),
+            &num_stacks,
+            |b, &num_stacks| {
+                b.iter(|| {
+                    REPORTING
+                        .top_k_stacks_by_retained(num_stacks)
+                        .iter()
+                        .map(|stats| stats.rich_report(&REPORTING, false, false).len())
+                        .sum::<usize>()
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    alloc_benchmarks,
+    hasher_benchmarks,
+    report_benchmarks
+);
+criterion_main!(benches);
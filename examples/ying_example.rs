@@ -11,7 +11,7 @@ use tracing::instrument;
 use ying_profiler::YingProfiler;
 
 #[global_allocator]
-static YING_ALLOC: YingProfiler = YingProfiler::default();
+static YING_ALLOC: YingProfiler = YingProfiler::default().with_sampling_latency_timing(true);
 
 #[tokio::main]
 async fn main() {
@@ -52,6 +52,10 @@ async fn cache_update_loop() {
         YingProfiler::profiled_bytes_allocated()
     );
     println!("Size of symbol map: {}", YING_ALLOC.symbol_map_size());
+    println!(
+        "Sampling latency: {}",
+        YingProfiler::sampling_latency_histogram()
+    );
     // Try changing last param with_filename to true to print out filenames
     let top_stacks = YING_ALLOC.top_k_stacks_by_allocated(10);
     // let top_stacks = YING_ALLOC.top_k_stacks_by_retained(10);
@@ -0,0 +1,141 @@
+/// Interactive queries over a profile captured with `YingProfiler::write_stacks_jsonl`, without re-running the
+/// program that was profiled.  The stacks are loaded into a profiler which is not the global allocator, so all the
+/// usual query and export functions work on them.
+///
+/// `cargo run --example ying_repl -- stacks.jsonl`
+///
+/// Commands:
+///   top [n]              top n stacks by retained bytes
+///   stack <hash>         full report of one stack, by the hash shown by `top`
+///   grep <pattern>       stacks with a frame containing pattern
+///   diff <other.jsonl>   stacks which are new or grew in another capture
+///   flame > <file.svg>   write a flamegraph of retained bytes
+///   quit
+use std::io::{BufRead, Write};
+use std::path::Path;
+
+use ying_profiler::callstack::Measurement;
+use ying_profiler::utils::{format_bytes, gen_flamegraph, read_stacks_jsonl};
+use ying_profiler::{callstack::StackStats, YingProfiler};
+
+static LOADED: YingProfiler = YingProfiler::new(1, usize::MAX);
+static OTHER: YingProfiler = YingProfiler::new(1, usize::MAX);
+
+fn main() {
+    let Some(path) = std::env::args().nth(1) else {
+        eprintln!("Usage: ying_repl <stacks.jsonl>");
+        std::process::exit(1);
+    };
+    match load(&LOADED, Path::new(&path)) {
+        Ok(n) => println!(
+            "Loaded {} stacks from {}, type `help` for commands",
+            n, path
+        ),
+        Err(e) => {
+            eprintln!("Could not load {}: {}", path, e);
+            std::process::exit(1);
+        }
+    }
+
+    let stdin = std::io::stdin();
+    loop {
+        print!("ying> ");
+        let _ = std::io::stdout().flush();
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line).unwrap_or(0) == 0 {
+            break;
+        }
+        let mut words = line.split_whitespace();
+        match (words.next(), words.next(), words.next()) {
+            (None, _, _) => {}
+            (Some("top"), n, _) => top(n.and_then(|n| n.parse().ok()).unwrap_or(10)),
+            (Some("stack"), Some(hash), _) => stack(hash),
+            (Some("grep"), Some(pattern), _) => grep(pattern),
+            (Some("diff"), Some(other), _) => diff(Path::new(other)),
+            (Some("flame"), Some(">"), Some(svg)) | (Some("flame"), Some(svg), None) => flame(svg),
+            (Some("quit" | "exit"), _, _) => break,
+            _ => println!("{}", HELP),
+        }
+    }
+}
+
+const HELP: &str =
+    "Commands: top [n], stack <hash>, grep <pattern>, diff <other.jsonl>, flame > <file.svg>, quit";
+
+fn summary_line(stats: &StackStats) -> String {
+    format!(
+        "{:#018x}  retained {:>12}  allocated {:>12}",
+        stats.stack_hash(),
+        format_bytes(stats.retained_profiled_bytes(), false).to_string(),
+        format_bytes(stats.allocated_bytes(), false).to_string()
+    )
+}
+
+fn top(n: usize) {
+    for stats in LOADED.top_k_stacks_by_retained(n) {
+        println!("{}", summary_line(&stats));
+    }
+}
+
+fn stack(hash: &str) {
+    let parsed = u64::from_str_radix(hash.trim_start_matches("0x"), 16);
+    match parsed
+        .ok()
+        .and_then(|hash| LOADED.snapshot().get(hash).cloned())
+    {
+        Some(stats) => println!("{}", stats.rich_report(&LOADED, false, false)),
+        None => println!("No stack with hash {}", hash),
+    }
+}
+
+fn grep(pattern: &str) {
+    let matching = LOADED.stacks_matching(pattern);
+    for stats in &matching {
+        println!("{}", summary_line(stats));
+    }
+    println!("{} stacks match", matching.len());
+}
+
+fn diff(other: &Path) {
+    OTHER.reset_state_for_testing_only();
+    if let Err(e) = load(&OTHER, other) {
+        println!("Could not load {}: {}", other.display(), e);
+        return;
+    }
+    // Stack hashes are of the frame names, so the same stack has the same hash in both captures
+    let base = LOADED.snapshot();
+    let after = OTHER.snapshot();
+    for stats in after.new_stacks_since(&base) {
+        println!("new    {}", summary_line(&stats));
+    }
+    let mut grown: Vec<(i64, StackStats)> = after
+        .stacks()
+        .filter_map(|stats| {
+            let before = base.get(stats.stack_hash())?;
+            let delta =
+                stats.retained_profiled_bytes() as i64 - before.retained_profiled_bytes() as i64;
+            (delta > 0).then(|| (delta, stats.clone()))
+        })
+        .collect();
+    grown.sort_unstable_by_key(|(delta, _)| -delta);
+    for (delta, stats) in grown {
+        println!("+{:<12} {}", delta, summary_line(&stats));
+    }
+}
+
+fn flame(svg: &str) {
+    match gen_flamegraph(&LOADED, Measurement::RetainedBytes, Path::new(svg)) {
+        Ok(()) => println!("Wrote {}", svg),
+        Err(e) => println!("Could not write {}: {}", svg, e),
+    }
+}
+
+/// Loads the stacks of a `write_stacks_jsonl` file into `profiler`, skipping its config line
+fn load(profiler: &YingProfiler, path: &Path) -> std::io::Result<usize> {
+    let stacks = read_stacks_jsonl(std::io::BufReader::new(std::fs::File::open(path)?))?;
+    for stack in &stacks {
+        let frames: Vec<&str> = stack.frames.iter().map(String::as_str).collect();
+        profiler.inject_synthetic_stack(&frames, stack.allocated_bytes, stack.retained_bytes);
+    }
+    Ok(stacks.len())
+}
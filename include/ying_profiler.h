@@ -0,0 +1,25 @@
+/* C API of the Ying profiler, built with the `capi` feature.  See src/capi.rs. */
+#ifndef YING_PROFILER_H
+#define YING_PROFILER_H
+
+#include <stddef.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* Total bytes retained by all allocations */
+size_t ying_total_retained(void);
+
+/* Bytes retained by sampled allocations */
+size_t ying_profiled_retained(void);
+
+/* Writes a text report of the top stacks by retained memory to the file at path.  Returns 0 on success, or -1
+ * if no profiler was registered with YingProfiler::register_capi, or the file could not be written. */
+int ying_dump_report(const char *path);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* YING_PROFILER_H */
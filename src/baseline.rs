@@ -0,0 +1,93 @@
+//! Comparing the live profile against a fixed baseline loaded from disk, eg last release's "known good" profile,
+//! for spotting what a new build allocates differently.
+use std::collections::HashMap;
+use std::io::BufReader;
+
+use log::warn;
+
+use super::*;
+
+impl<A: GlobalAlloc> YingProfiler<A> {
+    /// The k stacks whose sampled retained bytes grew the most compared to the baseline given to
+    /// `with_baseline`, largest increase first.  Stacks not in the baseline count from zero, and stacks whose
+    /// retained bytes did not grow are left out.  Empty without a baseline.  Byte counts are not scaled, so
+    /// the baseline should use the same sampling ratio.
+    pub fn top_movers_vs_baseline(&self, k: usize) -> Vec<BaselineDelta> {
+        self.lock_out_profiler(|| {
+            let state = self.get_state();
+            let baseline = self.baseline_stacks(state);
+            if baseline.is_empty() {
+                return Vec::new();
+            }
+            let mut movers: Vec<BaselineDelta> = state
+                .stack_stats
+                .iter()
+                .filter_map(|entry| {
+                    let stats = entry.value();
+                    let frames = self
+                        .resolved_stack(state, stats)
+                        .frame_names(&state.symbol_map);
+                    let base = baseline.get(&frames.join(";"));
+                    let delta = BaselineDelta {
+                        baseline_allocated_bytes: base.map_or(0, |base| base.allocated_bytes),
+                        baseline_retained_bytes: base.map_or(0, |base| base.retained_bytes),
+                        stats: stats.clone(),
+                    };
+                    (delta.retained_bytes_delta() > 0).then_some(delta)
+                })
+                .collect();
+            movers.sort_unstable_by_key(|delta| {
+                (
+                    Reverse(delta.retained_bytes_delta()),
+                    delta.stats.stack_hash(),
+                )
+            });
+            movers.truncate(k);
+            movers
+        })
+    }
+
+    // The baseline stacks by canonical key, loaded on first use.  Must be called with the profiler locked out.
+    fn baseline_stacks<'s>(&self, state: &'s YingState) -> &'s HashMap<String, OwnedStackStats> {
+        state.baseline.get_or_init(|| {
+            let Some(path) = self.baseline_path else {
+                return HashMap::new();
+            };
+            let stacks = std::fs::File::open(path)
+                .and_then(|file| utils::read_stacks_jsonl(BufReader::new(file)));
+            match stacks {
+                Ok(stacks) => stacks
+                    .into_iter()
+                    .map(|stack| (stack.canonical_key(), stack))
+                    .collect(),
+                Err(e) => {
+                    warn!("Could not load baseline profile {}: {}", path, e);
+                    HashMap::new()
+                }
+            }
+        })
+    }
+}
+
+/// One stack compared to the baseline, from [YingProfiler::top_movers_vs_baseline]
+#[derive(Debug, Clone)]
+pub struct BaselineDelta {
+    /// Sampled bytes allocated by the same stack in the baseline, or 0 if it wasn't there
+    pub baseline_allocated_bytes: u64,
+    /// Sampled retained bytes of the same stack in the baseline, or 0 if it wasn't there
+    pub baseline_retained_bytes: u64,
+    /// Current stats of the stack
+    pub stats: StackStats,
+}
+
+impl BaselineDelta {
+    /// Change in sampled retained bytes from the baseline.  Negative if the stack retains less than it did.
+    pub fn retained_bytes_delta(&self) -> i64 {
+        self.stats.retained_profiled_bytes() as i64 - self.baseline_retained_bytes as i64
+    }
+
+    /// Change in sampled bytes allocated from the baseline
+    pub fn allocated_bytes_delta(&self) -> i64 {
+        self.stats.allocated_bytes() as i64 - self.baseline_allocated_bytes as i64
+    }
+}
@@ -0,0 +1,185 @@
+//! A single budget for the memory used by the profiler's stacks and outstanding allocations.  When the estimate
+//! nears the budget, the profiler sheds load: it stops tracking new outstanding allocations and evicts cold
+//! stacks.  Symbols and interned frames are shared between stacks, so shedding can't free them, and they are
+//! estimated but not budgeted.
+use std::sync::atomic::AtomicBool;
+#[cfg(not(any(
+    feature = "disabled",
+    not(any(target_os = "linux", target_os = "macos"))
+)))]
+use std::sync::atomic::AtomicU64;
+
+use super::*;
+
+/// Fraction of the budget at which shedding starts
+#[cfg(not(any(
+    feature = "disabled",
+    not(any(target_os = "linux", target_os = "macos"))
+)))]
+const SHED_START: f64 = 0.9;
+/// Fraction of the budget below which shedding stops, and which evictions aim for
+#[cfg(not(any(
+    feature = "disabled",
+    not(any(target_os = "linux", target_os = "macos"))
+)))]
+const SHED_STOP: f64 = 0.8;
+/// Least millis between two evictions of cold stacks, as each one goes through every stack
+#[cfg(not(any(
+    feature = "disabled",
+    not(any(target_os = "linux", target_os = "macos"))
+)))]
+const EVICTION_INTERVAL_MILLIS: u64 = 1000;
+/// Samples between two checks of the memory estimate, as each check counts the entries of every map shard
+#[cfg(not(any(
+    feature = "disabled",
+    not(any(target_os = "linux", target_os = "macos"))
+)))]
+const BUDGET_CHECK_INTERVAL: u64 = 64;
+/// Rough bytes per symbol map entry, mostly the symbol names and filenames of the frame
+const SYMBOL_ENTRY_BYTES: usize = 256;
+/// Rough bytes per interned frame, including its index entry
+const INTERNED_FRAME_BYTES: usize = 40;
+const STACK_ENTRY_BYTES: usize = std::mem::size_of::<(u64, StackStats)>();
+const OUTSTANDING_ENTRY_BYTES: usize = std::mem::size_of::<(u64, AllocInfo)>();
+
+/// Budget set by `YingProfiler::with_max_profiler_memory_bytes`
+pub(crate) struct MemoryBudget {
+    // usize::MAX for no budget
+    max_bytes: usize,
+    shedding: AtomicBool,
+    #[cfg(not(any(
+        feature = "disabled",
+        not(any(target_os = "linux", target_os = "macos"))
+    )))]
+    last_eviction_millis: AtomicU64,
+    // Samples seen, for checking the estimate every BUDGET_CHECK_INTERVAL samples
+    #[cfg(not(any(
+        feature = "disabled",
+        not(any(target_os = "linux", target_os = "macos"))
+    )))]
+    samples: AtomicU64,
+    evicted_stacks: AtomicUsize,
+}
+
+impl MemoryBudget {
+    pub const fn new(max_bytes: usize) -> Self {
+        Self {
+            max_bytes,
+            shedding: AtomicBool::new(false),
+            #[cfg(not(any(
+                feature = "disabled",
+                not(any(target_os = "linux", target_os = "macos"))
+            )))]
+            last_eviction_millis: AtomicU64::new(0),
+            #[cfg(not(any(
+                feature = "disabled",
+                not(any(target_os = "linux", target_os = "macos"))
+            )))]
+            samples: AtomicU64::new(0),
+            evicted_stacks: AtomicUsize::new(0),
+        }
+    }
+
+    /// The budget in bytes, or usize::MAX for no budget
+    pub fn max_bytes(&self) -> usize {
+        self.max_bytes
+    }
+}
+
+impl<A: GlobalAlloc> YingProfiler<A> {
+    /// Estimated bytes used by the profiler's own maps: stacks, outstanding allocations, symbols and interned
+    /// frames.  Doesn't include the allocator overhead of the maps, so the real footprint is somewhat higher.
+    pub fn profiler_memory_estimate(&self) -> usize {
+        self.lock_out_profiler(|| self.memory_estimate(self.get_state()))
+    }
+
+    /// True while the estimate for stacks and outstanding allocations is near the budget set with
+    /// `with_max_profiler_memory_bytes`, so that the profiler is shedding load and its profile is less accurate
+    /// than usual
+    pub fn is_shedding(&self) -> bool {
+        self.budget.shedding.load(Relaxed)
+    }
+
+    /// Number of cold stacks evicted to stay within the memory budget
+    pub fn evicted_stacks(&self) -> usize {
+        self.budget.evicted_stacks.load(Relaxed)
+    }
+
+    fn memory_estimate(&self, state: &YingState) -> usize {
+        self.budgeted_memory_estimate(state)
+            + state.symbol_map.len() * SYMBOL_ENTRY_BYTES
+            + state.stacks.num_frames() * INTERNED_FRAME_BYTES
+    }
+
+    // The part of the estimate shedding can free: stacks and outstanding allocations
+    fn budgeted_memory_estimate(&self, state: &YingState) -> usize {
+        state.stack_stats.len() * STACK_ENTRY_BYTES
+            + state.num_outstanding.load(Relaxed) * OUTSTANDING_ENTRY_BYTES
+    }
+}
+
+#[cfg(not(any(
+    feature = "disabled",
+    not(any(target_os = "linux", target_os = "macos"))
+)))]
+impl<A: GlobalAlloc> YingProfiler<A> {
+    /// Called for every sample, with profiling locked out.  Every `BUDGET_CHECK_INTERVAL` samples, starting
+    /// with the first, starts or stops shedding as the budgeted estimate crosses the thresholds, and evicts cold
+    /// stacks while shedding.  Returns true while shedding.
+    pub(crate) fn check_memory_budget(&self, state: &YingState) -> bool {
+        let max_bytes = self.budget.max_bytes;
+        if max_bytes == usize::MAX {
+            return false;
+        }
+        if !self
+            .budget
+            .samples
+            .fetch_add(1, Relaxed)
+            .is_multiple_of(BUDGET_CHECK_INTERVAL)
+        {
+            return self.budget.shedding.load(Relaxed);
+        }
+        let estimate = self.budgeted_memory_estimate(state);
+        let shedding = if estimate as f64 >= max_bytes as f64 * SHED_START {
+            true
+        } else if (estimate as f64) < max_bytes as f64 * SHED_STOP {
+            false
+        } else {
+            self.budget.shedding.load(Relaxed)
+        };
+        self.budget.shedding.store(shedding, Relaxed);
+
+        if shedding {
+            let now = now_millis();
+            let last = self.budget.last_eviction_millis.load(Relaxed);
+            if now.saturating_sub(last) >= EVICTION_INTERVAL_MILLIS
+                && self
+                    .budget
+                    .last_eviction_millis
+                    .compare_exchange(last, now, Relaxed, Relaxed)
+                    .is_ok()
+            {
+                let target = (max_bytes as f64 * SHED_STOP) as usize;
+                self.evict_cold_stacks(state, estimate.saturating_sub(target));
+            }
+        }
+        shedding
+    }
+
+    // Evicts enough stacks to free `excess` bytes, least recently allocating first.  Only stacks with no
+    // retained sampled bytes are evicted, so no outstanding allocation is left pointing at a missing stack.
+    fn evict_cold_stacks(&self, state: &YingState, excess: usize) {
+        let mut cold: Vec<(u64, u64)> = state
+            .stack_stats
+            .iter()
+            .filter(|entry| entry.value().retained_profiled_bytes() == 0)
+            .map(|entry| (entry.value().last_alloc_millis(), *entry.key()))
+            .collect();
+        cold.sort_unstable();
+        let num_evicted = excess.div_ceil(STACK_ENTRY_BYTES).min(cold.len());
+        for &(_, stack_hash) in &cold[..num_evicted] {
+            state.stack_stats.remove(&stack_hash);
+        }
+        self.budget.evicted_stacks.fetch_add(num_evicted, Relaxed);
+    }
+}
@@ -1,4 +1,6 @@
 use std::borrow::Cow;
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+use std::ffi::CStr;
 use std::fmt;
 use std::hash::Hasher;
 
@@ -8,12 +10,22 @@ use regex::Regex;
 use wyhash::WyHash;
 
 use super::*;
-use crate::histogram::MillisHistogram;
+use crate::histogram::{MillisHistogram, RateWindow, Trend, TrendWindow};
+use crate::intern::{StackInterner, StoredStack};
+use crate::utils::{format_bytes, write_json_string};
 
 pub(crate) const MAX_NUM_FRAMES: usize = 30;
 
 pub type StdCallstack = Callstack<MAX_NUM_FRAMES>;
 
+/// Seed of `Callstack::compute_hash`, used unless a profiler is given its own with `with_stack_hash_seed`
+pub(crate) const DEFAULT_STACK_HASH_SEED: u64 = 17;
+
+/// Fake IP of the frame separating a stack in a `spawn_blocking` task from the stack which spawned the task.
+/// No real code is at address 1.
+#[cfg(feature = "tokio")]
+pub(crate) const SPAWN_BLOCKING_MARKER_IP: u64 = 1;
+
 /// An optimized Callstack struct that represents a single stack trace.
 /// No symbols are explicitly held here - the major savings is that
 /// we use an external dictionary to store symbols, because the same IPs
@@ -28,15 +40,144 @@ pub struct Callstack<const NF: usize> {
 impl<const NF: usize> Callstack<NF> {
     /// Creates a Callback from a backtrace::Backtrace, preferably unresolved for speed
     pub fn from_backtrace_unresolved(bt: &backtrace::Backtrace) -> Self {
+        Self::from_backtrace_skipping(bt, TOP_FRAMES_TO_SKIP)
+    }
+
+    /// Like `from_backtrace_unresolved`, but skipping the first `skip` frames instead of the profiler's own
+    pub(crate) fn from_backtrace_skipping(bt: &backtrace::Backtrace, skip: usize) -> Self {
+        let mut cb = Self { frames: [0; NF] };
+        for i in skip..(bt.frames().len().min(NF)) {
+            cb.frames[i - skip] = bt.frames()[i].ip() as u64;
+        }
+        cb
+    }
+
+    /// A Callstack with no frames at all.  Samples where no usable backtrace could be captured (eg very early
+    /// during startup, or in certain signal contexts) are all lumped together into this one stack.
+    pub fn unknown() -> Self {
+        Self { frames: [0; NF] }
+    }
+
+    /// True if this stack has no frames, ie it is the [Callstack::unknown] stack
+    pub fn is_unknown(&self) -> bool {
+        self.frames[0] == 0
+    }
+
+    /// A stack of the given IPs, innermost first, keeping at most the first NF
+    pub(crate) fn from_ips(ips: &[u64]) -> Self {
+        let mut cb = Self { frames: [0; NF] };
+        for (slot, ip) in cb.frames.iter_mut().zip(ips) {
+            *slot = *ip;
+        }
+        cb
+    }
+
+    /// Instruction pointers of the frames, innermost first
+    pub(crate) fn ips(&self) -> impl Iterator<Item = u64> + '_ {
+        self.frames.iter().copied().take_while(|ip| *ip != 0)
+    }
+
+    /// Creates a stack out of made up frames with the given symbol names, innermost first, and adds their
+    /// symbols to `symbol_map`.  The same name always gets the same IP, as with real code.  TESTING ONLY
+    #[cfg(feature = "test-util")]
+    pub(crate) fn synthetic(frames: &[&str], symbol_map: &SymbolMap) -> Self {
+        let mut cb = Self { frames: [0; NF] };
+        for (i, name) in frames.iter().take(NF).enumerate() {
+            let mut hasher = WyHash::with_seed(17);
+            hasher.write(name.as_bytes());
+            // Keep the top bit set so fake IPs, which are kernel addresses, never clash with real code
+            let ip = hasher.finish() | (1 << 63);
+            cb.frames[i] = ip;
+            symbol_map.insert(ip, vec![FriendlySymbol::synthetic(name)].into());
+        }
+        cb
+    }
+
+    /// The first `keep` frames of this stack followed by the frames of `spawner`, the stack which spawned the
+    /// blocking task this stack ran in, with a marker frame in between.  Frames beyond the maximum are dropped
+    /// from the spawner's end.
+    #[cfg(all(
+        feature = "tokio",
+        not(any(
+            feature = "disabled",
+            not(any(target_os = "linux", target_os = "macos"))
+        ))
+    ))]
+    pub(crate) fn stitched(&self, keep: usize, spawner: &Self) -> Self {
+        let len = self
+            .frames
+            .iter()
+            .take_while(|ip| **ip != 0)
+            .count()
+            .min(keep);
+        let mut cb = Self { frames: [0; NF] };
+        cb.frames[..len].copy_from_slice(&self.frames[..len]);
+        if len < NF {
+            cb.frames[len] = SPAWN_BLOCKING_MARKER_IP;
+            let spawner_frames = spawner.frames.iter().take_while(|ip| **ip != 0);
+            for (slot, ip) in cb.frames[len + 1..].iter_mut().zip(spawner_frames) {
+                *slot = *ip;
+            }
+        }
+        cb
+    }
+
+    /// This stack with `ip` added as its outermost frame, replacing the outermost frame if the stack is full
+    #[cfg(not(any(
+        feature = "disabled",
+        not(any(target_os = "linux", target_os = "macos"))
+    )))]
+    pub(crate) fn with_root_frame(&self, ip: u64) -> Self {
+        let len = self.frames.iter().take_while(|ip| **ip != 0).count();
+        let mut cb = self.clone();
+        cb.frames[len.min(NF - 1)] = ip;
+        cb
+    }
+
+    /// This stack with only the frames whose IP `keep` returns true for, moved up to close the gaps
+    #[cfg(not(any(
+        feature = "disabled",
+        not(any(target_os = "linux", target_os = "macos"))
+    )))]
+    pub(crate) fn filtered(&self, mut keep: impl FnMut(u64) -> bool) -> Self {
         let mut cb = Self { frames: [0; NF] };
-        for i in TOP_FRAMES_TO_SKIP..(bt.frames().len().min(NF)) {
-            cb.frames[i - TOP_FRAMES_TO_SKIP] = bt.frames()[i].ip() as u64;
+        for (slot, ip) in cb.frames.iter_mut().zip(self.ips().filter(|ip| keep(*ip))) {
+            *slot = ip;
         }
         cb
     }
 
+    /// Whether `keep` accepts the first symbol of the frame at `ip`.  Frames with no symbols are always kept.
+    #[cfg(not(any(
+        feature = "disabled",
+        not(any(target_os = "linux", target_os = "macos"))
+    )))]
+    pub(crate) fn frame_kept(symbols: &SymbolMap, ip: u64, keep: fn(&str) -> bool) -> bool {
+        symbols.get(&ip).is_none_or(|syms| {
+            syms.first()
+                .is_none_or(|symbol| keep(&symbol.friendly_name))
+        })
+    }
+
     pub fn compute_hash(&self) -> u64 {
-        let mut hasher = WyHash::with_seed(17);
+        self.compute_hash_with_seed(DEFAULT_STACK_HASH_SEED)
+    }
+
+    /// `compute_hash` with a different seed, so that the same stack hashes differently, see
+    /// `YingProfiler::with_stack_hash_seed`
+    pub fn compute_hash_with_seed(&self, seed: u64) -> u64 {
+        let mut hasher = WyHash::with_seed(seed);
+        hasher.write(unsafe { (self.frames).align_to::<u8>().1 });
+        hasher.finish()
+    }
+
+    /// A second hash of the frames, independent of `compute_hash`, for telling apart stacks whose hashes collide
+    #[cfg(not(any(
+        feature = "disabled",
+        not(any(target_os = "linux", target_os = "macos"))
+    )))]
+    pub(crate) fn compute_fingerprint(&self) -> u64 {
+        let mut hasher = WyHash::with_seed(0x9e37_79b9_7f4a_7c15);
         hasher.write(unsafe { (self.frames).align_to::<u8>().1 });
         hasher.finish()
     }
@@ -45,6 +186,32 @@ impl<const NF: usize> Callstack<NF> {
     /// all of them.  If it does not, resolves the backtrace symbols and updates the symbol map.
     /// Potentially very expensive due to resolving IPs
     pub fn populate_symbol_map(&self, bt: &mut backtrace::Backtrace, symbol_map: &SymbolMap) {
+        self.populate_symbol_map_with(bt, symbol_map, &BacktraceSymbolizer)
+    }
+
+    /// `populate_symbol_map`, resolving IPs with `symbolizer` instead of the `backtrace` crate
+    pub fn populate_symbol_map_with(
+        &self,
+        bt: &mut backtrace::Backtrace,
+        symbol_map: &SymbolMap,
+        symbolizer: &dyn Symbolizer,
+    ) {
+        self.populate_symbol_map_skipping(bt, symbol_map, TOP_FRAMES_TO_SKIP, symbolizer)
+    }
+
+    /// `populate_symbol_map_with` for a stack created with `from_backtrace_skipping`
+    pub(crate) fn populate_symbol_map_skipping(
+        &self,
+        bt: &mut backtrace::Backtrace,
+        symbol_map: &SymbolMap,
+        skip: usize,
+        symbolizer: &dyn Symbolizer,
+    ) {
+        // Stacks captured by walking frame pointers have no backtrace to resolve from
+        if bt.frames().is_empty() {
+            return self.resolve_missing_symbols(symbol_map, symbolizer);
+        }
+
         // For each IP in our trace that is not zero
         for (i, ip) in self.frames.iter().enumerate() {
             if *ip == 0 {
@@ -54,20 +221,161 @@ impl<const NF: usize> Callstack<NF> {
             // This is a concurrent hash map. It's OK for the contains/insert to not be atomic,
             // because for each IP the symbol should be identical, so multiple inserts are idempotent.
             if !symbol_map.contains_key(ip) {
-                // IP not there. Get the corresponding frame from the backtrace
-                let frame = &bt.frames()[i + TOP_FRAMES_TO_SKIP];
+                // IP not there. Get the corresponding frame from the backtrace.  Frames stitched on from
+                // elsewhere are not in the backtrace, but their symbols were added when they were captured.
+                if i + skip >= bt.frames().len() {
+                    continue;
+                }
+                let frame = &bt.frames()[i + skip];
+
+                let module_base = frame.module_base_address().map(|base| base as u64);
+                symbol_map.insert(*ip, Self::symbols_of(*ip, module_base, symbolizer));
+            }
+        }
+    }
+
+    /// Resolves the symbols of the IPs of this stack not yet in the symbol map, without the backtrace the stack
+    /// was captured from, eg for stacks recorded with lazy symbolization
+    pub(crate) fn resolve_missing_symbols(
+        &self,
+        symbol_map: &SymbolMap,
+        symbolizer: &dyn Symbolizer,
+    ) {
+        for ip in self.frames.iter().take_while(|ip| **ip != 0) {
+            if !symbol_map.contains_key(ip) {
+                symbol_map.insert(*ip, Self::symbols_of(*ip, None, symbolizer));
+            }
+        }
+    }
+
+    // Resolves an IP into FriendlySymbols, with the module the frame is in.  Frames of stripped binaries have no
+    // names, so they are named after the IP and module offset to resolve offline instead.
+    fn symbols_of(
+        ip: u64,
+        module_base: Option<u64>,
+        symbolizer: &dyn Symbolizer,
+    ) -> Arc<[FriendlySymbol]> {
+        let module = ModuleInfo::of(ip, module_base);
+        let symbols = symbolizer.symbolize(ip);
+        if symbols.is_empty() {
+            vec![FriendlySymbol::unresolved(ip, module)].into()
+        } else {
+            symbols
+                .into_iter()
+                .map(|s| s.in_module(module.clone()))
+                .collect()
+        }
+    }
+
+    /// Returns true if any resolved symbol in this stack, including inlined ones, contains `pattern`
+    pub fn any_symbol_contains(&self, symbols: &SymbolMap, pattern: &str) -> bool {
+        self.any_symbol_matches(symbols, |name| name.contains(pattern))
+    }
+
+    /// Returns true if any resolved symbol in this stack starts with `prefix`.  A leading `<` from trait
+    /// impl symbols such as `<my_crate::Foo as Trait>::method` is ignored.
+    pub fn any_symbol_starts_with(&self, symbols: &SymbolMap, prefix: &str) -> bool {
+        self.any_symbol_matches(symbols, |name| {
+            name.trim_start_matches('<').starts_with(prefix)
+        })
+    }
+
+    /// The name of the first symbol, going outwards from the allocation and including inlined symbols, which is
+    /// not in the standard library.  This is usually the line of user code which caused the allocation, rather
+    /// than generic allocation machinery such as `RawVec::grow`.
+    pub fn first_user_frame(&self, symbols: &SymbolMap) -> Option<String> {
+        self.frames
+            .iter()
+            .take_while(|ip| **ip != 0)
+            .find_map(|ip| {
+                symbols.get(ip).and_then(|syms| {
+                    syms.iter()
+                        .find(|s| !is_std_symbol(&s.friendly_name))
+                        .map(|s| s.friendly_name.clone())
+                })
+            })
+    }
+
+    /// The name of the first symbol, going outwards from the allocation and including inlined symbols, which is
+    /// not allocator plumbing or a pass-through collection function such as `RawVec::grow` or `Vec::reserve`, see
+    /// `PASS_THROUGH_FRAMES`, nor starts with one of `extra_pass_through`.  For stacks which grew a collection this
+    /// is the caller of the collection method, while other stacks keep their innermost frame.
+    pub fn allocation_site(
+        &self,
+        symbols: &SymbolMap,
+        extra_pass_through: &[&str],
+    ) -> Option<String> {
+        let passes_through = |name: &str| {
+            let name = name.trim_start_matches('<');
+            PASS_THROUGH_FRAMES
+                .iter()
+                .chain(extra_pass_through)
+                .any(|prefix| name.starts_with(prefix))
+        };
+        self.frames
+            .iter()
+            .take_while(|ip| **ip != 0)
+            .find_map(|ip| {
+                symbols.get(ip).and_then(|syms| {
+                    syms.iter()
+                        .find(|s| !passes_through(&s.friendly_name))
+                        .map(|s| s.friendly_name.clone())
+                })
+            })
+    }
+
+    /// The symbol names of all frames, including inlined symbols, innermost first and separated by `;`.
+    /// Symbol names have no addresses or hash suffixes, so stacks with the same source level call chain have
+    /// the same key even in different builds, where their instruction pointers and hashes differ.
+    /// Frames without resolved symbols are written as `<unresolved>`.
+    pub fn canonical_key(&self, symbols: &SymbolMap) -> String {
+        self.frame_names(symbols).join(";")
+    }
 
-                // Get the symbol out.  Resolve the backtrace if necessary
-                if frame.symbols().is_empty() {
-                    bt.resolve();
+    /// The symbol names of all frames, including inlined symbols, innermost first.  Frames without resolved
+    /// symbols are named `<unresolved>`.
+    pub fn frame_names(&self, symbols: &SymbolMap) -> Vec<String> {
+        let mut names = Vec::new();
+        for ip in self.frames.iter().take_while(|ip| **ip != 0) {
+            match symbols.get(ip) {
+                Some(syms) if !syms.is_empty() => {
+                    names.extend(syms.iter().map(|sym| sym.friendly_name.clone()));
                 }
-                let frame = &bt.frames()[i + TOP_FRAMES_TO_SKIP];
+                _ => names.push("<unresolved>".to_string()),
+            }
+        }
+        names
+    }
 
-                // Convert frame symbols into FriendlySymbols and add to symbol map
-                let friendlies = frame.symbols().iter().map(FriendlySymbol::from).collect();
-                symbol_map.insert(*ip, friendlies);
+    fn any_symbol_matches(&self, symbols: &SymbolMap, pred: impl Fn(&str) -> bool) -> bool {
+        self.frames.iter().take_while(|ip| **ip != 0).any(|ip| {
+            symbols
+                .get(ip)
+                .map(|syms| syms.iter().any(|s| pred(&s.friendly_name)))
+                .unwrap_or(false)
+        })
+    }
+
+    /// Writes the symbol names of all frames, including inlined symbols, as a JSON array.
+    pub(crate) fn write_json_frames(
+        &self,
+        symbols: &SymbolMap,
+        w: &mut impl std::io::Write,
+    ) -> std::io::Result<()> {
+        w.write_all(b"[")?;
+        let mut first = true;
+        for ip in self.frames.iter().take_while(|ip| **ip != 0) {
+            if let Some(syms) = symbols.get(ip) {
+                for sym in syms.iter() {
+                    if !first {
+                        w.write_all(b",")?;
+                    }
+                    first = false;
+                    write_json_string(w, &sym.friendly_name)?;
+                }
             }
         }
+        w.write_all(b"]")
     }
 
     /// Obtains a DecoratedCallstack for display.
@@ -85,6 +393,9 @@ impl<const NF: usize> Callstack<NF> {
             filter_poll: true,
             expand_frame,
             write_header: true,
+            hash: self.compute_hash(),
+            frame_filter: None,
+            module_offsets: false,
         }
     }
 
@@ -102,6 +413,9 @@ impl<const NF: usize> Callstack<NF> {
             filter_poll: true,
             expand_frame,
             write_header: true,
+            hash: self.compute_hash(),
+            frame_filter: None,
+            module_offsets: false,
         }
     }
 
@@ -117,6 +431,9 @@ impl<const NF: usize> Callstack<NF> {
             filter_poll: true,
             expand_frame: false,
             write_header: false,
+            hash: 0,
+            frame_filter: None,
+            module_offsets: false,
         }
     }
 }
@@ -128,6 +445,7 @@ impl<const NF: usize> Callstack<NF> {
 /// - `filter_poll` - if True, skips symbols in the frame which have `::poll::` in them
 /// - `expand_frame` - if False, does not print out inlined symbols at all
 /// - `write_header` - if True, adds "Callback <hash = 0x..>" header as the first line
+/// - `module_offsets` - if True, adds the module and offset of each frame, eg `[libfoo.so+0x1234]`
 pub struct DecoratedCallstack<'cb, 's, const NF: usize> {
     cb: &'cb Callstack<NF>,
     symbols: &'s SymbolMap,
@@ -135,26 +453,72 @@ pub struct DecoratedCallstack<'cb, 's, const NF: usize> {
     filter_poll: bool,
     expand_frame: bool,
     write_header: bool,
+    // Stack hash in the header, which depends on the profiler's hash seed
+    hash: u64,
+    // Only frames whose first symbol this accepts are written
+    frame_filter: Option<fn(&str) -> bool>,
+    module_offsets: bool,
+}
+
+impl<'cb, 's, const NF: usize> DecoratedCallstack<'cb, 's, NF> {
+    /// Shows `hash` in the header rather than the stack's hash with the default seed
+    pub fn with_header_hash(mut self, hash: u64) -> Self {
+        self.hash = hash;
+        self
+    }
+
+    /// Only writes the frames whose symbol name `keep` returns true for
+    pub fn with_frame_filter(mut self, keep: fn(&str) -> bool) -> Self {
+        self.frame_filter = Some(keep);
+        self
+    }
+
+    /// Adds the module each frame is in and the frame's offset within it, eg `[libfoo.so+0x1234]`
+    pub fn with_module_offsets(mut self) -> Self {
+        self.module_offsets = true;
+        self
+    }
 }
 
 impl<'cb, 's, const NF: usize> fmt::Display for DecoratedCallstack<'cb, 's, NF> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         if self.write_header {
-            writeln!(f, "Callback <hash = 0x{:0x}>", self.cb.compute_hash())?;
-        }
-        for ip in &self.cb.frames {
-            if let Some(symbols) = self.symbols.get(ip) {
-                if !symbols.is_empty() {
-                    writeln!(f, "  {}", stringify_symbol(&symbols[0], self.filename_info))?;
-                    // Don't expand inlined `::poll::` subcalls, they aren't interesting
-                    if self.expand_frame && !symbols[0].is_poll {
-                        for s in &symbols[1..] {
-                            if self.filter_poll && s.is_poll {
-                                continue;
-                            }
-                            writeln!(f, "    > {}", stringify_symbol(s, self.filename_info))?;
-                        }
+            writeln!(f, "Callback <hash = 0x{:0x}>", self.hash)?;
+        }
+        if self.cb.is_unknown() {
+            writeln!(f, "  <unknown: backtrace could not be captured>")?;
+        }
+        // Copy out the symbols of all frames first, so the symbol map isn't locked while writing each frame
+        let frames: Vec<_> = self
+            .cb
+            .frames
+            .iter()
+            .take_while(|ip| **ip != 0)
+            .filter_map(|ip| self.symbols.get(ip).map(|symbols| symbols.value().clone()))
+            .collect();
+        let shown = |symbols: &&Arc<[FriendlySymbol]>| {
+            self.frame_filter
+                .is_none_or(|keep| keep(&symbols[0].friendly_name))
+        };
+        for symbols in frames
+            .iter()
+            .filter(|symbols| !symbols.is_empty())
+            .filter(shown)
+        {
+            write!(f, "  {}", stringify_symbol(&symbols[0], self.filename_info))?;
+            match &symbols[0].module {
+                Some(module) if self.module_offsets && !symbols[0].unresolved => {
+                    writeln!(f, " [{}]", module)?
+                }
+                _ => writeln!(f)?,
+            }
+            // Don't expand inlined `::poll::` subcalls, they aren't interesting
+            if self.expand_frame && !symbols[0].is_poll {
+                for s in &symbols[1..] {
+                    if self.filter_poll && s.is_poll {
+                        continue;
                     }
+                    writeln!(f, "    > {}", stringify_symbol(s, self.filename_info))?;
                 }
             }
         }
@@ -162,8 +526,49 @@ impl<'cb, 's, const NF: usize> fmt::Display for DecoratedCallstack<'cb, 's, NF>
     }
 }
 
+const STD_PREFIXES: &[&str] = &["alloc::", "core::", "std::"];
+
+/// Symbol prefixes of the allocator entry points, and of collection functions which only pass allocations through
+/// on behalf of their caller, such as growing a `Vec`, `String`, `VecDeque` or `HashMap`.  Skipped by
+/// `Callstack::allocation_site`.
+pub const PASS_THROUGH_FRAMES: &[&str] = &[
+    "__rust_alloc",
+    "__rust_realloc",
+    "__rdl_",
+    "__rg_",
+    "alloc::alloc::",
+    "std::alloc::",
+    "alloc::raw_vec::",
+    "alloc::vec::",
+    "alloc::string::",
+    "alloc::collections::",
+    "hashbrown::",
+    "std::collections::",
+    "core::iter::",
+];
+
+// True for symbols in alloc/core/std, including trait impls for a generic type such as
+// `<I as alloc::vec::spec_from_iter::SpecFromIter<T,I>>::from_iter`
+fn is_std_symbol(name: &str) -> bool {
+    let name = name.trim_start_matches('<');
+    if STD_PREFIXES.iter().any(|prefix| name.starts_with(prefix)) {
+        return true;
+    }
+    match name.split_once(" as ") {
+        Some((self_type, trait_name)) if !self_type.contains("::") => STD_PREFIXES
+            .iter()
+            .any(|prefix| trait_name.starts_with(prefix)),
+        _ => false,
+    }
+}
+
 fn stringify_symbol(s: &FriendlySymbol, include_filename: bool) -> String {
-    if include_filename {
+    if let (true, true, Some(module)) = (include_filename, s.unresolved, &s.module) {
+        format!(
+            "{}\n\t(module {} loaded at {:#x})",
+            s.friendly_name, module.path, module.base
+        )
+    } else if include_filename {
         format!(
             "{}\n\t({:?}:{})",
             s.friendly_name, s.shorter_filename, s.line_no
@@ -197,6 +602,36 @@ static SYMBOL_REGEXES: Lazy<SymbolRegexes> = Lazy::new(|| {
     }
 });
 
+/// Resolves instruction pointers into symbols, for frames the `backtrace` crate can't resolve, eg of JIT
+/// compiled code or with a custom debug info format.  Install one with `YingProfiler::with_symbolizer`.
+/// It is called inside the allocator, with profiling locked out for the current thread, the first time each
+/// IP is seen.
+pub trait Symbolizer: Sync {
+    /// The symbols of `ip`, innermost inlined symbol first, or an empty Vec if it can't be resolved, in which
+    /// case the frame is named after its IP and module offset
+    fn symbolize(&self, ip: u64) -> Vec<FriendlySymbol>;
+}
+
+/// The default `Symbolizer`, which resolves IPs from the debug info of the running binary using the
+/// `backtrace` crate
+pub struct BacktraceSymbolizer;
+
+impl Symbolizer for BacktraceSymbolizer {
+    fn symbolize(&self, ip: u64) -> Vec<FriendlySymbol> {
+        let mut symbols = Vec::new();
+        backtrace::resolve(ip as *mut std::ffi::c_void, |s| {
+            if s.name().is_some() {
+                symbols.push(FriendlySymbol::from_parts(
+                    s.name(),
+                    s.filename(),
+                    s.lineno(),
+                ));
+            }
+        });
+        symbols
+    }
+}
+
 /// A wrapper around BacktraceSymbol with cleaned up, demangled symbol names
 /// and shortened filename and line number as well.
 ///
@@ -207,12 +642,213 @@ pub struct FriendlySymbol {
     is_poll: bool,
     shorter_filename: String,
     line_no: u32,
+    // Module containing the frame, if known
+    module: Option<ModuleInfo>,
+    // True if the frame had no symbol name, so it is named after its IP
+    unresolved: bool,
+}
+
+/// The executable or shared library a frame is in, for resolving its IP offline
+#[derive(Clone)]
+struct ModuleInfo {
+    path: String,
+    base: u64,
+    // Offset of the frame's IP from the base
+    offset: u64,
+}
+
+impl ModuleInfo {
+    /// The module containing `ip`, from the dynamic loader if it knows it, else just the `base` address
+    /// the backtrace reported
+    fn of(ip: u64, base: Option<u64>) -> Option<Self> {
+        #[cfg(any(target_os = "linux", target_os = "macos"))]
+        {
+            let mut info: libc::Dl_info = unsafe { std::mem::zeroed() };
+            let found = unsafe { libc::dladdr(ip as *const libc::c_void, &mut info) } != 0;
+            if found && !info.dli_fbase.is_null() {
+                let path = if info.dli_fname.is_null() {
+                    String::new()
+                } else {
+                    unsafe { CStr::from_ptr(info.dli_fname) }
+                        .to_string_lossy()
+                        .into_owned()
+                };
+                let base = info.dli_fbase as u64;
+                return Some(Self {
+                    path,
+                    base,
+                    offset: ip.wrapping_sub(base),
+                });
+            }
+        }
+        base.filter(|base| *base != 0 && *base <= ip)
+            .map(|base| Self {
+                path: String::new(),
+                base,
+                offset: ip - base,
+            })
+    }
+
+    /// File name of the module, without its directory
+    fn file_name(&self) -> &str {
+        self.path.rsplit('/').next().unwrap_or_default()
+    }
+}
+
+// Displays as eg `libfoo.so+0x1234`, or with the base address if the module's file is not known
+impl fmt::Display for ModuleInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.path.is_empty() {
+            write!(f, "module base {:#x}, offset {:#x}", self.base, self.offset)
+        } else {
+            write!(f, "{}+{:#x}", self.file_name(), self.offset)
+        }
+    }
+}
+
+impl FriendlySymbol {
+    /// A symbol with the given name, source file and line number, eg for a custom `Symbolizer`
+    pub fn new(name: &str, filename: &str, line_no: u32) -> Self {
+        Self {
+            friendly_name: name.to_string(),
+            is_poll: name.contains("::poll::"),
+            shorter_filename: filename.to_string(),
+            line_no,
+            module: None,
+            unresolved: false,
+        }
+    }
+
+    #[cfg(feature = "test-util")]
+    fn synthetic(name: &str) -> Self {
+        Self {
+            friendly_name: name.to_string(),
+            is_poll: name.contains("::poll::"),
+            shorter_filename: "<synthetic>".to_string(),
+            line_no: 0,
+            module: None,
+            unresolved: false,
+        }
+    }
+
+    /// The symbol of a marker frame, which stands for eg a point where stacks were stitched together
+    #[cfg(any(
+        feature = "tokio",
+        not(any(
+            feature = "disabled",
+            not(any(target_os = "linux", target_os = "macos"))
+        ))
+    ))]
+    pub(crate) fn marker(name: &str) -> Self {
+        Self {
+            friendly_name: name.to_string(),
+            is_poll: false,
+            shorter_filename: String::new(),
+            line_no: 0,
+            module: None,
+            unresolved: false,
+        }
+    }
+
+    /// The symbol of a frame which has no symbol name, eg in a stripped binary.  Named after its IP and,
+    /// if known, the module it is in and its offset there, eg `0x55d0c3a1b2c4 (my_app+0x1b2c4)`, which can
+    /// be resolved offline with `addr2line -e my_app 0x1b2c4` against the unstripped binary.
+    fn unresolved(ip: u64, module: Option<ModuleInfo>) -> Self {
+        let friendly_name = match &module {
+            Some(module) => format!("{:#x} ({})", ip, module),
+            None => format!("{:#x}", ip),
+        };
+        Self {
+            friendly_name,
+            is_poll: false,
+            shorter_filename: String::new(),
+            line_no: 0,
+            module,
+            unresolved: true,
+        }
+    }
+
+    fn in_module(mut self, module: Option<ModuleInfo>) -> Self {
+        self.module = module;
+        self
+    }
+
+    /// File name of the executable or shared library containing the frame, eg `libfoo.so`, if known
+    pub fn module_name(&self) -> Option<&str> {
+        self.module
+            .as_ref()
+            .map(|module| module.file_name())
+            .filter(|name| !name.is_empty())
+    }
+
+    /// Offset of the frame's IP from the base address of its module, eg for `addr2line -e libfoo.so <offset>`
+    pub fn module_offset(&self) -> Option<u64> {
+        self.module.as_ref().map(|module| module.offset)
+    }
+}
+
+impl FriendlySymbol {
+    pub(crate) fn name(&self) -> &str {
+        &self.friendly_name
+    }
+
+    pub(crate) fn filename(&self) -> &str {
+        &self.shorter_filename
+    }
+
+    pub(crate) fn line_no(&self) -> u32 {
+        self.line_no
+    }
+
+    /// Writes this symbol of instruction pointer `ip` as one line of the symbol map export format:
+    /// `<ip in hex>\t<line no>\t<filename>\t<symbol name>`.  The name goes last as it may contain anything.
+    pub(crate) fn write_export_line(
+        &self,
+        ip: u64,
+        w: &mut impl std::io::Write,
+    ) -> std::io::Result<()> {
+        writeln!(
+            w,
+            "{:#x}\t{}\t{}\t{}",
+            ip, self.line_no, self.shorter_filename, self.friendly_name
+        )
+    }
+
+    /// Parses a line written by `write_export_line`
+    pub(crate) fn parse_export_line(line: &str) -> Option<(u64, Self)> {
+        let mut fields = line.splitn(4, '\t');
+        let ip = u64::from_str_radix(fields.next()?.strip_prefix("0x")?, 16).ok()?;
+        let line_no = fields.next()?.parse().ok()?;
+        let shorter_filename = fields.next()?.to_string();
+        let friendly_name = fields.next()?.to_string();
+        Some((
+            ip,
+            Self {
+                is_poll: friendly_name.contains("::poll::"),
+                friendly_name,
+                shorter_filename,
+                line_no,
+                module: None,
+                unresolved: false,
+            },
+        ))
+    }
 }
 
 impl From<&BacktraceSymbol> for FriendlySymbol {
     fn from(s: &BacktraceSymbol) -> Self {
+        Self::from_parts(s.name(), s.filename(), s.lineno())
+    }
+}
+
+impl FriendlySymbol {
+    fn from_parts(
+        name: Option<backtrace::SymbolName<'_>>,
+        filename: Option<&std::path::Path>,
+        line_no: Option<u32>,
+    ) -> Self {
         // Get demangled name and strip the final ::<hex>
-        let friendly_name = if let Some(symbolname) = s.name() {
+        let friendly_name = if let Some(symbolname) = name {
             let demangled = format!("{}", symbolname);
             SYMBOL_REGEXES
                 .name_end_re
@@ -225,7 +861,7 @@ impl From<&BacktraceSymbol> for FriendlySymbol {
         let is_poll = friendly_name.contains("::poll::");
 
         // Get filename and convert common patterns
-        let shorter_filename = if let Some(p) = s.filename() {
+        let shorter_filename = if let Some(p) = filename {
             let filename = p.to_str().unwrap_or_default();
             let mut new_filename = None;
             for (re, abbrev) in &SYMBOL_REGEXES.filename_res {
@@ -241,56 +877,599 @@ impl From<&BacktraceSymbol> for FriendlySymbol {
             String::new()
         };
 
-        let line_no = s.lineno().unwrap_or(0);
+        let line_no = line_no.unwrap_or(0);
 
         Self {
             friendly_name,
             is_poll,
             shorter_filename,
             line_no,
+            module: None,
+            unresolved: false,
         }
     }
 }
 
+/// What to weigh stacks by in reports and flamegraphs.  The same stacks tell different stories: counts of
+/// allocations find churn hotspots, while retained bytes find leaks.
 #[derive(Copy, Clone, PartialEq, Debug)]
 pub enum Measurement {
     AllocatedBytes,
     RetainedBytes,
+    /// Number of sampled allocations
+    NumAllocations,
+    /// Number of sampled allocations not yet freed
+    LiveAllocations,
 }
 
-/// Central struct collecting stats about each stack trace
-#[derive(Debug, Clone)]
-pub struct StackStats {
-    stack: StdCallstack,
+impl Measurement {
+    /// The value of this measurement for one stack
+    pub fn of(&self, stats: &StackStats) -> u64 {
+        match self {
+            Measurement::AllocatedBytes => stats.allocated_bytes(),
+            Measurement::RetainedBytes => stats.retained_profiled_bytes(),
+            Measurement::NumAllocations => stats.num_allocations(),
+            Measurement::LiveAllocations => stats.live_allocations(),
+        }
+    }
+}
+
+// z-score for a 95% confidence interval
+const Z_95: f64 = 1.96;
+
+/// An estimate of real (not just sampled) bytes, scaled up by the sampling ratio, together with the margin of
+/// its approximate 95% confidence interval.  Displays as eg `4294967296 ± 858993459`.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Estimate {
+    value: u64,
+    margin: u64,
+}
+
+impl Estimate {
+    /// Each allocation is sampled with probability p = 1/sampling_ratio, so the number of samples is
+    /// binomial with variance n(1-p).  The relative error of the estimate is thus about sqrt((1-p)/n).
+    pub(crate) fn from_samples(sampled_bytes: u64, num_samples: u64, sampling_ratio: u32) -> Self {
+        let ratio = sampling_ratio.max(1) as f64;
+        let value = sampled_bytes as f64 * ratio;
+        let margin = if num_samples == 0 {
+            0.0
+        } else {
+            Z_95 * value * ((1.0 - 1.0 / ratio) / num_samples as f64).sqrt()
+        };
+        Self {
+            value: value as u64,
+            margin: margin as u64,
+        }
+    }
+
+    /// The estimated number of bytes
+    pub fn value(&self) -> u64 {
+        self.value
+    }
+
+    /// Half the width of the 95% confidence interval
+    pub fn margin(&self) -> u64 {
+        self.margin
+    }
+
+    pub fn low(&self) -> u64 {
+        self.value.saturating_sub(self.margin)
+    }
+
+    pub fn high(&self) -> u64 {
+        self.value.saturating_add(self.margin)
+    }
+
+    /// True if the confidence intervals of the two estimates don't overlap, ie the difference between them is
+    /// unlikely to be just sampling noise
+    pub fn differs_from(&self, other: &Estimate) -> bool {
+        self.high() < other.low() || other.high() < self.low()
+    }
+}
+
+impl fmt::Display for Estimate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ± {}", self.value, self.margin)
+    }
+}
+
+// Fewer samples than this give low confidence in what a stack is charged with, and more than HIGH_CONFIDENCE_SAMPLES
+// high confidence
+const LOW_CONFIDENCE_SAMPLES: u64 = 10;
+const HIGH_CONFIDENCE_SAMPLES: u64 = 100;
+
+/// How far the bytes charged to a stack can be trusted, from its number of samples.  A stack backed by a handful
+/// of samples may just be an unlucky sample, rather than a real hot spot or leak.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Confidence {
+    /// Fewer than 10 samples
+    Low,
+    Medium,
+    /// More than 100 samples, or every allocation sampled
+    High,
+}
+
+impl Confidence {
+    pub(crate) fn from_samples(num_samples: u64, sampling_ratio: u32) -> Self {
+        if sampling_ratio <= 1 || num_samples > HIGH_CONFIDENCE_SAMPLES {
+            Confidence::High
+        } else if num_samples < LOW_CONFIDENCE_SAMPLES {
+            Confidence::Low
+        } else {
+            Confidence::Medium
+        }
+    }
+}
+
+impl fmt::Display for Confidence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Confidence::Low => "low",
+            Confidence::Medium => "medium",
+            Confidence::High => "high",
+        };
+        f.write_str(s)
+    }
+}
+
+/// Stats of all stacks whose first frame outside the standard library is the same, see
+/// `YingProfiler::top_k_by_user_frame`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FrameGroup {
+    /// Symbol name of the frame the stacks are grouped by, or `<unknown>` for stacks with no such frame
+    pub frame: String,
+    pub allocated_bytes: u64,
+    pub retained_bytes: u64,
+    pub num_allocations: u64,
+    pub live_allocations: u64,
+    /// Number of distinct stacks in this group
+    pub num_stacks: usize,
+}
+
+impl fmt::Display for FrameGroup {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} profiled bytes allocated, {} retained, from {} stacks: {}",
+            self.allocated_bytes, self.retained_bytes, self.num_stacks, self.frame
+        )
+    }
+}
+
+/// The stats of one stack with its frames resolved into symbol names, from `StackStats::into_owned`.  Unlike
+/// `StackStats`, it doesn't refer to the profiler's interned stacks or symbol map, so it can be kept, sent
+/// elsewhere or compared with stats from other processes and builds.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OwnedStackStats {
+    pub stack_hash: u64,
+    /// Symbol names of the stack's frames, including inlined symbols, innermost first
+    pub frames: Vec<String>,
     pub allocated_bytes: u64,
     pub num_allocations: u64,
     pub freed_bytes: u64,
     pub num_frees: u64,
+    pub retained_bytes: u64,
+    pub max_alloc_size: u64,
+    pub first_seen_millis: u64,
+    pub last_alloc_millis: u64,
+}
+
+impl OwnedStackStats {
+    /// The frames as one string, the same as `StackStats::canonical_key`
+    pub fn canonical_key(&self) -> String {
+        self.frames.join(";")
+    }
+}
+
+// Display of StackStats::log_record
+struct StackLogRecord<'s>(&'s StackStats);
+
+impl fmt::Display for StackLogRecord<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let stats = self.0;
+        write!(
+            f,
+            "stack_hash={:#x} allocated_bytes={} retained_bytes={} num_allocations={} live_allocations={} \
+             churn_ratio={:.3}",
+            stats.stack_hash,
+            stats.allocated_bytes,
+            stats.retained_profiled_bytes(),
+            stats.num_allocations,
+            stats.live_allocations(),
+            stats.churn_ratio()
+        )
+    }
+}
+
+/// Central struct collecting stats about each stack trace
+#[derive(Debug, Clone)]
+pub struct StackStats {
+    // The call stack, interned in the profiler's StackInterner
+    stack: StoredStack,
+    allocated_bytes: u64,
+    num_allocations: u64,
+    freed_bytes: u64,
+    num_frees: u64,
     hist: MillisHistogram,
+    recent_allocs: RateWindow,
+    retained_trend: TrendWindow,
+    // Sampled bytes allocated, decayed exponentially over time by the heat decay thread if it is running
+    heat: u64,
+    // Largest sampled allocation, including growth by realloc()
+    max_alloc_size: u64,
+    // Running mean and sum of squared deviations of sampled allocation sizes, by Welford's algorithm
+    size_mean: f64,
+    size_m2: f64,
+    // Incremented on every reset, so that allocations from before a reset can be told apart
+    generation: u32,
+    pre_reset_freed_bytes: u64,
+    pre_reset_num_frees: u64,
+    // Retained bytes of outstanding allocations marked with `YingProfiler::mark_intentional`
+    intentional_bytes: u64,
+    // Secondary hash of the stack, to detect other stacks with the same stack hash
+    #[cfg(not(any(
+        feature = "disabled",
+        not(any(target_os = "linux", target_os = "macos"))
+    )))]
+    fingerprint: u64,
+    // Hash of the stack, with the profiler's hash seed
+    stack_hash: u64,
+    // Millis since the epoch when the stack was first seen, and when it last made a sampled allocation, or 0
+    first_seen_millis: u64,
+    last_alloc_millis: u64,
     #[cfg(feature = "profile-spans")]
     span: tracing::Span,
 }
 
 impl StackStats {
     // Constructor not public.  Only this crate should create new stats.
-    pub(crate) fn new(stack: StdCallstack, initial_alloc_bytes: Option<u64>) -> Self {
+    #[cfg(any(
+        feature = "test-util",
+        not(any(
+            feature = "disabled",
+            not(any(target_os = "linux", target_os = "macos"))
+        ))
+    ))]
+    pub(crate) fn new(
+        stack: &StdCallstack,
+        stacks: &StackInterner,
+        stack_hash: u64,
+        initial_alloc_bytes: Option<u64>,
+    ) -> Self {
+        let mut recent_allocs = RateWindow::new();
+        let mut retained_trend = TrendWindow::default();
+        let now = now_millis();
+        if let Some(bytes) = initial_alloc_bytes {
+            recent_allocs.add_event(now);
+            retained_trend.record(now, bytes);
+        }
         Self {
-            stack,
+            #[cfg(not(any(
+                feature = "disabled",
+                not(any(target_os = "linux", target_os = "macos"))
+            )))]
+            fingerprint: stack.compute_fingerprint(),
+            stack: stacks.intern(stack),
+            stack_hash,
             allocated_bytes: initial_alloc_bytes.unwrap_or(0),
             num_allocations: initial_alloc_bytes.map(|_| 1).unwrap_or(0),
             freed_bytes: 0,
             num_frees: 0,
             hist: MillisHistogram::new(),
+            recent_allocs,
+            retained_trend,
+            heat: initial_alloc_bytes.unwrap_or(0),
+            size_mean: initial_alloc_bytes.unwrap_or(0) as f64,
+            size_m2: 0.0,
+            max_alloc_size: initial_alloc_bytes.unwrap_or(0),
+            generation: 0,
+            pre_reset_freed_bytes: 0,
+            pre_reset_num_frees: 0,
+            intentional_bytes: 0,
+            first_seen_millis: now,
+            last_alloc_millis: initial_alloc_bytes.map(|_| now).unwrap_or(0),
             #[cfg(feature = "profile-spans")]
             span: tracing::Span::current(),
         }
     }
 
-    /// Update stats when an allocation is freed
-    pub(crate) fn update_free_stats(&mut self, size: u64, alloc_time_ms: u64) {
+    /// Stats for a made up stack, as if one allocation of `allocated` bytes was made, of which only `retained`
+    /// bytes are still outstanding.  TESTING ONLY
+    #[cfg(feature = "test-util")]
+    pub(crate) fn synthetic(
+        stack: &StdCallstack,
+        stacks: &StackInterner,
+        stack_hash: u64,
+        allocated: u64,
+        retained: u64,
+    ) -> Self {
+        let mut stats = Self::new(stack, stacks, stack_hash, Some(allocated.max(retained)));
+        let freed = stats.allocated_bytes - retained;
+        if freed > 0 {
+            stats.num_frees = 1;
+            stats.freed_bytes = freed;
+        }
+        stats
+    }
+
+    /// Update stats when a new allocation is sampled for this stack
+    #[cfg(not(any(
+        feature = "disabled",
+        not(any(target_os = "linux", target_os = "macos"))
+    )))]
+    pub(crate) fn update_alloc_stats(&mut self, size: u64) {
+        self.num_allocations += 1;
+        self.allocated_bytes += size;
+        self.heat += size;
+        self.max_alloc_size = self.max_alloc_size.max(size);
+        let delta = size as f64 - self.size_mean;
+        self.size_mean += delta / self.num_allocations as f64;
+        self.size_m2 += delta * (size as f64 - self.size_mean);
+        let now = now_millis();
+        self.last_alloc_millis = now;
+        self.recent_allocs.add_event(now);
+        self.retained_trend
+            .record(now, self.retained_profiled_bytes());
+    }
+
+    /// Update stats when a sampled allocation is moved by realloc().  The bytes allocated follow the new size,
+    /// but the number of allocations and frees don't change.  Allocations from before the last reset are ignored.
+    #[cfg(not(any(
+        feature = "disabled",
+        not(any(target_os = "linux", target_os = "macos"))
+    )))]
+    pub(crate) fn update_realloc_stats(&mut self, old_size: u64, new_size: u64, generation: u32) {
+        if generation != self.generation {
+            return;
+        }
+        if new_size > old_size {
+            self.allocated_bytes += new_size - old_size;
+            self.heat += new_size - old_size;
+            self.max_alloc_size = self.max_alloc_size.max(new_size);
+        } else {
+            self.allocated_bytes = self.allocated_bytes.saturating_sub(old_size - new_size);
+        }
+        self.retained_trend
+            .record(now_millis(), self.retained_profiled_bytes());
+    }
+
+    /// Update stats when an allocation is freed.  Frees of allocations from before the last reset are counted
+    /// separately, so they don't skew the post-reset stats.
+    #[cfg(not(any(
+        feature = "disabled",
+        not(any(target_os = "linux", target_os = "macos"))
+    )))]
+    pub(crate) fn update_free_stats(&mut self, size: u64, alloc_time_ms: u64, generation: u32) {
+        if generation != self.generation {
+            self.pre_reset_num_frees += 1;
+            self.pre_reset_freed_bytes += size;
+            return;
+        }
         self.num_frees += 1;
         self.freed_bytes += size;
         self.hist.add_sample(alloc_time_ms);
+        self.retained_trend
+            .record(now_millis(), self.retained_profiled_bytes());
+    }
+
+    /// Update the retained bytes marked intentional when an outstanding allocation marked with
+    /// `YingProfiler::mark_intentional` changes size from `old_size` to `new_size`: 0 to its size when marked,
+    /// and to 0 when freed.  Allocations from before the last reset are ignored.
+    pub(crate) fn update_intentional_bytes(
+        &mut self,
+        old_size: u64,
+        new_size: u64,
+        generation: u32,
+    ) {
+        if generation != self.generation {
+            return;
+        }
+        self.intentional_bytes = (self.intentional_bytes + new_size).saturating_sub(old_size);
+    }
+
+    /// Zero out all stats, keeping only the stack itself and when it was active, and start a new generation
+    pub(crate) fn reset(&mut self) {
+        self.generation = self.generation.wrapping_add(1);
+        self.pre_reset_freed_bytes = 0;
+        self.pre_reset_num_frees = 0;
+        self.intentional_bytes = 0;
+        self.allocated_bytes = 0;
+        self.num_allocations = 0;
+        self.freed_bytes = 0;
+        self.num_frees = 0;
+        self.hist = MillisHistogram::new();
+        self.recent_allocs = RateWindow::new();
+        self.retained_trend = TrendWindow::default();
+        self.heat = 0;
+        self.size_mean = 0.0;
+        self.size_m2 = 0.0;
+        self.max_alloc_size = 0;
+    }
+
+    /// Scales the allocation heat down by `factor`, for one step of heat decay
+    pub(crate) fn decay_heat(&mut self, factor: f64) {
+        self.heat = (self.heat as f64 * factor) as u64;
+    }
+
+    /// Sampled bytes allocated, with older allocations counting exponentially less, as decayed by
+    /// `YingProfiler::decay_heat`, eg on the thread started by `YingProfiler::spawn_heat_decay`.  Without any
+    /// decay this equals `allocated_bytes()`.
+    pub fn allocation_heat(&self) -> u64 {
+        self.heat
+    }
+
+    /// Size of the largest sampled allocation from this stack, including sizes reached by realloc()
+    pub fn max_alloc_size(&self) -> u64 {
+        self.max_alloc_size
+    }
+
+    /// Mean size in bytes of the sampled allocations from this stack, as requested when allocated, ie not
+    /// following later realloc().  0 if there are none.
+    pub fn mean_alloc_size(&self) -> f64 {
+        self.size_mean
+    }
+
+    /// Standard deviation of the sizes of the sampled allocations from this stack.  A high value relative to the
+    /// mean means the stack allocates wildly varying sizes, eg from unbounded input, rather than many uniformly
+    /// sized allocations.
+    pub fn stddev_alloc_size(&self) -> f64 {
+        if self.num_allocations == 0 {
+            return 0.0;
+        }
+        (self.size_m2 / self.num_allocations as f64).sqrt()
+    }
+
+    /// Sampled allocations per second for this stack over the last 10 seconds.
+    /// Unlike the cumulative counters, this drops back to zero once a stack stops allocating, which tells apart
+    /// a stack that is actively allocating now from one that allocated a lot a long time ago.
+    pub fn recent_alloc_rate(&self) -> f64 {
+        self.recent_allocs.rate_per_sec(now_millis())
+    }
+
+    /// Whether retained bytes from this stack went up or down by more than 10% over the last 10 seconds,
+    /// ie if this stack is actively leaking right now
+    pub fn growth_trend(&self) -> Trend {
+        self.retained_trend
+            .trend(now_millis(), self.retained_profiled_bytes())
+    }
+
+    /// Millis since the epoch when this stack was first seen
+    pub fn first_seen_millis(&self) -> u64 {
+        self.first_seen_millis
+    }
+
+    /// Millis since the epoch when this stack last made a sampled allocation, or 0 if it never has.
+    /// A stack which still retains a lot but hasn't allocated in a long time is likely sitting on leaked memory.
+    pub fn last_alloc_millis(&self) -> u64 {
+        self.last_alloc_millis
+    }
+
+    /// Total sampled bytes allocated from this stack, including growth from realloc()
+    pub fn allocated_bytes(&self) -> u64 {
+        self.allocated_bytes
+    }
+
+    /// Number of sampled allocations from this stack
+    pub fn num_allocations(&self) -> u64 {
+        self.num_allocations
+    }
+
+    /// Total sampled bytes from this stack which have been freed
+    pub fn freed_bytes(&self) -> u64 {
+        self.freed_bytes
+    }
+
+    /// Number of sampled allocations from this stack which have been freed
+    pub fn num_frees(&self) -> u64 {
+        self.num_frees
+    }
+
+    /// Fraction of the sampled bytes allocated from this stack which have since been freed.  Near 1.0 means the
+    /// stack only makes temporaries; near 0.0 means nearly everything it allocates is retained, as a leak would.
+    /// 0.0 if there are no allocations.
+    pub fn churn_ratio(&self) -> f64 {
+        if self.allocated_bytes == 0 {
+            return 0.0;
+        }
+        (self.freed_bytes as f64 / self.allocated_bytes as f64).min(1.0)
+    }
+
+    /// Number of sampled allocations from this stack which have not been freed
+    pub fn live_allocations(&self) -> u64 {
+        self.num_allocations.saturating_sub(self.num_frees)
+    }
+
+    /// A single line of `key=value` pairs for log pipelines, eg to track the top stacks over time:
+    /// ```text
+    /// stack_hash=0x1f2e... allocated_bytes=4096 retained_bytes=1024 num_allocations=4 live_allocations=1 churn_ratio=0.750
+    /// ```
+    /// Byte and allocation counts are sampled, as in `write_stacks_jsonl`.  Keys are only ever added at the end, so
+    /// parsers can rely on the existing ones.
+    pub fn log_record(&self) -> impl fmt::Display + '_ {
+        StackLogRecord(self)
+    }
+
+    /// Bytes freed since the last reset from allocations made before it.  These are not in `freed_bytes()`.
+    pub fn pre_reset_freed_bytes(&self) -> u64 {
+        self.pre_reset_freed_bytes
+    }
+
+    /// Number of allocations made before the last reset which were freed after it
+    pub fn pre_reset_num_frees(&self) -> u64 {
+        self.pre_reset_num_frees
+    }
+
+    /// Number of times the stats of this stack have been reset
+    pub fn generation(&self) -> u32 {
+        self.generation
+    }
+
+    /// Histogram of how long freed allocations from this stack lived
+    pub fn lifetime_histogram(&self) -> &MillisHistogram {
+        &self.hist
+    }
+
+    /// Replaces the fingerprint with that of the frames actually hashed, when a hash frame filter left some out
+    #[cfg(not(any(
+        feature = "disabled",
+        not(any(target_os = "linux", target_os = "macos"))
+    )))]
+    pub(crate) fn with_fingerprint(mut self, fingerprint: u64) -> Self {
+        self.fingerprint = fingerprint;
+        self
+    }
+
+    /// Secondary hash of the stack, see `Callstack::compute_fingerprint`
+    #[cfg(not(any(
+        feature = "disabled",
+        not(any(target_os = "linux", target_os = "macos"))
+    )))]
+    pub(crate) fn fingerprint(&self) -> u64 {
+        self.fingerprint
+    }
+
+    /// The call stack these stats were collected for, from the profiler's interned stacks
+    pub(crate) fn stack(&self, stacks: &StackInterner) -> StdCallstack {
+        stacks.resolve(&self.stack)
+    }
+
+    /// Hash identifying this stack, as used by eg `YingProfiler::reset_stack`
+    pub fn stack_hash(&self) -> u64 {
+        self.stack_hash
+    }
+
+    /// A textual key for this stack made of its symbol names only, see [Callstack::canonical_key].
+    /// Unlike `stack_hash()`, this can be compared between snapshots from different builds.
+    pub fn canonical_key<A: GlobalAlloc>(&self, profiler: &YingProfiler<A>) -> String {
+        profiler.lock_out_profiler(|| {
+            let state = profiler.get_state();
+            profiler
+                .resolved_stack(state, self)
+                .canonical_key(&state.symbol_map)
+        })
+    }
+
+    /// A self contained copy of these stats, with the stack's frames resolved into symbol names, for keeping or
+    /// sending elsewhere without `profiler`, the one which recorded them
+    pub fn into_owned<A: GlobalAlloc>(self, profiler: &YingProfiler<A>) -> OwnedStackStats {
+        let frames = profiler.lock_out_profiler(|| {
+            let state = profiler.get_state();
+            profiler
+                .resolved_stack(state, &self)
+                .frame_names(&state.symbol_map)
+        });
+        OwnedStackStats {
+            stack_hash: self.stack_hash,
+            frames,
+            allocated_bytes: self.allocated_bytes,
+            num_allocations: self.num_allocations,
+            freed_bytes: self.freed_bytes,
+            num_frees: self.num_frees,
+            retained_bytes: self.retained_profiled_bytes(),
+            max_alloc_size: self.max_alloc_size,
+            first_seen_millis: self.first_seen_millis,
+            last_alloc_millis: self.last_alloc_millis,
+        }
     }
 
     /// The number of "retained" bytes as seen by this stack from sampling
@@ -299,27 +1478,59 @@ impl StackStats {
         self.allocated_bytes.saturating_sub(self.freed_bytes)
     }
 
+    /// Sampled retained bytes of outstanding allocations marked with `YingProfiler::mark_intentional`
+    pub fn intentionally_retained_bytes(&self) -> u64 {
+        self.intentional_bytes
+    }
+
+    /// Sampled retained bytes not marked as deliberately kept with `YingProfiler::mark_intentional`, ie
+    /// those which could be an accidental leak
+    pub fn leak_suspect_bytes(&self) -> u64 {
+        self.retained_profiled_bytes()
+            .saturating_sub(self.intentional_bytes)
+    }
+
+    /// Estimate of the real bytes allocated from this stack, given the profiler's sampling ratio
+    pub fn allocated_bytes_estimate(&self, sampling_ratio: u32) -> Estimate {
+        Estimate::from_samples(self.allocated_bytes, self.num_allocations, sampling_ratio)
+    }
+
+    /// Estimate of the real bytes retained from this stack, given the profiler's sampling ratio
+    pub fn retained_bytes_estimate(&self, sampling_ratio: u32) -> Estimate {
+        let outstanding = self.num_allocations.saturating_sub(self.num_frees);
+        Estimate::from_samples(self.retained_profiled_bytes(), outstanding, sampling_ratio)
+    }
+
+    /// How far the bytes charged to this stack can be trusted, from its number of sampled allocations.  With a
+    /// sampling ratio of 1 nothing is left to chance, so confidence is always high.
+    pub fn confidence(&self, sampling_ratio: u32) -> Confidence {
+        Confidence::from_samples(self.num_allocations, sampling_ratio)
+    }
+
     /// Create a rich multi-line report of this StackStats
     /// * profiler: The `&YING_ALLOC` or global static defined to enable this profiler
     /// * with_filenames - if True, include source filename in stack trace
     /// * expand_frame - if True, include inlined symbols for each frame in each stack trace
-    pub fn rich_report(
+    pub fn rich_report<A: GlobalAlloc>(
         &self,
-        profiler: &YingProfiler,
+        profiler: &YingProfiler<A>,
         with_filenames: bool,
         expand_frame: bool,
     ) -> String {
+        let bytes = |n| format_bytes(n, profiler.raw_byte_counts);
         let profiled_alloc_bytes = YingProfiler::profiled_bytes_allocated();
         let pct = (self.allocated_bytes as f64) * 100.0 / (profiled_alloc_bytes as f64);
         let mut report = format!(
-            "{} profiled bytes allocated ({pct:.2}%) ({} allocations)\n",
-            self.allocated_bytes, self.num_allocations
+            "{} profiled allocated ({pct:.2}%) ({} allocations)\n",
+            bytes(self.allocated_bytes),
+            self.num_allocations
         );
         let retained = self.retained_profiled_bytes();
         let _ = writeln!(
             &mut report,
-            "  {} profiled bytes retained  ({} frees)",
-            retained, self.num_frees
+            "  {} profiled retained  ({} frees)",
+            bytes(retained),
+            self.num_frees
         );
         let retained_pct_allocs = (retained as f64) * 100.0 / (self.allocated_bytes as f64);
         let retained_pct_all =
@@ -328,7 +1539,53 @@ impl StackStats {
             &mut report,
             "    ({retained_pct_all:.2}% of all retained profiled allocs) ({retained_pct_allocs:.2}% of allocated bytes)",
         );
+        let allocated_est = self.allocated_bytes_estimate(profiler.sampling_ratio());
+        let retained_est = self.retained_bytes_estimate(profiler.sampling_ratio());
+        let _ = writeln!(
+            &mut report,
+            "  est. {} ± {} allocated, {} ± {} retained (95% confidence)",
+            bytes(allocated_est.value),
+            bytes(allocated_est.margin),
+            bytes(retained_est.value),
+            bytes(retained_est.margin)
+        );
+        let _ = writeln!(
+            &mut report,
+            "  {} confidence, from {} samples at 1 in {}",
+            self.confidence(profiler.sampling_ratio()),
+            self.num_allocations,
+            profiler.sampling_ratio()
+        );
+        let _ = writeln!(
+            &mut report,
+            "  largest allocation {}, mean size {} (stddev {})",
+            bytes(self.max_alloc_size),
+            bytes(self.mean_alloc_size() as u64),
+            bytes(self.stddev_alloc_size() as u64)
+        );
         let _ = writeln!(&mut report, "  {}", self.hist);
+        let _ = writeln!(
+            &mut report,
+            "  {:.2} sampled allocations/sec over the last {}s, retained is {}",
+            self.recent_alloc_rate(),
+            RateWindow::window_secs(),
+            self.growth_trend()
+        );
+        let now = now_millis();
+        let _ = write!(
+            &mut report,
+            "  first seen {}s ago",
+            now.saturating_sub(self.first_seen_millis) / 1000
+        );
+        if self.last_alloc_millis > 0 {
+            let _ = writeln!(
+                &mut report,
+                ", last allocated {}s ago",
+                now.saturating_sub(self.last_alloc_millis) / 1000
+            );
+        } else {
+            let _ = writeln!(&mut report, ", never allocated");
+        }
 
         #[cfg(feature = "profile-spans")]
         if !self.span.is_disabled() {
@@ -338,12 +1595,22 @@ impl StackStats {
         // TODO: this won't be needed once we upgrade from dashmap to something which does atomic reads
         // Also try to make locking or accesses more fine grained
         profiler.lock_out_profiler(|| {
+            let state = profiler.get_state();
+            let stack = profiler.resolved_stack(state, self);
             let decorated_stack = if with_filenames {
-                self.stack
-                    .with_symbols_and_filename(&profiler.get_state().symbol_map, expand_frame)
+                stack.with_symbols_and_filename(&state.symbol_map, expand_frame)
+            } else {
+                stack.with_symbols(&state.symbol_map, expand_frame)
+            }
+            .with_header_hash(self.stack_hash);
+            let decorated_stack = match profiler.display_frame_filter {
+                Some(keep) => decorated_stack.with_frame_filter(keep),
+                None => decorated_stack,
+            };
+            let decorated_stack = if profiler.module_offsets {
+                decorated_stack.with_module_offsets()
             } else {
-                self.stack
-                    .with_symbols(&profiler.get_state().symbol_map, expand_frame)
+                decorated_stack
             };
             let _ = writeln!(&mut report, "{}", decorated_stack);
         });
@@ -355,18 +1622,23 @@ impl StackStats {
     /// DTrace reports always have inlining > turned off.
     ///
     /// * profiler: The `&YING_ALLOC` or global static defined to enable this profiler
-    /// * measurement - enum for what to measure, allocated bytes or retained bytes
-    pub fn dtrace_report(&self, profiler: &YingProfiler, measurement: Measurement) -> String {
-        let metric = match measurement {
-            Measurement::AllocatedBytes => self.allocated_bytes,
-            Measurement::RetainedBytes => self.retained_profiled_bytes(),
-        };
+    /// * measurement - enum for what to measure, eg allocated bytes, retained bytes or number of allocations
+    pub fn dtrace_report<A: GlobalAlloc>(
+        &self,
+        profiler: &YingProfiler<A>,
+        measurement: Measurement,
+    ) -> String {
+        let metric = measurement.of(self);
 
         let mut report = String::new();
         profiler.lock_out_profiler(|| {
-            let decorated_stack = self
-                .stack
-                .with_symbols_no_inline_header(&profiler.get_state().symbol_map);
+            let state = profiler.get_state();
+            let stack = profiler.resolved_stack(state, self);
+            let decorated_stack = stack.with_symbols_no_inline_header(&state.symbol_map);
+            let decorated_stack = match profiler.display_frame_filter {
+                Some(keep) => decorated_stack.with_frame_filter(keep),
+                None => decorated_stack,
+            };
             let _ = write!(&mut report, "{}", decorated_stack);
         });
 
@@ -374,3 +1646,30 @@ impl StackStats {
         report
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unresolved_symbol_names() {
+        assert_eq!(FriendlySymbol::unresolved(0x10, None).name(), "0x10");
+        let symbol = FriendlySymbol::unresolved(0x10, ModuleInfo::of(0x10, Some(0x8)));
+        assert_eq!(symbol.name(), "0x10 (module base 0x8, offset 0x8)");
+        assert_eq!(symbol.module_offset(), Some(0x8));
+        assert_eq!(symbol.module_name(), None);
+
+        let ip = test_unresolved_symbol_names as *const () as u64;
+        let symbol = FriendlySymbol::unresolved(ip, ModuleInfo::of(ip, None));
+        assert!(symbol.name().starts_with(&format!("{:#x} (", ip)));
+        #[cfg(any(target_os = "linux", target_os = "macos"))]
+        {
+            let module = symbol.module_name().unwrap();
+            let offset = symbol.module_offset().unwrap();
+            assert!(symbol
+                .name()
+                .ends_with(&format!("({}+{:#x})", module, offset)));
+        }
+        assert!(stringify_symbol(&symbol, true).contains("loaded at"));
+    }
+}
@@ -0,0 +1,76 @@
+//! A minimal C API, for non-Rust code in the same process to read Ying's top line numbers and trigger a report.
+//! Reports need to know which profiler is the global allocator, so register it first with
+//! [YingProfiler::register_capi].  See `include/ying_profiler.h` for the C declarations.
+use std::ffi::CStr;
+use std::fs::File;
+use std::io::{self, Write};
+use std::os::raw::{c_char, c_int};
+
+use super::*;
+use crate::utils::DUMP_TOP_K;
+
+// Object safe part of YingProfiler used by the C API, so it works for any inner allocator
+trait CapiProfiler: Sync {
+    fn write_report(&self, path: &str) -> io::Result<()>;
+}
+
+impl<A: GlobalAlloc + Sync> CapiProfiler for YingProfiler<A> {
+    fn write_report(&self, path: &str) -> io::Result<()> {
+        let mut f = File::create(path)?;
+        let (summary, top_stacks) = self.lock_out_profiler(|| {
+            let top_stacks: Vec<String> = self
+                .top_k_stacks_by_retained(DUMP_TOP_K)
+                .iter()
+                .map(|stats| stats.rich_report(self, false, false))
+                .collect();
+            (self.summary().to_string(), top_stacks)
+        });
+        writeln!(f, "{}", summary)?;
+        for report in &top_stacks {
+            writeln!(f, "---\n{}\n", report)?;
+        }
+        Ok(())
+    }
+}
+
+static CAPI_PROFILER: OnceCell<&'static dyn CapiProfiler> = OnceCell::new();
+
+impl<A: GlobalAlloc + Sync> YingProfiler<A> {
+    /// Makes this profiler the one reported on by `ying_dump_report`.  Only the first registration counts;
+    /// returns false if another profiler was already registered.
+    pub fn register_capi(&'static self) -> bool {
+        CAPI_PROFILER.set(self).is_ok()
+    }
+}
+
+/// Total bytes retained by all allocations, see `YingProfiler::total_retained_bytes`
+#[no_mangle]
+pub extern "C" fn ying_total_retained() -> usize {
+    YingProfiler::total_retained_bytes()
+}
+
+/// Bytes retained by sampled allocations, see `YingProfiler::profiled_bytes_retained`
+#[no_mangle]
+pub extern "C" fn ying_profiled_retained() -> usize {
+    YingProfiler::profiled_bytes_retained()
+}
+
+/// Writes a text report, a summary line followed by the top stacks by retained memory, to the file at `path`,
+/// a NUL terminated UTF-8 string.  Returns 0 on success, or -1 if no profiler was registered with
+/// `YingProfiler::register_capi`, `path` is null or not UTF-8, or the file could not be written.
+///
+/// # Safety
+/// `path` must be null or point to a NUL terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn ying_dump_report(path: *const c_char) -> c_int {
+    let Some(profiler) = CAPI_PROFILER.get() else {
+        return -1;
+    };
+    if path.is_null() {
+        return -1;
+    }
+    match CStr::from_ptr(path).to_str() {
+        Ok(path) if profiler.write_report(path).is_ok() => 0,
+        _ => -1,
+    }
+}
@@ -0,0 +1,70 @@
+//! A local control channel over a Unix domain socket, for tooling on the same host to read stats and profiles
+//! from a running process without an HTTP stack.
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::thread::JoinHandle;
+
+use log::warn;
+
+use super::*;
+
+// Number of stacks `top` reports when not given a number
+const DEFAULT_TOP_STACKS: usize = 10;
+
+impl<A: GlobalAlloc + Sync> YingProfiler<A> {
+    /// Spawns a thread which listens on a Unix domain socket at `path` and answers one text command per line:
+    /// * `top [n]` - reports of the top n stacks by retained bytes, 10 by default
+    /// * `retained` - the one line `summary()`
+    /// * `pprof` - a pprof profile of all stacks, as from `write_pprof`
+    /// * `dump` - all stacks as JSON Lines, as from `write_stacks_jsonl`
+    ///
+    /// Eg `echo top 5 | socat - UNIX-CONNECT:/tmp/ying.sock`.  Connections are served one at a time, and
+    /// profiling is locked out on the thread, so its own allocations don't show up in the profile.  Returns an
+    /// error if the socket can't be bound, eg if a file already exists at `path`.
+    pub fn serve_unix_socket(&'static self, path: impl AsRef<Path>) -> io::Result<JoinHandle<()>> {
+        let listener = self.lock_out_profiler(|| UnixListener::bind(path))?;
+        Ok(std::thread::spawn(move || {
+            self.lock_out_profiler(|| {
+                for stream in listener.incoming() {
+                    if let Err(e) = stream.and_then(|stream| self.serve_connection(stream)) {
+                        warn!("Ying: control socket connection failed: {}", e);
+                    }
+                }
+            })
+        }))
+    }
+}
+
+impl<A: GlobalAlloc> YingProfiler<A> {
+    fn serve_connection(&self, stream: UnixStream) -> io::Result<()> {
+        let mut w = io::BufWriter::new(stream.try_clone()?);
+        for line in BufReader::new(stream).lines() {
+            let line = line?;
+            let mut words = line.split_whitespace();
+            match (words.next(), words.next()) {
+                (None, _) => continue,
+                (Some("top"), n) => {
+                    let n = n.and_then(|n| n.parse().ok()).unwrap_or(DEFAULT_TOP_STACKS);
+                    for stats in self.top_k_stacks_by_retained(n) {
+                        writeln!(w, "---\n{}\n", stats.rich_report(self, false, false))?;
+                    }
+                }
+                (Some("retained"), _) => writeln!(w, "{}", self.summary())?,
+                (Some("pprof"), _) => {
+                    self.write_pprof(&mut w)?;
+                }
+                (Some("dump"), _) => {
+                    self.write_stacks_jsonl(&mut w)?;
+                }
+                (Some(command), _) => writeln!(
+                    w,
+                    "Unknown command {}, expected one of: top [n], retained, pprof, dump",
+                    command
+                )?,
+            }
+            w.flush()?;
+        }
+        Ok(())
+    }
+}
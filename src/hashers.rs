@@ -0,0 +1,117 @@
+//! Hashers for Ying's internal maps.  The keys of these maps are stack hashes, instruction pointers and
+//! allocation pointers, all u64s which don't need the DoS resistance of the default SipHash.
+use std::hash::Hasher;
+
+#[cfg(feature = "fast-hash")]
+pub(crate) type MapHasher = std::hash::BuildHasherDefault<FxU64Hasher>;
+#[cfg(not(feature = "fast-hash"))]
+pub(crate) type MapHasher = std::collections::hash_map::RandomState;
+
+/// Hasher for maps keyed by allocation pointers, which are already unique
+pub(crate) type PointerMapHasher = std::hash::BuildHasherDefault<PointerHasher>;
+
+// Multiplier from rustc's FxHasher
+#[cfg_attr(not(feature = "fast-hash"), allow(dead_code))]
+const FX_SEED: u64 = 0x51_7c_c1_b7_27_22_0a_95;
+
+/// A multiply-rotate hasher in the style of rustc's FxHasher, which is a single multiply per u64 key.
+/// Pointers have their low bits zeroed by alignment, and multiplying leaves those low bits zero, so the
+/// result is rotated at the end to give hash tables well distributed low bits too.
+/// `pub` only because it shows up in the map types of public methods; the module itself is private.
+#[cfg_attr(not(feature = "fast-hash"), allow(dead_code))]
+#[derive(Default, Clone, Copy)]
+pub struct FxU64Hasher {
+    hash: u64,
+}
+
+impl Hasher for FxU64Hasher {
+    #[inline]
+    fn write(&mut self, bytes: &[u8]) {
+        for chunk in bytes.chunks(8) {
+            let mut buf = [0u8; 8];
+            buf[..chunk.len()].copy_from_slice(chunk);
+            self.write_u64(u64::from_le_bytes(buf));
+        }
+    }
+
+    #[inline]
+    fn write_u64(&mut self, i: u64) {
+        self.hash = (self.hash.rotate_left(5) ^ i).wrapping_mul(FX_SEED);
+    }
+
+    #[inline]
+    fn finish(&self) -> u64 {
+        self.hash.rotate_left(26)
+    }
+}
+
+// Allocations are at least 8 byte aligned, so the lowest bits of pointers carry no information
+const POINTER_ALIGN_BITS: u32 = 3;
+// The top bits of a hash pick the dashmap shard and hashbrown's control tag, but are all zero in pointers.
+// Copying the low varying pointer bits up there spreads pointers across shards and tags.
+const POINTER_HIGH_SHIFT: u32 = 51;
+
+/// Almost an identity hasher for pointers: the pointer minus its alignment bits, with the low bits also copied
+/// into the high bits.  Much cheaper than SipHash for the hottest map, outstanding_allocs.
+#[derive(Default, Clone, Copy)]
+pub struct PointerHasher {
+    ptr: u64,
+}
+
+impl Hasher for PointerHasher {
+    #[inline]
+    fn write(&mut self, bytes: &[u8]) {
+        // Only u64 keys are expected, but fold anything else in rather than panic
+        for chunk in bytes.chunks(8) {
+            let mut buf = [0u8; 8];
+            buf[..chunk.len()].copy_from_slice(chunk);
+            self.ptr = self.ptr.rotate_left(5) ^ u64::from_le_bytes(buf);
+        }
+    }
+
+    #[inline]
+    fn write_u64(&mut self, i: u64) {
+        self.ptr = i;
+    }
+
+    #[inline]
+    fn finish(&self) -> u64 {
+        let bits = self.ptr >> POINTER_ALIGN_BITS;
+        bits ^ (bits << POINTER_HIGH_SHIFT)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use std::hash::{BuildHasher, BuildHasherDefault};
+
+    #[test]
+    fn test_fx_hasher_spreads_aligned_pointers() {
+        let hasher = BuildHasherDefault::<FxU64Hasher>::default();
+        // 16-byte aligned pointers should still land in many different low-bit buckets
+        let buckets: HashSet<u64> = (0..1024u64)
+            .map(|n| hasher.hash_one(0x7f00_0000_0000 + n * 16) & 0xff)
+            .collect();
+        assert!(buckets.len() > 128);
+        assert_eq!(hasher.hash_one(42u64), hasher.hash_one(42u64));
+    }
+
+    #[test]
+    fn test_pointer_hasher_spreads_low_and_high_bits() {
+        let hasher = PointerMapHasher::default();
+        let hashes: Vec<u64> = (0..1024u64)
+            .map(|n| hasher.hash_one(0x7f00_0000_0000 + n * 16))
+            .collect();
+        // Distinct pointers get distinct hashes
+        assert_eq!(hashes.iter().collect::<HashSet<_>>().len(), hashes.len());
+        // The low bits (table index), the bits just below the top 7 (dashmap shard) and the top 7 bits
+        // (control tag) all vary
+        let distinct =
+            |bits: fn(&u64) -> u64| hashes.iter().map(bits).collect::<HashSet<_>>().len();
+        assert!(distinct(|h| h & 0x7f) >= 64);
+        assert!(distinct(|h| (h >> 51) & 0x3f) >= 32);
+        assert!(distinct(|h| h >> 57) >= 16);
+    }
+}
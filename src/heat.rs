@@ -0,0 +1,36 @@
+//! Exponential decay of each stack's allocation heat, so that `top_k_by_recent_allocation` shows what is
+//! allocating now rather than what allocated the most since startup.
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use super::*;
+
+impl<A: GlobalAlloc + Sync> YingProfiler<A> {
+    /// Spawns a thread which every `interval` decays the allocation heat of every stack with `decay_heat`, so
+    /// that heat from allocations made `half_life` ago counts half as much as from allocations made now.  Each
+    /// step briefly locks each shard of the stack map, so `interval` should be on the order of seconds for
+    /// profiles with many stacks.  Call once per profiler; profiling is locked out on the thread.
+    pub fn spawn_heat_decay(
+        &'static self,
+        interval: Duration,
+        half_life: Duration,
+    ) -> JoinHandle<()> {
+        std::thread::spawn(move || loop {
+            std::thread::sleep(interval);
+            self.decay_heat(interval, half_life);
+        })
+    }
+}
+
+impl<A: GlobalAlloc> YingProfiler<A> {
+    /// Decays the allocation heat of every stack by `elapsed` worth of a `half_life`, for driving the decay from
+    /// an existing timer rather than the thread of `spawn_heat_decay`
+    pub fn decay_heat(&self, elapsed: Duration, half_life: Duration) {
+        let factor = 0.5f64.powf(elapsed.as_secs_f64() / half_life.as_secs_f64());
+        self.lock_out_profiler(|| {
+            for mut entry in self.get_state().stack_stats.iter_mut() {
+                entry.value_mut().decay_heat(factor);
+            }
+        });
+    }
+}
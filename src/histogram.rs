@@ -1,4 +1,5 @@
 use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering::Relaxed};
 
 // In terms of milliseconds
 const BUCKETS_MILLIS: &[u64] = &[1000, 5_000, 10_000, 30_000, 100_000, u64::MAX];
@@ -28,6 +29,10 @@ impl MillisHistogram {
         }
     }
 
+    #[cfg(not(any(
+        feature = "disabled",
+        not(any(target_os = "linux", target_os = "macos"))
+    )))]
     pub(crate) fn add_sample(&mut self, millis: u64) {
         self.count += 1;
         self.sum += millis;
@@ -63,3 +68,524 @@ impl fmt::Display for MillisHistogram {
         Ok(())
     }
 }
+
+const RATE_WINDOW_BUCKETS: usize = 10;
+const RATE_BUCKET_MILLIS: u64 = 1000;
+
+/// Rolling count of events over the last 10 seconds, kept in 1 second buckets.
+/// Stale buckets are cleared lazily when new events come in, and skipped when reading, so there is no
+/// background work needed to keep the window up to date.
+#[derive(Copy, Clone, Debug)]
+pub struct RateWindow {
+    counts: [u64; RATE_WINDOW_BUCKETS],
+    // Bucket number (epoch millis / RATE_BUCKET_MILLIS) of the latest bucket written to
+    last_bucket: u64,
+}
+
+impl Default for RateWindow {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RateWindow {
+    pub fn new() -> Self {
+        Self {
+            counts: [0; RATE_WINDOW_BUCKETS],
+            last_bucket: 0,
+        }
+    }
+
+    /// The length of the window in seconds
+    pub fn window_secs() -> u64 {
+        RATE_WINDOW_BUCKETS as u64 * RATE_BUCKET_MILLIS / 1000
+    }
+
+    #[cfg(any(
+        feature = "test-util",
+        not(any(
+            feature = "disabled",
+            not(any(target_os = "linux", target_os = "macos"))
+        ))
+    ))]
+    pub(crate) fn add_event(&mut self, now_millis: u64) {
+        let bucket = now_millis / RATE_BUCKET_MILLIS;
+        if bucket > self.last_bucket {
+            // Clear out any buckets we skipped over since the last event
+            let stale = (bucket - self.last_bucket).min(RATE_WINDOW_BUCKETS as u64);
+            for b in (bucket + 1 - stale)..=bucket {
+                self.counts[(b % RATE_WINDOW_BUCKETS as u64) as usize] = 0;
+            }
+            self.last_bucket = bucket;
+        }
+        // If the clock went backwards a little, just count it in the latest bucket
+        let bucket = bucket.min(self.last_bucket);
+        if self.last_bucket - bucket < RATE_WINDOW_BUCKETS as u64 {
+            self.counts[(bucket % RATE_WINDOW_BUCKETS as u64) as usize] += 1;
+        }
+    }
+
+    /// Number of events within the window ending at now_millis
+    pub fn count_in_window(&self, now_millis: u64) -> u64 {
+        let bucket = now_millis / RATE_BUCKET_MILLIS;
+        // Only buckets written since (bucket - window) are still valid
+        let oldest_valid = (bucket + 1).saturating_sub(RATE_WINDOW_BUCKETS as u64);
+        (oldest_valid..=bucket.min(self.last_bucket))
+            .filter(|b| self.last_bucket - b < RATE_WINDOW_BUCKETS as u64)
+            .map(|b| self.counts[(b % RATE_WINDOW_BUCKETS as u64) as usize])
+            .sum()
+    }
+
+    /// Average events per second within the window ending at now_millis
+    pub fn rate_per_sec(&self, now_millis: u64) -> f64 {
+        self.count_in_window(now_millis) as f64 / Self::window_secs() as f64
+    }
+}
+
+// A change in retained bytes of more than this fraction over the window counts as a trend
+const TREND_THRESHOLD: f64 = 0.1;
+
+/// Direction retained memory has moved over a recent window
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Trend {
+    Growing,
+    Stable,
+    Shrinking,
+}
+
+impl fmt::Display for Trend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Trend::Growing => "growing",
+            Trend::Stable => "stable",
+            Trend::Shrinking => "shrinking",
+        };
+        f.write_str(s)
+    }
+}
+
+/// Retained bytes over the last 10 seconds, as the first value recorded in each 1 second bucket.  Like
+/// RateWindow, it is only updated when events come in, and old buckets are skipped when reading.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct TrendWindow {
+    // (bucket number, retained bytes at first event in bucket)
+    samples: [(u64, u64); RATE_WINDOW_BUCKETS],
+}
+
+impl TrendWindow {
+    #[cfg(any(
+        feature = "test-util",
+        not(any(
+            feature = "disabled",
+            not(any(target_os = "linux", target_os = "macos"))
+        ))
+    ))]
+    pub(crate) fn record(&mut self, now_millis: u64, retained: u64) {
+        let bucket = now_millis / RATE_BUCKET_MILLIS;
+        let slot = &mut self.samples[(bucket % RATE_WINDOW_BUCKETS as u64) as usize];
+        if slot.0 != bucket {
+            *slot = (bucket, retained);
+        }
+    }
+
+    /// Compares `retained` now against the oldest value within the window.  Without any events in the
+    /// window, nothing is moving, so the trend is stable.
+    pub fn trend(&self, now_millis: u64, retained: u64) -> Trend {
+        let bucket = now_millis / RATE_BUCKET_MILLIS;
+        let oldest_valid = (bucket + 1).saturating_sub(RATE_WINDOW_BUCKETS as u64);
+        let oldest = self
+            .samples
+            .iter()
+            .filter(|(b, _)| *b >= oldest_valid && *b <= bucket && *b > 0)
+            .min_by_key(|(b, _)| *b);
+        let Some(&(_, then)) = oldest else {
+            return Trend::Stable;
+        };
+        let threshold = (then as f64 * TREND_THRESHOLD) as u64;
+        if retained > then + threshold {
+            Trend::Growing
+        } else if retained + threshold < then {
+            Trend::Shrinking
+        } else {
+            Trend::Stable
+        }
+    }
+}
+
+// In terms of nanoseconds: <1us, <10us, <100us, <1ms, <10ms, longer
+const BUCKETS_NANOS: &[u64] = &[1_000, 10_000, 100_000, 1_000_000, 10_000_000, u64::MAX];
+const NUM_NANOS_BUCKETS: usize = BUCKETS_NANOS.len();
+
+/// Histogram of short durations such as the time spent profiling a sampled allocation.
+/// Based on fixed buckets of <1us, <10us, <100us, <1ms, <10ms, longer
+#[derive(Copy, Clone, Debug, Default)]
+pub struct NanosHistogram {
+    counts: [u64; NUM_NANOS_BUCKETS],
+    sum: u64,
+    count: u64,
+}
+
+impl NanosHistogram {
+    pub fn average_nanos(&self) -> f64 {
+        self.sum as f64 / self.count as f64
+    }
+
+    /// Total number of durations recorded
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn counts(&self) -> [u64; NUM_NANOS_BUCKETS] {
+        self.counts
+    }
+}
+
+impl fmt::Display for NanosHistogram {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Histogram avg: {:.2}us {{",
+            self.average_nanos() / 1000.0
+        )?;
+        for (bucket, count) in BUCKETS_NANOS.iter().zip(self.counts) {
+            write!(f, "{}us: {}, ", *bucket / 1000, count)?;
+        }
+        write!(f, "}}")?;
+        Ok(())
+    }
+}
+
+/// Lock-free version of NanosHistogram which can live in a static and be updated from within the allocator.
+/// Use `snapshot()` to read it.
+pub(crate) struct AtomicNanosHistogram {
+    counts: [AtomicU64; NUM_NANOS_BUCKETS],
+    sum: AtomicU64,
+    count: AtomicU64,
+}
+
+impl AtomicNanosHistogram {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const ZERO: AtomicU64 = AtomicU64::new(0);
+
+    pub const fn new() -> Self {
+        Self {
+            counts: [Self::ZERO; NUM_NANOS_BUCKETS],
+            sum: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+        }
+    }
+
+    #[cfg(not(any(
+        feature = "disabled",
+        not(any(target_os = "linux", target_os = "macos"))
+    )))]
+    pub fn add_sample(&self, nanos: u64) {
+        self.count.fetch_add(1, Relaxed);
+        self.sum.fetch_add(nanos, Relaxed);
+        let index = BUCKETS_NANOS.partition_point(|&bucket| bucket < nanos);
+        if index < NUM_NANOS_BUCKETS {
+            self.counts[index].fetch_add(1, Relaxed);
+        }
+    }
+
+    pub fn snapshot(&self) -> NanosHistogram {
+        NanosHistogram {
+            counts: std::array::from_fn(|i| self.counts[i].load(Relaxed)),
+            sum: self.sum.load(Relaxed),
+            count: self.count.load(Relaxed),
+        }
+    }
+}
+
+/// Number of buckets in `YingProfiler::global_size_histogram`, one per power of two of allocation size
+pub const NUM_SIZE_BUCKETS: usize = 64;
+
+/// Lock-free counts of allocations by size, in power of two buckets: bucket n counts sizes from 2^(n-1) up to
+/// but not including 2^n, and bucket 0 zero sized allocations.  Cheap enough to update on every allocation.
+pub(crate) struct AtomicSizeHistogram {
+    counts: [AtomicU64; NUM_SIZE_BUCKETS],
+}
+
+impl AtomicSizeHistogram {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const ZERO: AtomicU64 = AtomicU64::new(0);
+
+    pub const fn new() -> Self {
+        Self {
+            counts: [Self::ZERO; NUM_SIZE_BUCKETS],
+        }
+    }
+
+    #[cfg(not(any(
+        feature = "disabled",
+        not(any(target_os = "linux", target_os = "macos"))
+    )))]
+    #[inline]
+    pub fn add(&self, size: usize) {
+        let index = (usize::BITS - size.leading_zeros()) as usize;
+        self.counts[index.min(NUM_SIZE_BUCKETS - 1)].fetch_add(1, Relaxed);
+    }
+
+    pub fn snapshot(&self) -> [u64; NUM_SIZE_BUCKETS] {
+        std::array::from_fn(|i| self.counts[i].load(Relaxed))
+    }
+}
+
+/// Coarse size category of an allocation: tiny under 64 bytes, small under 1 KiB, medium under 64 KiB, large
+/// under 1 MiB, and huge from 1 MiB up
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum SizeCategory {
+    Tiny,
+    Small,
+    Medium,
+    Large,
+    Huge,
+}
+
+pub const NUM_SIZE_CATEGORIES: usize = 5;
+
+// Smallest size of each category after Tiny
+const CATEGORY_MIN_SIZES: [usize; NUM_SIZE_CATEGORIES - 1] = [64, 1024, 64 * 1024, 1024 * 1024];
+
+impl SizeCategory {
+    pub const ALL: [SizeCategory; NUM_SIZE_CATEGORIES] = [
+        SizeCategory::Tiny,
+        SizeCategory::Small,
+        SizeCategory::Medium,
+        SizeCategory::Large,
+        SizeCategory::Huge,
+    ];
+
+    /// The category of an allocation of `size` bytes
+    #[inline]
+    pub fn of(size: usize) -> Self {
+        let index = CATEGORY_MIN_SIZES
+            .iter()
+            .take_while(|min| size >= **min)
+            .count();
+        Self::ALL[index]
+    }
+
+    /// Largest allocation size in this category, inclusive, or `u64::MAX` for huge
+    pub fn max_size(self) -> u64 {
+        CATEGORY_MIN_SIZES
+            .get(self as usize)
+            .map_or(u64::MAX, |min| *min as u64 - 1)
+    }
+}
+
+impl fmt::Display for SizeCategory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            SizeCategory::Tiny => "tiny",
+            SizeCategory::Small => "small",
+            SizeCategory::Medium => "medium",
+            SizeCategory::Large => "large",
+            SizeCategory::Huge => "huge",
+        };
+        f.write_str(s)
+    }
+}
+
+/// Sampled allocations of one size category, from `YingProfiler::category_breakdown()`.  Multiply byte counts by
+/// the sampling ratio for an estimate.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct CategoryStats {
+    pub category: SizeCategory,
+    /// Sampled bytes allocated in this category.  Reallocs don't count.
+    pub allocated_bytes: u64,
+    pub num_allocations: u64,
+    /// Retained bytes of outstanding sampled allocations in this category, by their current size
+    pub retained_bytes: u64,
+}
+
+impl fmt::Display for CategoryStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} sampled bytes allocated in {} allocations, {} retained",
+            self.category, self.allocated_bytes, self.num_allocations, self.retained_bytes
+        )
+    }
+}
+
+/// Lock-free sampled allocated and retained bytes by size category, cheap enough to update on every sample
+pub(crate) struct AtomicCategoryCounters {
+    allocated_bytes: [AtomicU64; NUM_SIZE_CATEGORIES],
+    num_allocations: [AtomicU64; NUM_SIZE_CATEGORIES],
+    retained_bytes: [AtomicU64; NUM_SIZE_CATEGORIES],
+}
+
+impl AtomicCategoryCounters {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const ZERO: AtomicU64 = AtomicU64::new(0);
+
+    pub const fn new() -> Self {
+        Self {
+            allocated_bytes: [Self::ZERO; NUM_SIZE_CATEGORIES],
+            num_allocations: [Self::ZERO; NUM_SIZE_CATEGORIES],
+            retained_bytes: [Self::ZERO; NUM_SIZE_CATEGORIES],
+        }
+    }
+
+    /// A sampled allocation, retained until `record_free` if `tracked`
+    #[cfg(not(any(
+        feature = "disabled",
+        not(any(target_os = "linux", target_os = "macos"))
+    )))]
+    #[inline]
+    pub fn record_alloc(&self, size: usize, tracked: bool) {
+        let category = SizeCategory::of(size) as usize;
+        self.allocated_bytes[category].fetch_add(size as u64, Relaxed);
+        self.num_allocations[category].fetch_add(1, Relaxed);
+        if tracked {
+            self.retained_bytes[category].fetch_add(size as u64, Relaxed);
+        }
+    }
+
+    #[inline]
+    pub fn record_free(&self, size: usize) {
+        self.retained_bytes[SizeCategory::of(size) as usize].fetch_sub(size as u64, Relaxed);
+    }
+
+    /// A tracked allocation resized by realloc, which may move it to another category
+    #[cfg(not(any(
+        feature = "disabled",
+        not(any(target_os = "linux", target_os = "macos"))
+    )))]
+    #[inline]
+    pub fn record_resize(&self, old_size: usize, new_size: usize) {
+        self.record_free(old_size);
+        self.retained_bytes[SizeCategory::of(new_size) as usize]
+            .fetch_add(new_size as u64, Relaxed);
+    }
+
+    /// Zeroes allocated bytes and counts.  Retained bytes are left to `record_free`.
+    pub fn reset_allocated(&self) {
+        for counter in self.allocated_bytes.iter().chain(&self.num_allocations) {
+            counter.store(0, Relaxed);
+        }
+    }
+
+    pub fn snapshot(&self) -> Vec<CategoryStats> {
+        SizeCategory::ALL
+            .iter()
+            .map(|&category| {
+                let i = category as usize;
+                CategoryStats {
+                    category,
+                    allocated_bytes: self.allocated_bytes[i].load(Relaxed),
+                    num_allocations: self.num_allocations[i].load(Relaxed),
+                    retained_bytes: self.retained_bytes[i].load(Relaxed),
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(all(
+    test,
+    not(any(
+        feature = "disabled",
+        not(any(target_os = "linux", target_os = "macos"))
+    ))
+))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_window_rolls_off_old_events() {
+        let mut window = RateWindow::new();
+        let start = 1_000_000_000;
+        for i in 0..20 {
+            window.add_event(start + i * 100);
+        }
+        assert_eq!(window.count_in_window(start + 2000), 20);
+        assert_eq!(window.rate_per_sec(start + 2000), 2.0);
+
+        // 9 seconds later only the events in the second bucket remain in the window
+        assert_eq!(window.count_in_window(start + 10_500), 10);
+
+        // Much later, everything has rolled off even without new events
+        assert_eq!(window.count_in_window(start + 60_000), 0);
+
+        // A new event after a long gap should not resurrect old counts
+        window.add_event(start + 60_000);
+        assert_eq!(window.count_in_window(start + 60_000), 1);
+    }
+
+    #[test]
+    fn test_atomic_nanos_histogram_buckets() {
+        let hist = AtomicNanosHistogram::new();
+        hist.add_sample(500);
+        hist.add_sample(1_000);
+        hist.add_sample(50_000);
+        hist.add_sample(20_000_000);
+
+        let snapshot = hist.snapshot();
+        assert_eq!(snapshot.count(), 4);
+        assert_eq!(snapshot.counts(), [2, 0, 1, 0, 0, 1]);
+        assert_eq!(snapshot.average_nanos(), 20_051_500.0 / 4.0);
+    }
+
+    #[test]
+    fn test_atomic_size_histogram_buckets() {
+        let hist = AtomicSizeHistogram::new();
+        for size in [0, 1, 2, 3, 4, 64, 127, usize::MAX] {
+            hist.add(size);
+        }
+
+        let counts = hist.snapshot();
+        assert_eq!(&counts[..4], &[1, 1, 2, 1]);
+        assert_eq!(counts[7], 2);
+        assert_eq!(counts[NUM_SIZE_BUCKETS - 1], 1);
+        assert_eq!(counts.iter().sum::<u64>(), 8);
+    }
+
+    #[test]
+    fn test_size_categories() {
+        assert_eq!(SizeCategory::of(0), SizeCategory::Tiny);
+        assert_eq!(SizeCategory::of(63), SizeCategory::Tiny);
+        assert_eq!(SizeCategory::of(64), SizeCategory::Small);
+        assert_eq!(SizeCategory::of(64 * 1024 - 1), SizeCategory::Medium);
+        assert_eq!(SizeCategory::of(1024 * 1024), SizeCategory::Huge);
+        assert_eq!(SizeCategory::Large.max_size(), 1024 * 1024 - 1);
+        assert_eq!(SizeCategory::Huge.max_size(), u64::MAX);
+
+        let counters = AtomicCategoryCounters::new();
+        counters.record_alloc(32, true);
+        counters.record_alloc(100, false);
+        counters.record_resize(32, 2000);
+        let stats = counters.snapshot();
+        assert_eq!(stats[0].num_allocations, 1);
+        assert_eq!(stats[0].retained_bytes, 0);
+        assert_eq!(stats[1].allocated_bytes, 100);
+        assert_eq!(stats[1].retained_bytes, 0);
+        assert_eq!(stats[2].retained_bytes, 2000);
+        counters.record_free(2000);
+        counters.reset_allocated();
+        assert!(counters
+            .snapshot()
+            .iter()
+            .all(|stats| stats.allocated_bytes == 0 && stats.retained_bytes == 0));
+    }
+
+    #[test]
+    fn test_trend_window() {
+        let mut window = TrendWindow::default();
+        let start = 1_000_000_000;
+        assert_eq!(window.trend(start, 1000), Trend::Stable);
+
+        window.record(start, 1000);
+        window.record(start + 500, 1100);
+        assert_eq!(window.trend(start + 600, 1050), Trend::Stable);
+        assert_eq!(window.trend(start + 3000, 2000), Trend::Growing);
+        assert_eq!(window.trend(start + 3000, 500), Trend::Shrinking);
+
+        // The first sample rolls off the window, so growth is measured from the later sample
+        window.record(start + 5000, 2000);
+        assert_eq!(window.trend(start + 12_000, 2100), Trend::Stable);
+        // And with no events at all in the window, nothing is changing
+        assert_eq!(window.trend(start + 60_000, 5000), Trend::Stable);
+    }
+}
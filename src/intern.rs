@@ -0,0 +1,168 @@
+//! Interned storage of the call stacks of `StackStats`.  Stacks are stored as paths in a tree of frames rooted
+//! at the outermost frame, so stacks which share outer frames, the norm in async code where every stack starts
+//! in the runtime, share their storage.  A stack is then just the id of its innermost node.
+#[cfg(any(
+    feature = "test-util",
+    not(any(
+        feature = "disabled",
+        not(any(target_os = "linux", target_os = "macos"))
+    ))
+))]
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use crate::callstack::StdCallstack;
+
+/// Most frames kept in the tree.  Once full, new stacks are stored inline instead, bounding the tree's memory.
+#[cfg(any(
+    feature = "test-util",
+    not(any(
+        feature = "disabled",
+        not(any(target_os = "linux", target_os = "macos"))
+    ))
+))]
+const MAX_INTERNED_FRAMES: usize = 1 << 20;
+
+/// Parent id of the outermost frames
+#[cfg(any(
+    feature = "test-util",
+    not(any(
+        feature = "disabled",
+        not(any(target_os = "linux", target_os = "macos"))
+    ))
+))]
+const ROOT: u32 = u32::MAX;
+
+/// The call stack of a `StackStats`, resolved back into a `StdCallstack` with `StackInterner::resolve`
+// Only stored by the profiling path, so without it the variants are never built
+#[cfg_attr(
+    not(any(
+        feature = "test-util",
+        not(any(
+            feature = "disabled",
+            not(any(target_os = "linux", target_os = "macos"))
+        ))
+    )),
+    allow(dead_code)
+)]
+#[derive(Debug, Clone)]
+pub(crate) enum StoredStack {
+    /// Id of the innermost frame in the frame tree, or `ROOT` for a stack with no frames
+    Interned(u32),
+    /// The whole stack, for stacks seen after the frame tree filled up
+    Inline(Box<StdCallstack>),
+}
+
+/// Tree of frames which stacks are interned into.  Frames are only ever added, so a `StoredStack` stays valid for
+/// the life of the profiler.
+#[derive(Default)]
+pub(crate) struct StackInterner {
+    tree: Mutex<FrameTree>,
+}
+
+#[derive(Default)]
+struct FrameTree {
+    // (parent id, IP) of each frame, indexed by frame id
+    frames: Vec<(u32, u64)>,
+    // (parent id, IP) -> frame id, to find existing frames
+    #[cfg(any(
+        feature = "test-util",
+        not(any(
+            feature = "disabled",
+            not(any(target_os = "linux", target_os = "macos"))
+        ))
+    ))]
+    ids: HashMap<(u32, u64), u32>,
+}
+
+impl StackInterner {
+    #[cfg(any(
+        feature = "test-util",
+        not(any(
+            feature = "disabled",
+            not(any(target_os = "linux", target_os = "macos"))
+        ))
+    ))]
+    /// Stores `stack`, adding only those of its frames which aren't already in the tree
+    pub(crate) fn intern(&self, stack: &StdCallstack) -> StoredStack {
+        let mut tree = self.tree.lock().unwrap_or_else(|e| e.into_inner());
+        let ips: Vec<u64> = stack.ips().collect();
+        let existing = ips
+            .iter()
+            .rev()
+            .scan(ROOT, |parent, ip| {
+                *parent = tree.ids.get(&(*parent, *ip)).copied()?;
+                Some(())
+            })
+            .count();
+        if tree.frames.len() + ips.len() - existing > MAX_INTERNED_FRAMES {
+            return StoredStack::Inline(Box::new(stack.clone()));
+        }
+
+        let mut id = ROOT;
+        for ip in ips.iter().rev() {
+            let FrameTree { frames, ids } = &mut *tree;
+            id = *ids.entry((id, *ip)).or_insert_with(|| {
+                frames.push((id, *ip));
+                (frames.len() - 1) as u32
+            });
+        }
+        StoredStack::Interned(id)
+    }
+
+    /// The full stack stored by `intern`
+    pub(crate) fn resolve(&self, stored: &StoredStack) -> StdCallstack {
+        match stored {
+            StoredStack::Interned(id) => {
+                let tree = self.tree.lock().unwrap_or_else(|e| e.into_inner());
+                let mut ips = Vec::new();
+                let mut id = *id;
+                while let Some(&(parent, ip)) = tree.frames.get(id as usize) {
+                    ips.push(ip);
+                    id = parent;
+                }
+                StdCallstack::from_ips(&ips)
+            }
+            StoredStack::Inline(stack) => (**stack).clone(),
+        }
+    }
+
+    /// Number of frames in the tree, ie the number of unique (outer stack, IP) pairs
+    pub(crate) fn num_frames(&self) -> usize {
+        self.tree
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .frames
+            .len()
+    }
+}
+
+#[cfg(all(
+    test,
+    not(any(
+        feature = "disabled",
+        not(any(target_os = "linux", target_os = "macos"))
+    ))
+))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stacks_share_outer_frames() {
+        let interner = StackInterner::default();
+        let a = StdCallstack::from_ips(&[10, 3, 2, 1]);
+        let b = StdCallstack::from_ips(&[20, 3, 2, 1]);
+        let stored_a = interner.intern(&a);
+        assert_eq!(interner.num_frames(), 4);
+        let stored_b = interner.intern(&b);
+        assert_eq!(interner.num_frames(), 5);
+        // Interning again adds nothing
+        interner.intern(&a);
+        assert_eq!(interner.num_frames(), 5);
+
+        assert_eq!(interner.resolve(&stored_a).compute_hash(), a.compute_hash());
+        assert_eq!(interner.resolve(&stored_b).compute_hash(), b.compute_hash());
+        let unknown = interner.intern(&StdCallstack::unknown());
+        assert!(interner.resolve(&unknown).is_unknown());
+    }
+}
@@ -7,20 +7,52 @@
 //!
 //! Features:
 //! * Sampling profiler, so it uses little enough resources to be useful in production
+//!   - Sampling options: `with_sampling_jitter` and `with_sampling_seed`, `with_adaptive_sampling` to sample more
+//!     densely during allocation spikes, `with_cpu_budget_ppm` and `with_max_samples_per_sec` to cap profiling CPU
+//!   - Bounded profiler memory with `with_max_outstanding_allocs` and `with_max_profiler_memory_bytes`, watched with
+//!     `stack_cardinality_report` and `profiler_memory_estimate`.  Stacks are interned in a tree of frames.
+//!   - Cheaper stack capture with `with_frame_pointer_unwinding`, and `with_depth_budget` for small allocations
+//!   - Overhead and accounting checks: `sampling_latency_histogram`, `sampling_stats`, `config` and `self_check`
 //! * Track retained memory, including reallocs, as well as total allocations
 //! * Targets async Rust programs (especially Tokio apps that use tracing for instrumentation), so you know the stack
 //!   traces will be useful
 //!   - Specific support for tracing spans and finding allocations by span
 //!   - Removes extra `::poll::` lines in the stack trace for clarity
+//!   - With feature `tokio`, `top_k_tasks_by_retained` by Tokio task, and `YingProfiler::spawn_blocking` to stitch
+//!     the spawning stack onto allocations made in the blocking pool
 //! * Support for detecting leaks or large amounts of allocated memory that has not been freed
 //!   - Tracks realloc() calls as single long-lived allocation
-//! * Automatic and easy flamegraph generation
-//! * Allocation lifetime/length histogram
+//!   - Leak-like stacks by `StackStats::churn_ratio` with `top_k_by_retention`, allocations which stayed live with
+//!     `capture_live` and `survivors`, and growth since the last release with `with_baseline` and
+//!     `top_movers_vs_baseline`
+//!   - `assert_no_growth` in tests, and `outstanding_report` to audit every outstanding allocation at shutdown
+//!   - `mark_intentional` for deliberate leaks, `with_ignored_allocations` for arenas, `with_excluded_threads` for
+//!     background threads and `with_stack_allowlist` to focus on one subsystem
+//!   - `lookup_allocation` finds the stack of a live allocation by pointer, eg one found in a debugger
+//! * Automatic and easy flamegraph generation, weighted by allocated or retained bytes, or by number of allocations
+//!   or live allocations
+//! * Allocation lifetime/length histogram, and `global_size_histogram`, `category_breakdown`,
+//!   `retained_by_size_class` and `retained_by_generation` to split memory by size and age
 //! * Track span information (need feature profile_spans) in stacks
 //! * Get top stack traces by total allocation
 //! * Get top traces by retained allocation
+//!   - Or with `top_k_by` on any criteria, `top_k_by_recent_allocation` using allocation heat decayed by
+//!     `decay_heat` or `spawn_heat_decay`, and `top_movers` over intervals rolled by `roll_top_movers_interval` or
+//!     `spawn_top_movers_tracker`
+//!   - Grouped by the first line of your code with `top_k_by_user_frame`, or by the code calling into a collection
+//!     with `top_k_by_allocation_site`.  `stacks_matching` finds stacks through a module or function.
+//!   - Confidence intervals and a low, medium or high confidence for each stack, and per stack size statistics
+//! * Capture an incident window with `with_explicit_arming`, `arm()` and `disarm()`, measure a block of code with
+//!   `begin_session`, or label allocations with `ying_alloc_scope!`.  `clear_stats` starts the profile over.
+//! * Reports and exports: `summary`, `dump_all` and `dump_all_gzip` (feature `gzip`), `write_pprof`,
+//!   `stream_pprof_to`, `spawn_pprof_pusher`, `write_stacks_jsonl` and `snapshot`, `register_otel` (feature `otel`),
+//!   `serve_unix_socket`, and a C API (feature `capi`)
+//!   - Offline symbolization with `export_symbol_map` and `with_module_offsets`.  Frames without symbols are shown
+//!     with their module offset.  `with_lazy_symbolization` and `with_symbolizer` change how frames are resolved.
 //! * `ProfilerRunner` -- utility to spin up thread to dump out reports and optionally flamegraphs every N minutes when
 //!   total memory usage changes significantly
+//! * `on_memory_pressure` calls back when retained memory nears a limit, eg from `utils::detect_memory_limit`, and
+//!   `on_alloc_failure` when the inner allocator fails
 //! * Catch and prevent single allocations greater than say 64GB, dump out giant allocation stack trace
 //!
 //! To see an example which uses Ying and dumps out top stack traces by allocations:
@@ -45,6 +77,24 @@
 //! than 10%.  Options include a path to write out the text reports to, and if inlined stack frames should be expanded
 //! in the reports.
 //!
+//! ## Wrapping another allocator
+//!
+//! To profile on top of a different allocator such as jemalloc or mimalloc, wrap it with `new_with_allocator`:
+//!
+//! ```rust,ignore
+//! use tikv_jemallocator::Jemalloc;
+//! use ying_profiler::YingProfiler;
+//!
+//! #[global_allocator]
+//! static YING_ALLOC: YingProfiler<Jemalloc> =
+//!     YingProfiler::new_with_allocator(Jemalloc, 500, 64 * 1024 * 1024 * 1024);
+//! ```
+//!
+//! Ying's stack and symbol maps are always backed by the System allocator, through a custom dashmap.  Its other
+//! bookkeeping allocations such as symbol names go through the wrapped allocator with profiling locked out for the
+//! current thread, so Ying never profiles itself.  Global counters such as `YingProfiler::total_retained_bytes()`
+//! work the same no matter which allocator is wrapped.
+//!
 //! ## Why a new memory profiler?
 //!
 //! Rust as an ecosystem is lacking in good memory profiling tools.  [Bytehound](https://github.com/koute/bytehound)
@@ -80,19 +130,104 @@
 //! used with `tracing_subscriber` due to a problem with `current_span()` allocating and potentially causing a
 //! RefCell `borrow()` to fail.
 //!
+//! With the `tracing-logs` feature, Ying's own warnings such as denied giant allocations are emitted as
+//! `tracing::warn!` events with structured fields (`size`, `stack_hash`, `stack`) instead of being printed to stdout.
+//! The event is emitted with profiling locked out, so the subscriber's allocations are not profiled.
+//!
+//! ## Disabling profiling
+//!
+//! Enabling the `disabled` feature compiles out all profiling.  `YingProfiler` stays usable as the
+//! `#[global_allocator]` but becomes a straight passthrough to the inner allocator, with no counters, no sampling
+//! and no giant allocation checks.  All query functions return zeroes or empty results.
+//!
+//! Profiling is only supported on Linux and macOS.  On other targets `YingProfiler` is the same passthrough, so that
+//! cross-platform builds keep working, and a warning is logged the first time the profile is queried.
+//! `YingProfiler::is_passthrough()` tells whether profiling is compiled out.
+//!
 use std::alloc::{GlobalAlloc, Layout, System};
-use std::fmt::Write;
-use std::sync::atomic::{AtomicUsize, Ordering::Relaxed, Ordering::SeqCst};
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::fmt::{self, Write};
+#[cfg(not(any(
+    feature = "disabled",
+    not(any(target_os = "linux", target_os = "macos"))
+)))]
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering::Relaxed, Ordering::SeqCst};
+use std::sync::Arc;
+#[cfg(not(any(
+    feature = "disabled",
+    not(any(target_os = "linux", target_os = "macos"))
+)))]
+use std::sync::Mutex;
+#[cfg(not(any(
+    feature = "disabled",
+    not(any(target_os = "linux", target_os = "macos"))
+)))]
+use std::time::Instant;
 
+#[cfg(not(any(
+    feature = "disabled",
+    not(any(target_os = "linux", target_os = "macos"))
+)))]
 use backtrace::Backtrace;
 use coarsetime::Clock;
 use dashmap::DashMap;
 use once_cell::sync::OnceCell;
 
+pub mod baseline;
+mod budget;
 pub mod callstack;
+#[cfg(feature = "capi")]
+pub mod capi;
+#[cfg(unix)]
+mod control;
+mod hashers;
+mod heat;
 pub mod histogram;
+mod intern;
+mod movers;
+pub mod numa;
+#[cfg(feature = "otel")]
+mod otel;
+mod pprof;
+mod pressure;
+mod resolver;
+mod sampling;
+pub mod scope;
+pub mod selfcheck;
+pub mod session;
+pub mod snapshot;
+pub mod survivors;
+#[cfg(feature = "tokio")]
+pub mod tasks;
+#[cfg(not(any(
+    feature = "disabled",
+    not(any(target_os = "linux", target_os = "macos"))
+)))]
+mod unwind;
 pub mod utils;
-use callstack::{FriendlySymbol, StackStats, StdCallstack};
+use budget::MemoryBudget;
+use callstack::{
+    BacktraceSymbolizer, FrameGroup, FriendlySymbol, Measurement, OwnedStackStats, StackStats,
+    StdCallstack, Symbolizer, DEFAULT_STACK_HASH_SEED, MAX_NUM_FRAMES,
+};
+/// The hasher of the outstanding allocations map, for benchmarking it against the alternatives
+#[cfg(feature = "test-util")]
+pub use hashers::PointerHasher;
+use hashers::{MapHasher, PointerMapHasher};
+use histogram::{
+    AtomicCategoryCounters, AtomicNanosHistogram, AtomicSizeHistogram, CategoryStats,
+    NanosHistogram, NUM_SIZE_BUCKETS,
+};
+use numa::CpuCounters;
+use pressure::MemoryPressure;
+#[cfg(not(any(
+    feature = "disabled",
+    not(any(target_os = "linux", target_os = "macos"))
+)))]
+use sampling::thread_cpu_nanos;
+use sampling::{AdaptiveSampler, CpuBudget};
 
 /// The number of frames at the top of the stack to skip.  Most of these have to do with
 /// backtrace and this profiler infrastructure.  This number needs to be adjusted
@@ -100,19 +235,98 @@ use callstack::{FriendlySymbol, StackStats, StdCallstack};
 /// For release/bench builds with debug = 1 / strip = none, this should be 4.
 /// For debug builds, this is about 9.
 const TOP_FRAMES_TO_SKIP: usize = 3;
+/// The number of frames of the profiler's own to skip when walking frame pointers, which unlike a `Backtrace`
+/// doesn't include the frame of the function doing the capture
+#[cfg(not(any(
+    feature = "disabled",
+    not(any(target_os = "linux", target_os = "macos"))
+)))]
+const FP_FRAMES_TO_SKIP: usize = TOP_FRAMES_TO_SKIP - 1;
 
 const DEFAULT_GIANT_ALLOC_LIMIT: usize = 64 * 1024 * 1024 * 1024;
+// Minimum time between warnings about giant allocations from the same stack
+#[cfg(not(any(
+    feature = "disabled",
+    not(any(target_os = "linux", target_os = "macos"))
+)))]
+const GIANT_ALLOC_WARNING_INTERVAL_MILLIS: u64 = 1000;
 
-// A map for caching symbols in backtraces so we can mostly store u64's
-type SymbolMap = DashMap<u64, Vec<FriendlySymbol>>;
+// A map for caching symbols in backtraces so we can mostly store u64's.  Symbols are shared so that reports can
+// take cheap copies of them, rather than holding the map locked while formatting.
+type SymbolMap = DashMap<u64, Arc<[FriendlySymbol]>, MapHasher>;
 
 /// Ying is a memory profiling Allocator wrapper.
 /// Ying is the Chinese word for an eagle.
-pub struct YingProfiler {
+///
+/// Allocations are passed on to the inner allocator `A`, which is the `System` allocator unless created with
+/// `new_with_allocator`.
+pub struct YingProfiler<A: GlobalAlloc = System> {
+    /// The allocator which actually hands out memory
+    inner: A,
     /// Allocation sampling ratio.  Eg: 500 means 1 in 500 allocations are sampled.
     sampling_ratio: u32,
+    /// Optionally samples more densely when the allocation rate spikes
+    adaptive: AdaptiveSampler,
+    /// Optionally samples less densely when profiling uses too much CPU
+    cpu_budget: CpuBudget,
     /// Prevent and dump stack trace for giant single allocations beyond a certain size
     single_alloc_limit: usize,
+    /// If non-empty, only stacks with a frame whose symbol starts with one of these prefixes are recorded
+    stack_allowlist: &'static [&'static str],
+    /// Threads whose names start with one of these prefixes are never sampled
+    excluded_threads: &'static [&'static str],
+    /// Allocations, by address and size, which are never sampled
+    ignore_allocation: Option<fn(*const u8, usize) -> bool>,
+    /// Extra symbol prefixes of pass-through functions for `top_k_by_allocation_site`
+    pass_through_frames: &'static [&'static str],
+    /// Frames whose symbol this rejects are left out of stack hashes, but still displayed
+    hash_frame_filter: Option<fn(&str) -> bool>,
+    /// Frames whose symbol this rejects are left out of text reports, but still hashed
+    display_frame_filter: Option<fn(&str) -> bool>,
+    /// Resolves the IPs of sampled stacks into symbols
+    symbolizer: &'static dyn Symbolizer,
+    /// If not 0, symbols of new stacks are not resolved when sampled, but for this many top stacks in the
+    /// background and for other stacks when reported
+    lazy_symbols_top_n: usize,
+    /// Profile written by `write_stacks_jsonl` to compare against in `top_movers_vs_baseline`
+    baseline_path: Option<&'static str>,
+    /// Capture sampled stacks by walking frame pointers instead of a DWARF unwind, where possible
+    frame_pointers: bool,
+    /// Sampled allocations smaller than this only capture the innermost `shallow_stack_frames` frames
+    full_depth_min_size: usize,
+    shallow_stack_frames: usize,
+    /// Show the module and offset of each frame in text reports
+    module_offsets: bool,
+    /// Time each sampled allocation and record it in the sampling latency histogram
+    time_sampling: bool,
+    /// Maximum number of entries in outstanding_allocs
+    max_outstanding_allocs: usize,
+    /// Most samples per second on each thread, or u64::MAX for no limit
+    max_samples_per_sec: u64,
+    /// Count sampled allocations by the CPU they were made on
+    track_cpus: bool,
+    /// Randomize the number of allocations between samples around the sampling ratio
+    sampling_jitter: bool,
+    /// Seed for the per thread sampling jitter RNG, or None to seed from the time
+    sampling_seed: Option<u64>,
+    /// Check a secondary hash of each sampled stack against the recorded stack with the same hash
+    check_collisions: bool,
+    /// Print byte counts in reports as raw numbers rather than KiB/MiB/GiB
+    raw_byte_counts: bool,
+    /// Seed for stack hashes, to namespace them by service
+    stack_hash_seed: u64,
+    /// Callback for when total retained memory nears a limit
+    pressure: MemoryPressure,
+    /// Budget for the profiler's own memory, and whether it is shedding load to stay within it
+    budget: MemoryBudget,
+    /// Whether allocations are sampled and frees tracked.  Cleared by `disarm`, or from the start with
+    /// `with_explicit_arming`.
+    armed: AtomicBool,
+    /// Sample reallocs of allocations which aren't tracked, as new allocations
+    sample_untracked_reallocs: bool,
+    /// Generation of the whole profile, recorded with each outstanding allocation.  Bumped by `arm`,
+    /// `reset_stack` and `next_generation`.
+    generation: AtomicU32,
     /// Global thread local state cache
     tl_cache: YingLocalCache,
     /// Statistics... lazily initialized later
@@ -120,27 +334,47 @@ pub struct YingProfiler {
 }
 
 static TOTAL_RETAINED: AtomicUsize = AtomicUsize::new(0);
+static PEAK_RETAINED: AtomicUsize = AtomicUsize::new(0);
 static PROFILED_ALLOCATED: AtomicUsize = AtomicUsize::new(0);
 static PROFILED_RETAINED: AtomicUsize = AtomicUsize::new(0);
+static UNKNOWN_STACK_SAMPLES: AtomicUsize = AtomicUsize::new(0);
+static UNTRACKED_OUTSTANDING: AtomicUsize = AtomicUsize::new(0);
+static FREED_WITHOUT_ALLOC: AtomicUsize = AtomicUsize::new(0);
+static DENIED_GIANT_ALLOCS: AtomicUsize = AtomicUsize::new(0);
+static FAILED_ALLOCS: AtomicUsize = AtomicUsize::new(0);
+static THROTTLED_SAMPLES: AtomicUsize = AtomicUsize::new(0);
+static IGNORED_SAMPLES: AtomicUsize = AtomicUsize::new(0);
+static STACK_HASH_COLLISIONS: AtomicUsize = AtomicUsize::new(0);
+#[cfg(not(any(
+    feature = "disabled",
+    not(any(target_os = "linux", target_os = "macos"))
+)))]
+static NEXT_ALLOC_ID: AtomicU64 = AtomicU64::new(0);
+static SAMPLING_DECISIONS: AtomicUsize = AtomicUsize::new(0);
+static SAMPLED_ALLOCS: AtomicUsize = AtomicUsize::new(0);
+static REENTRANT_SKIPS: AtomicUsize = AtomicUsize::new(0);
+static RATIO_SKIPS: AtomicUsize = AtomicUsize::new(0);
+static SAMPLING_LATENCY: AtomicNanosHistogram = AtomicNanosHistogram::new();
+static ALLOC_SIZES: AtomicSizeHistogram = AtomicSizeHistogram::new();
+static SIZE_CATEGORIES: AtomicCategoryCounters = AtomicCategoryCounters::new();
 
 impl YingProfiler {
     /// sampling_ratio: number of allocations for every sampled allocation
     pub const fn new(sampling_ratio: u32, single_alloc_limit: usize) -> Self {
-        Self {
-            sampling_ratio,
-            single_alloc_limit,
-            tl_cache: YingLocalCache::new(),
-            state: OnceCell::new(),
-        }
+        Self::new_with_allocator(System, sampling_ratio, single_alloc_limit)
     }
 
     pub const fn default() -> Self {
-        Self {
-            sampling_ratio: 500,
-            single_alloc_limit: DEFAULT_GIANT_ALLOC_LIMIT,
-            tl_cache: YingLocalCache::new(),
-            state: OnceCell::new(),
-        }
+        Self::new_with_allocator(System, 500, DEFAULT_GIANT_ALLOC_LIMIT)
+    }
+
+    /// True if profiling is compiled out, with the `disabled` feature or on a target other than Linux and macOS,
+    /// leaving a passthrough to the inner allocator whose query functions all return zeroes or empty results
+    pub const fn is_passthrough() -> bool {
+        cfg!(any(
+            feature = "disabled",
+            not(any(target_os = "linux", target_os = "macos"))
+        ))
     }
 
     /// Total outstanding retained bytes (not just sampled but all allocations)
@@ -149,6 +383,12 @@ impl YingProfiler {
         TOTAL_RETAINED.load(Relaxed)
     }
 
+    /// Highest value of `total_retained_bytes()` seen since startup
+    #[inline]
+    pub fn peak_retained_bytes() -> usize {
+        PEAK_RETAINED.load(Relaxed)
+    }
+
     /// Total bytes allocated for profiled allocations
     #[inline]
     pub fn profiled_bytes_allocated() -> usize {
@@ -161,6 +401,468 @@ impl YingProfiler {
         PROFILED_RETAINED.load(Relaxed)
     }
 
+    /// Number of sampled allocations whose backtrace was too short to be useful.  These are all recorded under
+    /// a single "unknown" stack, so this tells how much of the profile could not be attributed.
+    #[inline]
+    pub fn unknown_stack_samples() -> usize {
+        UNKNOWN_STACK_SAMPLES.load(Relaxed)
+    }
+
+    /// Number of sampled allocations which were not tracked as outstanding because the limit set by
+    /// `with_max_outstanding_allocs` was reached
+    #[inline]
+    pub fn untracked_outstanding_allocs() -> usize {
+        UNTRACKED_OUTSTANDING.load(Relaxed)
+    }
+
+    /// Bytes freed which were never counted as allocated, and so were not subtracted from
+    /// `total_retained_bytes()`.  These are normally allocations made before the profiler was installed, eg
+    /// through `new_with_allocator` with an allocator already in use, and then freed through the profiler.
+    /// Allocations made before Ying's internal state is initialized are counted normally and are not included.
+    #[inline]
+    pub fn freed_without_alloc_bytes() -> usize {
+        FREED_WITHOUT_ALLOC.load(Relaxed)
+    }
+
+    /// Number of allocations denied for being over the single allocation limit.  Every denial is counted, even
+    /// though warnings for repeated denials from the same stack are rate limited.
+    #[inline]
+    pub fn denied_giant_allocs() -> usize {
+        DENIED_GIANT_ALLOCS.load(Relaxed)
+    }
+
+    /// Number of allocations the inner allocator failed, ie returned null for, such as on running out of memory.
+    /// See `on_alloc_failure` for a callback on each failure.
+    #[inline]
+    pub fn failed_allocs() -> usize {
+        FAILED_ALLOCS.load(Relaxed)
+    }
+
+    /// Number of allocations which were due to be sampled but were skipped by the limit set with
+    /// `with_max_samples_per_sec`
+    #[inline]
+    pub fn throttled_samples() -> usize {
+        THROTTLED_SAMPLES.load(Relaxed)
+    }
+
+    /// Number of allocations which were due to be sampled but were skipped by the predicate set with
+    /// `with_ignored_allocations`
+    #[inline]
+    pub fn ignored_samples() -> usize {
+        IGNORED_SAMPLES.load(Relaxed)
+    }
+
+    /// Number of sampled allocations whose stack had the same hash as a different, already recorded stack, and
+    /// so were counted against the wrong stack.  Always 0 unless checking is enabled with `with_collision_check`.
+    #[inline]
+    pub fn stack_hash_collisions() -> usize {
+        STACK_HASH_COLLISIONS.load(Relaxed)
+    }
+
+    /// Counts of sampling decisions since startup, to check how representative the sample is, eg that the observed
+    /// ratio of allocations to samples is close to the configured sampling ratio
+    pub fn sampling_stats() -> SamplingStats {
+        SamplingStats {
+            alloc_calls: SAMPLING_DECISIONS.load(Relaxed),
+            sampled: SAMPLED_ALLOCS.load(Relaxed),
+            skipped_reentrant: REENTRANT_SKIPS.load(Relaxed),
+            skipped_by_ratio: RATIO_SKIPS.load(Relaxed),
+            throttled: THROTTLED_SAMPLES.load(Relaxed),
+            ignored: IGNORED_SAMPLES.load(Relaxed),
+        }
+    }
+
+    /// Zeroes the process wide profiling counters, which are shared by every `YingProfiler` in the process:
+    /// `profiled_bytes_allocated()`, `unknown_stack_samples()`, `untracked_outstanding_allocs()`,
+    /// `stack_hash_collisions()`, `sampling_stats()` and the allocated bytes of `category_breakdown()`.  Retained
+    /// byte counters follow live allocations, so they are left alone, as are counters of all allocations such as
+    /// `total_retained_bytes()` and `failed_allocs()`.
+    pub fn reset_global_counters() {
+        for counter in [
+            &PROFILED_ALLOCATED,
+            &UNKNOWN_STACK_SAMPLES,
+            &UNTRACKED_OUTSTANDING,
+            &THROTTLED_SAMPLES,
+            &IGNORED_SAMPLES,
+            &STACK_HASH_COLLISIONS,
+            &SAMPLING_DECISIONS,
+            &SAMPLED_ALLOCS,
+            &REENTRANT_SKIPS,
+            &RATIO_SKIPS,
+        ] {
+            counter.store(0, SeqCst);
+        }
+        SIZE_CATEGORIES.reset_allocated();
+    }
+
+    /// Histogram of time spent profiling sampled allocations, on top of the underlying allocation itself.
+    /// Empty unless timing was turned on with `with_sampling_latency_timing`.  Compare against the sampling
+    /// ratio to estimate the overall overhead of profiling.
+    pub fn sampling_latency_histogram() -> NanosHistogram {
+        SAMPLING_LATENCY.snapshot()
+    }
+
+    /// Number of allocations of each size since startup, sampled or not, in power of two buckets: bucket n counts
+    /// allocations of 2^(n-1) up to 2^n - 1 bytes, and bucket 0 zero sized allocations.  A quick first look at the
+    /// whole program's size distribution, eg if nearly all allocations are under 64 bytes.  Reallocs are not
+    /// counted.
+    pub fn global_size_histogram() -> [u64; NUM_SIZE_BUCKETS] {
+        ALLOC_SIZES.snapshot()
+    }
+
+    /// Sampled bytes allocated and retained in each coarse size category, tiny to huge, for telling at a glance
+    /// whether memory is held in many small allocations or a few large buffers.  Kept up to date on every
+    /// sample, so much cheaper than `retained_by_size_class()`.  Allocations not tracked as outstanding, eg
+    /// when shedding load, count as allocated but not retained.
+    pub fn category_breakdown() -> Vec<CategoryStats> {
+        SIZE_CATEGORIES.snapshot()
+    }
+}
+
+impl<A: GlobalAlloc> YingProfiler<A> {
+    /// Wraps `inner` instead of the System allocator, eg jemalloc or mimalloc.
+    /// sampling_ratio: number of allocations for every sampled allocation
+    pub const fn new_with_allocator(
+        inner: A,
+        sampling_ratio: u32,
+        single_alloc_limit: usize,
+    ) -> Self {
+        Self {
+            inner,
+            sampling_ratio,
+            adaptive: AdaptiveSampler::disabled(),
+            cpu_budget: CpuBudget::disabled(),
+            single_alloc_limit,
+            stack_allowlist: &[],
+            excluded_threads: &[],
+            ignore_allocation: None,
+            pass_through_frames: &[],
+            hash_frame_filter: None,
+            display_frame_filter: None,
+            symbolizer: &BacktraceSymbolizer,
+            lazy_symbols_top_n: 0,
+            baseline_path: None,
+            frame_pointers: false,
+            full_depth_min_size: 0,
+            shallow_stack_frames: MAX_NUM_FRAMES,
+            module_offsets: false,
+            time_sampling: false,
+            max_outstanding_allocs: usize::MAX,
+            max_samples_per_sec: u64::MAX,
+            track_cpus: false,
+            sampling_jitter: false,
+            sampling_seed: None,
+            check_collisions: false,
+            raw_byte_counts: false,
+            stack_hash_seed: DEFAULT_STACK_HASH_SEED,
+            pressure: MemoryPressure::new(),
+            budget: MemoryBudget::new(usize::MAX),
+            armed: AtomicBool::new(true),
+            sample_untracked_reallocs: false,
+            generation: AtomicU32::new(0),
+            tl_cache: YingLocalCache::new(),
+            state: OnceCell::new(),
+        }
+    }
+
+    /// The wrapped allocator
+    pub fn inner(&self) -> &A {
+        &self.inner
+    }
+
+    /// Number of allocations for every sampled allocation, as configured
+    pub fn sampling_ratio(&self) -> u32 {
+        self.sampling_ratio
+    }
+
+    /// The sampling ratio currently in use, which differs from `sampling_ratio()` only with adaptive sampling
+    /// or a CPU budget.  When both are on, the CPU budget wins.
+    #[inline]
+    pub fn effective_sampling_ratio(&self) -> u32 {
+        let ratio = if self.adaptive.is_enabled() {
+            self.adaptive.ratio()
+        } else {
+            self.sampling_ratio
+        };
+        if self.cpu_budget.is_enabled() {
+            ratio.max(self.cpu_budget.ratio())
+        } else {
+            ratio
+        }
+    }
+
+    /// CPU time spent profiling sampled allocations over the last second or so, summed over all threads, in
+    /// parts per million of one core, eg 10000 is 1%.  Only measured with `with_cpu_budget_ppm`, else 0.
+    pub fn profiling_cpu_ppm(&self) -> u64 {
+        self.cpu_budget.last_cpu_ppm()
+    }
+
+    /// Caps the number of outstanding sampled allocations tracked, to bound profiler memory for workloads with
+    /// huge numbers of live objects.  Once the cap is reached, new sampled allocations still count towards their
+    /// stack's allocated bytes, but are not tracked as outstanding (see `untracked_outstanding_allocs()`).
+    ///
+    /// Accuracy tradeoff: frees of untracked allocations are never seen, so per-stack retained bytes, free counts
+    /// and lifetime histograms overstate retention for stacks allocating while the cap is hit, and
+    /// `profiled_bytes_retained()` leaves out untracked allocations entirely.
+    pub const fn with_max_outstanding_allocs(mut self, max: usize) -> Self {
+        self.max_outstanding_allocs = max;
+        self
+    }
+
+    /// Bounds the memory used by the profiler's stacks and outstanding allocations to roughly `bytes` regardless
+    /// of workload.  Once their estimate reaches 90% of the budget the profiler sheds load, and `is_shedding()`
+    /// returns true until it drops below 80%.  While shedding:
+    ///
+    /// - New sampled allocations are not tracked as outstanding, as with `with_max_outstanding_allocs`.  Their
+    ///   frees are never seen, so retained bytes of stacks allocating while shedding are overstated, and
+    ///   `profiled_bytes_retained()` leaves them out.
+    /// - Once a second, the least recently allocating stacks with no retained sampled bytes are evicted until
+    ///   the estimate is back under 80%.  Their allocation history is lost: a stack which allocates again
+    ///   starts over from zero, so top-k by allocated bytes undercounts it.  Evictions are counted in
+    ///   `evicted_stacks()`.
+    ///
+    /// Symbols and interned frames are shared between stacks, so evicting stacks doesn't free them, and they are
+    /// not budgeted.  They grow with the number of distinct call sites that allocate, which the binary bounds,
+    /// and are included in `profiler_memory_estimate()`.  The estimate counts the entries of every map, so it is
+    /// only checked every 64 samples, and the budget can be overshot by up to that many samples' worth of entries
+    /// before shedding starts.
+    pub const fn with_max_profiler_memory_bytes(mut self, bytes: usize) -> Self {
+        self.budget = MemoryBudget::new(bytes);
+        self
+    }
+
+    /// Samples at most `per_sec` allocations a second on each thread, with bursts of up to a second's worth, using
+    /// a token bucket.  Caps the worst case CPU cost of backtrace capture when a burst of allocations would
+    /// otherwise sample thousands of stacks at once.  Allocations over the limit are not sampled, and are counted
+    /// in `throttled_samples()`.  Stacks allocating mostly during bursts are underrepresented, as estimates still
+    /// scale by the sampling ratio alone.
+    pub const fn with_max_samples_per_sec(mut self, per_sec: u64) -> Self {
+        self.max_samples_per_sec = per_sec;
+        self
+    }
+
+    /// Records the CPU each sampled allocation was made on, for `cpu_allocation_stats()` and
+    /// `numa_node_allocation_stats()`.  Costs one `sched_getcpu()` call per sample, which is a vDSO call on Linux.
+    /// Threads can migrate between CPUs, so this is where memory was allocated rather than where it is used.
+    pub const fn with_cpu_tracking(mut self, enabled: bool) -> Self {
+        self.track_cpus = enabled;
+        self
+    }
+
+    /// Randomizes the number of allocations between samples, uniformly within the sampling ratio ± half of it, using a
+    /// cheap per thread xorshift generator.  With a plain counter, a loop which allocates A, B, C in turn with a
+    /// ratio that is a multiple of 3 only ever samples the same one of them; jitter removes this aliasing bias.
+    /// The average sampling ratio is unchanged.
+    pub const fn with_sampling_jitter(mut self, enabled: bool) -> Self {
+        self.sampling_jitter = enabled;
+        self
+    }
+
+    /// Seeds the per thread RNG used by `with_sampling_jitter`, so that the same single threaded allocation sequence
+    /// samples the same allocations on every run, eg for tests or for comparing two runs.  Every thread starts from
+    /// the same seed.  By default each thread's RNG is seeded from the time and its thread ID.
+    pub const fn with_sampling_seed(mut self, seed: u64) -> Self {
+        self.sampling_seed = Some(seed);
+        self
+    }
+
+    /// Checks that each sampled stack really is the recorded stack with the same 64 bit hash, by comparing a second,
+    /// independent 64 bit hash of its frames, and counts mismatches in `stack_hash_collisions()`.  With tens of
+    /// thousands of unique stacks a hash collision becomes possible, which would silently merge the stats of two
+    /// stacks.  Costs one more hash of the frames per sample.
+    pub const fn with_collision_check(mut self, enabled: bool) -> Self {
+        self.check_collisions = enabled;
+        self
+    }
+
+    /// Seeds stack hashes with `seed`, so the same stack gets a different `stack_hash` in profilers with different
+    /// seeds, eg to namespace stacks by service in a central profile store.  The default seed gives the same
+    /// hashes as `Callstack::compute_hash`.  Symbol based `canonical_key`s are unaffected.
+    pub const fn with_stack_hash_seed(mut self, seed: u64) -> Self {
+        self.stack_hash_seed = seed;
+        self
+    }
+
+    /// How reallocs of allocations which are not tracked, ie were not sampled, are counted.  A realloc of a
+    /// tracked allocation always moves it, keeping its stack and start time, and adjusts its stack's allocated
+    /// bytes by the change in size, without counting another allocation.
+    ///
+    /// By default a realloc of an untracked allocation stays untracked, so a buffer whose first small
+    /// allocation wasn't sampled is never seen however large it grows.  With this enabled, such reallocs are
+    /// sampled at the sampling ratio like allocations, and a sampled one is counted as a new allocation of the
+    /// new size from the stack of the realloc, eg `RawVec::grow` under a `Vec::push`.
+    pub const fn with_untracked_realloc_sampling(mut self, enabled: bool) -> Self {
+        self.sample_untracked_reallocs = enabled;
+        self
+    }
+
+    /// Starts the profiler disarmed: installed, but not sampling until `arm()` is called, eg to capture just
+    /// the window of an incident.  Total retained memory is still counted while disarmed.
+    pub const fn with_explicit_arming(mut self) -> Self {
+        self.armed = AtomicBool::new(false);
+        self
+    }
+
+    /// Print byte counts in `rich_report` as exact numbers of bytes, instead of the default human readable
+    /// KiB/MiB/GiB, eg for reports which are parsed by scripts
+    pub const fn with_raw_byte_counts(mut self, raw: bool) -> Self {
+        self.raw_byte_counts = raw;
+        self
+    }
+
+    /// Adaptive sampling: when the allocation rate rises above its usual level, sample more densely, down to
+    /// 1 in `min_ratio` allocations, so the culprit of a spike is caught in finer detail.  The rate is estimated
+    /// from sampled allocations and the ratio is recomputed about once a second, so steady state overhead is
+    /// unchanged.  Note that per-stack estimates scale by the configured `sampling_ratio`, so they overstate
+    /// stacks which allocated mostly during spikes.  A `min_ratio` above `sampling_ratio` is capped at it, and 0
+    /// turns adaptive sampling off.
+    pub const fn with_adaptive_sampling(mut self, min_ratio: u32) -> Self {
+        self.adaptive = AdaptiveSampler::new(self.sampling_ratio, min_ratio);
+        self
+    }
+
+    /// Keeps the CPU time spent profiling sampled allocations, summed over all threads, under `max_cpu_ppm` parts
+    /// per million of one core, eg 10000 for 1%.  The CPU time of each sample is measured on its thread, and
+    /// about once a second the sampling ratio doubles if profiling was over budget, or halves back towards
+    /// `sampling_ratio` if it used less than half of it, up to 1024 times `sampling_ratio`.  The ratio in use
+    /// is `effective_sampling_ratio()`.  Like adaptive sampling, estimates still scale by the configured
+    /// ratio, so they understate all stacks while backed off.  Measuring costs two clock reads per sample, and
+    /// only works on Unix.
+    pub const fn with_cpu_budget_ppm(mut self, max_cpu_ppm: u32) -> Self {
+        self.cpu_budget = CpuBudget::new(self.sampling_ratio, max_cpu_ppm);
+        self
+    }
+
+    /// Only record sampled stacks where at least one frame's symbol starts with one of the given prefixes,
+    /// eg `&["my_crate::storage::"]`.  Other samples are dropped, which focuses the profile on one subsystem.
+    /// Checking requires resolving symbols the first time a stack is seen, which is then cached per stack.
+    pub const fn with_stack_allowlist(mut self, prefixes: &'static [&'static str]) -> Self {
+        self.stack_allowlist = prefixes;
+        self
+    }
+
+    /// Never samples allocations which `ignore` returns true for, given their address and size, eg the bulk
+    /// allocations of a custom arena, so that one giant arena allocation doesn't dominate the profile:
+    /// ```
+    ///     use ying_profiler::YingProfiler;
+    ///     const ARENA_BYTES: usize = 64 * 1024 * 1024;
+    ///     static YING_ALLOC: YingProfiler =
+    ///         YingProfiler::default().with_ignored_allocations(|_ptr, size| size == ARENA_BYTES);
+    /// ```
+    /// Only allocations due to be sampled are checked, so `ignore` costs nothing for the rest, but it runs inside
+    /// the allocator and must not allocate.  Ignored allocations still count towards `total_retained_bytes()`,
+    /// and are counted by `ignored_samples()`.  Note that objects an arena hands out from its own memory never go
+    /// through the global allocator, so they are never tracked; ignoring the arena's bulk allocations hides the
+    /// arena from the profile entirely.
+    pub const fn with_ignored_allocations(mut self, ignore: fn(*const u8, usize) -> bool) -> Self {
+        self.ignore_allocation = Some(ignore);
+        self
+    }
+
+    /// Treats frames whose symbol starts with one of the given prefixes as pass-through functions, like the built
+    /// in `callstack::PASS_THROUGH_FRAMES`, for `top_k_by_allocation_site`.  Eg `&["bytes::bytes_mut::"]`
+    /// attributes the growth of a `BytesMut` to the code which wrote to it.
+    pub const fn with_pass_through_frames(mut self, prefixes: &'static [&'static str]) -> Self {
+        self.pass_through_frames = prefixes;
+        self
+    }
+
+    /// Never sample allocations made on threads whose names start with one of the given prefixes, eg
+    /// `&["log-writer", "metrics-"]`, to keep known noisy background threads out of the profile.  The thread's
+    /// OS-level name is checked at its first sampled allocation and cached per thread.  Rust truncates OS thread
+    /// names to 15 bytes on Linux, so longer prefixes never match there.  Only supported on Linux and macOS.
+    pub const fn with_excluded_threads(mut self, prefixes: &'static [&'static str]) -> Self {
+        self.excluded_threads = prefixes;
+        self
+    }
+
+    /// Only frames whose symbol name `keep` returns true for count toward the stack hash, so stacks which only
+    /// differ in the rejected frames are grouped together as one stack.  Eg rejecting the frames of a noisy
+    /// library groups allocations by your own call sites through it.  The rejected frames are still displayed,
+    /// as they were in the first stack seen of the group.  Each frame's symbols are resolved the first time it
+    /// is sampled, and the verdict is cached per frame.
+    pub const fn with_hash_frame_filter(mut self, keep: fn(&str) -> bool) -> Self {
+        self.hash_frame_filter = Some(keep);
+        self
+    }
+
+    /// Only frames whose symbol name `keep` returns true for are shown in the stacks of text reports such as
+    /// `rich_report` and `dtrace_report`.  Unlike `with_hash_frame_filter`, this doesn't change how stacks are
+    /// grouped, so the two can be combined to group by one set of frames while displaying another.
+    pub const fn with_display_frame_filter(mut self, keep: fn(&str) -> bool) -> Self {
+        self.display_frame_filter = Some(keep);
+        self
+    }
+
+    /// Resolves the IPs of sampled stacks with `symbolizer` instead of the `backtrace` crate, eg to name frames of
+    /// JIT compiled code.  IPs it returns no symbols for are shown as their IP and module offset.
+    pub const fn with_symbolizer(mut self, symbolizer: &'static dyn Symbolizer) -> Self {
+        self.symbolizer = symbolizer;
+        self
+    }
+
+    /// Don't resolve the symbols of a new stack when it is first sampled, which is the most expensive part of
+    /// profiling.  Instead the thread started by `spawn_symbol_resolver` resolves the top `top_n` stacks by
+    /// retained bytes, and the top `top_n` by recent allocation rate so that newly hot stacks aren't starved,
+    /// and any other stack is resolved when it is first reported.  Stacks checked by `with_stack_allowlist`
+    /// or `with_hash_frame_filter` are still resolved when first sampled.
+    pub const fn with_lazy_symbolization(mut self, top_n: usize) -> Self {
+        self.lazy_symbols_top_n = top_n;
+        self
+    }
+
+    /// Compare stacks against a baseline profile written by `write_stacks_jsonl`, eg by the previous release,
+    /// through `top_movers_vs_baseline`.  Stacks are matched by their frames' symbol names, as stack hashes
+    /// differ between builds.  The file is read the first time it is needed, and if it can't be read a
+    /// warning is logged and the baseline is empty.
+    pub const fn with_baseline(mut self, path: &'static str) -> Self {
+        self.baseline_path = Some(path);
+        self
+    }
+
+    /// Capture the stacks of sampled allocations by walking frame pointers rather than unwinding with DWARF debug
+    /// info, which cuts the cost of each sample several times over.  Only for programs built with frame pointers,
+    /// eg with `RUSTFLAGS="-C force-frame-pointers=yes"`, ideally including the standard library: a frame without
+    /// a frame pointer usually ends the stack early, but can crash the walk.  Stack hashes differ from those of
+    /// the usual capture.  Frame pointers are walked on x86_64 and aarch64; on other targets, and for samples
+    /// where the walk finds no frames beyond the profiler's own, stacks are captured as usual.
+    pub const fn with_frame_pointer_unwinding(mut self, enabled: bool) -> Self {
+        self.frame_pointers = enabled;
+        self
+    }
+
+    /// Capture only the innermost `shallow_frames` frames of the stacks of sampled allocations under
+    /// `full_depth_min_size` bytes, stopping the unwind there, and full stacks for larger ones.  Unwinding is most
+    /// of the cost of a sample, so this spends it where a deep stack pays off most, on big allocations.  Small
+    /// allocations whose stacks differ only further out share one stack.  Stacks are captured in full when
+    /// walking frame pointers, which is cheap at any depth, and a cut short stack is never stitched onto the stack
+    /// which spawned its blocking task.
+    pub const fn with_depth_budget(
+        mut self,
+        shallow_frames: usize,
+        full_depth_min_size: usize,
+    ) -> Self {
+        self.shallow_stack_frames = shallow_frames;
+        self.full_depth_min_size = full_depth_min_size;
+        self
+    }
+
+    /// Show the executable or shared library each frame is in, and the frame's offset within it, next to the
+    /// frame in `rich_report`, eg `my_crate::parse [libfoo.so+0x1234]`.  Tells which dependency an inlined
+    /// generic came from, and lets stacks be symbolized offline.
+    pub const fn with_module_offsets(mut self, enabled: bool) -> Self {
+        self.module_offsets = enabled;
+        self
+    }
+
+    /// Measure how long the profiling work for each sampled allocation takes, mostly backtrace capture and
+    /// symbol resolution for new stacks.  Read the results with `sampling_latency_histogram()`.  Off by default
+    /// as reading the clock adds a little overhead of its own.
+    pub const fn with_sampling_latency_timing(mut self, enabled: bool) -> Self {
+        self.time_sampling = enabled;
+        self
+    }
+
     #[inline]
     pub fn symbol_map_size(&self) -> usize {
         self.get_state().symbol_map.len()
@@ -169,66 +871,505 @@ impl YingProfiler {
     /// Number of entries for outstanding sampled allocations map
     #[inline]
     pub fn num_outstanding_allocs(&self) -> usize {
-        self.get_state().outstanding_allocs.len()
+        self.get_state().num_outstanding.load(Relaxed)
+    }
+
+    /// Sizes of the profiler's own data structures, read together in one call with profiling locked out, for
+    /// watching whether they grow without bound.  The maps are still updated by other threads while being
+    /// counted, so the numbers are consistent with each other only to within allocations made meanwhile.
+    pub fn stack_cardinality_report(&self) -> StackCardinality {
+        self.lock_out_profiler(|| {
+            let state = self.get_state();
+            StackCardinality {
+                num_stacks: state.stack_stats.len(),
+                outstanding_allocs: state.outstanding_allocs.len(),
+                symbol_map_entries: state.symbol_map.len(),
+                interned_frames: state.stacks.num_frames(),
+            }
+        })
+    }
+
+    /// The sampling configuration, limits and features of this profiler, which consumers of its reports need to
+    /// scale sampled values to estimates.  Also embedded in `write_pprof` and `write_stacks_jsonl` output.
+    pub fn config(&self) -> YingConfig {
+        let limit = |value: usize| (value != usize::MAX).then_some(value);
+        YingConfig {
+            version: env!("CARGO_PKG_VERSION"),
+            sampling_ratio: self.sampling_ratio,
+            effective_sampling_ratio: self.effective_sampling_ratio(),
+            adaptive_min_ratio: self
+                .adaptive
+                .is_enabled()
+                .then(|| self.adaptive.min_ratio()),
+            sampling_jitter: self.sampling_jitter,
+            sampling_seed: self.sampling_seed,
+            stack_hash_seed: self.stack_hash_seed,
+            frame_pointer_unwinding: self.frame_pointers,
+            single_alloc_limit: self.single_alloc_limit,
+            max_outstanding_allocs: limit(self.max_outstanding_allocs),
+            max_samples_per_sec: (self.max_samples_per_sec != u64::MAX)
+                .then_some(self.max_samples_per_sec),
+            max_profiler_memory_bytes: limit(self.budget.max_bytes()),
+            max_profiling_cpu_ppm: self
+                .cpu_budget
+                .is_enabled()
+                .then(|| self.cpu_budget.max_cpu_ppm()),
+            features: YingConfig::enabled_features(),
+        }
+    }
+
+    /// A one line "health check" of memory use, combining global counters with stats across all stacks.
+    /// Much cheaper than rendering per-stack reports, so it can be logged often.
+    pub fn summary(&self) -> ProfileSummary {
+        self.lock_out_profiler(|| {
+            let retained_by_stack =
+                self.stack_list_desc_by(|stats| stats.retained_profiled_bytes());
+            let stacks_retained: u64 = retained_by_stack
+                .iter()
+                .map(|&(_, retained)| retained)
+                .sum();
+            let top_10_retained: u64 = retained_by_stack
+                .iter()
+                .take(10)
+                .map(|&(_, retained)| retained)
+                .sum();
+            ProfileSummary {
+                total_retained_bytes: YingProfiler::total_retained_bytes(),
+                estimated_profiled_retained_bytes: YingProfiler::profiled_bytes_retained()
+                    * self.effective_sampling_ratio() as usize,
+                peak_retained_bytes: YingProfiler::peak_retained_bytes(),
+                outstanding_allocs: self.num_outstanding_allocs(),
+                num_stacks: retained_by_stack.len(),
+                top_10_retained_fraction: if stacks_retained == 0 {
+                    0.0
+                } else {
+                    top_10_retained as f64 / stacks_retained as f64
+                },
+            }
+        })
     }
 
     /// Get the top k stack traces by total profiled bytes allocated, in descending order.
     /// Note that "profiled bytes" refers to the bytes allocated during sampling by this profiler.
     pub fn top_k_stacks_by_allocated(&self, k: usize) -> Vec<StackStats> {
+        self.top_k_by(k, |stats| stats.allocated_bytes())
+    }
+
+    /// Get the top k stack traces by retained sampled memory, in descending order.
+    pub fn top_k_stacks_by_retained(&self, k: usize) -> Vec<StackStats> {
+        self.top_k_by(k, |stats| stats.retained_profiled_bytes())
+    }
+
+    /// Get the top k stack traces whose allocations come in and never leave, ie with a low `churn_ratio` and
+    /// many bytes allocated, in descending order.  Stacks are ranked by retained bytes times the fraction of
+    /// allocated bytes retained, so a stack allocating heavily with little freed ranks above one that retains
+    /// as much but only as a small part of a lot of temporaries.  Allocations marked with `mark_intentional`
+    /// are left out of the retained bytes, see `StackStats::leak_suspect_bytes`.
+    pub fn top_k_by_retention(&self, k: usize) -> Vec<StackStats> {
+        self.top_k_by(k, |stats| {
+            (stats.leak_suspect_bytes() as f64 * (1.0 - stats.churn_ratio())) as u64
+        })
+    }
+
+    /// Get the top k stack traces by allocation heat, ie sampled bytes allocated with older allocations counting
+    /// exponentially less, in descending order.  Shows which stacks are allocating heavily now, rather than
+    /// over the whole life of the process.  Needs heat decay with `spawn_heat_decay` or `decay_heat`; otherwise
+    /// this is the same as `top_k_stacks_by_allocated`.
+    pub fn top_k_by_recent_allocation(&self, k: usize) -> Vec<StackStats> {
+        self.top_k_by(k, |stats| stats.allocation_heat())
+    }
+
+    /// Get the stacks which made at least one sampled allocation bigger than `size` bytes, sorted by their
+    /// largest allocation in descending order.  Answers "which code paths make single allocations over 1MB",
+    /// a good place to start trimming peak memory.
+    pub fn stacks_with_allocations_above(&self, size: usize) -> Vec<StackStats> {
         self.lock_out_profiler(|| {
-            let stacks_by_alloc = self.stack_list_allocated_bytes_desc();
-            stacks_by_alloc
+            self.stack_list_desc_by(|stats| stats.max_alloc_size())
                 .iter()
-                .take(k)
-                .filter_map(|&(stack_hash, _bytes_allocated)| {
-                    self.get_stats_for_stack_hash(stack_hash)
-                })
+                .take_while(|&&(_stack_hash, max_size)| max_size > size as u64)
+                .filter_map(|&(stack_hash, _max_size)| self.get_stats_for_stack_hash(stack_hash))
                 .collect()
         })
     }
 
-    /// Get the top k stack traces by retained sampled memory, in descending order.
-    pub fn top_k_stacks_by_retained(&self, k: usize) -> Vec<StackStats> {
+    /// Get the top k stack traces sorted in descending order by any key computed from each stack's stats,
+    /// for example number of allocations or number of frees.  With the `rayon` feature, `key` is called from
+    /// Rayon's worker threads.
+    /// ```
+    ///     use ying_profiler::YingProfiler;
+    ///     static YING_ALLOC: YingProfiler = YingProfiler::default();
+    ///     let top_stacks = YING_ALLOC.top_k_by(10, |stats| stats.num_allocations());
+    /// ```
+    pub fn top_k_by(&self, k: usize, key: impl Fn(&StackStats) -> u64 + Sync) -> Vec<StackStats> {
         self.lock_out_profiler(|| {
-            let stacks_by_retained = self.stack_list_retained_bytes_desc();
-            stacks_by_retained
+            let stacks_by_key = self.stack_list_desc_by(key);
+            stacks_by_key
                 .iter()
                 .take(k)
-                .filter_map(|&(stack_hash, _bytes_retained)| {
-                    self.get_stats_for_stack_hash(stack_hash)
+                .filter_map(|&(stack_hash, _key)| self.get_stats_for_stack_hash(stack_hash))
+                .collect()
+        })
+    }
+
+    /// Get all stacks where any resolved frame (including inlined frames) has a symbol containing `pattern`,
+    /// for example `serde` or `my_crate::db`, sorted by retained sampled memory in descending order.
+    pub fn stacks_matching(&self, pattern: &str) -> Vec<StackStats> {
+        self.lock_out_profiler(|| {
+            let state = self.get_state();
+            let mut matching = Vec::new();
+            for entry in &state.stack_stats {
+                if self
+                    .resolved_stack(state, entry.value())
+                    .any_symbol_contains(&state.symbol_map, pattern)
+                {
+                    matching.push(entry.value().clone());
+                }
+            }
+            matching.sort_unstable_by_key(|stats| {
+                (Reverse(stats.retained_profiled_bytes()), stats.stack_hash())
+            });
+            matching
+        })
+    }
+
+    /// Retained bytes of outstanding sampled allocations, split by allocation size class regardless of stack.
+    /// Size classes go up by powers of 4 from 16 bytes, and only classes with outstanding allocations are returned,
+    /// smallest first.  Shows whether retained memory is mostly many small objects or a few big buffers.
+    pub fn retained_by_size_class(&self) -> Vec<SizeClassStats> {
+        self.lock_out_profiler(|| {
+            let mut classes = [SizeClassStats::default(); NUM_SIZE_CLASSES];
+            for entry in &self.get_state().outstanding_allocs {
+                let size = entry.value().size;
+                let class = &mut classes[size_class(size)];
+                class.num_allocs += 1;
+                class.sampled_bytes += size;
+            }
+            classes
+                .iter()
+                .enumerate()
+                .filter(|(_, class)| class.num_allocs > 0)
+                .map(|(n, class)| SizeClassStats {
+                    max_size: size_class_max(n),
+                    ..*class
                 })
                 .collect()
         })
     }
 
-    /// Returns a list of stack IDs (stack_hash, bytes_allocated) in order from highest
-    /// number of bytes allocated to lowest
-    fn stack_list_allocated_bytes_desc(&self) -> Vec<(u64, u64)> {
+    /// Retained bytes of outstanding sampled allocations, split by the profile generation they were made in, oldest
+    /// first.  Only generations with outstanding allocations are returned.  Memory from early generations which
+    /// is still live after later resets, eg from generation 0 at startup, is durable rather than recent churn.
+    pub fn retained_by_generation(&self) -> Vec<GenerationStats> {
+        self.lock_out_profiler(|| {
+            let mut generations: HashMap<u32, GenerationStats> = HashMap::new();
+            for entry in &self.get_state().outstanding_allocs {
+                let info = entry.value();
+                let stats = generations
+                    .entry(info.profile_generation)
+                    .or_insert_with(|| GenerationStats {
+                        generation: info.profile_generation,
+                        ..GenerationStats::default()
+                    });
+                stats.num_allocs += 1;
+                stats.sampled_bytes += info.size;
+            }
+            let mut generations: Vec<_> = generations.into_values().collect();
+            generations.sort_unstable_by_key(|stats| stats.generation);
+            generations
+        })
+    }
+
+    /// The tracked info for a live allocation starting at `ptr`, if it was sampled and is still outstanding.
+    /// Useful for finding which stack allocated a particular object seen in a debugger or a core dump; the stack
+    /// itself can then be looked up by hash in eg `snapshot()`.  Allocations not sampled, or not tracked due to
+    /// `with_max_outstanding_allocs`, return None.
+    pub fn lookup_allocation(&self, ptr: *const u8) -> Option<AllocInfo> {
+        self.lock_out_profiler(|| {
+            self.get_state()
+                .outstanding_allocs
+                .get(&(ptr as u64))
+                .map(|info| *info.value())
+        })
+    }
+
+    /// Marks the live allocation starting at `ptr` as deliberately kept, eg by `Box::leak` or `std::mem::forget`
+    /// for static config, so that it doesn't count towards its stack's `StackStats::leak_suspect_bytes`, which
+    /// ranks stacks in `top_k_by_retention`.  It is still counted in retained bytes everywhere else.  Returns
+    /// false if the allocation is not tracked, eg because it was not sampled.
+    pub fn mark_intentional(&self, ptr: *const u8) -> bool {
+        self.lock_out_profiler(|| {
+            let state = self.get_state();
+            let info = match state.outstanding_allocs.get_mut(&(ptr as u64)) {
+                Some(mut info) if !info.intentional => {
+                    info.intentional = true;
+                    *info
+                }
+                Some(_) => return true,
+                None => return false,
+            };
+            state
+                .stack_stats
+                .entry(info.stack_hash)
+                .and_modify(|stats| stats.update_intentional_bytes(0, info.size, info.generation));
+            true
+        })
+    }
+
+    /// Every outstanding sampled allocation with its size, age and stack, largest first.  Anything still listed
+    /// at a clean shutdown is a genuine leak, although only sampled allocations are seen, so multiply by the
+    /// sampling ratio for an estimate of all leaked memory.  Stacks are resolved once per stack rather than per
+    /// allocation, but this still walks every tracked allocation, so it is meant for audits rather than polling.
+    pub fn outstanding_report(&self) -> Vec<OutstandingAlloc> {
+        let mut allocs = Vec::new();
+        self.for_each_outstanding(|alloc| allocs.push(alloc));
+        allocs.sort_unstable_by_key(|alloc| (Reverse(alloc.info.size), alloc.ptr));
+        allocs
+    }
+
+    /// Calls `f` with every outstanding sampled allocation, in no particular order, for analyses the built in
+    /// reports don't cover, eg a joint histogram of size and age.  Stacks are resolved once per stack as in
+    /// `outstanding_report`, which collects and sorts the same allocations.  `f` runs with profiling locked out on
+    /// this thread and parts of the map of outstanding allocations locked, so it must not call back into the
+    /// profiler except for read only queries.  Sampled allocations it frees are taken off the map once it returns.
+    pub fn for_each_outstanding(&self, mut f: impl FnMut(OutstandingAlloc)) {
+        self.lock_out_profiler(|| {
+            let state = self.get_state();
+            let now = now_millis();
+            let mut stack_keys: HashMap<u64, Option<String>> = HashMap::new();
+            for entry in state.outstanding_allocs.iter() {
+                let info = *entry.value();
+                let stack = stack_keys
+                    .entry(info.stack_hash)
+                    .or_insert_with(|| {
+                        state.stack_stats.get(&info.stack_hash).map(|stats| {
+                            self.resolved_stack(state, &stats)
+                                .canonical_key(&state.symbol_map)
+                        })
+                    })
+                    .clone();
+                f(OutstandingAlloc {
+                    ptr: *entry.key(),
+                    info,
+                    age_millis: now.saturating_sub(info.alloc_ts),
+                    stack,
+                });
+            }
+        })
+    }
+
+    /// Groups stacks by their first frame outside of `alloc::`, `core::` and `std::`, ie the first line of user code
+    /// which led to the allocation, and returns the top k groups by `measurement`.
+    pub fn top_k_by_user_frame(&self, k: usize, measurement: Measurement) -> Vec<FrameGroup> {
+        self.top_k_by_frame(k, measurement, |stack, symbols| {
+            stack.first_user_frame(symbols)
+        })
+    }
+
+    /// Groups stacks by their allocation site: the caller of the collection method for stacks which grew a
+    /// collection through a pass-through function such as `RawVec::grow` or `HashMap::reserve`, and otherwise
+    /// the innermost frame outside the allocator.  Unlike `top_k_by_user_frame`, other library frames are kept,
+    /// so a collection grown by a dependency is attributed to the dependency.  The built in pass-through
+    /// functions are in `callstack::PASS_THROUGH_FRAMES`, and more can be added with `with_pass_through_frames`.
+    /// Returns the top k groups by `measurement`.
+    pub fn top_k_by_allocation_site(&self, k: usize, measurement: Measurement) -> Vec<FrameGroup> {
+        self.top_k_by_frame(k, measurement, |stack, symbols| {
+            stack.allocation_site(symbols, self.pass_through_frames)
+        })
+    }
+
+    fn top_k_by_frame(
+        &self,
+        k: usize,
+        measurement: Measurement,
+        frame_of: impl Fn(&StdCallstack, &SymbolMap) -> Option<String>,
+    ) -> Vec<FrameGroup> {
+        self.lock_out_profiler(|| {
+            let state = self.get_state();
+            let mut groups: HashMap<String, FrameGroup> = HashMap::new();
+            for entry in &state.stack_stats {
+                let stats = entry.value();
+                let frame = frame_of(&self.resolved_stack(state, stats), &state.symbol_map)
+                    .unwrap_or_else(|| "<unknown>".to_string());
+                let group = groups.entry(frame.clone()).or_insert_with(|| FrameGroup {
+                    frame,
+                    allocated_bytes: 0,
+                    retained_bytes: 0,
+                    num_allocations: 0,
+                    live_allocations: 0,
+                    num_stacks: 0,
+                });
+                group.allocated_bytes += stats.allocated_bytes();
+                group.retained_bytes += stats.retained_profiled_bytes();
+                group.num_allocations += stats.num_allocations();
+                group.live_allocations += stats.live_allocations();
+                group.num_stacks += 1;
+            }
+            let mut groups: Vec<FrameGroup> = groups.into_values().collect();
+            groups.sort_unstable_by(|a, b| {
+                let bytes = |group: &FrameGroup| match measurement {
+                    Measurement::AllocatedBytes => group.allocated_bytes,
+                    Measurement::RetainedBytes => group.retained_bytes,
+                    Measurement::NumAllocations => group.num_allocations,
+                    Measurement::LiveAllocations => group.live_allocations,
+                };
+                bytes(b).cmp(&bytes(a)).then_with(|| a.frame.cmp(&b.frame))
+            });
+            groups.truncate(k);
+            groups
+        })
+    }
+
+    /// Returns a list of stack IDs (stack_hash, key) in order from highest key to lowest.  Stacks with equal keys
+    /// are ordered by stack hash, so that reports list them in the same order every time and can be diffed.
+    #[cfg(not(feature = "rayon"))]
+    fn stack_list_desc_by(&self, key: impl Fn(&StackStats) -> u64 + Sync) -> Vec<(u64, u64)> {
         let mut items = Vec::new();
-        // TODO: filter away entries with minimal allocations, say <1% or some threshold
+        // TODO: filter away entries with minimal values, say <1% or some threshold
         for entry in &self.get_state().stack_stats {
-            items.push((*entry.key(), entry.value().allocated_bytes));
+            items.push((*entry.key(), key(entry.value())));
         }
-        items.sort_unstable_by(|a, b| b.1.cmp(&a.1));
+        items.sort_unstable_by_key(|&(stack_hash, key)| (Reverse(key), stack_hash));
         items
     }
 
-    /// Returns a list of stack IDs (stack_hash, bytes_retained) in order from highest
-    /// number of bytes retained to lowest
-    fn stack_list_retained_bytes_desc(&self) -> Vec<(u64, u64)> {
-        let mut items = Vec::new();
-        // TODO: filter away entries with minimal retained allocations, say <1% or some threshold
-        for entry in &self.get_state().stack_stats {
-            items.push((*entry.key(), entry.value().retained_profiled_bytes()));
-        }
-        items.sort_unstable_by(|a, b| b.1.cmp(&a.1));
+    /// `stack_list_desc_by` on the Rayon thread pool.  Profiling is locked out on the worker threads too, as
+    /// their allocations could otherwise be sampled and try to update the stack map being iterated.
+    #[cfg(feature = "rayon")]
+    fn stack_list_desc_by(&self, key: impl Fn(&StackStats) -> u64 + Sync) -> Vec<(u64, u64)> {
+        use rayon::prelude::*;
+        let tl_cache = &self.tl_cache;
+        let mut items: Vec<(u64, u64)> = self
+            .get_state()
+            .stack_stats
+            .par_iter()
+            .map_init(
+                || LockOutGuard::new(tl_cache),
+                |_guard, entry| (*entry.key(), key(entry.value())),
+            )
+            .collect();
+        items.par_sort_unstable_by_key(|&(stack_hash, key)| (Reverse(key), stack_hash));
         items
     }
 
+    /// Clears all stacks and outstanding allocations and starts sampling, so the profile covers exactly the
+    /// window from now until `disarm()`.  Allocations from before arming are not tracked, so their frees don't
+    /// show up either.  Arming an armed profiler starts its window again.
+    pub fn arm(&self) {
+        self.armed.store(false, SeqCst);
+        self.clear_profile();
+        self.generation.fetch_add(1, SeqCst);
+        self.armed.store(true, SeqCst);
+    }
+
+    /// Stops sampling and tracking of frees, freezing the profile as of now for reports, until the next `arm()`.
+    /// Allocations made in the window but freed after disarming still count as retained.
+    pub fn disarm(&self) {
+        self.armed.store(false, SeqCst);
+    }
+
+    /// Whether the profiler is sampling, ie it was not disarmed, nor created with `with_explicit_arming` and
+    /// not armed yet
+    pub fn is_armed(&self) -> bool {
+        self.armed.load(Relaxed)
+    }
+
+    // Empties all stacks and outstanding allocations, taking their bytes off the profiled retained bytes
+    fn clear_profile(&self) {
+        self.lock_out_profiler(|| {
+            let state = self.get_state();
+            state.stack_stats.clear();
+            // Each removed allocation's bytes are subtracted once, even if it is concurrently being freed
+            let mut removed_bytes = 0;
+            let mut num_removed = 0;
+            state.outstanding_allocs.retain(|_, info| {
+                removed_bytes += info.size as usize;
+                num_removed += 1;
+                SIZE_CATEGORIES.record_free(info.size as usize);
+                false
+            });
+            PROFILED_RETAINED.fetch_sub(removed_bytes, SeqCst);
+            state.num_outstanding.fetch_sub(num_removed, SeqCst);
+            #[cfg(feature = "tokio")]
+            state.task_stats.clear();
+        })
+    }
+
+    /// Empties all stacks and outstanding allocations of this profiler, with profiling locked out on this thread.
+    /// Profiled retained bytes drop by the bytes of the forgotten allocations, so they stay in step with
+    /// allocations sampled concurrently.  Counters shared by every profiler in the process, such as
+    /// `profiled_bytes_allocated()` and `sampling_stats()`, are left alone; zero them with
+    /// `YingProfiler::reset_global_counters()`.  Unlike `arm()`, sampling carries on throughout.
+    pub fn clear_stats(&self) {
+        self.clear_profile();
+    }
+
+    /// Zeroes the stats of a single stack, leaving the rest of the profile alone, eg to re-measure one stack after
+    /// optimizing it.  Its outstanding allocations stay tracked, but frees of them after the reset are counted
+    /// separately as pre-reset frees, so post-reset stats only reflect new allocations.
+    /// Returns false if there is no stack with that hash.
+    pub fn reset_stack(&self, stack_hash: u64) -> bool {
+        self.lock_out_profiler(|| {
+            let found = self
+                .get_state()
+                .stack_stats
+                .get_mut(&stack_hash)
+                .map(|mut stats| stats.reset())
+                .is_some();
+            if found {
+                self.generation.fetch_add(1, SeqCst);
+            }
+            found
+        })
+    }
+
+    /// Starts a new profile generation without resetting anything, eg once startup is done, and returns it.
+    /// Each outstanding allocation records the generation it was made in, see `retained_by_generation`.
+    pub fn next_generation(&self) -> u32 {
+        self.generation.fetch_add(1, SeqCst) + 1
+    }
+
+    /// The current profile generation.  Starts at 0, and goes up with each `arm`, `reset_stack` and
+    /// `next_generation`.
+    pub fn generation(&self) -> u32 {
+        self.generation.load(Relaxed)
+    }
+
+    /// Inserts stats for a made up stack, so reporting and query code can be tested deterministically without
+    /// real backtraces.  `frames` are symbol names, innermost first.  Replaces any existing stack with the same
+    /// frames, and returns the stack hash.  TESTING ONLY
+    /// ```
+    ///     use ying_profiler::YingProfiler;
+    ///     static YING_ALLOC: YingProfiler = YingProfiler::default();
+    ///     let hash = YING_ALLOC.inject_synthetic_stack(&["my_app::cache::insert", "my_app::main"], 4096, 1024);
+    ///     assert_eq!(YING_ALLOC.stacks_matching("my_app::cache")[0].stack_hash(), hash);
+    /// ```
+    #[cfg(feature = "test-util")]
+    pub fn inject_synthetic_stack(&self, frames: &[&str], allocated: u64, retained: u64) -> u64 {
+        self.lock_out_profiler(|| {
+            let state = self.get_state();
+            let stack = StdCallstack::synthetic(frames, &state.symbol_map);
+            let stack_hash = stack.compute_hash_with_seed(self.stack_hash_seed);
+            state.stack_stats.insert(
+                stack_hash,
+                StackStats::synthetic(&stack, &state.stacks, stack_hash, allocated, retained),
+            );
+            stack_hash
+        })
+    }
+
+    /// Like `clear_stats`, and also empties per stack bookkeeping, such as the top movers interval and giant
+    /// allocation warnings, with profiling locked out on this thread, so that the next test starts from
+    /// an empty profile.  Also zeroes the process wide counters with `YingProfiler::reset_global_counters()`,
+    /// so it affects every profiler in the process.  Allocations sampled concurrently on other threads may still
+    /// be recorded afterwards.
     pub fn reset_state_for_testing_only(&self) {
-        let state = self.get_state();
-        state.stack_stats.clear();
-        state.outstanding_allocs.clear();
+        self.clear_stats();
+        YingProfiler::<System>::reset_global_counters();
+        self.lock_out_profiler(|| {
+            let state = self.get_state();
+            state.giant_alloc_warnings.clear();
+            *state.movers.lock().unwrap() = Default::default();
+        })
     }
 
     pub fn testing_only_guarantee_next_sample(&self) {
@@ -237,27 +1378,133 @@ impl YingProfiler {
             .test_only_reset_sampling_counter()
     }
 
+    /// Sets the ratio adaptive sampling is using, as if the allocation rate had spiked.  TESTING ONLY
+    #[cfg(feature = "test-util")]
+    pub fn testing_only_set_adaptive_ratio(&self, ratio: u32) {
+        self.adaptive.set_ratio(ratio);
+    }
+}
+
+#[cfg(not(any(
+    feature = "disabled",
+    not(any(target_os = "linux", target_os = "macos"))
+)))]
+impl<A: GlobalAlloc> YingProfiler<A> {
+    // Removes a sampled allocation which was freed from outstanding_allocs and updates the stats, if it is still
+    // there.  With `alloc_id`, only if the entry is still that allocation rather than a later one at the same
+    // address.  Must be called with profiling locked out.
+    fn record_sampled_free(&self, state: &YingState, ptr: u64, size: usize, alloc_id: Option<u64>) {
+        // Only adjust PROFILED_RETAINED if we actually removed the entry, so that the counter
+        // can never drift from what outstanding_allocs holds.
+        let removed = match alloc_id {
+            Some(alloc_id) => state
+                .outstanding_allocs
+                .remove_if(&ptr, |_, info| info.alloc_id == alloc_id),
+            None => state.outstanding_allocs.remove(&ptr),
+        };
+        if let Some((_, info)) = removed {
+            state.num_outstanding.fetch_sub(1, SeqCst);
+            PROFILED_RETAINED.fetch_sub(size, SeqCst);
+            SIZE_CATEGORIES.record_free(size);
+            let alloc_time_ms = Clock::recent_since_epoch()
+                .as_millis()
+                .saturating_sub(info.alloc_ts);
+
+            // Update memory profiling freed bytes stats
+            state
+                .stack_stats
+                .entry(info.stack_hash)
+                .and_modify(|stats| {
+                    stats.update_free_stats(size as u64, alloc_time_ms, info.generation);
+                    if info.intentional {
+                        stats.update_intentional_bytes(size as u64, 0, info.generation);
+                    }
+                });
+            #[cfg(feature = "tokio")]
+            if let Some(task_id) = info.task_id {
+                tasks::record_free(&state.task_stats, task_id, size as u64);
+            }
+        }
+    }
+
+    // A sampled allocation freed while profiling is locked out on this thread, which may be in the middle of
+    // iterating outstanding_allocs, eg in a `for_each_outstanding` callback.  Its entry is removed later by
+    // `finish_deferred_frees`, so that it can't be mistaken for a later allocation at the same address.
+    #[cold]
+    fn defer_free(&self, state: &YingState, ptr: u64, size: usize) {
+        let alloc_id = match state.outstanding_allocs.get(&ptr) {
+            Some(info) => info.alloc_id,
+            None => return,
+        };
+        state
+            .deferred_frees
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push((ptr, alloc_id, size));
+        state.has_deferred_frees.store(true, SeqCst);
+    }
+
+    // Removes the allocations freed while profiling was locked out.  Must be called with profiling locked out,
+    // and without any map entries held, so just one load unless there are any.
+    #[inline]
+    fn finish_deferred_frees(&self, state: &YingState) {
+        if state.has_deferred_frees.load(Relaxed) && state.has_deferred_frees.swap(false, SeqCst) {
+            let frees = std::mem::take(
+                &mut *state
+                    .deferred_frees
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner()),
+            );
+            for (ptr, alloc_id, size) in frees {
+                self.record_sampled_free(state, ptr, size, Some(alloc_id));
+            }
+        }
+    }
+
     #[inline]
     fn check_and_deny_giant_allocations(&self, ptr: *mut u8, layout: Layout) -> *mut u8 {
         // Sorry there is an edge case where this check cannot happen if YING is not initialized
         if layout.size() >= self.single_alloc_limit && self.state.get().is_some() {
+            DENIED_GIANT_ALLOCS.fetch_add(1, Relaxed);
             // Prevent allocation sampling while we are telling the world who did this
             self.lock_out_profiler(|| {
-                println!(
-                    "WARNING: Huge memory allocation of {} bytes denied by Ying profiler",
-                    layout.size()
-                );
-
                 let mut bt = Backtrace::new_unresolved();
 
                 // 2. Create a Callstack, check if there is a similar stack
                 let stack = StdCallstack::from_backtrace_unresolved(&bt);
                 let state = self.get_state();
-                stack.populate_symbol_map(&mut bt, &state.symbol_map);
-                println!(
-                    "Stack trace:\n{}",
-                    stack.with_symbols_and_filename(&state.symbol_map, true)
+
+                // Warn at most once per interval per stack, so that a loop of giant allocations doesn't flood
+                // the logs and resolve symbols on every attempt
+                let stack_hash = stack.compute_hash_with_seed(self.stack_hash_seed);
+                let now = now_millis();
+                if let Some(last_warned) = state.giant_alloc_warnings.get(&stack_hash) {
+                    if now.saturating_sub(*last_warned) < GIANT_ALLOC_WARNING_INTERVAL_MILLIS {
+                        return;
+                    }
+                }
+                state.giant_alloc_warnings.insert(stack_hash, now);
+
+                stack.populate_symbol_map_with(&mut bt, &state.symbol_map, self.symbolizer);
+                let decorated_stack = stack
+                    .with_symbols_and_filename(&state.symbol_map, true)
+                    .with_header_hash(stack_hash);
+
+                #[cfg(feature = "tracing-logs")]
+                tracing::warn!(
+                    size = layout.size(),
+                    stack_hash,
+                    stack = %decorated_stack,
+                    "Huge memory allocation denied by Ying profiler"
                 );
+                #[cfg(not(feature = "tracing-logs"))]
+                {
+                    println!(
+                        "WARNING: Huge memory allocation of {} bytes denied by Ying profiler",
+                        layout.size()
+                    );
+                    println!("Stack trace:\n{}", decorated_stack);
+                }
             });
             std::ptr::null_mut::<u8>()
         } else {
@@ -265,8 +1512,79 @@ impl YingProfiler {
         }
     }
 
+    // Checks a sampled stack against the allowlist, resolving its symbols on first sight.  Stacks already in
+    // stack_stats were allowed before, and rejected stacks are remembered so they are only resolved once.
+    fn is_stack_allowed(
+        &self,
+        state: &YingState,
+        stack: &StdCallstack,
+        stack_hash: u64,
+        bt: &mut Backtrace,
+    ) -> bool {
+        if state.stack_stats.contains_key(&stack_hash) {
+            return true;
+        }
+        if state.disallowed_stacks.contains_key(&stack_hash) {
+            return false;
+        }
+        stack.populate_symbol_map_with(bt, &state.symbol_map, self.symbolizer);
+        let allowed = self
+            .stack_allowlist
+            .iter()
+            .any(|prefix| stack.any_symbol_starts_with(&state.symbol_map, prefix));
+        if !allowed {
+            state.disallowed_stacks.insert(stack_hash, ());
+        }
+        allowed
+    }
+
+    // The stack to hash, without the frames rejected by the hash frame filter, or None if there is no filter.
+    // Frames not seen before have their symbols resolved, and each frame's verdict is cached.
+    fn hashed_stack(
+        &self,
+        state: &YingState,
+        stack: &StdCallstack,
+        bt: &mut Backtrace,
+    ) -> Option<StdCallstack> {
+        let keep = self.hash_frame_filter?;
+        Some(stack.filtered(|ip| {
+            if let Some(verdict) = state.hash_frame_verdicts.get(&ip) {
+                return *verdict;
+            }
+            if !state.symbol_map.contains_key(&ip) {
+                stack.populate_symbol_map_with(bt, &state.symbol_map, self.symbolizer);
+            }
+            let verdict = StdCallstack::frame_kept(&state.symbol_map, ip, keep);
+            state.hash_frame_verdicts.insert(ip, verdict);
+            verdict
+        }))
+    }
+
+    // Checks the current thread's name against the excluded prefixes, caching the verdict in its thread local
+    #[inline]
+    fn is_thread_excluded(&self, tl_state: &mut YingThreadLocal) -> bool {
+        if self.excluded_threads.is_empty() {
+            return false;
+        }
+        let tid = thread_id();
+        if tl_state.name_checked_thread != tid {
+            let mut buf = [0u8; 64];
+            tl_state.thread_excluded = current_thread_name(&mut buf).is_some_and(|name| {
+                self.excluded_threads
+                    .iter()
+                    .any(|prefix| name.starts_with(prefix))
+            });
+            tl_state.name_checked_thread = tid;
+        }
+        tl_state.thread_excluded
+    }
+}
+
+impl<A: GlobalAlloc> YingProfiler<A> {
     #[inline]
     fn get_state(&self) -> &YingState {
+        #[cfg(not(any(feature = "disabled", target_os = "linux", target_os = "macos")))]
+        warn_unsupported_target_once();
         // We need to lock out the profiler here, to ensure no tracking of allocations or messes
         self.lock_out_profiler(|| self.state.get_or_init(YingState::new))
     }
@@ -287,31 +1605,451 @@ impl YingProfiler {
         let tl_state = self.tl_cache.get_thread_local();
         tl_state.set_allocator_lock();
         let return_val = func();
+        // Sampled allocations freed by `func` are only removed once no map entries can be held, ie when leaving
+        // the outermost lock out
+        #[cfg(not(any(
+            feature = "disabled",
+            not(any(target_os = "linux", target_os = "macos"))
+        )))]
+        if let (1, Some(state)) = (tl_state.alloc_lock, self.state.get()) {
+            self.finish_deferred_frees(state);
+        }
         tl_state.release_allocator_lock();
         return_val
     }
 }
 
+/// Overall memory stats from `YingProfiler::summary()`.  Displays as a single line suitable for logging.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ProfileSummary {
+    /// Outstanding retained bytes across all allocations, not just sampled ones
+    pub total_retained_bytes: usize,
+    /// Retained bytes of sampled allocations, scaled up by the sampling ratio in use, see
+    /// `YingProfiler::effective_sampling_ratio`
+    pub estimated_profiled_retained_bytes: usize,
+    /// Highest total retained bytes seen since startup
+    pub peak_retained_bytes: usize,
+    /// Number of outstanding sampled allocations
+    pub outstanding_allocs: usize,
+    /// Number of unique stacks recorded
+    pub num_stacks: usize,
+    /// Fraction (0.0-1.0) of sampled retained bytes from the top 10 stacks by retained bytes
+    pub top_10_retained_fraction: f64,
+}
+
+impl fmt::Display for ProfileSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Ying: {} bytes retained (peak {}), est. {} bytes retained by profiled allocs, {} outstanding sampled \
+             allocs, {} stacks, top 10 stacks hold {:.1}% of retained",
+            self.total_retained_bytes,
+            self.peak_retained_bytes,
+            self.estimated_profiled_retained_bytes,
+            self.outstanding_allocs,
+            self.num_stacks,
+            self.top_10_retained_fraction * 100.0
+        )
+    }
+}
+
+/// Counts of sampling decisions since startup, from `YingProfiler::sampling_stats()`.  Allocations which are
+/// neither sampled nor skipped by one of the counted reasons were made while the profiler was disarmed or on an
+/// excluded thread.  Displays as a single line suitable for logging.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct SamplingStats {
+    /// Allocations seen, including reallocs sampled as new allocations, see `with_untracked_realloc_sampling`
+    pub alloc_calls: usize,
+    /// Allocations sampled and recorded against their stack
+    pub sampled: usize,
+    /// Allocations made by the profiler itself, while recording a sample or building a report
+    pub skipped_reentrant: usize,
+    /// Allocations not due to be sampled at the current sampling ratio
+    pub skipped_by_ratio: usize,
+    /// Allocations due to be sampled but skipped by `with_max_samples_per_sec`
+    pub throttled: usize,
+    /// Allocations due to be sampled but skipped by `with_ignored_allocations`
+    pub ignored: usize,
+}
+
+impl SamplingStats {
+    /// Allocations seen per sampled allocation, outside of the profiler's own allocations, to compare with the
+    /// configured sampling ratio.  Per thread sampling counters and skipped samples make it differ a little.
+    /// 0 if nothing was sampled.
+    pub fn observed_ratio(&self) -> f64 {
+        if self.sampled == 0 {
+            return 0.0;
+        }
+        self.alloc_calls.saturating_sub(self.skipped_reentrant) as f64 / self.sampled as f64
+    }
+}
+
+impl fmt::Display for SamplingStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Ying: {} allocs seen, {} sampled (1 in {:.1}), skipped {} reentrant, {} by ratio, {} throttled, \
+             {} ignored",
+            self.alloc_calls,
+            self.sampled,
+            self.observed_ratio(),
+            self.skipped_reentrant,
+            self.skipped_by_ratio,
+            self.throttled,
+            self.ignored
+        )
+    }
+}
+
+/// Sizes of the profiler's internal maps, from `YingProfiler::stack_cardinality_report()`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct StackCardinality {
+    /// Number of unique stacks recorded
+    pub num_stacks: usize,
+    /// Number of outstanding sampled allocations being tracked
+    pub outstanding_allocs: usize,
+    /// Number of instruction pointers with cached symbols
+    pub symbol_map_entries: usize,
+    /// Number of frames stored for all stacks, with frames shared by stacks with the same outer frames
+    /// stored only once
+    pub interned_frames: usize,
+}
+
+impl fmt::Display for StackCardinality {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Ying: {} stacks, {} outstanding sampled allocs, {} symbol map entries, {} interned frames",
+            self.num_stacks,
+            self.outstanding_allocs,
+            self.symbol_map_entries,
+            self.interned_frames
+        )
+    }
+}
+
+/// Configuration of a profiler, from `YingProfiler::config()`.  Sampled byte counts and allocation counts are
+/// multiplied by the sampling ratio to estimate the real ones.  Displays as a single line of `key=value` pairs.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct YingConfig {
+    /// Version of ying-profiler
+    pub version: &'static str,
+    /// Number of allocations for every sampled allocation, as configured
+    pub sampling_ratio: u32,
+    /// The sampling ratio in use when the config was read, which differs only with adaptive sampling or a CPU
+    /// budget
+    pub effective_sampling_ratio: u32,
+    /// Least sampling ratio that adaptive sampling tightens to, if it is on
+    pub adaptive_min_ratio: Option<u32>,
+    /// Whether the number of allocations between samples is randomized around the sampling ratio
+    pub sampling_jitter: bool,
+    /// Seed of the sampling jitter, if one was set
+    pub sampling_seed: Option<u64>,
+    /// Seed of stack hashes, which must match to compare stack hashes between profiles
+    pub stack_hash_seed: u64,
+    /// Whether stacks are captured by walking frame pointers, which changes their hashes
+    pub frame_pointer_unwinding: bool,
+    /// Allocations of at least this many bytes are denied
+    pub single_alloc_limit: usize,
+    /// Most outstanding sampled allocations tracked, if limited
+    pub max_outstanding_allocs: Option<usize>,
+    /// Most samples per second on each thread, if limited
+    pub max_samples_per_sec: Option<u64>,
+    /// Budget for the profiler's own memory, if set
+    pub max_profiler_memory_bytes: Option<usize>,
+    /// Budget for the CPU spent profiling, in parts per million of one core, if set
+    pub max_profiling_cpu_ppm: Option<u32>,
+    /// Cargo features ying-profiler was built with
+    pub features: Vec<&'static str>,
+}
+
+impl YingConfig {
+    fn enabled_features() -> Vec<&'static str> {
+        [
+            ("profile-spans", cfg!(feature = "profile-spans")),
+            ("disabled", cfg!(feature = "disabled")),
+            ("tracing-logs", cfg!(feature = "tracing-logs")),
+            ("fast-hash", cfg!(feature = "fast-hash")),
+            ("tokio", cfg!(feature = "tokio")),
+            ("rayon", cfg!(feature = "rayon")),
+            ("otel", cfg!(feature = "otel")),
+            ("capi", cfg!(feature = "capi")),
+            ("gzip", cfg!(feature = "gzip")),
+        ]
+        .into_iter()
+        .filter_map(|(name, enabled)| enabled.then_some(name))
+        .collect()
+    }
+
+    /// Each setting with its value as JSON, in a fixed order
+    pub(crate) fn json_fields(&self) -> Vec<(&'static str, String)> {
+        fn json<T: fmt::Display>(value: Option<T>) -> String {
+            value.map_or_else(|| "null".to_string(), |v| v.to_string())
+        }
+        let features: Vec<String> = self.features.iter().map(|f| format!("\"{}\"", f)).collect();
+        vec![
+            ("version", format!("\"{}\"", self.version)),
+            ("sampling_ratio", self.sampling_ratio.to_string()),
+            (
+                "effective_sampling_ratio",
+                self.effective_sampling_ratio.to_string(),
+            ),
+            ("adaptive_min_ratio", json(self.adaptive_min_ratio)),
+            ("sampling_jitter", self.sampling_jitter.to_string()),
+            // Seeds as hex strings, like stack hashes, as they may not fit in a JSON number
+            (
+                "sampling_seed",
+                json(self.sampling_seed.map(|seed| format!("\"{:#x}\"", seed))),
+            ),
+            (
+                "stack_hash_seed",
+                format!("\"{:#x}\"", self.stack_hash_seed),
+            ),
+            (
+                "frame_pointer_unwinding",
+                self.frame_pointer_unwinding.to_string(),
+            ),
+            ("single_alloc_limit", self.single_alloc_limit.to_string()),
+            ("max_outstanding_allocs", json(self.max_outstanding_allocs)),
+            ("max_samples_per_sec", json(self.max_samples_per_sec)),
+            (
+                "max_profiler_memory_bytes",
+                json(self.max_profiler_memory_bytes),
+            ),
+            ("max_profiling_cpu_ppm", json(self.max_profiling_cpu_ppm)),
+            ("features", format!("[{}]", features.join(","))),
+        ]
+    }
+}
+
+impl fmt::Display for YingConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Ying config:")?;
+        for (key, value) in self.json_fields() {
+            write!(f, " {}={}", key, value)?;
+        }
+        Ok(())
+    }
+}
+
+/// An outstanding sampled allocation, from `YingProfiler::outstanding_report()`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OutstandingAlloc {
+    /// Address of the allocation
+    pub ptr: u64,
+    /// Size, stack hash and time of the allocation
+    pub info: AllocInfo,
+    /// How long ago the allocation was made, in millis
+    pub age_millis: u64,
+    /// Symbols of the allocating stack as in `StackStats::canonical_key`, or None if the stack is no longer
+    /// recorded
+    pub stack: Option<String>,
+}
+
+impl fmt::Display for OutstandingAlloc {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:#x}: {} bytes, {}s old, stack {:x}: {}",
+            self.ptr,
+            self.info.size,
+            self.age_millis / 1000,
+            self.info.stack_hash,
+            self.stack.as_deref().unwrap_or("<unknown>")
+        )
+    }
+}
+
+/// Outstanding sampled allocations of one size class, from `YingProfiler::retained_by_size_class()`
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct SizeClassStats {
+    /// Largest allocation size in this class, inclusive.  The class starts just above the previous class's max.
+    /// `u64::MAX` for the largest class.
+    pub max_size: u64,
+    /// Number of outstanding sampled allocations
+    pub num_allocs: u64,
+    /// Retained bytes of outstanding sampled allocations.  Multiply by the sampling ratio for an estimate.
+    pub sampled_bytes: u64,
+}
+
+/// Outstanding sampled allocations made in one profile generation, from `YingProfiler::retained_by_generation()`
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct GenerationStats {
+    /// The profile generation, see `YingProfiler::generation()`
+    pub generation: u32,
+    /// Number of outstanding sampled allocations
+    pub num_allocs: u64,
+    /// Retained bytes of outstanding sampled allocations.  Multiply by the sampling ratio for an estimate.
+    pub sampled_bytes: u64,
+}
+
+// Size classes are <= 16, <= 64, <= 256 ... <= 64MB, and the rest
+const NUM_SIZE_CLASSES: usize = 13;
+
+fn size_class(size: u64) -> usize {
+    // Number of base 4 digits of (size - 1) beyond the first two, ie 16 and below is class 0
+    let bits = 64 - size.saturating_sub(1).leading_zeros() as usize;
+    (bits.saturating_sub(3) / 2).min(NUM_SIZE_CLASSES - 1)
+}
+
+fn size_class_max(class: usize) -> u64 {
+    if class == NUM_SIZE_CLASSES - 1 {
+        u64::MAX
+    } else {
+        16 << (2 * class)
+    }
+}
+
+/// What is tracked for each outstanding sampled allocation, see `YingProfiler::lookup_allocation`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct AllocInfo {
+    stack_hash: u64,
+    // When the allocation was made, in epoch millis
+    alloc_ts: u64,
+    // Generation of the stack's stats when the allocation was made.  Allocations from an older generation
+    // predate the last reset of the stack, and their frees are kept apart from post-reset stats.
+    generation: u32,
+    // Generation of the whole profile when the allocation was made
+    profile_generation: u32,
+    // Current size in bytes, following reallocs
+    size: u64,
+    // Unique id of the sampled allocation, kept across reallocs, which tells it apart from a later allocation that
+    // reused its address
+    alloc_id: u64,
+    // Marked as deliberately kept with `mark_intentional`
+    intentional: bool,
+    // Tokio task which made the allocation
+    #[cfg(feature = "tokio")]
+    task_id: Option<tokio::task::Id>,
+}
+
+impl AllocInfo {
+    /// Hash of the stack which made the allocation, as in `StackStats::stack_hash`
+    pub fn stack_hash(&self) -> u64 {
+        self.stack_hash
+    }
+
+    /// When the allocation was made, in millis since the epoch
+    pub fn alloc_millis(&self) -> u64 {
+        self.alloc_ts
+    }
+
+    /// Profile generation the allocation was made in, see `YingProfiler::generation()`
+    pub fn profile_generation(&self) -> u32 {
+        self.profile_generation
+    }
+
+    /// Current size of the allocation in bytes, following any reallocs
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Whether the allocation was marked as deliberately kept with `YingProfiler::mark_intentional`
+    pub fn is_intentional(&self) -> bool {
+        self.intentional
+    }
+
+    /// The Tokio task which made the allocation, if it was made inside one
+    #[cfg(feature = "tokio")]
+    pub fn task_id(&self) -> Option<tokio::task::Id> {
+        self.task_id
+    }
+}
+
 // Private state.  We can't put this in the main YingProfiler struct as that one has to be const static
 struct YingState {
     symbol_map: SymbolMap,
     // Main map of stack hash to StackStats
-    stack_stats: DashMap<u64, StackStats>,
+    stack_stats: DashMap<u64, StackStats, MapHasher>,
     // Map of outstanding sampled allocations.  Used to figure out amount of outstanding allocations and
     // statistics about how long lived outstanding allocations are.
-    // (*ptr as u64 -> (stack hash, start_timestamp_epoch_millis))
-    outstanding_allocs: DashMap<u64, (u64, u64)>,
+    // (*ptr as u64 -> AllocInfo)
+    outstanding_allocs: DashMap<u64, AllocInfo, PointerMapHasher>,
+    // Number of entries in outstanding_allocs, kept in step with it so that sampling doesn't have to count the
+    // entries of every shard
+    num_outstanding: AtomicUsize,
+    // Stack hashes rejected by the stack allowlist, so their symbols are only checked once
+    #[cfg(not(any(
+        feature = "disabled",
+        not(any(target_os = "linux", target_os = "macos"))
+    )))]
+    disallowed_stacks: DashMap<u64, (), MapHasher>,
+    // Frame IP -> whether the hash frame filter keeps it, so each frame's symbol is only checked once
+    #[cfg(not(any(
+        feature = "disabled",
+        not(any(target_os = "linux", target_os = "macos"))
+    )))]
+    hash_frame_verdicts: DashMap<u64, bool, MapHasher>,
+    // Sampled allocations freed while profiling was locked out on the freeing thread, as (ptr, alloc_id, size).
+    // They can't be removed from outstanding_allocs then, as the thread may hold parts of it locked.
+    #[cfg(not(any(
+        feature = "disabled",
+        not(any(target_os = "linux", target_os = "macos"))
+    )))]
+    deferred_frees: Mutex<Vec<(u64, u64, usize)>>,
+    #[cfg(not(any(
+        feature = "disabled",
+        not(any(target_os = "linux", target_os = "macos"))
+    )))]
+    has_deferred_frees: AtomicBool,
+    // Sampled allocations by CPU, indexed by CPU number
+    cpu_counters: Vec<CpuCounters>,
+    // Sampled allocations by Tokio task, for tasks with outstanding sampled allocations
+    #[cfg(feature = "tokio")]
+    task_stats: tasks::TaskMap,
+    // Stacks which spawned blocking tasks through YingProfiler::spawn_blocking, by stack hash
+    #[cfg(feature = "tokio")]
+    spawner_stacks: DashMap<u64, StdCallstack, MapHasher>,
+    // Stack hash -> millis when a giant allocation from that stack was last warned about
+    giant_alloc_warnings: DashMap<u64, u64, MapHasher>,
+    // Stack activity over the last interval, for YingProfiler::top_movers
+    movers: movers::MoverTracker,
+    // Call stacks of stack_stats, interned so that stacks share their common outer frames
+    stacks: intern::StackInterner,
+    // Stacks of the baseline profile by canonical key, loaded on first use
+    baseline: OnceCell<HashMap<String, OwnedStackStats>>,
 }
 
 impl YingState {
     pub fn new() -> Self {
-        let symbol_map = SymbolMap::with_capacity(1000);
-        let stack_stats = DashMap::with_capacity(1000);
-        let outstanding_allocs = DashMap::with_capacity(5000);
+        let symbol_map = SymbolMap::with_capacity_and_hasher(1000, MapHasher::default());
+        let stack_stats = DashMap::with_capacity_and_hasher(1000, MapHasher::default());
+        let outstanding_allocs =
+            DashMap::with_capacity_and_hasher(5000, PointerMapHasher::default());
         Self {
             symbol_map,
             stack_stats,
             outstanding_allocs,
+            num_outstanding: AtomicUsize::new(0),
+            #[cfg(not(any(
+                feature = "disabled",
+                not(any(target_os = "linux", target_os = "macos"))
+            )))]
+            disallowed_stacks: DashMap::with_hasher(MapHasher::default()),
+            #[cfg(not(any(
+                feature = "disabled",
+                not(any(target_os = "linux", target_os = "macos"))
+            )))]
+            hash_frame_verdicts: DashMap::with_hasher(MapHasher::default()),
+            #[cfg(not(any(
+                feature = "disabled",
+                not(any(target_os = "linux", target_os = "macos"))
+            )))]
+            deferred_frees: Mutex::new(Vec::new()),
+            #[cfg(not(any(
+                feature = "disabled",
+                not(any(target_os = "linux", target_os = "macos"))
+            )))]
+            has_deferred_frees: AtomicBool::new(false),
+            cpu_counters: CpuCounters::new_table(),
+            #[cfg(feature = "tokio")]
+            task_stats: DashMap::with_hasher(MapHasher::default()),
+            #[cfg(feature = "tokio")]
+            spawner_stacks: DashMap::with_hasher(MapHasher::default()),
+            giant_alloc_warnings: DashMap::with_hasher(MapHasher::default()),
+            movers: Default::default(),
+            stacks: Default::default(),
+            baseline: OnceCell::new(),
         }
     }
 }
@@ -329,6 +2067,27 @@ struct YingLocalCache {
     local_states: [YingThreadLocal; YING_CACHE_SIZE],
 }
 
+// Locks out profiling on the current thread for as long as it lives, for work on threads other than the caller's
+#[cfg(feature = "rayon")]
+struct LockOutGuard<'c> {
+    tl_cache: &'c YingLocalCache,
+}
+
+#[cfg(feature = "rayon")]
+impl<'c> LockOutGuard<'c> {
+    fn new(tl_cache: &'c YingLocalCache) -> Self {
+        tl_cache.get_thread_local().set_allocator_lock();
+        Self { tl_cache }
+    }
+}
+
+#[cfg(feature = "rayon")]
+impl<'c> Drop for LockOutGuard<'c> {
+    fn drop(&mut self) {
+        self.tl_cache.get_thread_local().release_allocator_lock();
+    }
+}
+
 impl YingLocalCache {
     const fn new() -> Self {
         Self {
@@ -374,6 +2133,22 @@ struct YingThreadLocal {
     // of re-entrant allocations done).  Nonzero prevents allocator from sampling.
     alloc_lock: u32,
     sample_count: u32,
+    // With sampling jitter: allocations left until the next sample, 0 if not yet drawn, and the xorshift state
+    jitter_countdown: u32,
+    rng_state: u64,
+    // With excluded threads: the thread ID whose name was last checked, and whether that thread is excluded.
+    // Several threads can share one YingThreadLocal, so the verdict is only valid for the thread that checked.
+    name_checked_thread: usize,
+    thread_excluded: bool,
+    // With a limit on samples per second: the token bucket, in thousandths of a sample, and when it was refilled
+    sample_tokens: u64,
+    tokens_refilled_millis: u64,
+    // Hash of the stack which spawned the blocking task this thread is running, or 0
+    #[cfg(feature = "tokio")]
+    spawner_hash: u64,
+    // Number of frames of the blocking pool's thread below the blocking task, when spawner_hash is set
+    #[cfg(feature = "tokio")]
+    pool_frames: u32,
 }
 
 impl YingThreadLocal {
@@ -381,9 +2156,23 @@ impl YingThreadLocal {
         Self {
             alloc_lock: 0,
             sample_count: 0,
+            jitter_countdown: 0,
+            rng_state: 0,
+            name_checked_thread: 0,
+            thread_excluded: false,
+            sample_tokens: 0,
+            tokens_refilled_millis: 0,
+            #[cfg(feature = "tokio")]
+            spawner_hash: 0,
+            #[cfg(feature = "tokio")]
+            pool_frames: 0,
         }
     }
 
+    #[cfg(not(any(
+        feature = "disabled",
+        not(any(target_os = "linux", target_os = "macos"))
+    )))]
     #[inline]
     fn is_allocator_locked(&self) -> bool {
         self.alloc_lock > 0
@@ -400,84 +2189,397 @@ impl YingThreadLocal {
     }
 
     /// Obtains the counter, checks for sampling ratio, and updates counter in one go
+    #[cfg(not(any(
+        feature = "disabled",
+        not(any(target_os = "linux", target_os = "macos"))
+    )))]
     #[inline]
-    fn should_sample(&mut self, ratio: u32) -> bool {
+    fn should_sample(&mut self, ratio: u32, jitter: bool, seed: Option<u64>) -> bool {
+        if jitter {
+            return self.should_sample_jittered(ratio, seed);
+        }
         self.sample_count += 1; // update counter for next sampling
-        self.sample_count % ratio == 0
+        self.sample_count.is_multiple_of(ratio)
+    }
+
+    // Like `should_sample`, but with a random number of allocations between samples averaging `ratio`
+    #[cfg(not(any(
+        feature = "disabled",
+        not(any(target_os = "linux", target_os = "macos"))
+    )))]
+    #[inline]
+    fn should_sample_jittered(&mut self, ratio: u32, seed: Option<u64>) -> bool {
+        if self.jitter_countdown == 0 {
+            if self.rng_state == 0 {
+                self.rng_state = sampling::initial_rng_state(seed, hash_usize(thread_id()) as u64);
+            }
+            self.jitter_countdown =
+                sampling::jittered_interval(ratio, sampling::xorshift64(&mut self.rng_state));
+        }
+        self.jitter_countdown -= 1;
+        self.jitter_countdown == 0
+    }
+
+    // Takes a token for one sample from the bucket, which refills by `per_sec` thousandths of a token every
+    // millisecond, up to a second's worth of tokens
+    #[cfg(not(any(
+        feature = "disabled",
+        not(any(target_os = "linux", target_os = "macos"))
+    )))]
+    #[inline]
+    fn take_sample_token(&mut self, per_sec: u64) -> bool {
+        let now = now_millis();
+        let refill = now
+            .saturating_sub(self.tokens_refilled_millis)
+            .saturating_mul(per_sec);
+        self.sample_tokens = self
+            .sample_tokens
+            .saturating_add(refill)
+            .min(per_sec.saturating_mul(1000));
+        self.tokens_refilled_millis = now;
+        if self.sample_tokens >= 1000 {
+            self.sample_tokens -= 1000;
+            true
+        } else {
+            false
+        }
     }
 
     // Resets counter to 0 to guarantee next call to alloc() will sample.  TESTING ONLY
     #[inline]
     fn test_only_reset_sampling_counter(&mut self) {
         self.sample_count = 0;
+        self.jitter_countdown = 1;
     }
 }
 
+// Warns the first time the profile is used on a target where profiling is compiled out, as reports will be empty
+#[cfg(not(any(feature = "disabled", target_os = "linux", target_os = "macos")))]
+fn warn_unsupported_target_once() {
+    static WARNED: AtomicBool = AtomicBool::new(false);
+    if !WARNED.swap(true, Relaxed) {
+        log::warn!(
+            "Ying: profiling is not supported on this target, so allocations are passed straight through and all \
+             reports are empty"
+        );
+    }
+}
+
+// Only does an atomic read-modify-write when there is a new peak, so most calls are just a load
+#[cfg(not(any(
+    feature = "disabled",
+    not(any(target_os = "linux", target_os = "macos"))
+)))]
+#[inline]
+fn update_peak_retained(total_retained: usize) {
+    if total_retained > PEAK_RETAINED.load(Relaxed) {
+        PEAK_RETAINED.fetch_max(total_retained, Relaxed);
+    }
+}
+
+// Subtracts freed bytes from TOTAL_RETAINED without wrapping below zero.  Frees of memory that was never counted
+// as allocated would otherwise wrap it around to an absurdly large number; the excess is counted separately.
+#[cfg(not(any(
+    feature = "disabled",
+    not(any(target_os = "linux", target_os = "macos"))
+)))]
+#[inline]
+fn sub_total_retained(bytes: usize) {
+    let prev = TOTAL_RETAINED
+        .fetch_update(SeqCst, SeqCst, |total| Some(total.saturating_sub(bytes)))
+        .unwrap_or_else(|prev| prev);
+    if prev < bytes {
+        FREED_WITHOUT_ALLOC.fetch_add(bytes - prev, Relaxed);
+    }
+}
+
+/// Current wall clock time in epoch milliseconds.  Uses the coarse clock, which is cheap and does not allocate.
+/// Note that `Clock::recent_since_epoch()` only advances when `Clock::update()` is called, so it can't be used
+/// for measuring rates.
+#[inline]
+pub(crate) fn now_millis() -> u64 {
+    Clock::now_since_epoch().as_millis()
+}
+
 #[cfg(unix)]
 pub(crate) fn thread_id() -> usize {
-    unsafe { libc::pthread_self() }
+    unsafe { libc::pthread_self() as usize }
 }
 
-#[cfg(windows)]
+// The address of a thread local is unique among live threads, and reading it does not allocate
+#[cfg(not(unix))]
 pub(crate) fn thread_id() -> usize {
-    unsafe { libc::GetCurrentThreadId() as usize }
+    thread_local! {
+        static ID: u8 = const { 0 };
+    }
+    ID.with(|id| id as *const u8 as usize)
+}
+
+// Writes the current thread's OS-level name into `buf` and returns it.  Does not allocate.
+#[cfg(not(any(
+    feature = "disabled",
+    not(any(target_os = "linux", target_os = "macos"))
+)))]
+fn current_thread_name(buf: &mut [u8; 64]) -> Option<&str> {
+    let ret = unsafe {
+        libc::pthread_getname_np(
+            libc::pthread_self(),
+            buf.as_mut_ptr() as *mut libc::c_char,
+            buf.len(),
+        )
+    };
+    if ret != 0 {
+        return None;
+    }
+    let len = buf.iter().position(|&b| b == 0)?;
+    std::str::from_utf8(&buf[..len]).ok()
+}
+
+#[cfg(not(any(
+    feature = "disabled",
+    not(any(target_os = "linux", target_os = "macos"))
+)))]
+impl<A: GlobalAlloc> YingProfiler<A> {
+    /// Whether an allocation on this thread should be sampled now.  Counts towards the sampling ratio.
+    #[inline]
+    fn should_profile(&self, tl_state: &mut YingThreadLocal) -> bool {
+        SAMPLING_DECISIONS.fetch_add(1, Relaxed);
+        if tl_state.is_allocator_locked() {
+            REENTRANT_SKIPS.fetch_add(1, Relaxed);
+            return false;
+        }
+        if !self.is_armed() {
+            return false;
+        }
+        if !tl_state.should_sample(
+            self.effective_sampling_ratio(),
+            self.sampling_jitter,
+            self.sampling_seed,
+        ) {
+            RATIO_SKIPS.fetch_add(1, Relaxed);
+            return false;
+        }
+        !self.is_thread_excluded(tl_state) && self.within_sample_rate(tl_state)
+    }
+
+    /// Whether an allocation due to be sampled is ignored, see `with_ignored_allocations`
+    #[inline]
+    fn is_ignored(&self, ptr: *const u8, size: usize) -> bool {
+        match self.ignore_allocation {
+            Some(ignore) if ignore(ptr, size) => {
+                IGNORED_SAMPLES.fetch_add(1, Relaxed);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    #[inline]
+    fn within_sample_rate(&self, tl_state: &mut YingThreadLocal) -> bool {
+        if self.max_samples_per_sec == u64::MAX
+            || tl_state.take_sample_token(self.max_samples_per_sec)
+        {
+            return true;
+        }
+        THROTTLED_SAMPLES.fetch_add(1, Relaxed);
+        false
+    }
+
+    /// Captures the stack of a sampled allocation of `size` bytes, by walking frame pointers if enabled, or just
+    /// the innermost frames if the allocation is under the depth budget's size threshold, or else a full
+    /// backtrace.  Stacks captured without a backtrace come with an empty one, and their symbols are resolved from
+    /// their IPs alone.  Also returns the number of frames in the stack, counted as `Backtrace::frames()` does, or
+    /// None if the stack was cut short.  Always inlined, like `profile_sampled_alloc`.
+    #[inline(always)]
+    fn capture_stack(&self, size: usize) -> (Backtrace, StdCallstack, Option<usize>) {
+        if self.frame_pointers {
+            let walked = unwind::frame_pointer_stack(FP_FRAMES_TO_SKIP)
+                .filter(|(_, num_frames)| *num_frames > FP_FRAMES_TO_SKIP);
+            if let Some((stack, num_frames)) = walked {
+                // Counting the capturing function's frame too, as a Backtrace does
+                return (Backtrace::from(Vec::new()), stack, Some(num_frames + 1));
+            }
+        }
+        if size < self.full_depth_min_size {
+            let shallow = unwind::shallow_stack(FP_FRAMES_TO_SKIP, self.shallow_stack_frames)
+                .filter(|(stack, _)| !stack.is_unknown());
+            if let Some((stack, num_frames)) = shallow {
+                return (Backtrace::from(Vec::new()), stack, num_frames);
+            }
+        }
+
+        let bt = Backtrace::new_unresolved();
+        // Backtraces with no frames beyond the profiler's own are all routed into one "unknown" stack, rather than
+        // a misleading stack made up of a frame or two.
+        let stack = if bt.frames().len() <= TOP_FRAMES_TO_SKIP {
+            UNKNOWN_STACK_SAMPLES.fetch_add(1, SeqCst);
+            StdCallstack::unknown()
+        } else {
+            StdCallstack::from_backtrace_unresolved(&bt)
+        };
+        let num_frames = bt.frames().len();
+        (bt, stack, Some(num_frames))
+    }
+
+    /// Records a sampled allocation of `size` bytes at `ptr` against the current stack.  Always inlined, so
+    /// that the stack has the same number of profiler frames to skip from both alloc() and realloc().
+    #[inline(always)]
+    unsafe fn profile_sampled_alloc(
+        &self,
+        tl_state: &mut YingThreadLocal,
+        ptr: *mut u8,
+        size: usize,
+    ) {
+        tl_state.set_allocator_lock();
+        SAMPLED_ALLOCS.fetch_add(1, Relaxed);
+        let start = self.time_sampling.then(Instant::now);
+        let cpu_start = self.cpu_budget.is_enabled().then(thread_cpu_nanos);
+        if self.adaptive.is_enabled() {
+            self.adaptive.on_sample(now_millis());
+        }
+
+        // -- Beginning of section that may allocate
+        // 1. Capture the stack unresolved for speed, and 2. create a Callstack out of it
+        #[cfg_attr(not(feature = "tokio"), allow(unused_variables))]
+        let (mut bt, stack, num_frames) = self.capture_stack(size);
+        let state = self.get_state();
+        // An entry for a block freed earlier at this address would otherwise shadow this allocation
+        self.finish_deferred_frees(state);
+        // Stacks cut short by the depth budget don't reach the frames a spawner would be stitched over
+        #[cfg(feature = "tokio")]
+        let stack = match num_frames {
+            Some(num_frames) => tasks::stitch_spawner(state, tl_state, num_frames, stack),
+            None => stack,
+        };
+        let stack = scope::label_stack(state, stack);
+        let hashed = self.hashed_stack(state, &stack, &mut bt);
+        let hashed_stack = hashed.as_ref().unwrap_or(&stack);
+        let stack_hash = hashed_stack.compute_hash_with_seed(self.stack_hash_seed);
+        if self.stack_allowlist.is_empty()
+            || self.is_stack_allowed(state, &stack, stack_hash, &mut bt)
+        {
+            PROFILED_ALLOCATED.fetch_add(size, SeqCst);
+            if self.track_cpus {
+                if let Some(cpu) = numa::current_cpu() {
+                    state.cpu_counters[cpu].record(size as u64);
+                }
+            }
+            let shedding = self.check_memory_budget(state);
+            let track_outstanding = !shedding
+                && (self.max_outstanding_allocs == usize::MAX
+                    || state.num_outstanding.load(Relaxed) < self.max_outstanding_allocs);
+            if track_outstanding {
+                PROFILED_RETAINED.fetch_add(size, SeqCst);
+            } else {
+                UNTRACKED_OUTSTANDING.fetch_add(1, SeqCst);
+            }
+            SIZE_CATEGORIES.record_alloc(size, track_outstanding);
+
+            let generation = state
+                .stack_stats
+                .entry(stack_hash)
+                .and_modify(|stats| {
+                    if self.check_collisions
+                        && stats.fingerprint() != hashed_stack.compute_fingerprint()
+                    {
+                        STACK_HASH_COLLISIONS.fetch_add(1, Relaxed);
+                    }
+                    // 4. Update stats
+                    stats.update_alloc_stats(size as u64);
+                })
+                .or_insert_with(|| {
+                    // 3. Resolve symbols if needed (new stack entry), unless left for later by lazy symbolization
+                    if self.lazy_symbols_top_n == 0 {
+                        stack.populate_symbol_map_with(&mut bt, &state.symbol_map, self.symbolizer);
+                    }
+                    let fingerprint = hashed.as_ref().map(|hashed| hashed.compute_fingerprint());
+                    let stats =
+                        StackStats::new(&stack, &state.stacks, stack_hash, Some(size as u64));
+                    match fingerprint {
+                        Some(fingerprint) => stats.with_fingerprint(fingerprint),
+                        None => stats,
+                    }
+                })
+                .generation();
+
+            #[cfg(feature = "tokio")]
+            let task_id = tasks::current_task_id();
+            #[cfg(feature = "tokio")]
+            if let (Some(task_id), true) = (task_id, track_outstanding) {
+                tasks::record_alloc(&state.task_stats, task_id, size as u64);
+            }
+
+            // 4. Record allocation so we can track outstanding vs transient allocs
+            if track_outstanding {
+                state
+                    .outstanding_allocs
+                    .entry(ptr as u64)
+                    .or_insert_with(|| {
+                        state.num_outstanding.fetch_add(1, SeqCst);
+                        AllocInfo {
+                            stack_hash,
+                            alloc_ts: Clock::recent_since_epoch().as_millis(),
+                            generation,
+                            profile_generation: self.generation.load(Relaxed),
+                            size: size as u64,
+                            alloc_id: NEXT_ALLOC_ID.fetch_add(1, Relaxed),
+                            intentional: false,
+                            #[cfg(feature = "tokio")]
+                            task_id,
+                        }
+                    });
+            }
+        }
+
+        // -- End of core profiling section, no more allocations --
+        if let Some(start) = start {
+            SAMPLING_LATENCY.add_sample(start.elapsed().as_nanos() as u64);
+        }
+        if let Some(cpu_start) = cpu_start {
+            self.cpu_budget
+                .on_sample(now_millis(), thread_cpu_nanos().saturating_sub(cpu_start));
+        }
+        tl_state.release_allocator_lock();
+    }
 }
 
-unsafe impl GlobalAlloc for YingProfiler {
+#[cfg(not(any(
+    feature = "disabled",
+    not(any(target_os = "linux", target_os = "macos"))
+)))]
+unsafe impl<A: GlobalAlloc> GlobalAlloc for YingProfiler<A> {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
         // NOTE: the code between here and the state.0 = true must be re-entrant
         // and therefore not allocate, otherwise there will be an infinite loop.
-        let alloc_ptr = self.check_and_deny_giant_allocations(System.alloc(layout), layout);
+        let alloc_ptr = self.check_and_deny_giant_allocations(
+            self.check_alloc_failure(self.inner.alloc(layout), layout),
+            layout,
+        );
         if !alloc_ptr.is_null() {
-            TOTAL_RETAINED.fetch_add(layout.size(), SeqCst);
+            ALLOC_SIZES.add(layout.size());
+            let total_retained = TOTAL_RETAINED.fetch_add(layout.size(), SeqCst) + layout.size();
+            update_peak_retained(total_retained);
+            self.check_memory_pressure(total_retained);
 
             // Now, sample allocation - if it falls below threshold, then profile
             // Also, we set a ThreadLocal to avoid re-entry: ie the code below might allocate,
             // and we avoid profiling if we are already in the loop below.  Avoids cycles.
             let tl_state = self.tl_cache.get_thread_local();
-            if !tl_state.is_allocator_locked() && tl_state.should_sample(self.sampling_ratio) {
-                tl_state.set_allocator_lock();
-
-                PROFILED_ALLOCATED.fetch_add(layout.size(), SeqCst);
-                PROFILED_RETAINED.fetch_add(layout.size(), SeqCst);
-
-                // -- Beginning of section that may allocate
-                // 1. Get unresolved backtrace for speed
-                let mut bt = Backtrace::new_unresolved();
-
-                // 2. Create a Callstack, check if there is a similar stack
-                let stack = StdCallstack::from_backtrace_unresolved(&bt);
-                let stack_hash = stack.compute_hash();
-                self.get_state()
-                    .stack_stats
-                    .entry(stack_hash)
-                    .and_modify(|stats| {
-                        // 4. Update stats
-                        stats.num_allocations += 1;
-                        stats.allocated_bytes += layout.size() as u64;
-                    })
-                    .or_insert_with(|| {
-                        // 3. Resolve symbols if needed (new stack entry)
-                        stack.populate_symbol_map(&mut bt, &self.get_state().symbol_map);
-                        StackStats::new(stack, Some(layout.size() as u64))
-                    });
-
-                // 4. Record allocation so we can track outstanding vs transient allocs
-                self.get_state()
-                    .outstanding_allocs
-                    .entry(alloc_ptr as u64)
-                    .or_insert_with(|| (stack_hash, Clock::recent_since_epoch().as_millis()));
-
-                // -- End of core profiling section, no more allocations --
-                tl_state.release_allocator_lock();
+            if self.should_profile(tl_state) && !self.is_ignored(alloc_ptr, layout.size()) {
+                self.profile_sampled_alloc(tl_state, alloc_ptr, layout.size());
             }
         }
         alloc_ptr
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        System.dealloc(ptr, layout);
-        TOTAL_RETAINED.fetch_sub(layout.size(), SeqCst);
+        self.inner.dealloc(ptr, layout);
+        // alloc() counts every allocation in TOTAL_RETAINED, whether or not YING_STATE is initialized yet, so
+        // frees are always subtracted too.  The only frees never counted as allocated are of memory from before
+        // the profiler was installed, which can't be told apart without a per-allocation header; those are
+        // clamped and counted by sub_total_retained().
+        sub_total_retained(layout.size());
+        self.rearm_memory_pressure();
 
         // Return immediately and skip rest of this if YING_STATE is not initialized.  It could cause
         // an infinite loop because during initialization of YING_STATE, dealloc() could be then called
@@ -488,28 +2590,19 @@ unsafe impl GlobalAlloc for YingProfiler {
         // If the allocation was recorded in outstanding_allocs, then remove it and update stats
         // about number of bytes freed etc.  Do this with protection to guard against possible re-entry.
         let state = self.get_state();
-        if state.outstanding_allocs.contains_key(&(ptr as u64)) {
-            PROFILED_RETAINED.fetch_sub(layout.size(), SeqCst);
+        if self.is_armed() && state.outstanding_allocs.contains_key(&(ptr as u64)) {
             let tl_state = self.tl_cache.get_thread_local();
             if !tl_state.is_allocator_locked() {
                 tl_state.set_allocator_lock();
 
                 // -- Beginning of section that may allocate
-                if let Some((_, (stack_hash, alloc_ts))) =
-                    state.outstanding_allocs.remove(&(ptr as u64))
-                {
-                    let alloc_time_ms = Clock::recent_since_epoch()
-                        .as_millis()
-                        .saturating_sub(alloc_ts);
-
-                    // Update memory profiling freed bytes stats
-                    state.stack_stats.entry(stack_hash).and_modify(|stats| {
-                        stats.update_free_stats(layout.size() as u64, alloc_time_ms)
-                    });
-                }
+                self.finish_deferred_frees(state);
+                self.record_sampled_free(state, ptr as u64, layout.size(), None);
 
                 // -- End of core profiling section, no more allocations --
                 tl_state.release_allocator_lock();
+            } else {
+                self.defer_free(state, ptr as u64, layout.size());
             }
         }
     }
@@ -518,24 +2611,37 @@ unsafe impl GlobalAlloc for YingProfiler {
     // the following: - update original allocated bytes (but not allocations); move outstanding_allocs
     // because the pointer moved, but preserve original starting timestamp.
     // The above also saves us cycles from having to call alloc() and dealloc() separately.
+    // A zero new_size breaks the GlobalAlloc::realloc contract.  It can't be handled as C's realloc() does, by
+    // freeing and returning null, since null means the old block is still valid and callers would free it again.
+    // Nor can it panic, as unwinding out of an allocator is UB, so it fails and leaves the old block as it was.
     unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        if new_size == 0 {
+            return std::ptr::null_mut();
+        }
         let old_size = layout.size();
         // SAFETY: the caller must ensure that the `new_size` does not overflow.
         // `layout.align()` comes from a `Layout` and is thus guaranteed to be valid.
         let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
         // SAFETY: the caller must ensure that `new_layout` is greater than zero.
-        let new_ptr = self.check_and_deny_giant_allocations(System.alloc(new_layout), new_layout);
+        let new_ptr = self.check_and_deny_giant_allocations(
+            self.check_alloc_failure(self.inner.alloc(new_layout), new_layout),
+            new_layout,
+        );
         if !new_ptr.is_null() {
             // SAFETY: the previously allocated block cannot overlap the newly allocated block.
             // The safety contract for `dealloc` must be upheld by the caller.
             std::ptr::copy_nonoverlapping(ptr, new_ptr, std::cmp::min(old_size, new_size));
-            System.dealloc(ptr, layout);
+            self.inner.dealloc(ptr, layout);
 
             // 1. Update global statistics
             if new_size > old_size {
-                TOTAL_RETAINED.fetch_add(new_size - old_size, SeqCst);
+                let growth = new_size - old_size;
+                let total_retained = TOTAL_RETAINED.fetch_add(growth, SeqCst) + growth;
+                update_peak_retained(total_retained);
+                self.check_memory_pressure(total_retained);
             } else {
-                TOTAL_RETAINED.fetch_sub(old_size - new_size, SeqCst);
+                sub_total_retained(old_size - new_size);
+                self.rearm_memory_pressure();
             }
 
             // 2. IF the old pointer was in outstanding_allocs, move it and make a new entry,
@@ -543,41 +2649,99 @@ unsafe impl GlobalAlloc for YingProfiler {
             //    But only if state is alredy initialized - otherwise any state initialization that
             //    results in a realloc() could cause this to infinite loop
             let state = self.get_state();
-            if state.outstanding_allocs.contains_key(&(ptr as u64)) {
-                if new_size > old_size {
-                    PROFILED_RETAINED.fetch_add(new_size - old_size, SeqCst);
-                } else {
-                    PROFILED_RETAINED.fetch_sub(old_size - new_size, SeqCst);
-                }
-
+            if self.is_armed() && state.outstanding_allocs.contains_key(&(ptr as u64)) {
                 let tl_state = self.tl_cache.get_thread_local();
                 if !tl_state.is_allocator_locked() {
                     tl_state.set_allocator_lock();
 
                     // -- Beginning of section that may allocate
-                    if let Some((_, (stack_hash, alloc_ts))) =
-                        state.outstanding_allocs.remove(&(ptr as u64))
-                    {
-                        state
-                            .outstanding_allocs
-                            .insert(new_ptr as u64, (stack_hash, alloc_ts));
+                    if let Some((_, info)) = state.outstanding_allocs.remove(&(ptr as u64)) {
+                        state.num_outstanding.fetch_sub(1, SeqCst);
+                        if new_size > old_size {
+                            PROFILED_RETAINED.fetch_add(new_size - old_size, SeqCst);
+                        } else {
+                            PROFILED_RETAINED.fetch_sub(old_size - new_size, SeqCst);
+                        }
+                        SIZE_CATEGORIES.record_resize(old_size, new_size);
+
+                        state.num_outstanding.fetch_add(1, SeqCst);
+                        let replaced = state.outstanding_allocs.insert(
+                            new_ptr as u64,
+                            AllocInfo {
+                                size: new_size as u64,
+                                ..info
+                            },
+                        );
+                        if replaced.is_some() {
+                            state.num_outstanding.fetch_sub(1, SeqCst);
+                        }
 
                         // Update memory profiling freed bytes stats
-                        state.stack_stats.entry(stack_hash).and_modify(|stats| {
-                            if new_size > old_size {
-                                stats.allocated_bytes += (new_size - old_size) as u64;
-                            } else {
-                                stats.allocated_bytes -= (old_size - new_size) as u64;
-                            }
-                            // Don't change number of allocations or frees
-                        });
+                        state
+                            .stack_stats
+                            .entry(info.stack_hash)
+                            .and_modify(|stats| {
+                                stats.update_realloc_stats(
+                                    old_size as u64,
+                                    new_size as u64,
+                                    info.generation,
+                                );
+                                if info.intentional {
+                                    stats.update_intentional_bytes(
+                                        old_size as u64,
+                                        new_size as u64,
+                                        info.generation,
+                                    );
+                                }
+                            });
+                        #[cfg(feature = "tokio")]
+                        if let Some(task_id) = info.task_id {
+                            tasks::record_realloc(
+                                &state.task_stats,
+                                task_id,
+                                old_size as u64,
+                                new_size as u64,
+                            );
+                        }
                     }
 
                     // -- End of core profiling section, no more allocations --
                     tl_state.release_allocator_lock();
+                } else {
+                    // The moved allocation is no longer tracked, as if it was freed
+                    self.defer_free(state, ptr as u64, old_size);
+                }
+            } else if self.sample_untracked_reallocs {
+                // 3. Otherwise, optionally sample it as a new allocation, see with_untracked_realloc_sampling
+                let tl_state = self.tl_cache.get_thread_local();
+                if self.should_profile(tl_state) && !self.is_ignored(new_ptr, new_size) {
+                    self.profile_sampled_alloc(tl_state, new_ptr, new_size);
                 }
             }
         }
         new_ptr
     }
 }
+
+// With the `disabled` feature, or on targets other than Linux and macOS, Ying is nothing more than a passthrough to
+// the inner allocator.  No counters are touched, so every query function reports zero.
+#[cfg(any(
+    feature = "disabled",
+    not(any(target_os = "linux", target_os = "macos"))
+))]
+unsafe impl<A: GlobalAlloc> GlobalAlloc for YingProfiler<A> {
+    #[inline]
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.inner.alloc(layout)
+    }
+
+    #[inline]
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.inner.dealloc(ptr, layout)
+    }
+
+    #[inline]
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        self.inner.realloc(ptr, layout, new_size)
+    }
+}
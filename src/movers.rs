@@ -0,0 +1,90 @@
+//! Top movers: the stacks whose retained bytes grew the most over the last interval, tracked by a background
+//! thread so that continuous leak detection needs no snapshot bookkeeping by the caller.
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use super::*;
+use crate::session::{StackBaseline, StackDelta};
+
+/// Light snapshots of the stacks' counters kept by the top movers thread, without copies of the stacks
+#[derive(Default)]
+pub(crate) struct MoverState {
+    // Counters of every stack at the start of the current interval
+    baseline: HashMap<u64, StackBaseline>,
+    // Activity of each stack active over the last complete interval
+    last_interval: Vec<(u64, StackBaseline)>,
+}
+
+pub(crate) type MoverTracker = Mutex<MoverState>;
+
+impl<A: GlobalAlloc + Sync> YingProfiler<A> {
+    /// Spawns a thread which every `interval` records how much each stack's sampled retained bytes changed
+    /// over that interval, for `top_movers`, with `roll_top_movers_interval`.  Call once per profiler; profiling
+    /// is locked out on the thread while it works.
+    pub fn spawn_top_movers_tracker(&'static self, interval: Duration) -> JoinHandle<()> {
+        std::thread::spawn(move || {
+            self.roll_top_movers_interval();
+            loop {
+                std::thread::sleep(interval);
+                self.roll_top_movers_interval();
+            }
+        })
+    }
+}
+
+impl<A: GlobalAlloc> YingProfiler<A> {
+    /// The k stacks whose sampled retained bytes grew the most over the last complete interval, as ended by
+    /// `roll_top_movers_interval` or the thread started by `spawn_top_movers_tracker`, largest increase first.
+    /// Stacks whose retained bytes did not grow are left out.  The deltas are those of the interval, but each
+    /// stack's `stats` are its current ones.  Empty until an interval has been completed.
+    pub fn top_movers(&self, k: usize) -> Vec<StackDelta> {
+        self.lock_out_profiler(|| {
+            let state = self.get_state();
+            let mut movers: Vec<(u64, StackBaseline)> = state
+                .movers
+                .lock()
+                .unwrap()
+                .last_interval
+                .iter()
+                .filter(|(_, activity)| activity.retained_bytes_delta() > 0)
+                .copied()
+                .collect();
+            movers.sort_unstable_by_key(|(stack_hash, activity)| {
+                (Reverse(activity.retained_bytes_delta()), *stack_hash)
+            });
+            movers
+                .into_iter()
+                .filter_map(|(stack_hash, activity)| {
+                    let stats = state.stack_stats.get(&stack_hash)?.value().clone();
+                    Some(activity.into_delta(stats))
+                })
+                .take(k)
+                .collect()
+        })
+    }
+
+    /// Records how much each stack's sampled retained bytes changed since the last call, for `top_movers`, and
+    /// starts a new interval.  Only the stacks' counters are copied, not the stacks themselves.  For driving the
+    /// intervals from an existing timer rather than the thread of `spawn_top_movers_tracker`.
+    pub fn roll_top_movers_interval(&self) {
+        self.lock_out_profiler(|| {
+            let state = self.get_state();
+            let mut baseline = HashMap::with_capacity(state.stack_stats.len());
+            let mut last_interval = Vec::new();
+            let mut movers = state.movers.lock().unwrap();
+            for entry in state.stack_stats.iter() {
+                let stats = entry.value();
+                let activity =
+                    StackBaseline::activity_since(movers.baseline.get(entry.key()), stats);
+                last_interval.extend(activity.map(|activity| (*entry.key(), activity)));
+                baseline.insert(*entry.key(), StackBaseline::of(stats));
+            }
+            *movers = MoverState {
+                baseline,
+                last_interval,
+            };
+        })
+    }
+}
@@ -0,0 +1,148 @@
+//! Sampled allocations by CPU and NUMA node, enabled with `YingProfiler::with_cpu_tracking`.
+//! On multi-socket machines memory is usually placed on the NUMA node of the CPU which first touches it, so
+//! skew between nodes points at cross-socket memory access.
+use std::sync::atomic::AtomicU64;
+
+use super::*;
+
+/// CPUs with higher IDs are counted against the last slot
+pub(crate) const MAX_TRACKED_CPUS: usize = 1024;
+
+#[derive(Default)]
+pub(crate) struct CpuCounters {
+    sampled_bytes: AtomicU64,
+    num_allocations: AtomicU64,
+}
+
+impl CpuCounters {
+    pub fn new_table() -> Vec<CpuCounters> {
+        (0..MAX_TRACKED_CPUS)
+            .map(|_| CpuCounters::default())
+            .collect()
+    }
+
+    #[cfg(not(any(
+        feature = "disabled",
+        not(any(target_os = "linux", target_os = "macos"))
+    )))]
+    #[inline]
+    pub fn record(&self, size: u64) {
+        self.sampled_bytes.fetch_add(size, Relaxed);
+        self.num_allocations.fetch_add(1, Relaxed);
+    }
+}
+
+/// The CPU the calling thread is running on.  A single vDSO call on Linux with no allocation.
+#[cfg(all(target_os = "linux", not(feature = "disabled")))]
+#[inline]
+pub(crate) fn current_cpu() -> Option<usize> {
+    let cpu = unsafe { libc::sched_getcpu() };
+    (cpu >= 0).then(|| (cpu as usize).min(MAX_TRACKED_CPUS - 1))
+}
+
+#[cfg(all(target_os = "macos", not(feature = "disabled")))]
+#[inline]
+pub(crate) fn current_cpu() -> Option<usize> {
+    None
+}
+
+/// Sampled allocations made on one CPU or NUMA node
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct CpuAllocStats {
+    /// CPU number, or NUMA node number
+    pub id: usize,
+    /// Bytes of sampled allocations.  Multiply by the sampling ratio for an estimate of all bytes.
+    pub sampled_bytes: u64,
+    pub num_allocations: u64,
+}
+
+impl<A: GlobalAlloc> YingProfiler<A> {
+    /// Sampled allocations by the CPU they were made on, for CPUs with any allocations, in CPU order.
+    /// Always empty unless CPU tracking was enabled with `with_cpu_tracking`, or on platforms other than Linux.
+    pub fn cpu_allocation_stats(&self) -> Vec<CpuAllocStats> {
+        self.lock_out_profiler(|| {
+            self.get_state()
+                .cpu_counters
+                .iter()
+                .enumerate()
+                .map(|(id, counters)| CpuAllocStats {
+                    id,
+                    sampled_bytes: counters.sampled_bytes.load(Relaxed),
+                    num_allocations: counters.num_allocations.load(Relaxed),
+                })
+                .filter(|stats| stats.num_allocations > 0)
+                .collect()
+        })
+    }
+
+    /// Sampled allocations by NUMA node, summed over the CPUs of each node as listed in
+    /// `/sys/devices/system/node`.  Machines without NUMA information count as a single node 0.
+    pub fn numa_node_allocation_stats(&self) -> Vec<CpuAllocStats> {
+        let cpu_stats = self.cpu_allocation_stats();
+        let cpu_nodes = self.lock_out_profiler(read_cpu_nodes);
+        let mut node_stats: Vec<CpuAllocStats> = Vec::new();
+        for cpu in cpu_stats {
+            let node = cpu_nodes
+                .iter()
+                .find(|(cpus, _)| cpus.contains(&cpu.id))
+                .map(|&(_, node)| node)
+                .unwrap_or(0);
+            match node_stats.iter_mut().find(|stats| stats.id == node) {
+                Some(stats) => {
+                    stats.sampled_bytes += cpu.sampled_bytes;
+                    stats.num_allocations += cpu.num_allocations;
+                }
+                None => node_stats.push(CpuAllocStats { id: node, ..cpu }),
+            }
+        }
+        node_stats.sort_unstable_by_key(|stats| stats.id);
+        node_stats
+    }
+}
+
+// (CPUs, node number) for each NUMA node
+fn read_cpu_nodes() -> Vec<(Vec<usize>, usize)> {
+    let Ok(entries) = std::fs::read_dir("/sys/devices/system/node") else {
+        return Vec::new();
+    };
+    entries
+        .filter_map(|entry| {
+            let entry = entry.ok()?;
+            let node = entry
+                .file_name()
+                .to_str()?
+                .strip_prefix("node")?
+                .parse()
+                .ok()?;
+            let cpulist = std::fs::read_to_string(entry.path().join("cpulist")).ok()?;
+            Some((parse_cpu_list(&cpulist), node))
+        })
+        .collect()
+}
+
+// Parses the kernel's CPU list format, eg "0-3,8-11"
+fn parse_cpu_list(list: &str) -> Vec<usize> {
+    list.trim()
+        .split(',')
+        .filter_map(|range| match range.split_once('-') {
+            Some((start, end)) => Some(start.parse().ok()?..=end.parse().ok()?),
+            None => {
+                let cpu = range.parse().ok()?;
+                Some(cpu..=cpu)
+            }
+        })
+        .flatten()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_cpu_list() {
+        assert_eq!(parse_cpu_list("0-3,8-9\n"), vec![0, 1, 2, 3, 8, 9]);
+        assert_eq!(parse_cpu_list("5"), vec![5]);
+        assert!(parse_cpu_list("\n").is_empty());
+    }
+}
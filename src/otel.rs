@@ -0,0 +1,120 @@
+//! Export of Ying's counters and top stacks as OpenTelemetry metrics, for OTLP pipelines.
+use opentelemetry::metrics::Meter;
+use opentelemetry::KeyValue;
+
+use super::*;
+
+/// Number of top user frames by retained bytes exported as `ying.top_frame.retained_bytes`, which bounds the
+/// cardinality of its `frame` attribute
+const OTEL_TOP_FRAMES: usize = 10;
+
+impl<A: GlobalAlloc + Sync> YingProfiler<A> {
+    /// Registers Ying's global counters, the size of its maps, and the retained bytes of the top user frames (see
+    /// `top_k_by_user_frame`) as observable instruments with `meter`, to be exported with the rest of the app's
+    /// metrics, eg via OTLP.  Values are read when the meter's reader collects, with profiling locked out so the
+    /// metrics SDK's own allocations while observing aren't profiled.  Call once per meter, as each call
+    /// registers new callbacks.
+    ///
+    /// Instruments, all in bytes unless noted:
+    /// - `ying.retained_bytes`, `ying.peak_retained_bytes` - all memory retained, and its peak
+    /// - `ying.profiled.allocated_bytes`, `ying.profiled.retained_bytes` - sampled allocations only
+    /// - `ying.stacks`, `ying.outstanding_allocs` - sizes of the profiler's maps, as counts
+    /// - `ying.denied_giant_allocs`, `ying.stack_hash_collisions` - counts
+    /// - `ying.top_frame.retained_bytes` - estimated retained bytes, with the user frame as the `frame` attribute
+    pub fn register_otel(&'static self, meter: &Meter) {
+        meter
+            .u64_observable_gauge("ying.retained_bytes")
+            .with_description("Total bytes retained by all allocations")
+            .with_unit("By")
+            .with_callback(move |gauge| {
+                self.lock_out_profiler(|| {
+                    gauge.observe(YingProfiler::total_retained_bytes() as u64, &[])
+                });
+            })
+            .build();
+        meter
+            .u64_observable_gauge("ying.peak_retained_bytes")
+            .with_description("Highest total retained bytes seen")
+            .with_unit("By")
+            .with_callback(move |gauge| {
+                self.lock_out_profiler(|| {
+                    gauge.observe(YingProfiler::peak_retained_bytes() as u64, &[])
+                });
+            })
+            .build();
+        meter
+            .u64_observable_counter("ying.profiled.allocated_bytes")
+            .with_description("Bytes allocated by sampled allocations")
+            .with_unit("By")
+            .with_callback(move |counter| {
+                self.lock_out_profiler(|| {
+                    counter.observe(YingProfiler::profiled_bytes_allocated() as u64, &[])
+                });
+            })
+            .build();
+        meter
+            .u64_observable_gauge("ying.profiled.retained_bytes")
+            .with_description("Bytes retained by sampled allocations")
+            .with_unit("By")
+            .with_callback(move |gauge| {
+                self.lock_out_profiler(|| {
+                    gauge.observe(YingProfiler::profiled_bytes_retained() as u64, &[])
+                });
+            })
+            .build();
+        meter
+            .u64_observable_gauge("ying.stacks")
+            .with_description("Number of unique stacks recorded")
+            .with_callback(move |gauge| {
+                let cardinality = self.stack_cardinality_report();
+                self.lock_out_profiler(|| gauge.observe(cardinality.num_stacks as u64, &[]));
+            })
+            .build();
+        meter
+            .u64_observable_gauge("ying.outstanding_allocs")
+            .with_description("Number of outstanding sampled allocations tracked")
+            .with_callback(move |gauge| {
+                let cardinality = self.stack_cardinality_report();
+                self.lock_out_profiler(|| {
+                    gauge.observe(cardinality.outstanding_allocs as u64, &[])
+                });
+            })
+            .build();
+        meter
+            .u64_observable_counter("ying.denied_giant_allocs")
+            .with_description("Allocations denied for exceeding the single allocation limit")
+            .with_callback(move |counter| {
+                self.lock_out_profiler(|| {
+                    counter.observe(YingProfiler::denied_giant_allocs() as u64, &[])
+                });
+            })
+            .build();
+        meter
+            .u64_observable_counter("ying.stack_hash_collisions")
+            .with_description("Stack hash collisions detected with the collision check")
+            .with_callback(move |counter| {
+                self.lock_out_profiler(|| {
+                    counter.observe(YingProfiler::stack_hash_collisions() as u64, &[])
+                });
+            })
+            .build();
+        meter
+            .u64_observable_gauge("ying.top_frame.retained_bytes")
+            .with_description("Estimated bytes retained by the top user frames")
+            .with_unit("By")
+            .with_callback(move |gauge| {
+                self.lock_out_profiler(|| {
+                    let ratio = self.effective_sampling_ratio() as u64;
+                    for group in
+                        self.top_k_by_user_frame(OTEL_TOP_FRAMES, Measurement::RetainedBytes)
+                    {
+                        gauge.observe(
+                            group.retained_bytes * ratio,
+                            &[KeyValue::new("frame", group.frame)],
+                        );
+                    }
+                })
+            })
+            .build();
+    }
+}
@@ -0,0 +1,424 @@
+//! Export of stacks as pprof profiles, for `go tool pprof` and continuous profiling backends.
+//! Only the small part of profile.proto which is needed is encoded, by hand.  Profiles are not gzipped, except by
+//! `dump_all_gzip`; pprof tools accept both.
+use std::collections::HashMap;
+use std::io::{self, Read, Write as _};
+use std::net::TcpStream;
+use std::thread::JoinHandle;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::warn;
+
+use super::*;
+use crate::session::SessionReport;
+
+/// Sample types of Ying's profiles, as (type, unit).  Values are estimates, ie sampled values scaled up by the
+/// sampling ratio.
+const SAMPLE_TYPES: [(&str, &str); 3] = [
+    ("alloc_objects", "count"),
+    ("alloc_space", "bytes"),
+    ("inuse_space", "bytes"),
+];
+
+// Attempts to push each profile, with the wait between attempts doubling from PUSH_RETRY_BACKOFF
+const PUSH_ATTEMPTS: u32 = 4;
+const PUSH_RETRY_BACKOFF: Duration = Duration::from_millis(250);
+const PUSH_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Protobuf wire types
+const VARINT: u64 = 0;
+const LEN: u64 = 2;
+
+fn put_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+fn put_uint(buf: &mut Vec<u8>, field: u64, value: u64) {
+    put_varint(buf, field << 3 | VARINT);
+    put_varint(buf, value);
+}
+
+fn put_bytes(buf: &mut Vec<u8>, field: u64, bytes: &[u8]) {
+    put_varint(buf, field << 3 | LEN);
+    put_varint(buf, bytes.len() as u64);
+    buf.extend_from_slice(bytes);
+}
+
+fn put_packed(buf: &mut Vec<u8>, field: u64, values: impl Iterator<Item = u64>) {
+    let mut packed = Vec::new();
+    for value in values {
+        put_varint(&mut packed, value);
+    }
+    put_bytes(buf, field, &packed);
+}
+
+// Builds one Profile message.  Repeated fields may come in any order in protobuf, so functions, locations and
+// samples are written as they are first seen, and the string table at the end.
+struct PprofBuilder<'m> {
+    symbols: &'m SymbolMap,
+    buf: Vec<u8>,
+    strings: HashMap<String, u64>,
+    string_table: Vec<String>,
+    // (name, filename) string indices -> function id
+    functions: HashMap<(u64, u64), u64>,
+    // Instruction pointer -> location id
+    locations: HashMap<u64, u64>,
+    // String indices of the profile's comments
+    comments: Vec<u64>,
+}
+
+impl<'m> PprofBuilder<'m> {
+    fn new(symbols: &'m SymbolMap) -> Self {
+        let mut builder = Self {
+            symbols,
+            buf: Vec::new(),
+            strings: HashMap::new(),
+            string_table: Vec::new(),
+            functions: HashMap::new(),
+            locations: HashMap::new(),
+            comments: Vec::new(),
+        };
+        // The string table must start with ""
+        builder.string("");
+        for (kind, unit) in SAMPLE_TYPES {
+            let value_type = builder.value_type(kind, unit);
+            put_bytes(&mut builder.buf, 1, &value_type);
+        }
+        builder
+    }
+
+    fn string(&mut self, s: &str) -> u64 {
+        if let Some(&index) = self.strings.get(s) {
+            return index;
+        }
+        let index = self.string_table.len() as u64;
+        self.strings.insert(s.to_string(), index);
+        self.string_table.push(s.to_string());
+        index
+    }
+
+    fn value_type(&mut self, kind: &str, unit: &str) -> Vec<u8> {
+        let mut msg = Vec::new();
+        put_uint(&mut msg, 1, self.string(kind));
+        put_uint(&mut msg, 2, self.string(unit));
+        msg
+    }
+
+    fn function(&mut self, symbol: &FriendlySymbol) -> u64 {
+        let key = (self.string(symbol.name()), self.string(symbol.filename()));
+        if let Some(&id) = self.functions.get(&key) {
+            return id;
+        }
+        let id = self.functions.len() as u64 + 1;
+        self.functions.insert(key, id);
+        let mut msg = Vec::new();
+        put_uint(&mut msg, 1, id);
+        put_uint(&mut msg, 2, key.0);
+        put_uint(&mut msg, 3, key.0);
+        put_uint(&mut msg, 4, key.1);
+        put_bytes(&mut self.buf, 5, &msg);
+        id
+    }
+
+    // A location per instruction pointer, with a line per symbol, innermost inlined symbol first as in pprof
+    fn location(&mut self, ip: u64) -> u64 {
+        if let Some(&id) = self.locations.get(&ip) {
+            return id;
+        }
+        let id = self.locations.len() as u64 + 1;
+        self.locations.insert(ip, id);
+        let mut msg = Vec::new();
+        put_uint(&mut msg, 1, id);
+        put_uint(&mut msg, 3, ip);
+        if let Some(symbols) = self.symbols.get(&ip).map(|symbols| symbols.value().clone()) {
+            for symbol in symbols.iter() {
+                let mut line = Vec::new();
+                put_uint(&mut line, 1, self.function(symbol));
+                put_uint(&mut line, 2, symbol.line_no() as u64);
+                put_bytes(&mut msg, 4, &line);
+            }
+        }
+        put_bytes(&mut self.buf, 4, &msg);
+        id
+    }
+
+    // Adds the profiler's config as comments, one `key=value` per setting, so tools can tell how it was sampled
+    fn config(&mut self, config: &YingConfig) {
+        for (key, value) in config.json_fields() {
+            let comment = self.string(&format!("ying.{}={}", key, value));
+            self.comments.push(comment);
+        }
+    }
+
+    fn sample(&mut self, stack: &StdCallstack, values: [i64; 3]) {
+        let location_ids: Vec<u64> = stack.ips().map(|ip| self.location(ip)).collect();
+        let mut msg = Vec::new();
+        put_packed(&mut msg, 1, location_ids.into_iter());
+        put_packed(&mut msg, 2, values.iter().map(|&v| v as u64));
+        put_bytes(&mut self.buf, 2, &msg);
+    }
+
+    fn finish(mut self, taken_at: SystemTime, duration: Duration, sampling_ratio: u32) -> Vec<u8> {
+        let period_type = self.value_type("space", "bytes");
+        let time_nanos = taken_at
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as u64;
+        for s in std::mem::take(&mut self.string_table) {
+            put_bytes(&mut self.buf, 6, s.as_bytes());
+        }
+        put_uint(&mut self.buf, 9, time_nanos);
+        put_uint(&mut self.buf, 10, duration.as_nanos() as u64);
+        put_bytes(&mut self.buf, 11, &period_type);
+        put_uint(&mut self.buf, 12, sampling_ratio as u64);
+        put_packed(&mut self.buf, 13, self.comments.iter().copied());
+        self.buf
+    }
+}
+
+impl<A: GlobalAlloc> YingProfiler<A> {
+    /// Writes all stacks as an uncompressed pprof heap profile, with estimated allocation counts, allocated
+    /// bytes and retained (`inuse_space`) bytes per stack.  Returns the number of stacks written.
+    pub fn write_pprof<W: io::Write>(&self, mut w: W) -> io::Result<usize> {
+        let (profile, num_stacks) = self.lock_out_profiler(|| {
+            let ratio = self.sampling_ratio as i64;
+            let state = self.get_state();
+            let mut builder = PprofBuilder::new(&state.symbol_map);
+            builder.config(&self.config());
+            let mut num_stacks = 0;
+            for entry in state.stack_stats.iter() {
+                let stats = entry.value();
+                builder.sample(
+                    &self.resolved_stack(state, stats),
+                    [
+                        stats.num_allocations() as i64 * ratio,
+                        stats.allocated_bytes() as i64 * ratio,
+                        stats.retained_profiled_bytes() as i64 * ratio,
+                    ],
+                );
+                num_stacks += 1;
+            }
+            let profile = builder.finish(SystemTime::now(), Duration::ZERO, self.sampling_ratio);
+            (profile, num_stacks)
+        });
+        w.write_all(&profile)?;
+        Ok(num_stacks)
+    }
+
+    // A pprof profile of the activity in a session.  inuse_space is the change in retained bytes, which may be
+    // negative.
+    fn session_pprof(&self, report: &SessionReport) -> Vec<u8> {
+        self.lock_out_profiler(|| {
+            let ratio = self.sampling_ratio as i64;
+            let state = self.get_state();
+            let mut builder = PprofBuilder::new(&state.symbol_map);
+            builder.config(&self.config());
+            for delta in &report.stacks {
+                builder.sample(
+                    &self.resolved_stack(state, &delta.stats),
+                    [
+                        delta.num_allocations as i64 * ratio,
+                        delta.allocated_bytes as i64 * ratio,
+                        delta.retained_bytes_delta() * ratio,
+                    ],
+                );
+            }
+            builder.finish(SystemTime::now(), report.duration, self.sampling_ratio)
+        })
+    }
+}
+
+impl<A: GlobalAlloc + Sync> YingProfiler<A> {
+    /// Spawns a thread which every `interval` writes a pprof profile of only the changes since its last write to
+    /// `w`, eg a pipe or socket to a continuous profiling agent, or a `File` made from a raw file descriptor.
+    /// Each profile is preceded by its length as a protobuf varint, the usual framing for a stream of protobuf
+    /// messages.  Allocation values are of the interval only, and `inuse_space` is the change in retained bytes.
+    ///
+    /// The thread runs until writing fails, eg when the other end of the pipe is closed, and returns the error.
+    pub fn stream_pprof_to<W: io::Write + Send + 'static>(
+        &'static self,
+        mut w: W,
+        interval: Duration,
+    ) -> JoinHandle<io::Result<()>> {
+        std::thread::spawn(move || {
+            let mut session = self.begin_session();
+            loop {
+                std::thread::sleep(interval);
+                let report = session.finish_and_restart();
+                self.lock_out_profiler(|| {
+                    let profile = self.session_pprof(&report);
+                    let mut len = Vec::new();
+                    put_varint(&mut len, profile.len() as u64);
+                    w.write_all(&len)?;
+                    w.write_all(&profile)?;
+                    w.flush()
+                })?;
+            }
+        })
+    }
+
+    /// Spawns a thread which every `interval` POSTs a pprof profile of the changes since its last push to `url`,
+    /// for ingestion by a continuous profiling backend.  `labels`, eg `[("service", "api"), ("instance", "a1")]`,
+    /// are appended to the URL as query parameters, after any already in it.  Only plain `http://` URLs are
+    /// supported; use a local agent or proxy for TLS, or for backends such as Parca which ingest over gRPC.
+    ///
+    /// A failed push is retried a few times with exponential backoff, then dropped with a warning; the next
+    /// interval's profile only covers that interval.  Profiling is locked out on the pushing thread while it
+    /// works, so its own allocations don't show up in the profiles.  Returns an error if the URL is not usable.
+    pub fn spawn_pprof_pusher(
+        &'static self,
+        url: &str,
+        interval: Duration,
+        labels: &[(&str, &str)],
+    ) -> io::Result<JoinHandle<()>> {
+        let target = HttpTarget::parse(url, labels)?;
+        Ok(std::thread::spawn(move || {
+            let mut session = self.begin_session();
+            loop {
+                std::thread::sleep(interval);
+                let report = session.finish_and_restart();
+                let profile = self.session_pprof(&report);
+                let mut backoff = PUSH_RETRY_BACKOFF;
+                for attempt in 1..=PUSH_ATTEMPTS {
+                    match self.lock_out_profiler(|| target.post(&profile)) {
+                        Ok(()) => break,
+                        Err(e) if attempt == PUSH_ATTEMPTS => {
+                            self.lock_out_profiler(|| {
+                                warn!(
+                                    "Ying: dropping pprof profile after {} attempts: {}",
+                                    attempt, e
+                                )
+                            });
+                        }
+                        Err(_) => {
+                            std::thread::sleep(backoff);
+                            backoff *= 2;
+                        }
+                    }
+                }
+            }
+        }))
+    }
+}
+
+// Where profiles are POSTed to, from an http:// URL
+struct HttpTarget {
+    // host:port, for connecting
+    addr: String,
+    // host, with the port if not the default, for the Host header
+    host: String,
+    // Path and query string, including the labels
+    path: String,
+}
+
+impl HttpTarget {
+    fn parse(url: &str, labels: &[(&str, &str)]) -> io::Result<Self> {
+        let invalid =
+            |msg: &str| io::Error::new(io::ErrorKind::InvalidInput, format!("{}: {}", msg, url));
+        let rest = url
+            .strip_prefix("http://")
+            .ok_or_else(|| invalid("Only http:// URLs are supported"))?;
+        let (host, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, "/"),
+        };
+        if host.is_empty() {
+            return Err(invalid("No host in URL"));
+        }
+        let addr = if host.contains(':') {
+            host.to_string()
+        } else {
+            format!("{}:80", host)
+        };
+        let mut path = path.to_string();
+        for (key, value) in labels {
+            path.push(if path.contains('?') { '&' } else { '?' });
+            percent_encode(&mut path, key);
+            path.push('=');
+            percent_encode(&mut path, value);
+        }
+        Ok(Self {
+            addr,
+            host: host.to_string(),
+            path,
+        })
+    }
+
+    fn post(&self, body: &[u8]) -> io::Result<()> {
+        let mut stream = TcpStream::connect(&self.addr)?;
+        stream.set_read_timeout(Some(PUSH_TIMEOUT))?;
+        stream.set_write_timeout(Some(PUSH_TIMEOUT))?;
+        write!(
+            stream,
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/octet-stream\r\nContent-Length: {}\r\n\
+             Connection: close\r\n\r\n",
+            self.path,
+            self.host,
+            body.len()
+        )?;
+        stream.write_all(body)?;
+
+        // Only the status line matters, eg "HTTP/1.1 200 OK"
+        let mut response = Vec::new();
+        stream.take(1024).read_to_end(&mut response)?;
+        let response = String::from_utf8_lossy(&response);
+        let status_line = response.lines().next().unwrap_or_default();
+        match status_line.split(' ').nth(1) {
+            Some(status) if status.starts_with('2') => Ok(()),
+            _ => Err(io::Error::other(format!(
+                "pprof push rejected: {}",
+                status_line
+            ))),
+        }
+    }
+}
+
+// Appends `s` to `out`, percent encoding all but unreserved URL characters
+fn percent_encode(out: &mut String, s: &str) {
+    for b in s.bytes() {
+        if b.is_ascii_alphanumeric() || b"-._~".contains(&b) {
+            out.push(b as char);
+        } else {
+            let _ = write!(out, "%{:02X}", b);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_varint_encoding() {
+        let mut buf = Vec::new();
+        put_varint(&mut buf, 1);
+        put_varint(&mut buf, 300);
+        put_varint(&mut buf, -1i64 as u64);
+        assert_eq!(&buf[..3], &[0x01, 0xac, 0x02]);
+        assert_eq!(buf.len(), 3 + 10);
+        assert_eq!(buf[12], 0x01);
+    }
+
+    #[test]
+    fn test_http_target_parse() {
+        let target = HttpTarget::parse(
+            "http://localhost:4040/ingest?format=pprof",
+            &[("service", "my api")],
+        )
+        .unwrap();
+        assert_eq!(target.addr, "localhost:4040");
+        assert_eq!(target.host, "localhost:4040");
+        assert_eq!(target.path, "/ingest?format=pprof&service=my%20api");
+
+        let target = HttpTarget::parse("http://parca", &[]).unwrap();
+        assert_eq!(target.addr, "parca:80");
+        assert_eq!(target.path, "/");
+
+        assert!(HttpTarget::parse("https://parca/ingest", &[]).is_err());
+        assert!(HttpTarget::parse("http:///ingest", &[]).is_err());
+    }
+}
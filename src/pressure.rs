@@ -0,0 +1,151 @@
+//! Callbacks when total retained memory nears a limit, eg to dump a report before the OOM killer strikes, and
+//! when the inner allocator runs out of memory.
+use std::sync::atomic::{AtomicBool, AtomicPtr};
+use std::sync::Mutex;
+
+use super::*;
+
+type PressureCallback = Box<dyn Fn(usize) + Send + Sync>;
+
+/// Threshold and callback set by `YingProfiler::on_memory_pressure`
+pub(crate) struct MemoryPressure {
+    // Total retained bytes at which the callback is called.  usize::MAX when no callback is set.
+    threshold: AtomicUsize,
+    // Set once the threshold is crossed, so the callback is called once per crossing rather than on every alloc
+    latched: AtomicBool,
+    callback: Mutex<Option<PressureCallback>>,
+    // fn(Layout) called when the inner allocator fails, or null
+    on_failure: AtomicPtr<()>,
+}
+
+impl MemoryPressure {
+    pub const fn new() -> Self {
+        Self {
+            threshold: AtomicUsize::new(usize::MAX),
+            latched: AtomicBool::new(false),
+            callback: Mutex::new(None),
+            on_failure: AtomicPtr::new(std::ptr::null_mut()),
+        }
+    }
+}
+
+impl<A: GlobalAlloc> YingProfiler<A> {
+    /// Calls `cb` with the total retained bytes once they cross `fraction` of `limit_bytes`, eg 0.9 of a container's
+    /// memory limit, to dump a report as a last gasp before the process is killed.  The callback runs on the
+    /// allocating thread with profiling locked out, so it may call reporting methods such as `dump_all`.
+    /// It is called once per crossing: it is called again only after retained memory drops 10% below the
+    /// threshold and then crosses it again.  Replaces any previous callback.  Never called with the `disabled`
+    /// feature.
+    pub fn on_memory_pressure(
+        &self,
+        limit_bytes: usize,
+        fraction: f64,
+        cb: impl Fn(usize) + Send + Sync + 'static,
+    ) {
+        let threshold = (limit_bytes as f64 * fraction.clamp(0.0, 1.0)) as usize;
+        self.lock_out_profiler(|| {
+            let mut callback = self
+                .pressure
+                .callback
+                .lock()
+                .unwrap_or_else(|e| e.into_inner());
+            *callback = Some(Box::new(cb));
+            self.pressure.latched.store(false, SeqCst);
+            self.pressure.threshold.store(threshold, SeqCst);
+        });
+    }
+
+    /// Called after every increase of the total retained bytes.  Just one load unless the threshold is crossed.
+    #[cfg(not(any(
+        feature = "disabled",
+        not(any(target_os = "linux", target_os = "macos"))
+    )))]
+    #[inline]
+    pub(crate) fn check_memory_pressure(&self, total_retained: usize) {
+        if total_retained >= self.pressure.threshold.load(Relaxed) {
+            self.memory_pressure_crossed(total_retained);
+        }
+    }
+
+    #[cfg(not(any(
+        feature = "disabled",
+        not(any(target_os = "linux", target_os = "macos"))
+    )))]
+    #[cold]
+    #[inline(never)]
+    fn memory_pressure_crossed(&self, total_retained: usize) {
+        // Allocations by the profiler itself may hold its maps locked, which the callback might need.  The next
+        // allocation outside of the profiler will notice the crossing instead.
+        if self.tl_cache.get_thread_local().is_allocator_locked()
+            || self.pressure.latched.swap(true, SeqCst)
+        {
+            return;
+        }
+        self.lock_out_profiler(|| {
+            let callback = self
+                .pressure
+                .callback
+                .lock()
+                .unwrap_or_else(|e| e.into_inner());
+            if let Some(cb) = callback.as_ref() {
+                cb(total_retained);
+            }
+        });
+    }
+
+    /// Calls `cb` with the layout of every allocation the inner allocator fails, ie returns null for, such as
+    /// on a genuine out of memory.  `cb` runs inside the allocator with memory already exhausted, so it must not
+    /// allocate: eg bump an atomic counter or write a fixed message with `libc::write`.  That is why it is a
+    /// plain function rather than a closure.  Allocations denied by the single allocation limit are not
+    /// failures of the inner allocator.  Replaces any previous callback.  Never called with the `disabled`
+    /// feature.
+    pub fn on_alloc_failure(&self, cb: fn(Layout)) {
+        self.pressure.on_failure.store(cb as *mut (), SeqCst);
+    }
+
+    /// Counts an allocation failure of the inner allocator, and calls the failure callback
+    #[cfg(not(any(
+        feature = "disabled",
+        not(any(target_os = "linux", target_os = "macos"))
+    )))]
+    #[inline]
+    pub(crate) fn check_alloc_failure(&self, ptr: *mut u8, layout: Layout) -> *mut u8 {
+        if ptr.is_null() {
+            self.alloc_failed(layout);
+        }
+        ptr
+    }
+
+    #[cfg(not(any(
+        feature = "disabled",
+        not(any(target_os = "linux", target_os = "macos"))
+    )))]
+    #[cold]
+    #[inline(never)]
+    fn alloc_failed(&self, layout: Layout) {
+        FAILED_ALLOCS.fetch_add(1, Relaxed);
+        let cb = self.pressure.on_failure.load(SeqCst);
+        if !cb.is_null() {
+            // Safety: only ever set from a fn(Layout) in on_alloc_failure
+            let cb: fn(Layout) = unsafe { std::mem::transmute(cb) };
+            // Sampling any allocation the callback makes anyway could recurse into the failing allocator
+            self.lock_out_profiler(|| cb(layout));
+        }
+    }
+
+    /// Called after every decrease of the total retained bytes.  Re-arms the callback once retained memory has
+    /// dropped well below the threshold, so that hovering around the threshold doesn't call it repeatedly.
+    #[cfg(not(any(
+        feature = "disabled",
+        not(any(target_os = "linux", target_os = "macos"))
+    )))]
+    #[inline]
+    pub(crate) fn rearm_memory_pressure(&self) {
+        if self.pressure.latched.load(Relaxed) {
+            let threshold = self.pressure.threshold.load(Relaxed);
+            if TOTAL_RETAINED.load(Relaxed) < threshold - threshold / 10 {
+                self.pressure.latched.store(false, Relaxed);
+            }
+        }
+    }
+}
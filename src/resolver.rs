@@ -0,0 +1,57 @@
+//! Lazy symbolization: with `with_lazy_symbolization`, new stacks are recorded without resolving their symbols,
+//! which are resolved in the background only for the top stacks, and for any other stack when it is reported.
+use std::collections::HashSet;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use super::*;
+
+impl<A: GlobalAlloc + Sync> YingProfiler<A> {
+    /// Spawns a thread which every `interval` resolves the symbols of the top stacks, as set by
+    /// `with_lazy_symbolization`, so that reports on them need no symbolization.  Stacks already resolved cost
+    /// only a lookup per frame.  Call once per profiler; profiling is locked out on the thread.
+    pub fn spawn_symbol_resolver(&'static self, interval: Duration) -> JoinHandle<()> {
+        std::thread::spawn(move || loop {
+            std::thread::sleep(interval);
+            self.resolve_top_stacks();
+        })
+    }
+}
+
+impl<A: GlobalAlloc> YingProfiler<A> {
+    /// The call stack of `stats`, with the symbols of any frames left unresolved by lazy symbolization resolved
+    pub(crate) fn resolved_stack(&self, state: &YingState, stats: &StackStats) -> StdCallstack {
+        let stack = stats.stack(&state.stacks);
+        if self.lazy_symbols_top_n > 0 {
+            stack.resolve_missing_symbols(&state.symbol_map, self.symbolizer);
+        }
+        stack
+    }
+
+    // Resolves the top stacks by retained bytes, and by recent allocation rate, which drops to zero for stacks
+    // no longer allocating, so that a newly hot stack is resolved before it has retained much
+    fn resolve_top_stacks(&self) {
+        let top_n = self.lazy_symbols_top_n;
+        if top_n == 0 {
+            return;
+        }
+        self.lock_out_profiler(|| {
+            let by_retained = self.stack_list_desc_by(|stats| stats.retained_profiled_bytes());
+            let by_rate = self.stack_list_desc_by(|stats| stats.recent_alloc_rate() as u64);
+            let stack_hashes: HashSet<u64> = by_retained
+                .iter()
+                .take(top_n)
+                .chain(by_rate.iter().take(top_n).filter(|&&(_, rate)| rate > 0))
+                .map(|&(stack_hash, _)| stack_hash)
+                .collect();
+            let state = self.get_state();
+            for stack_hash in stack_hashes {
+                if let Some(stats) = state.stack_stats.get(&stack_hash) {
+                    stats
+                        .stack(&state.stacks)
+                        .resolve_missing_symbols(&state.symbol_map, self.symbolizer);
+                }
+            }
+        })
+    }
+}
@@ -0,0 +1,493 @@
+//! Adaptive sampling: samples more densely while the allocation rate spikes above its usual level.
+//! CPU budget: samples less densely while profiling uses more CPU than its budget.
+//! Also sampling jitter, which randomizes the gap between samples to avoid lockstep bias.
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering::Relaxed};
+
+// How often the sampling ratio is recomputed
+#[cfg(not(any(
+    feature = "disabled",
+    not(any(target_os = "linux", target_os = "macos"))
+)))]
+const ADJUST_INTERVAL_MILLIS: u64 = 1000;
+// Weight of the latest interval in the baseline allocation rate.  At 0.1 per second, a new sustained rate
+// becomes the baseline within half a minute or so, after which sampling relaxes again.
+#[cfg(not(any(
+    feature = "disabled",
+    not(any(target_os = "linux", target_os = "macos"))
+)))]
+const BASELINE_WEIGHT: f64 = 0.1;
+
+// The configured ratio, and what was counted since the ratio was last recomputed, which happens at most once per
+// interval, by the thread which notices first
+#[cfg(not(any(
+    feature = "disabled",
+    not(any(target_os = "linux", target_os = "macos"))
+)))]
+struct Interval {
+    base_ratio: u32,
+    start_millis: AtomicU64,
+    count: AtomicU64,
+}
+
+#[cfg(not(any(
+    feature = "disabled",
+    not(any(target_os = "linux", target_os = "macos"))
+)))]
+impl Interval {
+    const fn new(base_ratio: u32) -> Self {
+        Self {
+            base_ratio,
+            start_millis: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+        }
+    }
+
+    // Adds `amount` to the count.  Once an interval has passed, returns the count over it, which is reset, and its
+    // length in millis, for the ratio to be recomputed.
+    fn add(&self, now_millis: u64, amount: u64) -> Option<(u64, u64)> {
+        self.count.fetch_add(amount, Relaxed);
+        let start = self.start_millis.load(Relaxed);
+        if start == 0 {
+            let _ = self
+                .start_millis
+                .compare_exchange(0, now_millis, Relaxed, Relaxed);
+            return None;
+        }
+        let elapsed = now_millis.saturating_sub(start);
+        if elapsed >= ADJUST_INTERVAL_MILLIS
+            && self
+                .start_millis
+                .compare_exchange(start, now_millis, Relaxed, Relaxed)
+                .is_ok()
+        {
+            return Some((self.count.swap(0, Relaxed), elapsed));
+        }
+        None
+    }
+}
+
+/// Tracks an estimate of the recent allocation rate, and tightens the sampling ratio from `base_ratio` down
+/// to at most `min_ratio` in proportion to how far the rate is above its baseline.
+///
+/// The rate is estimated from sampled allocations only, each one standing for `ratio` allocations, so there is
+/// no extra work for allocations which are not sampled.  The ratio is recomputed at most once per interval.
+pub(crate) struct AdaptiveSampler {
+    // 0 means adaptive sampling is off
+    min_ratio: u32,
+    ratio: AtomicU32,
+    // Allocations over the current interval
+    #[cfg(not(any(
+        feature = "disabled",
+        not(any(target_os = "linux", target_os = "macos"))
+    )))]
+    interval: Interval,
+    // Allocations/sec moving average, as f64 bits.  0 means not measured yet.
+    #[cfg(not(any(
+        feature = "disabled",
+        not(any(target_os = "linux", target_os = "macos"))
+    )))]
+    baseline_rate: AtomicU64,
+}
+
+impl AdaptiveSampler {
+    pub const fn disabled() -> Self {
+        Self::new(0, 0)
+    }
+
+    /// `min_ratio` is capped at `base_ratio`, since adaptive sampling only ever samples more densely
+    pub const fn new(base_ratio: u32, min_ratio: u32) -> Self {
+        let min_ratio = if min_ratio > base_ratio {
+            base_ratio
+        } else {
+            min_ratio
+        };
+        Self {
+            min_ratio,
+            ratio: AtomicU32::new(base_ratio),
+            #[cfg(not(any(
+                feature = "disabled",
+                not(any(target_os = "linux", target_os = "macos"))
+            )))]
+            interval: Interval::new(base_ratio),
+            #[cfg(not(any(
+                feature = "disabled",
+                not(any(target_os = "linux", target_os = "macos"))
+            )))]
+            baseline_rate: AtomicU64::new(0),
+        }
+    }
+
+    #[inline]
+    pub fn is_enabled(&self) -> bool {
+        self.min_ratio > 0
+    }
+
+    /// The least sampling ratio the sampler tightens to, or 0 if disabled
+    pub fn min_ratio(&self) -> u32 {
+        self.min_ratio
+    }
+
+    /// The current sampling ratio.  Only valid if enabled.
+    #[inline]
+    pub fn ratio(&self) -> u32 {
+        self.ratio.load(Relaxed)
+    }
+
+    /// Sets the current ratio as if a spike had been measured.  TESTING ONLY
+    #[cfg(feature = "test-util")]
+    pub fn set_ratio(&self, ratio: u32) {
+        self.ratio.store(ratio, Relaxed);
+    }
+}
+
+#[cfg(not(any(
+    feature = "disabled",
+    not(any(target_os = "linux", target_os = "macos"))
+)))]
+impl AdaptiveSampler {
+    /// Called for every sampled allocation
+    pub fn on_sample(&self, now_millis: u64) {
+        if let Some((allocs, elapsed)) = self.interval.add(now_millis, self.ratio() as u64) {
+            self.adjust(allocs as f64 * 1000.0 / elapsed as f64);
+        }
+    }
+
+    fn adjust(&self, rate: f64) {
+        let baseline = f64::from_bits(self.baseline_rate.load(Relaxed));
+        let baseline = if baseline == 0.0 {
+            rate
+        } else {
+            baseline + BASELINE_WEIGHT * (rate - baseline)
+        };
+        self.baseline_rate.store(baseline.to_bits(), Relaxed);
+        self.ratio.store(
+            ratio_for_rate(self.interval.base_ratio, self.min_ratio, rate, baseline),
+            Relaxed,
+        );
+    }
+}
+
+// Sampling gets denser in proportion to how far the rate is above the baseline, eg at twice the usual
+// rate, twice as many allocations are sampled
+#[cfg(not(any(
+    feature = "disabled",
+    not(any(target_os = "linux", target_os = "macos"))
+)))]
+fn ratio_for_rate(base_ratio: u32, min_ratio: u32, rate: f64, baseline: f64) -> u32 {
+    if rate <= baseline {
+        return base_ratio;
+    }
+    // Not clamp(), which panics if min_ratio > base_ratio, and this runs inside the allocator
+    let ratio = base_ratio as f64 * baseline / rate;
+    (ratio as u32).max(min_ratio).max(1).min(base_ratio)
+}
+
+// Most the CPU budget widens the sampling ratio by, as a multiple of the configured ratio
+#[cfg(not(any(
+    feature = "disabled",
+    not(any(target_os = "linux", target_os = "macos"))
+)))]
+const MAX_CPU_BACKOFF_FACTOR: u32 = 1024;
+
+/// Measures the CPU time spent profiling sampled allocations, summed over all threads, and widens the sampling
+/// ratio while it is over a budget, in parts per million of one core's time.  Once profiling uses less than
+/// half the budget, the ratio is narrowed back towards `base_ratio`.  Like adaptive sampling, the ratio is
+/// recomputed at most once per interval, by the thread which notices first.
+pub(crate) struct CpuBudget {
+    // 0 means no budget
+    max_cpu_ppm: u32,
+    ratio: AtomicU32,
+    // CPU nanos spent profiling over the current interval
+    #[cfg(not(any(
+        feature = "disabled",
+        not(any(target_os = "linux", target_os = "macos"))
+    )))]
+    interval: Interval,
+    // CPU used by profiling over the last complete interval, in parts per million of one core
+    last_cpu_ppm: AtomicU64,
+}
+
+impl CpuBudget {
+    pub const fn disabled() -> Self {
+        Self::new(0, 0)
+    }
+
+    pub const fn new(base_ratio: u32, max_cpu_ppm: u32) -> Self {
+        Self {
+            max_cpu_ppm,
+            ratio: AtomicU32::new(base_ratio),
+            #[cfg(not(any(
+                feature = "disabled",
+                not(any(target_os = "linux", target_os = "macos"))
+            )))]
+            interval: Interval::new(base_ratio),
+            last_cpu_ppm: AtomicU64::new(0),
+        }
+    }
+
+    #[inline]
+    pub fn is_enabled(&self) -> bool {
+        self.max_cpu_ppm > 0
+    }
+
+    /// The budget in parts per million of one core, or 0 if there is none
+    pub fn max_cpu_ppm(&self) -> u32 {
+        self.max_cpu_ppm
+    }
+
+    /// The current sampling ratio.  Only valid if enabled.
+    #[inline]
+    pub fn ratio(&self) -> u32 {
+        self.ratio.load(Relaxed)
+    }
+
+    /// CPU used by profiling over the last complete interval, in parts per million of one core
+    pub fn last_cpu_ppm(&self) -> u64 {
+        self.last_cpu_ppm.load(Relaxed)
+    }
+}
+
+#[cfg(not(any(
+    feature = "disabled",
+    not(any(target_os = "linux", target_os = "macos"))
+)))]
+impl CpuBudget {
+    /// Called after every sampled allocation with the CPU time spent profiling it
+    pub fn on_sample(&self, now_millis: u64, cpu_nanos: u64) {
+        if let Some((cpu_nanos, elapsed)) = self.interval.add(now_millis, cpu_nanos) {
+            // nanos per millisecond of wall time are parts per million
+            let cpu_ppm = cpu_nanos / elapsed;
+            self.last_cpu_ppm.store(cpu_ppm, Relaxed);
+            self.ratio.store(
+                ratio_for_cpu(
+                    self.ratio(),
+                    self.interval.base_ratio,
+                    cpu_ppm,
+                    self.max_cpu_ppm,
+                ),
+                Relaxed,
+            );
+        }
+    }
+}
+
+// Sampling twice as sparsely roughly halves the CPU spent profiling, so the ratio doubles while over budget and
+// halves back while under half of it
+#[cfg(not(any(
+    feature = "disabled",
+    not(any(target_os = "linux", target_os = "macos"))
+)))]
+fn ratio_for_cpu(ratio: u32, base_ratio: u32, cpu_ppm: u64, max_cpu_ppm: u32) -> u32 {
+    if cpu_ppm > max_cpu_ppm as u64 {
+        ratio
+            .saturating_mul(2)
+            .min(base_ratio.saturating_mul(MAX_CPU_BACKOFF_FACTOR))
+    } else if cpu_ppm < max_cpu_ppm as u64 / 2 {
+        (ratio / 2).max(base_ratio)
+    } else {
+        ratio
+    }
+}
+
+/// CPU time used by the current thread so far, in nanoseconds, or 0 where it can't be read
+#[cfg(not(any(
+    feature = "disabled",
+    not(any(target_os = "linux", target_os = "macos"))
+)))]
+#[inline]
+pub(crate) fn thread_cpu_nanos() -> u64 {
+    #[cfg(unix)]
+    {
+        let mut ts = libc::timespec {
+            tv_sec: 0,
+            tv_nsec: 0,
+        };
+        if unsafe { libc::clock_gettime(libc::CLOCK_THREAD_CPUTIME_ID, &mut ts) } == 0 {
+            return ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64;
+        }
+    }
+    0
+}
+
+/// Xorshift64 step.  A cheap, allocation free PRNG, plenty for spreading samples out.  `state` must be nonzero.
+#[cfg(not(any(
+    feature = "disabled",
+    not(any(target_os = "linux", target_os = "macos"))
+)))]
+#[inline]
+pub(crate) fn xorshift64(state: &mut u64) -> u64 {
+    let mut x = *state;
+    x ^= x << 13;
+    x ^= x >> 7;
+    x ^= x << 17;
+    *state = x;
+    x
+}
+
+/// Initial xorshift state for a thread.  With a configured seed the state depends only on the seed, so the same
+/// allocation sequence on a thread samples identically across runs; otherwise it mixes the time with the thread.
+/// The seed is scrambled with the splitmix64 finalizer, as xorshift output is poor for small seeds.
+#[cfg(not(any(
+    feature = "disabled",
+    not(any(target_os = "linux", target_os = "macos"))
+)))]
+pub(crate) fn initial_rng_state(seed: Option<u64>, thread_hash: u64) -> u64 {
+    let mut z = seed.unwrap_or_else(|| crate::now_millis() ^ thread_hash);
+    z = z.wrapping_add(0x9e3779b97f4a7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    (z ^ (z >> 31)) | 1
+}
+
+/// Number of allocations until the next sample with jitter: uniform within ratio ± ratio/2, so that the mean
+/// stays at `ratio` but samples don't line up with a repeating allocation pattern.
+#[cfg(not(any(
+    feature = "disabled",
+    not(any(target_os = "linux", target_os = "macos"))
+)))]
+#[inline]
+pub(crate) fn jittered_interval(ratio: u32, random: u64) -> u32 {
+    let spread = ratio / 2;
+    ratio - spread + (random % (2 * spread as u64 + 1)) as u32
+}
+
+#[cfg(all(
+    test,
+    not(any(
+        feature = "disabled",
+        not(any(target_os = "linux", target_os = "macos"))
+    ))
+))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ratio_tightens_with_rate_spike() {
+        assert_eq!(ratio_for_rate(1000, 10, 5000.0, 5000.0), 1000);
+        assert_eq!(ratio_for_rate(1000, 10, 1000.0, 5000.0), 1000);
+        assert_eq!(ratio_for_rate(1000, 10, 20_000.0, 5000.0), 250);
+        assert_eq!(ratio_for_rate(1000, 10, 5_000_000.0, 5000.0), 10);
+    }
+
+    #[test]
+    fn test_min_ratio_above_base_ratio() {
+        // Never sparser than the base ratio, and no panic during a spike
+        assert_eq!(ratio_for_rate(100, 1000, 20_000.0, 5000.0), 100);
+        let sampler = AdaptiveSampler::new(100, 1000);
+        assert!(sampler.is_enabled());
+        assert_eq!(sampler.min_ratio(), 100);
+        sampler.adjust(5000.0);
+        sampler.adjust(5_000_000.0);
+        assert_eq!(sampler.ratio(), 100);
+    }
+
+    #[test]
+    fn test_zero_min_ratio() {
+        // A ratio of 0 would never sample, so a spike tightens to sampling every allocation at most
+        assert_eq!(ratio_for_rate(1000, 0, 5_000_000.0, 5000.0), 1);
+        let sampler = AdaptiveSampler::new(1000, 0);
+        assert!(!sampler.is_enabled());
+        assert_eq!(sampler.ratio(), 1000);
+    }
+
+    #[test]
+    fn test_ratio_for_cpu_backs_off_and_recovers() {
+        // 1% budget: over it the ratio doubles, under half of it the ratio halves back
+        assert_eq!(ratio_for_cpu(100, 100, 20_000, 10_000), 200);
+        assert_eq!(ratio_for_cpu(200, 100, 8_000, 10_000), 200);
+        assert_eq!(ratio_for_cpu(400, 100, 1_000, 10_000), 200);
+        assert_eq!(ratio_for_cpu(100, 100, 0, 10_000), 100);
+        assert_eq!(ratio_for_cpu(102_400, 100, 50_000, 10_000), 102_400);
+    }
+
+    #[test]
+    fn test_cpu_budget_adjusts_once_per_interval() {
+        let budget = CpuBudget::new(100, 10_000);
+        assert!(budget.is_enabled());
+        assert!(!CpuBudget::disabled().is_enabled());
+
+        // 5ms of profiling CPU within a second is 0.5%, within budget
+        let mut now = 1_000_000;
+        budget.on_sample(now, 0);
+        for _n in 0..10 {
+            now += 100;
+            budget.on_sample(now, 500_000);
+        }
+        assert_eq!(budget.ratio(), 100);
+        assert_eq!(budget.last_cpu_ppm(), 5000);
+
+        // 50ms in the next second is 5%, so sampling backs off
+        for _n in 0..10 {
+            now += 100;
+            budget.on_sample(now, 5_000_000);
+        }
+        assert_eq!(budget.ratio(), 200);
+    }
+
+    #[test]
+    fn test_thread_cpu_nanos_advances() {
+        let start = thread_cpu_nanos();
+        let mut x = 0u64;
+        for i in 0..1_000_000u64 {
+            x = x.wrapping_add(std::hint::black_box(i));
+        }
+        std::hint::black_box(x);
+        #[cfg(unix)]
+        assert!(thread_cpu_nanos() > start);
+    }
+
+    #[test]
+    fn test_jittered_interval_mean_and_range() {
+        assert_eq!(jittered_interval(1, 12345), 1);
+        let mut rng = 0x9e3779b97f4a7c15;
+        let intervals: Vec<u32> = (0..10_000)
+            .map(|_| jittered_interval(100, xorshift64(&mut rng)))
+            .collect();
+        assert!(intervals.iter().all(|&n| (50..=150).contains(&n)));
+        let mean = intervals.iter().map(|&n| n as f64).sum::<f64>() / intervals.len() as f64;
+        assert!((mean - 100.0).abs() < 2.0, "mean {}", mean);
+        // Not stuck on one value
+        assert!(intervals.iter().any(|&n| n != intervals[0]));
+    }
+
+    #[test]
+    fn test_initial_rng_state_from_seed() {
+        assert_eq!(
+            initial_rng_state(Some(42), 1),
+            initial_rng_state(Some(42), 2)
+        );
+        assert_ne!(
+            initial_rng_state(Some(42), 1),
+            initial_rng_state(Some(43), 1)
+        );
+        assert_ne!(initial_rng_state(Some(0), 1), 0);
+        assert_ne!(initial_rng_state(None, 1), 0);
+    }
+
+    #[test]
+    fn test_adaptive_sampler_adjusts_once_per_interval() {
+        let sampler = AdaptiveSampler::new(100, 10);
+        assert!(sampler.is_enabled());
+        assert!(!AdaptiveSampler::disabled().is_enabled());
+
+        // Steady state: 10 samples per second = 1000 allocs/sec
+        let mut now = 1_000_000;
+        for _second in 0..5 {
+            for _n in 0..10 {
+                now += 100;
+                sampler.on_sample(now);
+            }
+        }
+        assert_eq!(sampler.ratio(), 100);
+
+        // Rate goes up 5x within one interval, sampling gets denser
+        for _n in 0..50 {
+            now += 20;
+            sampler.on_sample(now);
+        }
+        now += 20;
+        sampler.on_sample(now);
+        assert!(sampler.ratio() < 100);
+        assert!(sampler.ratio() >= 10);
+    }
+}
@@ -0,0 +1,118 @@
+//! Labelled allocation scopes: `ying_alloc_scope!` labels a block of code, and sampled allocations made on the
+//! same thread inside it get a `<scope: label>` root frame, so they can be grouped without relying on symbols.
+//!
+//! ```
+//!     use ying_profiler::ying_alloc_scope;
+//!     let tokens: Vec<String> = ying_alloc_scope!("parse_request", {
+//!         "GET /index.html".split(' ').map(String::from).collect()
+//!     });
+//! ```
+use std::cell::Cell;
+#[cfg(not(any(
+    feature = "disabled",
+    not(any(target_os = "linux", target_os = "macos"))
+)))]
+use std::hash::Hasher;
+
+#[cfg(not(any(
+    feature = "disabled",
+    not(any(target_os = "linux", target_os = "macos"))
+)))]
+use wyhash::WyHash;
+
+use super::*;
+
+/// Labels sampled allocations made on the current thread during `$body` with `$label`, a `&'static str`.
+/// Sampled stacks get a `<scope: label>` frame as their outermost frame, so eg `stacks_matching("<scope: label>")`
+/// finds them and flamegraphs group them under the label.  The previous label is restored when the block is
+/// left, including by early return or panic.  Scopes nest; the innermost label wins.  Evaluates to `$body`.
+#[macro_export]
+macro_rules! ying_alloc_scope {
+    ($label:expr, $body:block) => {{
+        let _ying_scope = $crate::scope::AllocScope::enter($label);
+        $body
+    }};
+}
+
+// The label of each thread's innermost scope, in slots hashed by thread ID like YingLocalCache.  Kept outside
+// of the profiler, as `ying_alloc_scope!` can't know which profiler is the global allocator.  Each slot
+// remembers which thread set it, so a thread never picks up another thread's label from a shared slot.
+struct ScopeLabels {
+    slots: [Cell<(usize, Option<&'static str>)>; YING_CACHE_SIZE],
+}
+
+// Safety: slots are only accessed by the thread whose ID hashes to them, as with YingLocalCache
+unsafe impl Sync for ScopeLabels {}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY_SLOT: Cell<(usize, Option<&'static str>)> = Cell::new((0, None));
+
+static SCOPE_LABELS: ScopeLabels = ScopeLabels {
+    slots: [EMPTY_SLOT; YING_CACHE_SIZE],
+};
+
+impl ScopeLabels {
+    #[inline]
+    fn slot(&self) -> &Cell<(usize, Option<&'static str>)> {
+        &self.slots[hash_usize(thread_id()) % YING_CACHE_SIZE]
+    }
+}
+
+/// The label of the current thread's innermost `ying_alloc_scope!`, if any
+#[inline]
+pub(crate) fn current_label() -> Option<&'static str> {
+    match SCOPE_LABELS.slot().get() {
+        (tid, label) if tid == thread_id() => label,
+        _ => None,
+    }
+}
+
+/// Guard which labels the current thread's sampled allocations until dropped, see `ying_alloc_scope!`
+pub struct AllocScope {
+    previous: Option<&'static str>,
+}
+
+impl AllocScope {
+    /// Labels sampled allocations on this thread with `label` until the returned guard is dropped
+    pub fn enter(label: &'static str) -> Self {
+        let previous = current_label();
+        SCOPE_LABELS.slot().set((thread_id(), Some(label)));
+        Self { previous }
+    }
+}
+
+impl Drop for AllocScope {
+    fn drop(&mut self) {
+        SCOPE_LABELS.slot().set((thread_id(), self.previous));
+    }
+}
+
+/// Fake IP of the marker frame for a scope label.  The top bit is set so that it never clashes with real code.
+#[cfg(not(any(
+    feature = "disabled",
+    not(any(target_os = "linux", target_os = "macos"))
+)))]
+pub(crate) fn scope_marker_ip(label: &str) -> u64 {
+    let mut hasher = WyHash::with_seed(0x5c0e);
+    hasher.write(label.as_bytes());
+    hasher.finish() | (1 << 63)
+}
+
+// Adds the marker frame of the current thread's scope label, if any, to a sampled stack
+#[cfg(not(any(
+    feature = "disabled",
+    not(any(target_os = "linux", target_os = "macos"))
+)))]
+#[inline]
+pub(crate) fn label_stack(state: &YingState, stack: StdCallstack) -> StdCallstack {
+    match current_label() {
+        Some(label) => {
+            let ip = scope_marker_ip(label);
+            state.symbol_map.entry(ip).or_insert_with(|| {
+                vec![FriendlySymbol::marker(&format!("<scope: {}>", label))].into()
+            });
+            stack.with_root_frame(ip)
+        }
+        None => stack,
+    }
+}
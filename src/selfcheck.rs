@@ -0,0 +1,243 @@
+//! Checks of the profiler's own accounting, for debugging the profiler itself, see [YingProfiler::self_check].
+use super::*;
+
+/// Counters at or above this are taken to have wrapped below zero
+const UNDERFLOW_THRESHOLD: u64 = 1 << 62;
+/// How far the retained bytes counter may be from the outstanding allocations it tracks, as allocations being
+/// made or freed on other threads during the check update the two at slightly different times
+const RETAINED_TOLERANCE_BYTES: u64 = 64 * 1024;
+
+/// A broken invariant of the profiler's accounting, found by [YingProfiler::self_check]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SelfCheckViolation {
+    /// A stack has more frees than allocations
+    FreesExceedAllocations {
+        stack_hash: u64,
+        num_allocations: u64,
+        num_frees: u64,
+    },
+    /// A stack has more outstanding sampled allocations than its allocations minus its frees
+    OutstandingExceedsLive {
+        stack_hash: u64,
+        live: u64,
+        outstanding: u64,
+    },
+    /// A stack's outstanding sampled allocations add up to more bytes than it retains
+    OutstandingExceedsRetained {
+        stack_hash: u64,
+        retained_bytes: u64,
+        outstanding_bytes: u64,
+    },
+    /// `profiled_bytes_retained()` doesn't match the bytes of all outstanding sampled allocations
+    RetainedCounterMismatch {
+        counter: u64,
+        outstanding_bytes: u64,
+    },
+    /// A counter has wrapped below zero
+    Underflow { counter: String, value: u64 },
+}
+
+impl fmt::Display for SelfCheckViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SelfCheckViolation::FreesExceedAllocations {
+                stack_hash,
+                num_allocations,
+                num_frees,
+            } => write!(
+                f,
+                "stack {:#x} has {} frees but only {} allocations",
+                stack_hash, num_frees, num_allocations
+            ),
+            SelfCheckViolation::OutstandingExceedsLive {
+                stack_hash,
+                live,
+                outstanding,
+            } => write!(
+                f,
+                "stack {:#x} has {} outstanding allocations but only {} live",
+                stack_hash, outstanding, live
+            ),
+            SelfCheckViolation::OutstandingExceedsRetained {
+                stack_hash,
+                retained_bytes,
+                outstanding_bytes,
+            } => write!(
+                f,
+                "stack {:#x} has {} outstanding bytes but only retains {}",
+                stack_hash, outstanding_bytes, retained_bytes
+            ),
+            SelfCheckViolation::RetainedCounterMismatch {
+                counter,
+                outstanding_bytes,
+            } => write!(
+                f,
+                "profiled retained counter is {} but outstanding allocations add up to {} bytes",
+                counter, outstanding_bytes
+            ),
+            SelfCheckViolation::Underflow { counter, value } => {
+                write!(f, "{} has underflowed to {}", counter, value)
+            }
+        }
+    }
+}
+
+/// Result of [YingProfiler::self_check]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SelfCheckReport {
+    /// Number of stacks checked
+    pub stacks_checked: usize,
+    /// Number of outstanding sampled allocations checked
+    pub outstanding_checked: usize,
+    /// Sum of the retained sampled bytes of all stacks.  Can be more than `outstanding_bytes`, as allocations
+    /// not tracked as outstanding, see `YingProfiler::untracked_outstanding_allocs()`, still count towards their stack.
+    pub stacks_retained_bytes: u64,
+    /// Sum of the sizes of all outstanding sampled allocations
+    pub outstanding_bytes: u64,
+    /// Outstanding sampled allocations whose stack is gone, eg evicted after `reset_stack`.  Their frees are
+    /// ignored, so they don't break the accounting.
+    pub orphaned_outstanding: usize,
+    /// Broken invariants, empty if the accounting is consistent
+    pub violations: Vec<SelfCheckViolation>,
+}
+
+impl SelfCheckReport {
+    /// True if no invariant is broken
+    pub fn is_ok(&self) -> bool {
+        self.violations.is_empty()
+    }
+}
+
+impl fmt::Display for SelfCheckReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Ying self check: {} stacks, {} outstanding allocs ({} orphaned), {} violations",
+            self.stacks_checked,
+            self.outstanding_checked,
+            self.orphaned_outstanding,
+            self.violations.len()
+        )?;
+        for violation in &self.violations {
+            write!(f, "\n  {}", violation)?;
+        }
+        Ok(())
+    }
+}
+
+impl<A: GlobalAlloc> YingProfiler<A> {
+    /// Verifies the invariants of the profiler's accounting: no stack has more frees than allocations, or more
+    /// outstanding allocations or bytes than it has live, no counter has underflowed, and
+    /// `profiled_bytes_retained()` matches the outstanding allocations.  The counter check assumes this profiler
+    /// is the global allocator, as the counters are global.  Goes through every stack and outstanding allocation
+    /// with profiling locked out on this thread, so it's slow on big profiles.  Allocations on other threads
+    /// during the check may show up as violations which aren't really there; re-run it to confirm one.
+    pub fn self_check(&self) -> SelfCheckReport {
+        self.lock_out_profiler(|| {
+            let state = self.get_state();
+            let mut violations = Vec::new();
+
+            // (count, bytes) of outstanding allocations per stack, from the stack's current generation only,
+            // as older ones were counted before the stack was reset
+            let mut outstanding: HashMap<u64, (u64, u64)> = HashMap::new();
+            let mut outstanding_bytes = 0;
+            let mut outstanding_checked = 0;
+            let mut orphaned_outstanding = 0;
+            for entry in &state.outstanding_allocs {
+                let info = entry.value();
+                outstanding_bytes += info.size;
+                outstanding_checked += 1;
+                match state.stack_stats.get(&info.stack_hash) {
+                    Some(stats) if stats.generation() == info.generation => {
+                        let per_stack = outstanding.entry(info.stack_hash).or_default();
+                        per_stack.0 += 1;
+                        per_stack.1 += info.size;
+                    }
+                    Some(_) => {}
+                    None => orphaned_outstanding += 1,
+                }
+            }
+
+            let mut stacks_checked = 0;
+            let mut stacks_retained_bytes = 0;
+            for entry in &state.stack_stats {
+                let stats = entry.value();
+                stacks_checked += 1;
+                let stack_hash = stats.stack_hash();
+                stacks_retained_bytes += stats.retained_profiled_bytes();
+                for (counter, value) in [
+                    ("allocated bytes", stats.allocated_bytes()),
+                    ("freed bytes", stats.freed_bytes()),
+                    ("allocations", stats.num_allocations()),
+                    ("frees", stats.num_frees()),
+                ] {
+                    if value >= UNDERFLOW_THRESHOLD {
+                        violations.push(SelfCheckViolation::Underflow {
+                            counter: format!("{} of stack {:#x}", counter, stack_hash),
+                            value,
+                        });
+                    }
+                }
+                if stats.num_frees() > stats.num_allocations() {
+                    violations.push(SelfCheckViolation::FreesExceedAllocations {
+                        stack_hash,
+                        num_allocations: stats.num_allocations(),
+                        num_frees: stats.num_frees(),
+                    });
+                }
+                let (num_outstanding, bytes_outstanding) =
+                    outstanding.get(&stack_hash).copied().unwrap_or_default();
+                let live = stats.num_allocations().saturating_sub(stats.num_frees());
+                if num_outstanding > live {
+                    violations.push(SelfCheckViolation::OutstandingExceedsLive {
+                        stack_hash,
+                        live,
+                        outstanding: num_outstanding,
+                    });
+                }
+                if bytes_outstanding > stats.retained_profiled_bytes() {
+                    violations.push(SelfCheckViolation::OutstandingExceedsRetained {
+                        stack_hash,
+                        retained_bytes: stats.retained_profiled_bytes(),
+                        outstanding_bytes: bytes_outstanding,
+                    });
+                }
+            }
+
+            for (counter, value) in [
+                ("total retained bytes", YingProfiler::total_retained_bytes()),
+                (
+                    "profiled retained bytes",
+                    YingProfiler::profiled_bytes_retained(),
+                ),
+                (
+                    "profiled allocated bytes",
+                    YingProfiler::profiled_bytes_allocated(),
+                ),
+            ] {
+                if value as u64 >= UNDERFLOW_THRESHOLD {
+                    violations.push(SelfCheckViolation::Underflow {
+                        counter: counter.to_string(),
+                        value: value as u64,
+                    });
+                }
+            }
+            let counter = YingProfiler::profiled_bytes_retained() as u64;
+            if counter.abs_diff(outstanding_bytes) > RETAINED_TOLERANCE_BYTES {
+                violations.push(SelfCheckViolation::RetainedCounterMismatch {
+                    counter,
+                    outstanding_bytes,
+                });
+            }
+
+            SelfCheckReport {
+                stacks_checked,
+                outstanding_checked,
+                stacks_retained_bytes,
+                outstanding_bytes,
+                orphaned_outstanding,
+                violations,
+            }
+        })
+    }
+}
@@ -0,0 +1,293 @@
+//! Scoped profiling sessions, for answering "what did this block of code allocate?"
+//!
+//! ```
+//!     use ying_profiler::YingProfiler;
+//!     static YING_ALLOC: YingProfiler = YingProfiler::default();
+//!     let session = YING_ALLOC.begin_session();
+//!     let _v: Vec<u64> = (0..1000).collect();
+//!     let report = session.finish();
+//!     println!("{}", report);
+//! ```
+use std::collections::HashMap;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use super::*;
+
+// Counters of one stack at the start of a session, or of its activity over an interval
+#[derive(Copy, Clone, Debug)]
+pub(crate) struct StackBaseline {
+    generation: u32,
+    allocated_bytes: u64,
+    num_allocations: u64,
+    freed_bytes: u64,
+    num_frees: u64,
+}
+
+impl StackBaseline {
+    pub(crate) fn of(stats: &StackStats) -> Self {
+        Self {
+            generation: stats.generation(),
+            allocated_bytes: stats.allocated_bytes(),
+            num_allocations: stats.num_allocations(),
+            freed_bytes: stats.freed_bytes(),
+            num_frees: stats.num_frees(),
+        }
+    }
+
+    /// Activity of `stats` since `base`, its counters at an earlier point, or all its activity if there is no
+    /// base from the same generation.  None if the stack did nothing since.
+    pub(crate) fn activity_since(base: Option<&StackBaseline>, stats: &StackStats) -> Option<Self> {
+        let now = Self::of(stats);
+        let activity = match base.filter(|base| base.generation == now.generation) {
+            Some(base) => Self {
+                generation: now.generation,
+                allocated_bytes: now.allocated_bytes.saturating_sub(base.allocated_bytes),
+                num_allocations: now.num_allocations.saturating_sub(base.num_allocations),
+                freed_bytes: now.freed_bytes.saturating_sub(base.freed_bytes),
+                num_frees: now.num_frees.saturating_sub(base.num_frees),
+            },
+            None => now,
+        };
+        if activity.allocated_bytes == 0 && activity.num_allocations == 0 && activity.num_frees == 0
+        {
+            return None;
+        }
+        Some(activity)
+    }
+
+    /// Change in sampled retained bytes, for activity over an interval
+    pub(crate) fn retained_bytes_delta(&self) -> i64 {
+        self.allocated_bytes as i64 - self.freed_bytes as i64
+    }
+
+    /// Activity over an interval, with the given stats of the stack
+    pub(crate) fn into_delta(self, stats: StackStats) -> StackDelta {
+        StackDelta {
+            allocated_bytes: self.allocated_bytes,
+            num_allocations: self.num_allocations,
+            freed_bytes: self.freed_bytes,
+            num_frees: self.num_frees,
+            stats,
+        }
+    }
+}
+
+/// A profiling session started by [YingProfiler::begin_session].  Holds a baseline of the stats of every
+/// stack at the start of the session, which is diffed against by [ProfileSession::finish].
+/// Sessions are independent of each other, so they may overlap or nest; each diffs against its own baseline.
+/// Dropping a session without finishing it just releases the baseline.
+pub struct ProfileSession<'p, A: GlobalAlloc = System> {
+    profiler: &'p YingProfiler<A>,
+    started: Instant,
+    baseline: HashMap<u64, StackBaseline>,
+}
+
+impl<A: GlobalAlloc> YingProfiler<A> {
+    /// Starts a session which records the sampled allocations and frees made between now and
+    /// [ProfileSession::finish], per stack.  Note that allocations from all threads are included, not just
+    /// those of the calling thread.
+    pub fn begin_session(&self) -> ProfileSession<'_, A> {
+        self.lock_out_profiler(|| {
+            let baseline = self
+                .get_state()
+                .stack_stats
+                .iter()
+                .map(|entry| (*entry.key(), StackBaseline::of(entry.value())))
+                .collect();
+            ProfileSession {
+                profiler: self,
+                started: Instant::now(),
+                baseline,
+            }
+        })
+    }
+}
+
+/// Growth in retained bytes which `assert_no_growth` lets through, for small buffers which stay allocated after
+/// first use, such as lazily initialized statics and thread locals
+const NO_GROWTH_TOLERANCE_BYTES: usize = 1024;
+/// How many times, and how long apart, `assert_no_growth_within` checks retained bytes before failing, to let
+/// frees on other threads, eg of tasks dropped by a runtime, settle
+const SETTLE_ATTEMPTS: usize = 10;
+const SETTLE_INTERVAL: Duration = Duration::from_millis(10);
+/// Number of growing stacks shown when `assert_no_growth_within` fails
+const NO_GROWTH_TOP_STACKS: usize = 5;
+
+impl<A: GlobalAlloc> YingProfiler<A> {
+    /// Leak assertion for tests: runs `f`, and panics if total retained bytes grew by more than 1 KiB, listing the
+    /// stacks whose sampled retained bytes grew the most.  See `assert_no_growth_within`.
+    pub fn assert_no_growth(&self, f: impl FnOnce()) {
+        self.assert_no_growth_within(NO_GROWTH_TOLERANCE_BYTES, f)
+    }
+
+    /// Leak assertion for tests: runs `f`, and panics if total retained bytes grew by more than `tolerance_bytes`,
+    /// listing the stacks whose sampled retained bytes grew the most.  Everything `f` allocated must be freed by
+    /// the time it returns.  Frees still pending on other threads are given up to 100ms to settle.
+    ///
+    /// Retained bytes are counted across all threads and exactly, not sampled, so this needs the profiler to be
+    /// the global allocator, and tests which allocate concurrently, such as other tests run in parallel by the
+    /// test harness, can make it fail.  Run such tests serially.  Growing stacks are found from sampled
+    /// allocations, so the list may miss small leaks unless the sampling ratio is 1.
+    pub fn assert_no_growth_within(&self, tolerance_bytes: usize, f: impl FnOnce()) {
+        let session = self.begin_session();
+        let baseline = YingProfiler::total_retained_bytes();
+        f();
+        let mut growth = 0;
+        for attempt in 0..SETTLE_ATTEMPTS {
+            growth = YingProfiler::total_retained_bytes().saturating_sub(baseline);
+            if growth <= tolerance_bytes {
+                return;
+            }
+            if attempt + 1 < SETTLE_ATTEMPTS {
+                std::thread::sleep(SETTLE_INTERVAL);
+            }
+        }
+
+        let report = session.finish();
+        let mut growing: Vec<&StackDelta> = report
+            .stacks
+            .iter()
+            .filter(|delta| delta.retained_bytes_delta() > 0)
+            .collect();
+        growing.sort_unstable_by_key(|delta| Reverse(delta.retained_bytes_delta()));
+        let mut message = format!(
+            "Retained memory grew by {} bytes, more than the tolerance of {} bytes.  Top growing stacks:",
+            growth, tolerance_bytes
+        );
+        for delta in growing.iter().take(NO_GROWTH_TOP_STACKS) {
+            message.push_str(&format!(
+                "\n--- retained {:+} sampled bytes\n{}",
+                delta.retained_bytes_delta(),
+                delta.stats.rich_report(self, false, false)
+            ));
+        }
+        panic!("{}", message);
+    }
+}
+
+impl<'p, A: GlobalAlloc> ProfileSession<'p, A> {
+    /// Ends the session, returning the stacks which allocated or freed sampled memory during the session along
+    /// with their byte deltas, sorted by bytes allocated during the session in descending order.
+    /// A stack which was reset during the session only reports its activity since the reset.
+    pub fn finish(self) -> SessionReport {
+        let profiler = self.profiler;
+        profiler.lock_out_profiler(|| {
+            let stacks = profiler
+                .get_state()
+                .stack_stats
+                .iter()
+                .filter_map(|entry| self.delta(entry.value()))
+                .collect();
+            self.report(stacks)
+        })
+    }
+
+    /// Like `finish`, but starts a new session right away from the same pass over the stacks, so that
+    /// consecutive reports don't miss any allocations in between.  Used for periodic deltas.
+    pub(crate) fn finish_and_restart(&mut self) -> SessionReport {
+        let profiler = self.profiler;
+        profiler.lock_out_profiler(|| {
+            let mut stacks = Vec::new();
+            let mut baseline = HashMap::with_capacity(self.baseline.len());
+            for entry in profiler.get_state().stack_stats.iter() {
+                stacks.extend(self.delta(entry.value()));
+                baseline.insert(*entry.key(), StackBaseline::of(entry.value()));
+            }
+            let report = self.report(stacks);
+            self.baseline = baseline;
+            self.started = Instant::now();
+            report
+        })
+    }
+
+    fn report(&self, mut stacks: Vec<StackDelta>) -> SessionReport {
+        stacks.sort_unstable_by_key(|delta| {
+            (Reverse(delta.allocated_bytes), delta.stats.stack_hash())
+        });
+        SessionReport {
+            duration: self.started.elapsed(),
+            stacks,
+        }
+    }
+
+    fn delta(&self, stats: &StackStats) -> Option<StackDelta> {
+        StackBaseline::activity_since(self.baseline.get(&stats.stack_hash()), stats)
+            .map(|activity| activity.into_delta(stats.clone()))
+    }
+}
+
+/// Sampled activity of one stack during a [ProfileSession]
+#[derive(Debug, Clone)]
+pub struct StackDelta {
+    /// Sampled bytes allocated during the session
+    pub allocated_bytes: u64,
+    pub num_allocations: u64,
+    /// Sampled bytes freed during the session, including frees of allocations made before the session
+    pub freed_bytes: u64,
+    pub num_frees: u64,
+    /// Stats of the stack as of the end of the session, eg for `stack_hash()` and `rich_report()`
+    pub stats: StackStats,
+}
+
+impl StackDelta {
+    /// Change in sampled retained bytes over the session.  Negative if the session freed more than it allocated.
+    pub fn retained_bytes_delta(&self) -> i64 {
+        self.allocated_bytes as i64 - self.freed_bytes as i64
+    }
+}
+
+/// Result of [ProfileSession::finish]
+#[derive(Debug, Clone)]
+pub struct SessionReport {
+    pub duration: Duration,
+    /// Stacks active during the session, by sampled bytes allocated in descending order
+    pub stacks: Vec<StackDelta>,
+}
+
+impl SessionReport {
+    /// Total sampled bytes allocated during the session
+    pub fn allocated_bytes(&self) -> u64 {
+        self.stacks.iter().map(|delta| delta.allocated_bytes).sum()
+    }
+
+    /// Total change in sampled retained bytes over the session
+    pub fn retained_bytes_delta(&self) -> i64 {
+        self.stacks
+            .iter()
+            .map(|delta| delta.retained_bytes_delta())
+            .sum()
+    }
+
+    /// The delta for one stack, if it was active during the session
+    pub fn stack(&self, stack_hash: u64) -> Option<&StackDelta> {
+        self.stacks
+            .iter()
+            .find(|delta| delta.stats.stack_hash() == stack_hash)
+    }
+}
+
+impl fmt::Display for SessionReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Session of {:?}: {} sampled bytes allocated, retained changed by {} bytes, over {} stacks",
+            self.duration,
+            self.allocated_bytes(),
+            self.retained_bytes_delta(),
+            self.stacks.len()
+        )?;
+        for delta in &self.stacks {
+            writeln!(
+                f,
+                "  stack {:#018x}: {} bytes in {} allocations, {} bytes freed, retained {:+}",
+                delta.stats.stack_hash(),
+                delta.allocated_bytes,
+                delta.num_allocations,
+                delta.freed_bytes,
+                delta.retained_bytes_delta()
+            )?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,66 @@
+//! Point in time copies of the stats of all stacks, which can be kept and compared with later snapshots.
+use std::collections::HashMap;
+use std::time::SystemTime;
+
+use super::*;
+
+/// The stats of every stack at one point in time, from [YingProfiler::snapshot].  Unlike the profiler's live
+/// state, a snapshot does not change, so it can be kept as a baseline, eg from before a deploy.
+#[derive(Debug, Clone)]
+pub struct YingSnapshot {
+    taken_at: SystemTime,
+    stacks: HashMap<u64, StackStats>,
+}
+
+impl YingSnapshot {
+    /// When the snapshot was taken
+    pub fn taken_at(&self) -> SystemTime {
+        self.taken_at
+    }
+
+    /// Number of stacks in the snapshot
+    pub fn num_stacks(&self) -> usize {
+        self.stacks.len()
+    }
+
+    /// The stats of one stack, if it was in the snapshot
+    pub fn get(&self, stack_hash: u64) -> Option<&StackStats> {
+        self.stacks.get(&stack_hash)
+    }
+
+    /// All stacks in the snapshot, in no particular order
+    pub fn stacks(&self) -> impl Iterator<Item = &StackStats> {
+        self.stacks.values()
+    }
+
+    /// Stacks in this snapshot whose hash was not in `baseline`, ie code paths which started allocating after
+    /// the baseline was taken, sorted by retained sampled bytes in descending order.
+    pub fn new_stacks_since(&self, baseline: &YingSnapshot) -> Vec<StackStats> {
+        let mut new_stacks: Vec<StackStats> = self
+            .stacks
+            .iter()
+            .filter(|(stack_hash, _)| !baseline.stacks.contains_key(stack_hash))
+            .map(|(_, stats)| stats.clone())
+            .collect();
+        new_stacks.sort_unstable_by_key(|stats| {
+            (Reverse(stats.retained_profiled_bytes()), stats.stack_hash())
+        });
+        new_stacks
+    }
+}
+
+impl<A: GlobalAlloc> YingProfiler<A> {
+    /// Takes a snapshot of the stats of all stacks.  This copies every stack, so it is about as expensive as a
+    /// full report.
+    pub fn snapshot(&self) -> YingSnapshot {
+        self.lock_out_profiler(|| YingSnapshot {
+            taken_at: SystemTime::now(),
+            stacks: self
+                .get_state()
+                .stack_stats
+                .iter()
+                .map(|entry| (*entry.key(), entry.value().clone()))
+                .collect(),
+        })
+    }
+}
@@ -0,0 +1,119 @@
+//! Allocations which stayed live across a window, found by capturing the live sampled allocations at the start and
+//! end of it, for isolating leaks from memory which is merely churning.
+use std::collections::HashMap;
+use std::time::SystemTime;
+
+use super::*;
+
+/// The outstanding sampled allocations at one point in time, from [YingProfiler::capture_live].  Compare two
+/// captures with [YingProfiler::survivors].
+#[derive(Debug, Clone)]
+pub struct LiveCapture {
+    taken_at: SystemTime,
+    allocs: HashMap<u64, AllocInfo>,
+}
+
+impl LiveCapture {
+    /// When the capture was taken
+    pub fn taken_at(&self) -> SystemTime {
+        self.taken_at
+    }
+
+    /// Number of outstanding sampled allocations captured
+    pub fn num_allocs(&self) -> usize {
+        self.allocs.len()
+    }
+
+    // Whether `info` at `ptr` is the same allocation as one in this capture, rather than a later one which reused
+    // the address
+    fn contains(&self, ptr: u64, info: &AllocInfo) -> bool {
+        self.allocs
+            .get(&ptr)
+            .is_some_and(|earlier| earlier.alloc_id == info.alloc_id)
+    }
+}
+
+/// Allocations of one stack which survived between two captures, from [YingProfiler::survivors]
+#[derive(Debug, Clone)]
+pub struct SurvivorStack {
+    pub stack_hash: u64,
+    /// Symbols of the stack as in `StackStats::canonical_key`, or None if the stack is no longer recorded
+    pub stack: Option<String>,
+    /// Number of sampled allocations live at both captures
+    pub num_allocs: usize,
+    /// Sampled bytes of those allocations, at their size in the later capture
+    pub retained_bytes: u64,
+    /// When the oldest of those allocations was made, in millis since the epoch
+    pub oldest_alloc_millis: u64,
+}
+
+impl fmt::Display for SurvivorStack {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} bytes in {} surviving allocations, stack {:x}: {}",
+            self.retained_bytes,
+            self.num_allocs,
+            self.stack_hash,
+            self.stack.as_deref().unwrap_or("<unknown>")
+        )
+    }
+}
+
+impl<A: GlobalAlloc> YingProfiler<A> {
+    /// Captures the address and details of every outstanding sampled allocation, to compare with a later
+    /// capture using `survivors`.  This copies the map of outstanding allocations, so it costs memory in
+    /// proportion to the number of them.
+    pub fn capture_live(&self) -> LiveCapture {
+        self.lock_out_profiler(|| LiveCapture {
+            taken_at: SystemTime::now(),
+            allocs: self
+                .get_state()
+                .outstanding_allocs
+                .iter()
+                .map(|entry| (*entry.key(), *entry.value()))
+                .collect(),
+        })
+    }
+
+    /// The allocations in `later` which were also live in `earlier`, ie which stayed live for the whole window
+    /// between the two captures, grouped by stack and sorted by retained sampled bytes in descending order.
+    /// Stacks which keep showing up here across successive windows, with growing bytes, are the strongest leak
+    /// signal the profiler has.  An allocation moved by realloc during the window counts as a new one.
+    pub fn survivors(&self, earlier: &LiveCapture, later: &LiveCapture) -> Vec<SurvivorStack> {
+        self.lock_out_profiler(|| {
+            let mut groups: HashMap<u64, SurvivorStack> = HashMap::new();
+            for (ptr, info) in later.allocs.iter() {
+                if !earlier.contains(*ptr, info) {
+                    continue;
+                }
+                let group = groups
+                    .entry(info.stack_hash)
+                    .or_insert_with(|| SurvivorStack {
+                        stack_hash: info.stack_hash,
+                        stack: None,
+                        num_allocs: 0,
+                        retained_bytes: 0,
+                        oldest_alloc_millis: u64::MAX,
+                    });
+                group.num_allocs += 1;
+                group.retained_bytes += info.size;
+                group.oldest_alloc_millis = group.oldest_alloc_millis.min(info.alloc_ts);
+            }
+            let state = self.get_state();
+            let mut survivors: Vec<SurvivorStack> = groups
+                .into_values()
+                .map(|mut group| {
+                    group.stack = state.stack_stats.get(&group.stack_hash).map(|stats| {
+                        self.resolved_stack(state, &stats)
+                            .canonical_key(&state.symbol_map)
+                    });
+                    group
+                })
+                .collect();
+            survivors
+                .sort_unstable_by_key(|group| (Reverse(group.retained_bytes), group.stack_hash));
+            survivors
+        })
+    }
+}
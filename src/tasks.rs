@@ -0,0 +1,211 @@
+//! Sampled allocations by the Tokio task which made them, with the `tokio` feature.
+//! Also stitches the stacks of `spawn_blocking` tasks onto the stacks which spawned them.
+use backtrace::Backtrace;
+use callstack::SPAWN_BLOCKING_MARKER_IP;
+use tokio::task::{Id, JoinHandle};
+
+use super::*;
+
+/// Sampled allocations of one Tokio task, from `YingProfiler::top_k_tasks_by_retained`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct TaskStats {
+    pub task_id: Id,
+    /// Bytes of sampled allocations made by the task
+    pub allocated_bytes: u64,
+    pub num_allocations: u64,
+    /// Bytes of sampled allocations made by the task which are still outstanding, even if the task has finished
+    pub retained_bytes: u64,
+}
+
+impl TaskStats {
+    #[cfg(not(any(
+        feature = "disabled",
+        not(any(target_os = "linux", target_os = "macos"))
+    )))]
+    fn new(task_id: Id) -> Self {
+        Self {
+            task_id,
+            allocated_bytes: 0,
+            num_allocations: 0,
+            retained_bytes: 0,
+        }
+    }
+}
+
+pub(crate) type TaskMap = DashMap<Id, TaskStats, MapHasher>;
+
+/// The task the current thread is polling, if any.  Reads a thread local, does not allocate.
+#[cfg(not(any(
+    feature = "disabled",
+    not(any(target_os = "linux", target_os = "macos"))
+)))]
+#[inline]
+pub(crate) fn current_task_id() -> Option<Id> {
+    tokio::task::try_id()
+}
+
+#[cfg(not(any(
+    feature = "disabled",
+    not(any(target_os = "linux", target_os = "macos"))
+)))]
+pub(crate) fn record_alloc(tasks: &TaskMap, task_id: Id, size: u64) {
+    let mut stats = tasks
+        .entry(task_id)
+        .or_insert_with(|| TaskStats::new(task_id));
+    stats.allocated_bytes += size;
+    stats.num_allocations += 1;
+    stats.retained_bytes += size;
+}
+
+// Once a task retains nothing it is dropped from the map, so that the map doesn't grow with every short lived task
+#[cfg(not(any(
+    feature = "disabled",
+    not(any(target_os = "linux", target_os = "macos"))
+)))]
+pub(crate) fn record_free(tasks: &TaskMap, task_id: Id, size: u64) {
+    if let Some(mut stats) = tasks.get_mut(&task_id) {
+        stats.retained_bytes = stats.retained_bytes.saturating_sub(size);
+    }
+    tasks.remove_if(&task_id, |_, stats| stats.retained_bytes == 0);
+}
+
+#[cfg(not(any(
+    feature = "disabled",
+    not(any(target_os = "linux", target_os = "macos"))
+)))]
+pub(crate) fn record_realloc(tasks: &TaskMap, task_id: Id, old_size: u64, new_size: u64) {
+    if let Some(mut stats) = tasks.get_mut(&task_id) {
+        stats.retained_bytes = (stats.retained_bytes + new_size).saturating_sub(old_size);
+    }
+}
+
+/// Swaps the blocking pool's frames at the bottom of a sampled stack for the stack which spawned the current
+/// blocking task, if any.  `bt_len` is the number of frames in the sampled backtrace.
+#[cfg(not(any(
+    feature = "disabled",
+    not(any(target_os = "linux", target_os = "macos"))
+)))]
+#[inline]
+pub(crate) fn stitch_spawner(
+    state: &YingState,
+    tl_state: &YingThreadLocal,
+    bt_len: usize,
+    stack: StdCallstack,
+) -> StdCallstack {
+    if tl_state.spawner_hash == 0 {
+        return stack;
+    }
+    match state.spawner_stacks.get(&tl_state.spawner_hash) {
+        Some(spawner) => {
+            let task_frames =
+                bt_len.saturating_sub(TOP_FRAMES_TO_SKIP + tl_state.pool_frames as usize);
+            stack.stitched(task_frames, spawner.value())
+        }
+        None => stack,
+    }
+}
+
+// Number of frames below the caller of this function, ie excluding the caller's own frame.  Found as the number of
+// frames two backtraces from different call sites in this function have in common, which doesn't depend on how
+// many frames the backtrace machinery adds at the top.  Must be called directly, with profiling locked out here
+// rather than through lock_out_profiler(), which could add frames in between.
+#[inline(never)]
+fn frames_below_caller(tl_state: &mut YingThreadLocal) -> usize {
+    tl_state.set_allocator_lock();
+    let first = frame_ips();
+    let second = frame_ips();
+    let common = first
+        .iter()
+        .rev()
+        .zip(second.iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    drop((first, second));
+    tl_state.release_allocator_lock();
+    common.saturating_sub(1)
+}
+
+#[inline(never)]
+fn frame_ips() -> Vec<u64> {
+    let bt = Backtrace::new_unresolved();
+    bt.frames().iter().map(|frame| frame.ip() as u64).collect()
+}
+
+// Marks the current thread as running a blocking task for as long as it lives, even if the task panics
+struct SpawnerGuard<'p, A: GlobalAlloc> {
+    profiler: &'p YingProfiler<A>,
+}
+
+impl<'p, A: GlobalAlloc> SpawnerGuard<'p, A> {
+    fn new(profiler: &'p YingProfiler<A>, spawner_hash: u64, pool_frames: usize) -> Self {
+        let tl_state = profiler.tl_cache.get_thread_local();
+        tl_state.spawner_hash = spawner_hash;
+        tl_state.pool_frames = pool_frames as u32;
+        Self { profiler }
+    }
+}
+
+impl<'p, A: GlobalAlloc> Drop for SpawnerGuard<'p, A> {
+    fn drop(&mut self) {
+        self.profiler.tl_cache.get_thread_local().spawner_hash = 0;
+    }
+}
+
+impl<A: GlobalAlloc + Sync> YingProfiler<A> {
+    /// Like `tokio::task::spawn_blocking`, but allocations sampled in the blocking task have the stack of the
+    /// caller, ie the spawner, stitched onto their own stack after a `<spawn_blocking>` marker frame.  Without
+    /// this, allocations in the blocking pool only show the pool's worker thread, disconnected from the code
+    /// which started the work.
+    ///
+    /// Capturing the spawner's stack costs a few backtraces, plus symbol resolution the first time a stack is
+    /// seen, on every call, so this is meant for coarse grained blocking work rather than very frequent tiny tasks.
+    // Not inlined, so that the caller's frame is always below this one
+    #[inline(never)]
+    pub fn spawn_blocking<F, R>(&'static self, f: F) -> JoinHandle<R>
+    where
+        F: FnOnce() -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        // The spawner's stack starts at the caller of this function
+        let caller_frames = frames_below_caller(self.tl_cache.get_thread_local());
+        let mut bt = Backtrace::new_unresolved();
+        let skip = bt.frames().len().saturating_sub(caller_frames);
+        let spawner_hash = self.lock_out_profiler(|| {
+            let stack = StdCallstack::from_backtrace_skipping(&bt, skip);
+            let state = self.get_state();
+            stack.populate_symbol_map_skipping(&mut bt, &state.symbol_map, skip, self.symbolizer);
+            state
+                .symbol_map
+                .entry(SPAWN_BLOCKING_MARKER_IP)
+                .or_insert_with(|| vec![FriendlySymbol::marker("<spawn_blocking>")].into());
+            let spawner_hash = stack.compute_hash();
+            state.spawner_stacks.entry(spawner_hash).or_insert(stack);
+            spawner_hash
+        });
+        tokio::task::spawn_blocking(move || {
+            // Frames of the blocking pool below this closure, which are cut off sampled stacks
+            let pool_frames = frames_below_caller(self.tl_cache.get_thread_local());
+            let _guard = SpawnerGuard::new(self, spawner_hash, pool_frames);
+            f()
+        })
+    }
+}
+
+impl<A: GlobalAlloc> YingProfiler<A> {
+    /// The top k Tokio tasks by retained sampled bytes, in descending order.  Only allocations made while a task
+    /// was being polled are counted, and tasks are forgotten once all their sampled allocations are freed.
+    /// Allocations not tracked as outstanding due to `with_max_outstanding_allocs` are not counted either.
+    pub fn top_k_tasks_by_retained(&self, k: usize) -> Vec<TaskStats> {
+        self.lock_out_profiler(|| {
+            let mut tasks: Vec<TaskStats> = self
+                .get_state()
+                .task_stats
+                .iter()
+                .map(|entry| *entry.value())
+                .collect();
+            tasks.sort_unstable_by_key(|stats| Reverse(stats.retained_bytes));
+            tasks.truncate(k);
+            tasks
+        })
+    }
+}
@@ -0,0 +1,109 @@
+//! Cheaper alternatives to `Backtrace::new_unresolved` for capturing sampled stacks: walking frame pointers, for
+//! programs built with `-C force-frame-pointers=yes`, and unwinding only the innermost frames.
+use crate::callstack::{StdCallstack, MAX_NUM_FRAMES};
+
+// Largest gap between consecutive frame pointers taken to be a real stack frame.  Anything further apart is
+// taken as the end of the chain, eg a frame without a frame pointer whose "saved frame pointer" is other data.
+const MAX_FRAME_BYTES: usize = 1024 * 1024;
+
+/// Walks the frame pointer chain from the caller of this function outwards, returning the stack of return
+/// addresses with the first `skip` left out, and the number of frames walked including the skipped ones.
+/// None on targets where frame pointers can't be walked, for the caller to capture a `Backtrace` instead.
+///
+/// Every frame on the chain must have a frame pointer.  The walk stops at a null, misaligned or non-increasing
+/// frame pointer, or one further than `MAX_FRAME_BYTES` from the last, which ends it at the outermost frame and
+/// at most frames without a frame pointer, but can't rule out following one into unmapped memory.
+#[inline(never)]
+pub(crate) fn frame_pointer_stack(skip: usize) -> Option<(StdCallstack, usize)> {
+    let mut fp = current_frame_pointer()?;
+    // Frame pointers of this thread's stack are above any local of this function, as the stack grows down
+    let stack_low = &fp as *const usize as usize;
+    let mut ips = [0u64; MAX_NUM_FRAMES];
+    let mut num_frames = 0usize;
+    while fp >= stack_low && fp % std::mem::align_of::<usize>() == 0 {
+        // SAFETY: fp is an aligned frame pointer within this thread's stack.  A frame starts with the saved frame
+        // pointer of its caller, followed by the return address into the caller.
+        let (next_fp, return_addr) = unsafe {
+            let frame = fp as *const usize;
+            (*frame, *frame.add(1))
+        };
+        if return_addr == 0 {
+            break;
+        }
+        if let Some(slot) = num_frames.checked_sub(skip).and_then(|i| ips.get_mut(i)) {
+            *slot = return_addr as u64;
+        }
+        num_frames += 1;
+        if next_fp <= fp || next_fp - fp > MAX_FRAME_BYTES {
+            break;
+        }
+        fp = next_fp;
+    }
+    Some((StdCallstack::from_ips(&ips), num_frames))
+}
+
+// The frame pointer register, ie the frame of the function this is inlined into
+#[inline(always)]
+fn current_frame_pointer() -> Option<usize> {
+    #[cfg(target_arch = "x86_64")]
+    {
+        let fp: usize;
+        unsafe {
+            std::arch::asm!("mov {}, rbp", out(reg) fp, options(nomem, nostack, preserves_flags));
+        }
+        Some(fp)
+    }
+    #[cfg(target_arch = "aarch64")]
+    {
+        let fp: usize;
+        unsafe {
+            std::arch::asm!("mov {}, x29", out(reg) fp, options(nomem, nostack, preserves_flags));
+        }
+        Some(fp)
+    }
+    #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+    {
+        None
+    }
+}
+
+/// Captures at most `max_frames` frames, after leaving out the first `skip` beyond the caller of this function,
+/// stopping the unwind there rather than unwinding the whole stack.  Returns the stack, and the number of frames
+/// captured including this function's and the skipped ones, as `Backtrace::frames()` would count them, or None
+/// if the unwind was stopped early.  None if this function's own frame couldn't be found to count from.
+#[inline(never)]
+pub(crate) fn shallow_stack(
+    skip: usize,
+    max_frames: usize,
+) -> Option<(StdCallstack, Option<usize>)> {
+    let own_address = shallow_stack as *const () as usize;
+    let limit = skip + max_frames.min(MAX_NUM_FRAMES);
+    let mut ips = [0u64; MAX_NUM_FRAMES];
+    // Frames seen after this function's own, once it has been found among the unwinder's frames
+    let mut num_frames: Option<usize> = None;
+    let mut stopped = false;
+    backtrace::trace(|frame| match num_frames.as_mut() {
+        None => {
+            if frame.symbol_address() as usize == own_address {
+                num_frames = Some(0);
+            }
+            true
+        }
+        Some(n) if *n == limit => {
+            stopped = true;
+            false
+        }
+        Some(n) => {
+            if let Some(i) = n.checked_sub(skip) {
+                ips[i] = frame.ip() as u64;
+            }
+            *n += 1;
+            true
+        }
+    });
+    let num_frames = num_frames?;
+    Some((
+        StdCallstack::from_ips(&ips),
+        (!stopped).then_some(num_frames + 1),
+    ))
+}
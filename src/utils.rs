@@ -1,6 +1,6 @@
 use std::fmt::Write as _;
 use std::io::Write as _;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::time::Duration;
 use std::{fs::File, io::Cursor};
 
@@ -99,7 +99,7 @@ impl ProfilerRunner {
     }
 
     /// Spawn a new background thread to run profiler and get stats
-    pub fn spawn(&self, profiler: &'static YingProfiler) {
+    pub fn spawn<A: GlobalAlloc + Sync>(&self, profiler: &'static YingProfiler<A>) {
         let check_interval_secs = self.check_interval_secs;
         let report_pct_change_trigger = self.report_pct_change_trigger;
         let reporting_path = PathBuf::from(self.reporting_path.clone());
@@ -126,6 +126,7 @@ impl ProfilerRunner {
                     "Ying: total allocated memory is {:.2} MB and ratio to last = {}",
                     new_allocated, ratio
                 );
+                info!("{}", profiler2.summary());
 
                 // Threshold for change exceeded, do report
                 if (ratio.abs() * 100.0) >= report_pct_change_trigger as f64 {
@@ -137,19 +138,15 @@ impl ProfilerRunner {
 
                     last_retained_mem = new_allocated;
 
-                    let top_stacks = match measurement {
-                        Measurement::AllocatedBytes => profiler2.top_k_stacks_by_allocated(10),
-                        Measurement::RetainedBytes => profiler2.top_k_stacks_by_retained(10),
-                    };
+                    let top_stacks = profiler2.top_k_by(10, |stats| measurement.of(stats));
                     for s in &top_stacks {
                         // In case the app does not use log, we still output to STDOUT the report
                         println!("---\n{}\n", s.rich_report(profiler2, false, expand_frames));
                     }
 
                     // Formulate profiling filename based on ISO8601 timestamp and number of MBs
-                    let dt = chrono::offset::Local::now();
-                    let dt_str = dt.to_rfc3339_opts(chrono::SecondsFormat::Secs, true);
-                    let dump_name = format!("ying.{}.{}MB.report", dt_str, new_allocated as i64);
+                    let base_name = dump_base_name(new_allocated as i64);
+                    let dump_name = format!("{}.report", base_name);
 
                     let mut report_path = reporting_path.clone();
                     report_path.push(dump_name);
@@ -166,7 +163,7 @@ impl ProfilerRunner {
                     }
 
                     if gen_flamegraphs {
-                        let graph_name = format!("ying.{}.{}MB.svg", dt_str, new_allocated as i64);
+                        let graph_name = format!("{}.svg", base_name);
                         let mut graph_path = reporting_path.clone();
                         graph_path.push(graph_name);
                         if let Err(e) = gen_flamegraph(profiler2, measurement, &graph_path) {
@@ -179,65 +176,534 @@ impl ProfilerRunner {
     }
 }
 
+/// Base file name for dumps, based on the ISO8601 timestamp and number of MBs retained
+fn dump_base_name(retained_mb: i64) -> String {
+    let dt = chrono::offset::Local::now();
+    let dt_str = dt.to_rfc3339_opts(chrono::SecondsFormat::Secs, true);
+    format!("ying.{}.{}MB", dt_str, retained_mb)
+}
+
 /// Function to produce a FlameGraph file to a specific path.
-/// Specify whether to measure retained or allocated bytes, and the path to write flamegraph file to
-/// (should probably end in .svg)
-pub fn gen_flamegraph(
-    profiler: &YingProfiler,
+/// Specify what to weigh stacks by, eg retained bytes to find leaks or number of allocations to find churn, and
+/// the path to write flamegraph file to (should probably end in .svg)
+pub fn gen_flamegraph<A: GlobalAlloc>(
+    profiler: &YingProfiler<A>,
     measurement: Measurement,
-    path: &PathBuf,
+    path: &Path,
 ) -> Result<(), String> {
-    // Generate dtrace-compatible output
+    let top_stacks = profiler.top_k_by(50, |stats| measurement.of(stats));
+    let report = dtrace_stacks_report(profiler, &top_stacks, measurement)?;
+    write_flamegraph(report, path)
+}
+
+/// Generate dtrace-compatible output for a list of stacks
+fn dtrace_stacks_report<A: GlobalAlloc>(
+    profiler: &YingProfiler<A>,
+    stacks: &[StackStats],
+    measurement: Measurement,
+) -> Result<String, String> {
     let mut report = String::new();
-    match measurement {
-        Measurement::RetainedBytes => {
-            let top_stacks = profiler.top_k_stacks_by_retained(50);
-            for s in &top_stacks {
-                writeln!(
-                    &mut report,
-                    "{}",
-                    s.dtrace_report(profiler, Measurement::RetainedBytes)
-                )
-                .map_err(|e| e.to_string())?;
-            }
-        }
-        Measurement::AllocatedBytes => {
-            let top_stacks = profiler.top_k_stacks_by_allocated(50);
-            for s in &top_stacks {
-                writeln!(
-                    &mut report,
-                    "{}",
-                    s.dtrace_report(profiler, Measurement::AllocatedBytes)
-                )
-                .map_err(|e| e.to_string())?;
-            }
-        }
+    for s in stacks {
+        writeln!(&mut report, "{}", s.dtrace_report(profiler, measurement))
+            .map_err(|e| e.to_string())?;
     }
+    Ok(report)
+}
+
+/// Folds dtrace-compatible stacks output and writes it out as a flamegraph
+fn write_flamegraph(dtrace_report: String, path: &Path) -> Result<(), String> {
+    if let Ok(f) = File::create(path) {
+        write_flamegraph_to(dtrace_report, f)?;
+    }
+    Ok(())
+}
 
+/// Folds dtrace-compatible stacks output and writes it out as a flamegraph to `w`
+fn write_flamegraph_to(dtrace_report: String, w: impl std::io::Write) -> Result<(), String> {
     // Fold/collapse output to folded lines
     let mut folder = dtrace::Folder::default();
     let mut folded_buf = Vec::new();
     let folded_out = Cursor::new(&mut folded_buf);
     folder
-        .collapse(Cursor::new(report), folded_out)
+        .collapse(Cursor::new(dtrace_report), folded_out)
         .map_err(|e| e.to_string())?;
 
     // Now, generate the flamegraph from folded lines
-    if let Ok(f) = File::create(path) {
-        flamegraph::from_reader(
-            &mut flamegraph::Options::default(),
-            Cursor::new(&folded_buf),
-            f,
-        )
-        .map_err(|e| e.to_string())?;
+    flamegraph::from_reader(
+        &mut flamegraph::Options::default(),
+        Cursor::new(&folded_buf),
+        w,
+    )
+    .map_err(|e| e.to_string())
+}
+
+/// Output formats which [YingProfiler::dump_all] can write
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DumpFormat {
+    /// Human readable report of the top stacks, same as `ProfilerRunner` writes out (`.report`)
+    Text,
+    /// DTrace-style stacks, each followed by its retained bytes, usable by flamegraph tooling (`.stacks`)
+    DTrace,
+    /// Flamegraph SVG (`.svg`)
+    Flamegraph,
+    /// pprof heap profile of all stacks, same as `YingProfiler::write_pprof` writes out (`.pprof`)
+    Pprof,
+}
+
+impl DumpFormat {
+    fn extension(&self) -> &'static str {
+        match self {
+            DumpFormat::Text => "report",
+            DumpFormat::DTrace => "stacks",
+            DumpFormat::Flamegraph => "svg",
+            DumpFormat::Pprof => "pprof",
+        }
     }
-    Ok(())
+}
+
+/// Number of top stacks written out by [YingProfiler::dump_all]
+pub(crate) const DUMP_TOP_K: usize = 50;
+
+// A file written by dump_all, gzipped as it is written when asked for so that no report is buffered whole
+// just to compress it
+enum DumpFile {
+    Plain(std::io::BufWriter<File>),
+    #[cfg(feature = "gzip")]
+    Gzip(flate2::write::GzEncoder<std::io::BufWriter<File>>),
+}
+
+impl DumpFile {
+    fn create(path: &Path, gzip: bool) -> std::io::Result<Self> {
+        let w = std::io::BufWriter::new(File::create(path)?);
+        if gzip {
+            #[cfg(feature = "gzip")]
+            return Ok(DumpFile::Gzip(flate2::write::GzEncoder::new(
+                w,
+                flate2::Compression::default(),
+            )));
+        }
+        Ok(DumpFile::Plain(w))
+    }
+
+    // Writes out the gzip trailer, if any, and flushes.  Dropping a DumpFile would do both too but lose any error.
+    fn finish(self) -> std::io::Result<()> {
+        match self {
+            DumpFile::Plain(mut w) => w.flush(),
+            #[cfg(feature = "gzip")]
+            DumpFile::Gzip(w) => w.finish()?.flush(),
+        }
+    }
+}
+
+impl std::io::Write for DumpFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            DumpFile::Plain(w) => w.write(buf),
+            #[cfg(feature = "gzip")]
+            DumpFile::Gzip(w) => w.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            DumpFile::Plain(w) => w.flush(),
+            #[cfg(feature = "gzip")]
+            DumpFile::Gzip(w) => w.flush(),
+        }
+    }
+}
+
+impl<A: GlobalAlloc> YingProfiler<A> {
+    /// Writes out the top stacks by retained memory in several formats at once, to files in directory `dir`.
+    /// All the formats are written from one snapshot of the stacks taken under a single lock of the profiler,
+    /// so that they are consistent with each other.  Returns the paths of the files written.
+    pub fn dump_all(&self, dir: &Path, formats: &[DumpFormat]) -> Result<Vec<PathBuf>, String> {
+        self.dump_files(dir, formats, false)
+    }
+
+    /// Same as `dump_all`, but gzips each file as it is written, adding `.gz` to its name, eg `.pprof.gz` or
+    /// `.report.gz`.  Compression streams straight to the file, so no whole report is held in memory for it.
+    #[cfg(feature = "gzip")]
+    pub fn dump_all_gzip(
+        &self,
+        dir: &Path,
+        formats: &[DumpFormat],
+    ) -> Result<Vec<PathBuf>, String> {
+        self.dump_files(dir, formats, true)
+    }
+
+    fn dump_files(
+        &self,
+        dir: &Path,
+        formats: &[DumpFormat],
+        gzip: bool,
+    ) -> Result<Vec<PathBuf>, String> {
+        self.lock_out_profiler(|| {
+            let top_stacks = self.top_k_stacks_by_retained(DUMP_TOP_K);
+            let retained_mb = YingProfiler::total_retained_bytes() / (1024 * 1024);
+            let base_name = dump_base_name(retained_mb as i64);
+
+            let mut paths = Vec::new();
+            for format in formats {
+                let gz = if gzip { ".gz" } else { "" };
+                let path = dir.join(format!("{}.{}{}", base_name, format.extension(), gz));
+                let mut f = DumpFile::create(&path, gzip).map_err(|e| e.to_string())?;
+                match format {
+                    DumpFormat::Text => {
+                        for s in &top_stacks {
+                            writeln!(f, "---\n{}\n", s.rich_report(self, false, false))
+                                .map_err(|e| e.to_string())?;
+                        }
+                    }
+                    DumpFormat::DTrace => {
+                        for s in &top_stacks {
+                            writeln!(f, "{}", s.dtrace_report(self, Measurement::RetainedBytes))
+                                .map_err(|e| e.to_string())?;
+                        }
+                    }
+                    DumpFormat::Flamegraph => {
+                        let report =
+                            dtrace_stacks_report(self, &top_stacks, Measurement::RetainedBytes)?;
+                        write_flamegraph_to(report, &mut f)?;
+                    }
+                    DumpFormat::Pprof => {
+                        self.write_pprof(&mut f).map_err(|e| e.to_string())?;
+                    }
+                }
+                f.finish().map_err(|e| e.to_string())?;
+                paths.push(path);
+            }
+            Ok(paths)
+        })
+    }
+
+    /// Writes one JSON object per stack per line (JSON Lines), streamed straight from the stack map without
+    /// collecting or sorting the stacks first, so that exporting very large profiles needs little memory.
+    /// Stacks are in no particular order.  Each line looks like:
+    /// ```text
+    /// {"stack_hash":"0x1f2e...","allocated_bytes":4096,"num_allocations":2,"freed_bytes":1024,"num_frees":1,"retained_bytes":3072,"frames":["my_app::load","my_app::main"]}
+    /// ```
+    /// Byte counts are of sampled allocations.  The stack hash is a hex string as it may not fit in a JSON
+    /// number.  The stacks are preceded by one line with the profiler's `config()`, which has the sampling ratio
+    /// to scale byte counts by:
+    /// ```text
+    /// {"config":{"version":"0.2.0","sampling_ratio":500,...}}
+    /// ```
+    /// Returns the number of stacks written.  Wrap `w` in a `BufWriter` when writing to a file.
+    pub fn write_stacks_jsonl<W: std::io::Write>(&self, mut w: W) -> std::io::Result<usize> {
+        self.lock_out_profiler(|| {
+            let config: Vec<String> = self
+                .config()
+                .json_fields()
+                .into_iter()
+                .map(|(key, value)| format!("\"{}\":{}", key, value))
+                .collect();
+            writeln!(w, "{{\"config\":{{{}}}}}", config.join(","))?;
+            let state = self.get_state();
+            let mut num_stacks = 0;
+            for entry in &state.stack_stats {
+                let stats = entry.value();
+                write!(
+                    w,
+                    "{{\"stack_hash\":\"{:#x}\",\"allocated_bytes\":{},\"num_allocations\":{},\"freed_bytes\":{},\
+                     \"num_frees\":{},\"retained_bytes\":{},\"frames\":",
+                    stats.stack_hash(),
+                    stats.allocated_bytes(),
+                    stats.num_allocations(),
+                    stats.freed_bytes(),
+                    stats.num_frees(),
+                    stats.retained_profiled_bytes()
+                )?;
+                self.resolved_stack(state, stats)
+                    .write_json_frames(&state.symbol_map, &mut w)?;
+                w.write_all(b"}\n")?;
+                num_stacks += 1;
+            }
+            w.flush()?;
+            Ok(num_stacks)
+        })
+    }
+
+    /// Exports the symbol map, ie the resolved symbols of every instruction pointer seen, so that stacks from
+    /// this process can be symbolized elsewhere without the binary, eg by `import_symbol_map` in an offline tool.
+    /// The format is text, one symbol per line, with inlined symbols of one instruction pointer on consecutive
+    /// lines, innermost first:
+    /// ```text
+    /// 0x55d3c2a1b2c3\t42\tsrc/main.rs\tmy_app::main
+    /// ```
+    /// Returns the number of instruction pointers exported.
+    pub fn export_symbol_map<W: std::io::Write>(&self, mut w: W) -> std::io::Result<usize> {
+        self.lock_out_profiler(|| {
+            let symbol_map = &self.get_state().symbol_map;
+            for entry in symbol_map {
+                for symbol in entry.value().iter() {
+                    symbol.write_export_line(*entry.key(), &mut w)?;
+                }
+            }
+            w.flush()?;
+            Ok(symbol_map.len())
+        })
+    }
+
+    /// Imports symbols written by `export_symbol_map`, for instruction pointers not already in the symbol map.
+    /// Symbols resolved by this process are left alone.  Returns the number of instruction pointers added.
+    pub fn import_symbol_map<R: std::io::BufRead>(&self, r: R) -> std::io::Result<usize> {
+        self.lock_out_profiler(|| {
+            let mut imported: HashMap<u64, Vec<FriendlySymbol>> = HashMap::new();
+            for line in r.lines() {
+                let line = line?;
+                if line.is_empty() {
+                    continue;
+                }
+                let (ip, symbol) = FriendlySymbol::parse_export_line(&line).ok_or_else(|| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!("Invalid symbol map line: {}", line),
+                    )
+                })?;
+                imported.entry(ip).or_default().push(symbol);
+            }
+
+            let symbol_map = &self.get_state().symbol_map;
+            let mut num_added = 0;
+            for (ip, symbols) in imported {
+                if !symbol_map.contains_key(&ip) {
+                    symbol_map.insert(ip, symbols.into());
+                    num_added += 1;
+                }
+            }
+            Ok(num_added)
+        })
+    }
+}
+
+const CGROUP_V2_MEMORY_MAX: &str = "/sys/fs/cgroup/memory.max";
+const CGROUP_V1_MEMORY_LIMIT: &str = "/sys/fs/cgroup/memory/memory.limit_in_bytes";
+
+/// The memory limit of this process in bytes, if any: the cgroup v2 or v1 memory limit of its container, or
+/// otherwise its `RLIMIT_AS` address space limit.  Eg for setting the threshold of
+/// `YingProfiler::on_memory_pressure` relative to the container limit:
+/// ```no_run
+///     use ying_profiler::{utils::detect_memory_limit, YingProfiler};
+///     static YING_ALLOC: YingProfiler = YingProfiler::default();
+///     if let Some(limit) = detect_memory_limit() {
+///         YING_ALLOC.on_memory_pressure(limit, 0.9, |_| println!("{}", YING_ALLOC.summary()));
+///     }
+/// ```
+pub fn detect_memory_limit() -> Option<usize> {
+    [CGROUP_V2_MEMORY_MAX, CGROUP_V1_MEMORY_LIMIT]
+        .iter()
+        .find_map(|path| parse_memory_limit(&std::fs::read_to_string(path).ok()?))
+        .or_else(address_space_limit)
+}
+
+// Parses a cgroup memory limit file.  cgroup v2 writes "max" for no limit, while v1 writes a huge number instead,
+// so anything from 2^62 bytes up counts as no limit.
+fn parse_memory_limit(contents: &str) -> Option<usize> {
+    let limit: u64 = contents.trim().parse().ok()?;
+    (limit < 1 << 62).then_some(limit as usize)
+}
+
+#[cfg(unix)]
+fn address_space_limit() -> Option<usize> {
+    let mut rlim = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    if unsafe { libc::getrlimit(libc::RLIMIT_AS, &mut rlim) } != 0
+        || rlim.rlim_cur == libc::RLIM_INFINITY
+    {
+        return None;
+    }
+    Some(rlim.rlim_cur as usize)
+}
+
+#[cfg(not(unix))]
+fn address_space_limit() -> Option<usize> {
+    None
+}
+
+/// Formats `bytes` for reports, as KiB/MiB/GiB/TiB with two decimals, or as an exact number of bytes if `raw`.
+/// Returns a Display wrapper rather than a String, so it can be written into a report without allocating.
+pub fn format_bytes(bytes: u64, raw: bool) -> FormattedBytes {
+    FormattedBytes { bytes, raw }
+}
+
+/// Human readable byte count from [format_bytes]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct FormattedBytes {
+    bytes: u64,
+    raw: bool,
+}
+
+impl fmt::Display for FormattedBytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+        if self.raw || self.bytes < 1024 {
+            return write!(f, "{} bytes", self.bytes);
+        }
+        let mut value = self.bytes as f64 / 1024.0;
+        let mut unit = 0;
+        while value >= 1024.0 && unit < UNITS.len() - 1 {
+            value /= 1024.0;
+            unit += 1;
+        }
+        write!(f, "{:.2} {}", value, UNITS[unit])
+    }
+}
+
+/// Writes `s` as a quoted JSON string, escaping quotes, backslashes and control characters
+/// Reads stacks written by `YingProfiler::write_stacks_jsonl`, eg to compare with a capture from another process or
+/// build.  The config line and blank lines are skipped.  Stats which aren't in the file, such as
+/// `max_alloc_size`, are 0.
+pub fn read_stacks_jsonl<R: std::io::BufRead>(r: R) -> std::io::Result<Vec<OwnedStackStats>> {
+    let mut stacks = Vec::new();
+    for line in r.lines() {
+        let line = line?;
+        if !line.starts_with("{\"stack_hash\"") {
+            continue;
+        }
+        let stack = parse_stack_line(&line).ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Invalid stack line: {}", line),
+            )
+        })?;
+        stacks.push(stack);
+    }
+    Ok(stacks)
+}
+
+fn parse_stack_line(line: &str) -> Option<OwnedStackStats> {
+    let hash_start = line.find("\"stack_hash\":\"0x")? + "\"stack_hash\":\"0x".len();
+    let hash_hex = line[hash_start..].split('"').next()?;
+    Some(OwnedStackStats {
+        stack_hash: u64::from_str_radix(hash_hex, 16).ok()?,
+        frames: json_frames_field(line)?,
+        allocated_bytes: json_number_field(line, "allocated_bytes")?,
+        num_allocations: json_number_field(line, "num_allocations")?,
+        freed_bytes: json_number_field(line, "freed_bytes")?,
+        num_frees: json_number_field(line, "num_frees")?,
+        retained_bytes: json_number_field(line, "retained_bytes")?,
+        max_alloc_size: 0,
+        first_seen_millis: 0,
+        last_alloc_millis: 0,
+    })
+}
+
+fn json_number_field(line: &str, key: &str) -> Option<u64> {
+    let start = line.find(&format!("\"{}\":", key))? + key.len() + 3;
+    let digits = line[start..].split(|c: char| !c.is_ascii_digit()).next()?;
+    digits.parse().ok()
+}
+
+// The JSON strings of the "frames" array, unescaped
+fn json_frames_field(line: &str) -> Option<Vec<String>> {
+    let start = line.find("\"frames\":[")? + "\"frames\":[".len();
+    let mut chars = line[start..].chars();
+    let mut frames = Vec::new();
+    loop {
+        match chars.next()? {
+            ']' => return Some(frames),
+            ',' => {}
+            '"' => {
+                let mut frame = String::new();
+                loop {
+                    match chars.next()? {
+                        '"' => break,
+                        '\\' => match chars.next()? {
+                            'n' => frame.push('\n'),
+                            'r' => frame.push('\r'),
+                            't' => frame.push('\t'),
+                            'u' => {
+                                let hex: String = chars.by_ref().take(4).collect();
+                                frame.push(char::from_u32(u32::from_str_radix(&hex, 16).ok()?)?);
+                            }
+                            c => frame.push(c),
+                        },
+                        c => frame.push(c),
+                    }
+                }
+                frames.push(frame);
+            }
+            _ => return None,
+        }
+    }
+}
+
+pub(crate) fn write_json_string(w: &mut impl std::io::Write, s: &str) -> std::io::Result<()> {
+    w.write_all(b"\"")?;
+    let mut start = 0;
+    for (i, c) in s.char_indices() {
+        let escaped = match c {
+            '"' => "\\\"",
+            '\\' => "\\\\",
+            '\n' => "\\n",
+            '\r' => "\\r",
+            '\t' => "\\t",
+            c if (c as u32) < 0x20 => "",
+            _ => continue,
+        };
+        w.write_all(&s.as_bytes()[start..i])?;
+        if escaped.is_empty() {
+            write!(w, "\\u{:04x}", c as u32)?;
+        } else {
+            w.write_all(escaped.as_bytes())?;
+        }
+        start = i + c.len_utf8();
+    }
+    w.write_all(&s.as_bytes()[start..])?;
+    w.write_all(b"\"")
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_parse_memory_limit() {
+        assert_eq!(parse_memory_limit("536870912\n"), Some(512 * 1024 * 1024));
+        assert_eq!(parse_memory_limit("max\n"), None);
+        assert_eq!(parse_memory_limit("9223372036854771712\n"), None);
+        assert_eq!(parse_memory_limit(""), None);
+    }
+
+    // Without a cgroup limit, the address space limit stands in for the memory limit
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_address_space_limit() {
+        let mut original = libc::rlimit {
+            rlim_cur: 0,
+            rlim_max: 0,
+        };
+        assert_eq!(
+            unsafe { libc::getrlimit(libc::RLIMIT_AS, &mut original) },
+            0
+        );
+        // 64 TiB is more than this test process will ever map
+        let limited = libc::rlimit {
+            rlim_cur: original.rlim_cur.min(original.rlim_max).min(1 << 46),
+            rlim_max: original.rlim_max,
+        };
+        assert_eq!(unsafe { libc::setrlimit(libc::RLIMIT_AS, &limited) }, 0);
+        let limit = address_space_limit();
+        let detected = detect_memory_limit();
+        assert_eq!(unsafe { libc::setrlimit(libc::RLIMIT_AS, &original) }, 0);
+
+        assert_eq!(limit, Some(limited.rlim_cur as usize));
+        assert!(detected.is_some());
+    }
+
+    #[test]
+    fn test_format_bytes() {
+        assert_eq!(format_bytes(0, false).to_string(), "0 bytes");
+        assert_eq!(format_bytes(1023, false).to_string(), "1023 bytes");
+        assert_eq!(format_bytes(1536, false).to_string(), "1.50 KiB");
+        assert_eq!(format_bytes(3 << 30, false).to_string(), "3.00 GiB");
+        assert_eq!(format_bytes(5 << 50, false).to_string(), "5120.00 TiB");
+        assert_eq!(format_bytes(3 << 30, true).to_string(), "3221225472 bytes");
+    }
+
     #[test]
     fn test_profiler_runner_builder() {
         let runner = ProfilerRunnerBuilder::default()
@@ -249,4 +715,41 @@ mod tests {
         assert_eq!(runner.report_pct_change_trigger, 10);
         assert_eq!(runner.reporting_path, "");
     }
+
+    #[test]
+    fn test_write_json_string_escapes() {
+        let mut out = Vec::new();
+        write_json_string(&mut out, "<T as \"q\">\\\n\u{1}ü").unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            r#""<T as \"q\">\\\n\u0001ü""#
+        );
+    }
+
+    #[test]
+    fn test_read_stacks_jsonl() {
+        let mut frame = Vec::new();
+        write_json_string(&mut frame, "<T as \"q\">\\\n\u{1}").unwrap();
+        let input = format!(
+            "{{\"config\":{{\"sampling_ratio\":500}}}}\n\n\
+             {{\"stack_hash\":\"0x1f2e\",\"allocated_bytes\":4096,\"num_allocations\":2,\"freed_bytes\":1024,\
+             \"num_frees\":1,\"retained_bytes\":3072,\"frames\":[{},\"my_app::main\"]}}\n",
+            String::from_utf8(frame).unwrap()
+        );
+        let stacks = read_stacks_jsonl(Cursor::new(input)).unwrap();
+        assert_eq!(stacks.len(), 1);
+        assert_eq!(stacks[0].stack_hash, 0x1f2e);
+        assert_eq!(stacks[0].allocated_bytes, 4096);
+        assert_eq!(stacks[0].num_allocations, 2);
+        assert_eq!(stacks[0].freed_bytes, 1024);
+        assert_eq!(stacks[0].num_frees, 1);
+        assert_eq!(stacks[0].retained_bytes, 3072);
+        assert_eq!(
+            stacks[0].frames,
+            vec!["<T as \"q\">\\\n\u{1}", "my_app::main"]
+        );
+
+        let err = read_stacks_jsonl(Cursor::new("{\"stack_hash\":\"0x1\"}\n")).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
 }
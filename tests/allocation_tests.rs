@@ -1,3 +1,9 @@
+// These tests need profiling compiled in
+#![cfg(all(
+    not(feature = "disabled"),
+    any(target_os = "linux", target_os = "macos")
+))]
+
 use std::alloc::GlobalAlloc;
 use std::fmt::Write;
 use std::time::Duration;
@@ -7,7 +13,8 @@ use moka::sync::Cache;
 use rand::distributions::Alphanumeric;
 use rand::{rngs::SmallRng, Rng, SeedableRng};
 use serial_test::serial;
-use ying_profiler::callstack::Measurement;
+use ying_profiler::callstack::{Measurement, StackStats};
+use ying_profiler::utils::DumpFormat;
 // use ying_profiler::utils::gen_flamegraph;
 use ying_profiler::YingProfiler;
 
@@ -44,9 +51,9 @@ fn basic_allocation_free_test() {
 
     // The top stat should be for our allocations
     let stat = &top_stacks[0];
-    assert_eq!(stat.freed_bytes, 0);
-    let allocated = stat.allocated_bytes;
-    assert_eq!(allocated / stat.num_allocations, 512);
+    assert_eq!(stat.freed_bytes(), 0);
+    let allocated = stat.allocated_bytes();
+    assert_eq!(allocated / stat.num_allocations(), 512);
 
     // Now drop some of those items, maybe say half.  The freed stats should update.
     items.truncate(NUM_ALLOCS / 2);
@@ -67,7 +74,7 @@ fn basic_allocation_free_test() {
     );
 
     // Number of freed bytes should be roughly half
-    assert!(stat.freed_bytes > 0);
+    assert!(stat.freed_bytes() > 0);
     assert!(stat.retained_profiled_bytes() > 0);
 }
 
@@ -83,6 +90,14 @@ fn test_giant_allocation() {
     // We should get back a null pointer so allocation should fail.
     let ptr = unsafe { YingProfiler::alloc(&YING_ALLOC, layout) };
     assert_eq!(ptr as u64, 0);
+
+    // Repeated attempts from the same stack are all denied and counted, though only the first is warned about
+    let denied_before = YingProfiler::denied_giant_allocs();
+    for _ in 0..5 {
+        let ptr = unsafe { YingProfiler::alloc(&YING_ALLOC, layout) };
+        assert_eq!(ptr as u64, 0);
+    }
+    assert_eq!(YingProfiler::denied_giant_allocs(), denied_before + 5);
 }
 
 // Reproduces deadlock produced when we print out stack traces and also insert new symbols at the same time
@@ -181,10 +196,283 @@ async fn stress_test() {
     // probably times constant factor of at least 2, plus frees
     // This tests that the sampling is working correctly.  If somehow we stop sampling these numbers
     // would not be so high.
-    let total_allocs: u64 = top_stacks.iter().map(|s| s.num_allocations).sum();
-    let total_frees: u64 = top_stacks.iter().map(|s| s.num_frees).sum();
+    let total_allocs: u64 = top_stacks.iter().map(|s| s.num_allocations()).sum();
+    let total_frees: u64 = top_stacks.iter().map(|s| s.num_frees()).sum();
 
     let total_expected_allocs = num_outer_loops * num_inner_loops / 5;
     assert!(total_allocs >= total_expected_allocs as u64);
     assert!(total_frees >= (total_expected_allocs * 9 / 10) as u64); // > 90% of allocs freed
 }
+
+#[test]
+#[serial]
+fn top_k_by_custom_key_test() {
+    YING_ALLOC.reset_state_for_testing_only();
+
+    let _items: Vec<_> = (0..NUM_ALLOCS).map(|_n| Box::new([0u64; 64])).collect();
+
+    let top_stacks = YING_ALLOC.top_k_by(5, |stats| stats.num_allocations());
+    assert!(!top_stacks.is_empty());
+    assert!(top_stacks.len() <= 5);
+    // Should be sorted by number of allocations, descending
+    for pair in top_stacks.windows(2) {
+        assert!(pair[0].num_allocations() >= pair[1].num_allocations());
+    }
+}
+
+// Many small allocations rather than one big one, so that enough of them are sampled
+#[allow(clippy::vec_box)]
+#[inline(never)]
+fn allocate_for_matching_test() -> Vec<Box<[u64; 64]>> {
+    (0..NUM_ALLOCS).map(|_n| Box::new([0u64; 64])).collect()
+}
+
+#[test]
+#[serial]
+fn stacks_matching_test() {
+    YING_ALLOC.reset_state_for_testing_only();
+
+    let _items = allocate_for_matching_test();
+
+    let matching = YING_ALLOC.stacks_matching("allocate_for_matching_test");
+    assert!(!matching.is_empty());
+    for pair in matching.windows(2) {
+        assert!(pair[0].retained_profiled_bytes() >= pair[1].retained_profiled_bytes());
+    }
+
+    assert!(YING_ALLOC
+        .stacks_matching("no_such_function_anywhere_in_this_binary")
+        .is_empty());
+}
+
+#[test]
+#[serial]
+fn dump_all_formats_test() {
+    YING_ALLOC.reset_state_for_testing_only();
+    let _items: Vec<_> = (0..NUM_ALLOCS).map(|_n| Box::new([0u64; 64])).collect();
+
+    let dir = std::env::temp_dir().join("ying_dump_all_test");
+    std::fs::create_dir_all(&dir).unwrap();
+    let paths = YING_ALLOC
+        .dump_all(
+            &dir,
+            &[
+                DumpFormat::Text,
+                DumpFormat::DTrace,
+                DumpFormat::Flamegraph,
+                DumpFormat::Pprof,
+            ],
+        )
+        .unwrap();
+
+    assert_eq!(paths.len(), 4);
+    for path in &paths {
+        let metadata = std::fs::metadata(path).unwrap();
+        assert!(metadata.len() > 0);
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(feature = "gzip")]
+#[test]
+#[serial]
+fn dump_all_gzip_test() {
+    YING_ALLOC.reset_state_for_testing_only();
+    let _items: Vec<_> = (0..NUM_ALLOCS).map(|_n| Box::new([0u64; 64])).collect();
+
+    let dir = std::env::temp_dir().join("ying_dump_all_gzip_test");
+    std::fs::create_dir_all(&dir).unwrap();
+    let paths = YING_ALLOC
+        .dump_all_gzip(&dir, &[DumpFormat::Text, DumpFormat::Pprof])
+        .unwrap();
+
+    assert_eq!(paths.len(), 2);
+    assert!(paths[0].to_string_lossy().ends_with(".report.gz"));
+    assert!(paths[1].to_string_lossy().ends_with(".pprof.gz"));
+    for path in &paths {
+        // Gzip magic number
+        let contents = std::fs::read(path).unwrap();
+        assert_eq!(contents[..2], [0x1f, 0x8b]);
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+#[serial]
+fn sampling_error_estimate_test() {
+    YING_ALLOC.reset_state_for_testing_only();
+
+    let items: Vec<_> = (0..NUM_ALLOCS).map(|_n| Box::new([0u64; 64])).collect();
+    assert_eq!(items.len(), NUM_ALLOCS);
+
+    let top_stacks = YING_ALLOC.top_k_stacks_by_allocated(1);
+    let stat = &top_stacks[0];
+    let ratio = YING_ALLOC.sampling_ratio();
+    let estimate = stat.allocated_bytes_estimate(ratio);
+    assert_eq!(estimate.value(), stat.allocated_bytes() * ratio as u64);
+    // With a few thousand samples, the margin is well under the estimate itself
+    assert!(estimate.margin() > 0);
+    assert!(estimate.margin() < estimate.value() / 4);
+    assert!(estimate.low() < estimate.value() && estimate.value() < estimate.high());
+    assert!(!estimate.differs_from(&estimate));
+
+    // Sampling every allocation leaves no uncertainty
+    assert_eq!(stat.allocated_bytes_estimate(1).margin(), 0);
+
+    let report = stat.rich_report(&YING_ALLOC, false, false);
+    assert!(report.contains("(95% confidence)"));
+}
+
+#[allow(clippy::vec_box)]
+#[inline(never)]
+fn allocate_for_reset_stack_test() -> Vec<Box<[u64; 64]>> {
+    (0..NUM_ALLOCS).map(|_n| Box::new([0u64; 64])).collect()
+}
+
+// Allocates on a new thread, whose stack doesn't include the caller's frames, so that allocations made before and
+// after a reset share a stack.  Returns the items and the stats of their stack.
+#[allow(clippy::vec_box)]
+fn allocate_and_find() -> (Vec<Box<[u64; 64]>>, StackStats) {
+    let items = std::thread::spawn(allocate_for_reset_stack_test)
+        .join()
+        .unwrap();
+    // The Vec holding the items may also be sampled, so pick the stack of the boxed items by its count
+    let stats = YING_ALLOC
+        .stacks_matching("allocate_for_reset_stack_test")
+        .into_iter()
+        .max_by_key(|s| s.num_allocations())
+        .unwrap();
+    (items, stats)
+}
+
+#[test]
+#[serial]
+fn reset_stack_test() {
+    YING_ALLOC.reset_state_for_testing_only();
+
+    let _other_items: Vec<_> = (0..NUM_ALLOCS).map(|_n| Box::new([0u64; 32])).collect();
+    let find_stack = |hash: u64| {
+        YING_ALLOC
+            .stacks_matching("allocate_for_reset_stack_test")
+            .into_iter()
+            .find(|s| s.stack_hash() == hash)
+            .unwrap()
+    };
+
+    let (items, first) = allocate_and_find();
+
+    // Before the reset
+    {
+        assert!(first.num_allocations() > 0);
+        let num_stacks = YING_ALLOC.top_k_stacks_by_allocated(1000).len();
+
+        assert!(YING_ALLOC.reset_stack(first.stack_hash()));
+        assert!(!YING_ALLOC.reset_stack(0xdead_beef));
+        let reset_stats = find_stack(first.stack_hash());
+        assert_eq!(reset_stats.allocated_bytes(), 0);
+        assert_eq!(reset_stats.num_allocations(), 0);
+        // Other stacks are untouched
+        assert_eq!(YING_ALLOC.top_k_stacks_by_allocated(1000).len(), num_stacks);
+        assert!(YING_ALLOC.top_k_stacks_by_allocated(1)[0].allocated_bytes() > 0);
+
+        // Allocations from before the reset are still tracked, but their frees are kept apart from
+        // post-reset stats
+        drop(items);
+        let reset_stats = find_stack(first.stack_hash());
+        assert_eq!(reset_stats.generation(), first.generation() + 1);
+        assert_eq!(reset_stats.num_frees(), 0);
+        assert_eq!(reset_stats.freed_bytes(), 0);
+        assert_eq!(reset_stats.pre_reset_num_frees(), first.num_allocations());
+        assert_eq!(reset_stats.pre_reset_freed_bytes(), first.allocated_bytes());
+        assert_eq!(reset_stats.retained_profiled_bytes(), 0);
+    }
+
+    // After the reset, the same stack counts new allocations from zero
+    {
+        let (_items, new_stats) = allocate_and_find();
+        assert_eq!(new_stats.stack_hash(), first.stack_hash());
+        assert_eq!(new_stats.generation(), first.generation() + 1);
+        assert!(new_stats.num_allocations() > 0);
+        assert_eq!(
+            new_stats.allocated_bytes(),
+            new_stats.num_allocations() * 512
+        );
+        assert_eq!(
+            new_stats.retained_profiled_bytes(),
+            new_stats.allocated_bytes()
+        );
+    }
+}
+
+#[test]
+#[serial]
+fn summary_test() {
+    YING_ALLOC.reset_state_for_testing_only();
+
+    let items: Vec<_> = (0..NUM_ALLOCS).map(|_n| Box::new([0u64; 64])).collect();
+    assert_eq!(items.len(), NUM_ALLOCS);
+
+    let summary = YING_ALLOC.summary();
+    assert!(summary.total_retained_bytes >= NUM_ALLOCS * 512);
+    assert!(summary.peak_retained_bytes >= summary.total_retained_bytes);
+    assert!(summary.outstanding_allocs > 0);
+    assert!(summary.num_stacks > 0);
+    assert!(summary.top_10_retained_fraction > 0.0 && summary.top_10_retained_fraction <= 1.0);
+
+    let line = summary.to_string();
+    assert!(line.starts_with("Ying: "));
+    assert!(!line.contains('\n'));
+
+    // Peak stays put after memory is freed
+    drop(items);
+    assert!(YingProfiler::peak_retained_bytes() >= summary.total_retained_bytes);
+    assert!(YingProfiler::total_retained_bytes() < summary.total_retained_bytes);
+}
+
+#[test]
+#[serial]
+fn growth_trend_test() {
+    use ying_profiler::histogram::Trend;
+
+    YING_ALLOC.reset_state_for_testing_only();
+
+    // Keep allocating from one stack for over a second, retaining everything
+    let mut items = Vec::with_capacity(NUM_ALLOCS * 3);
+    for _round in 0..3 {
+        items.extend((0..NUM_ALLOCS).map(|_n| Box::new([0u64; 64])));
+        std::thread::sleep(Duration::from_millis(600));
+    }
+    let stack = YING_ALLOC.top_k_stacks_by_retained(1)[0].clone();
+    assert_eq!(stack.growth_trend(), Trend::Growing);
+    assert!(stack
+        .rich_report(&YING_ALLOC, false, false)
+        .contains("retained is growing"));
+
+    drop(items);
+    let stack = YING_ALLOC
+        .stacks_matching("growth_trend_test")
+        .into_iter()
+        .find(|s| s.stack_hash() == stack.stack_hash())
+        .unwrap();
+    assert_eq!(stack.growth_trend(), Trend::Shrinking);
+}
+
+#[test]
+#[serial]
+fn retained_by_size_class_test() {
+    YING_ALLOC.reset_state_for_testing_only();
+
+    let small: Vec<_> = (0..NUM_ALLOCS).map(|_n| Box::new([0u64; 64])).collect();
+    // 1 in 5 allocations per thread are sampled, so at least one of these is
+    let big: Vec<_> = (0..5).map(|_n| vec![1u8; 3 << 20]).collect();
+    assert_eq!(small.len() + big.len(), NUM_ALLOCS + 5);
+
+    let classes = YING_ALLOC.retained_by_size_class();
+    assert!(classes.windows(2).all(|w| w[0].max_size < w[1].max_size));
+    // 512 byte boxes go in the 257-1024 byte class
+    let small_class = classes.iter().find(|c| c.max_size == 1024).unwrap();
+    assert!(small_class.num_allocs >= (NUM_ALLOCS / 10) as u64);
+    assert!(small_class.sampled_bytes >= small_class.num_allocs * 512);
+    let big_class = classes.iter().find(|c| c.max_size == 4 << 20).unwrap();
+    assert!(big_class.sampled_bytes >= 3 << 20);
+}
@@ -0,0 +1,43 @@
+// Tests for the stack allowlist, which needs its own global allocator configuration
+#![cfg(all(
+    not(feature = "disabled"),
+    any(target_os = "linux", target_os = "macos")
+))]
+
+use ying_profiler::YingProfiler;
+
+#[global_allocator]
+static YING_ALLOC: YingProfiler = YingProfiler::new(1, 64 * 1024 * 1024 * 1024)
+    .with_stack_allowlist(&["allowlist_tests::allocate_allowed"]);
+
+#[inline(never)]
+fn allocate_allowed() -> Vec<[u64; 32]> {
+    (0..500).map(|_n| [0u64; 32]).collect()
+}
+
+#[inline(never)]
+fn allocate_other() -> Vec<[u64; 32]> {
+    (0..500).map(|_n| [1u64; 32]).collect()
+}
+
+#[test]
+fn stack_allowlist_drops_other_stacks_test() {
+    let allowed = allocate_allowed();
+    let other = allocate_other();
+    assert_eq!(allowed.len() + other.len(), 1000);
+
+    assert!(!YING_ALLOC.stacks_matching("allocate_allowed").is_empty());
+    assert!(YING_ALLOC.stacks_matching("allocate_other").is_empty());
+
+    // Every recorded stack must go through the allowlisted function
+    let num_stacks = YING_ALLOC.top_k_stacks_by_allocated(1000).len();
+    assert!(num_stacks > 0);
+    assert_eq!(
+        YING_ALLOC.stacks_matching("allocate_allowed").len(),
+        num_stacks
+    );
+
+    // Only allowed samples count towards profiled bytes
+    assert!(YingProfiler::profiled_bytes_allocated() >= 500 * 256);
+    assert!(YingProfiler::profiled_bytes_allocated() < 1000 * 256);
+}
@@ -0,0 +1,35 @@
+// Tests for the C API, which registers a profiler process wide
+#![cfg(all(
+    feature = "capi",
+    not(feature = "disabled"),
+    any(target_os = "linux", target_os = "macos")
+))]
+
+use std::ffi::CString;
+
+use ying_profiler::capi::{ying_dump_report, ying_profiled_retained, ying_total_retained};
+use ying_profiler::YingProfiler;
+
+#[global_allocator]
+static YING_ALLOC: YingProfiler = YingProfiler::new(1, 64 * 1024 * 1024 * 1024);
+
+#[test]
+fn capi_test() {
+    let items: Vec<_> = (0..100).map(|_n| Box::new([0u64; 16])).collect();
+    assert!(ying_total_retained() >= 100 * 128);
+    assert!(ying_profiled_retained() > 0);
+
+    let path = std::env::temp_dir().join(format!("ying_capi_test_{}.report", std::process::id()));
+    let c_path = CString::new(path.to_str().unwrap()).unwrap();
+    // Nothing to report on until a profiler is registered
+    assert_eq!(unsafe { ying_dump_report(c_path.as_ptr()) }, -1);
+
+    assert!(YING_ALLOC.register_capi());
+    assert!(!YING_ALLOC.register_capi());
+    assert_eq!(unsafe { ying_dump_report(std::ptr::null()) }, -1);
+    assert_eq!(unsafe { ying_dump_report(c_path.as_ptr()) }, 0);
+    let report = std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert!(report.contains("Callback <hash"));
+    assert_eq!(items.len(), 100);
+}
@@ -0,0 +1,27 @@
+// Tests for counting sampled allocations by CPU, which needs its own global allocator configuration
+#![cfg(all(not(feature = "disabled"), target_os = "linux"))]
+
+use ying_profiler::YingProfiler;
+
+#[global_allocator]
+static YING_ALLOC: YingProfiler =
+    YingProfiler::new(1, 64 * 1024 * 1024 * 1024).with_cpu_tracking(true);
+
+#[test]
+fn cpu_and_numa_node_stats_test() {
+    let items: Vec<_> = (0..1000).map(|_n| Box::new([0u64; 16])).collect();
+    assert_eq!(items.len(), 1000);
+
+    let cpu_stats = YING_ALLOC.cpu_allocation_stats();
+    assert!(!cpu_stats.is_empty());
+    let cpu_bytes: u64 = cpu_stats.iter().map(|stats| stats.sampled_bytes).sum();
+    let cpu_allocs: u64 = cpu_stats.iter().map(|stats| stats.num_allocations).sum();
+    assert!(cpu_bytes >= 1000 * 128);
+    assert!(cpu_allocs >= 1000);
+
+    // Every sampled byte belongs to exactly one node
+    let node_stats = YING_ALLOC.numa_node_allocation_stats();
+    assert!(!node_stats.is_empty());
+    let node_bytes: u64 = node_stats.iter().map(|stats| stats.sampled_bytes).sum();
+    assert!(node_bytes >= cpu_bytes);
+}
@@ -0,0 +1,23 @@
+// Only runs with `--features disabled`, or on targets Ying doesn't profile, where it is a pure passthrough allocator
+#![cfg(any(
+    feature = "disabled",
+    not(any(target_os = "linux", target_os = "macos"))
+))]
+
+use ying_profiler::YingProfiler;
+
+#[global_allocator]
+static YING_ALLOC: YingProfiler = YingProfiler::new(1, 64 * 1024 * 1024 * 1024);
+
+#[test]
+fn disabled_profiler_reports_nothing() {
+    let items: Vec<_> = (0..4000).map(|_n| Box::new([0u64; 64])).collect();
+    assert_eq!(items.len(), 4000);
+
+    assert_eq!(YingProfiler::total_retained_bytes(), 0);
+    assert_eq!(YingProfiler::profiled_bytes_allocated(), 0);
+    assert_eq!(YingProfiler::profiled_bytes_retained(), 0);
+    assert_eq!(YING_ALLOC.num_outstanding_allocs(), 0);
+    assert!(YING_ALLOC.top_k_stacks_by_allocated(5).is_empty());
+    assert!(YingProfiler::is_passthrough());
+}
@@ -0,0 +1,48 @@
+// Tests for wrapping an allocator other than System, which needs its own global allocator
+#![cfg(all(
+    not(feature = "disabled"),
+    any(target_os = "linux", target_os = "macos")
+))]
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering::Relaxed};
+
+use ying_profiler::YingProfiler;
+
+/// Delegates to System, but counts how many allocations it was asked for
+struct CountingAlloc {
+    allocs: AtomicUsize,
+}
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.allocs.fetch_add(1, Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static YING_ALLOC: YingProfiler<CountingAlloc> = YingProfiler::new_with_allocator(
+    CountingAlloc {
+        allocs: AtomicUsize::new(0),
+    },
+    5,
+    64 * 1024 * 1024 * 1024,
+);
+
+#[test]
+fn wraps_inner_allocator_test() {
+    let before = YING_ALLOC.inner().allocs.load(Relaxed);
+    let items: Vec<_> = (0..2000).map(|_n| Box::new([0u64; 16])).collect();
+    assert_eq!(items.len(), 2000);
+
+    // Every allocation went to the inner allocator, and they were still profiled
+    assert!(YING_ALLOC.inner().allocs.load(Relaxed) - before >= 2000);
+    assert!(YingProfiler::total_retained_bytes() >= 2000 * 128);
+    assert!(YingProfiler::profiled_bytes_allocated() > 0);
+    assert!(!YING_ALLOC.top_k_stacks_by_allocated(5).is_empty());
+}
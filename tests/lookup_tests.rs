@@ -0,0 +1,83 @@
+// Tests for looking up individual outstanding allocations, which needs every allocation sampled
+#![cfg(all(
+    not(feature = "disabled"),
+    any(target_os = "linux", target_os = "macos")
+))]
+
+use ying_profiler::YingProfiler;
+
+#[global_allocator]
+static YING_ALLOC: YingProfiler = YingProfiler::new(1, 64 * 1024 * 1024 * 1024);
+
+#[test]
+fn lookup_allocation_test() {
+    let item = Box::new([0u64; 100]);
+    let ptr = &*item as *const [u64; 100] as *const u8;
+
+    let info = YING_ALLOC.lookup_allocation(ptr).unwrap();
+    assert_eq!(info.size(), 800);
+    assert!(info.alloc_millis() > 0);
+    let snapshot = YING_ALLOC.snapshot();
+    assert!(snapshot.get(info.stack_hash()).is_some());
+
+    drop(item);
+    assert!(YING_ALLOC.lookup_allocation(ptr).is_none());
+}
+
+#[inline(never)]
+fn allocate_leak() -> Box<[u64; 300]> {
+    Box::new([7u64; 300])
+}
+
+#[test]
+fn outstanding_report_test() {
+    let leak = allocate_leak();
+    let ptr = &*leak as *const [u64; 300] as u64;
+
+    let report = YING_ALLOC.outstanding_report();
+    let alloc = report.iter().find(|alloc| alloc.ptr == ptr).unwrap();
+    assert_eq!(alloc.info.size(), 2400);
+    assert!(alloc.stack.as_ref().unwrap().contains("allocate_leak"));
+    assert!(alloc.to_string().contains("2400 bytes"));
+    // Largest first
+    assert!(report
+        .windows(2)
+        .all(|pair| pair[0].info.size() >= pair[1].info.size()));
+
+    drop(leak);
+    let report = YING_ALLOC.outstanding_report();
+    assert!(report.iter().all(|alloc| alloc.ptr != ptr));
+}
+
+#[test]
+fn for_each_outstanding_test() {
+    let leak = allocate_leak();
+    let ptr = &*leak as *const [u64; 300] as u64;
+
+    let mut found = None;
+    let mut num_allocs = 0;
+    YING_ALLOC.for_each_outstanding(|alloc| {
+        num_allocs += 1;
+        if alloc.ptr == ptr {
+            found = Some(alloc);
+        }
+    });
+    let alloc = found.unwrap();
+    assert_eq!(alloc.info.size(), 2400);
+    assert!(alloc.age_millis < 60_000);
+    assert!(alloc.stack.unwrap().contains("allocate_leak"));
+    assert!(num_allocs >= 1);
+    drop(leak);
+}
+
+#[test]
+fn self_check_test() {
+    let items: Vec<_> = (0..100).map(|n| Box::new([n as u64; 32])).collect();
+    let report = YING_ALLOC.self_check();
+    assert!(report.is_ok(), "{}", report);
+    assert!(report.stacks_checked > 0);
+    assert!(report.outstanding_checked >= 100);
+    assert!(report.outstanding_bytes >= 100 * 256);
+    assert!(report.stacks_retained_bytes >= report.outstanding_bytes);
+    drop(items);
+}
@@ -0,0 +1,28 @@
+// Tests for capping outstanding allocation tracking, which needs its own global allocator configuration
+#![cfg(all(
+    not(feature = "disabled"),
+    any(target_os = "linux", target_os = "macos")
+))]
+
+use ying_profiler::YingProfiler;
+
+const MAX_OUTSTANDING: usize = 100;
+
+#[global_allocator]
+static YING_ALLOC: YingProfiler =
+    YingProfiler::new(1, 64 * 1024 * 1024 * 1024).with_max_outstanding_allocs(MAX_OUTSTANDING);
+
+#[test]
+fn max_outstanding_allocs_test() {
+    let items: Vec<_> = (0..1000).map(|_n| Box::new([0u64; 16])).collect();
+    assert_eq!(items.len(), 1000);
+
+    assert!(YING_ALLOC.num_outstanding_allocs() <= MAX_OUTSTANDING);
+    assert!(YingProfiler::untracked_outstanding_allocs() >= 1000 - MAX_OUTSTANDING);
+
+    // Untracked allocations still count as allocated, but not as retained
+    assert!(YingProfiler::profiled_bytes_allocated() >= 1000 * 128);
+    assert!(YingProfiler::profiled_bytes_retained() <= MAX_OUTSTANDING * 128 * 2);
+    let top_stack = &YING_ALLOC.top_k_stacks_by_allocated(1)[0];
+    assert_eq!(top_stack.num_allocations(), 1000);
+}
@@ -0,0 +1,72 @@
+// Tests for labelling allocations with ying_alloc_scope!, which needs every allocation sampled
+#![cfg(all(
+    not(feature = "disabled"),
+    any(target_os = "linux", target_os = "macos")
+))]
+
+use ying_profiler::{ying_alloc_scope, YingProfiler};
+
+#[global_allocator]
+static YING_ALLOC: YingProfiler = YingProfiler::new(1, 64 * 1024 * 1024 * 1024);
+
+#[inline(never)]
+fn allocate_scoped(n: u64) -> Vec<[u64; 16]> {
+    (0..200).map(|_i| [n; 16]).collect()
+}
+
+#[inline(never)]
+fn allocate_then_return_early() -> Option<Vec<[u64; 16]>> {
+    ying_alloc_scope!("early_return", {
+        let v = allocate_scoped(1);
+        if !v.is_empty() {
+            return Some(v);
+        }
+    });
+    None
+}
+
+#[inline(never)]
+fn allocate_after_scope() -> Vec<[u64; 16]> {
+    (0..200).map(|_i| [2u64; 16]).collect()
+}
+
+#[test]
+fn alloc_scope_labels_stacks_test() {
+    let nested = ying_alloc_scope!("outer_scope", {
+        let outer = allocate_scoped(3);
+        let inner = ying_alloc_scope!("inner_scope", { allocate_scoped(4) });
+        (outer, inner)
+    });
+    let early = allocate_then_return_early().unwrap();
+    let unscoped = allocate_after_scope();
+    assert_eq!(
+        nested.0.len() + nested.1.len() + early.len() + unscoped.len(),
+        800
+    );
+
+    // The innermost label wins, and the outer one is restored after the inner scope
+    assert!(!YING_ALLOC
+        .stacks_matching("<scope: outer_scope>")
+        .is_empty());
+    assert!(!YING_ALLOC
+        .stacks_matching("<scope: inner_scope>")
+        .is_empty());
+    assert!(YING_ALLOC
+        .stacks_matching("<scope: inner_scope>")
+        .iter()
+        .all(|stats| !stats
+            .canonical_key(&YING_ALLOC)
+            .contains("<scope: outer_scope>")));
+
+    // Early return still leaves the scope
+    assert!(!YING_ALLOC
+        .stacks_matching("<scope: early_return>")
+        .is_empty());
+    assert!(!YING_ALLOC
+        .stacks_matching("allocate_after_scope")
+        .is_empty());
+    assert!(YING_ALLOC
+        .stacks_matching("allocate_after_scope")
+        .iter()
+        .all(|stats| !stats.canonical_key(&YING_ALLOC).contains("<scope:")));
+}
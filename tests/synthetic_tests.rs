@@ -0,0 +1,2168 @@
+// Deterministic tests of reporting and query code using synthetic stacks.  The profiler here is not the global
+// allocator, so it only ever contains the stacks injected by each test, and the global counters only change
+// when a test calls it directly.
+#![cfg(all(
+    not(feature = "disabled"),
+    any(target_os = "linux", target_os = "macos")
+))]
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::io::{Read, Write};
+use std::net::TcpListener;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[cfg(feature = "otel")]
+use opentelemetry::metrics::{
+    AsyncInstrument, AsyncInstrumentBuilder, Callback, InstrumentProvider, Meter,
+    ObservableCounter, ObservableGauge,
+};
+#[cfg(feature = "otel")]
+use opentelemetry::KeyValue;
+use serial_test::serial;
+use ying_profiler::callstack::{Confidence, FriendlySymbol, Measurement, Symbolizer};
+use ying_profiler::histogram::SizeCategory;
+use ying_profiler::utils::gen_flamegraph;
+use ying_profiler::YingProfiler;
+
+static PROFILER: YingProfiler = YingProfiler::new(10, 64 * 1024 * 1024 * 1024);
+
+// Frames of the two stacks most tests use, a cache which retains much of what it allocates and a parser which
+// frees nearly all of it
+const CACHE_STACK: [&str; 2] = ["my_app::cache::insert", "my_app::main"];
+const PARSE_STACK: [&str; 2] = ["my_app::parse::tokens", "my_app::main"];
+
+// Starts `PROFILER` over with just the cache stack, 4000 bytes allocated and 3000 retained, and the parse
+// stack, 9000 bytes allocated and 100 retained.  Returns their stack hashes.
+fn reset_to_cache_and_parse() -> (u64, u64) {
+    PROFILER.reset_state_for_testing_only();
+    let cache = PROFILER.inject_synthetic_stack(&CACHE_STACK, 4000, 3000);
+    let parse = PROFILER.inject_synthetic_stack(&PARSE_STACK, 9000, 100);
+    (cache, parse)
+}
+
+#[test]
+#[serial]
+fn synthetic_stacks_sort_and_match_test() {
+    let (cache, parse) = reset_to_cache_and_parse();
+    assert_ne!(cache, parse);
+
+    let by_allocated = PROFILER.top_k_stacks_by_allocated(10);
+    assert_eq!(by_allocated.len(), 2);
+    assert_eq!(by_allocated[0].stack_hash(), parse);
+    assert_eq!(by_allocated[0].allocated_bytes(), 9000);
+
+    let by_retained = PROFILER.top_k_stacks_by_retained(10);
+    assert_eq!(by_retained[0].stack_hash(), cache);
+    assert_eq!(by_retained[0].retained_profiled_bytes(), 3000);
+    assert_eq!(by_retained[0].freed_bytes(), 1000);
+
+    assert_eq!(PROFILER.stacks_matching("my_app::main").len(), 2);
+    let matching = PROFILER.stacks_matching("cache::");
+    assert_eq!(matching.len(), 1);
+    let report = matching[0].rich_report(&PROFILER, true, false);
+    assert!(report.contains("my_app::cache::insert"));
+    assert!(report.contains("<synthetic>"));
+    assert!(report.contains("largest allocation 3.91 KiB"));
+
+    // Injecting the same frames again replaces the stack
+    let again = PROFILER.inject_synthetic_stack(&CACHE_STACK, 100, 100);
+    assert_eq!(again, cache);
+    assert_eq!(PROFILER.top_k_stacks_by_allocated(10).len(), 2);
+}
+
+#[test]
+#[serial]
+fn equal_stacks_stable_order_test() {
+    PROFILER.reset_state_for_testing_only();
+    let mut hashes: Vec<u64> = (0..20)
+        .map(|n| {
+            let leaf = format!("my_app::module_{}::allocate", n);
+            PROFILER.inject_synthetic_stack(&[&leaf, "my_app::main"], 1000, 500)
+        })
+        .collect();
+    hashes.sort_unstable();
+
+    for _ in 0..3 {
+        let by_retained: Vec<u64> = PROFILER
+            .top_k_stacks_by_retained(20)
+            .iter()
+            .map(|stats| stats.stack_hash())
+            .collect();
+        assert_eq!(by_retained, hashes);
+        let by_allocated: Vec<u64> = PROFILER
+            .top_k_stacks_by_allocated(20)
+            .iter()
+            .map(|stats| stats.stack_hash())
+            .collect();
+        assert_eq!(by_allocated, hashes);
+    }
+}
+
+// The stack list is built and sorted on the Rayon pool, in the same order as without Rayon
+#[cfg(feature = "rayon")]
+#[test]
+#[serial]
+fn parallel_stack_list_order_test() {
+    PROFILER.reset_state_for_testing_only();
+    let mut expected: Vec<(u64, u64)> = (0..2000)
+        .map(|n| {
+            let leaf = format!("my_app::module_{}::allocate", n);
+            let retained = (n % 7) * 100;
+            let hash = PROFILER.inject_synthetic_stack(&[&leaf, "my_app::main"], 1000, retained);
+            (hash, retained)
+        })
+        .collect();
+    expected.sort_unstable_by_key(|&(hash, retained)| (std::cmp::Reverse(retained), hash));
+
+    let by_retained: Vec<(u64, u64)> = PROFILER
+        .top_k_stacks_by_retained(2000)
+        .iter()
+        .map(|stats| (stats.stack_hash(), stats.retained_profiled_bytes()))
+        .collect();
+    assert_eq!(by_retained, expected);
+}
+
+#[test]
+#[serial]
+fn canonical_key_test() {
+    let (cache, parse) = reset_to_cache_and_parse();
+
+    let stacks = PROFILER.top_k_stacks_by_allocated(10);
+    let key_of = |hash| {
+        stacks
+            .iter()
+            .find(|stats| stats.stack_hash() == hash)
+            .unwrap()
+            .canonical_key(&PROFILER)
+    };
+    assert_eq!(key_of(cache), "my_app::cache::insert;my_app::main");
+    assert_eq!(key_of(parse), "my_app::parse::tokens;my_app::main");
+}
+
+#[test]
+#[serial]
+fn unknown_stack_report_test() {
+    PROFILER.reset_state_for_testing_only();
+    // Samples without a usable backtrace are all lumped into the stack with no frames
+    let unknown = PROFILER.inject_synthetic_stack(&[], 2000, 2000);
+    PROFILER.inject_synthetic_stack(&CACHE_STACK, 4000, 3000);
+
+    let by_retained = PROFILER.top_k_stacks_by_retained(10);
+    assert_eq!(by_retained.len(), 2);
+    assert_eq!(by_retained[1].stack_hash(), unknown);
+    assert!(by_retained[1]
+        .rich_report(&PROFILER, false, false)
+        .contains("<unknown: backtrace could not be captured>"));
+    assert!(!by_retained[0]
+        .rich_report(&PROFILER, false, false)
+        .contains("<unknown"));
+}
+
+#[test]
+#[serial]
+fn stack_cardinality_report_test() {
+    reset_to_cache_and_parse();
+
+    let cardinality = PROFILER.stack_cardinality_report();
+    assert_eq!(cardinality.num_stacks, 2);
+    assert_eq!(cardinality.outstanding_allocs, 0);
+    // Symbols of earlier tests' stacks stay in the symbol map
+    assert!(cardinality.symbol_map_entries >= 3);
+    // The two stacks share my_app::main, and interned frames of earlier stacks are kept too
+    assert!(cardinality.interned_frames >= 3);
+    assert!(cardinality.to_string().contains("2 stacks"));
+}
+
+#[test]
+#[serial]
+fn activity_window_test() {
+    PROFILER.reset_state_for_testing_only();
+
+    let hash = PROFILER.inject_synthetic_stack(&CACHE_STACK, 4000, 3000);
+    let stats = PROFILER.top_k_stacks_by_retained(1).remove(0);
+    assert_eq!(stats.stack_hash(), hash);
+    // The profiler uses a coarse clock, so only check the timestamps are about now
+    assert!(now_millis().abs_diff(stats.first_seen_millis()) < 1000);
+    assert!(stats.last_alloc_millis() >= stats.first_seen_millis());
+
+    let report = stats.rich_report(&PROFILER, false, false);
+    assert!(report.contains("first seen 0s ago, last allocated 0s ago"));
+}
+
+#[test]
+#[serial]
+fn group_by_user_frame_test() {
+    PROFILER.reset_state_for_testing_only();
+
+    PROFILER.inject_synthetic_stack(
+        &[
+            "alloc::raw_vec::RawVec<T,A>::grow_one",
+            "<I as alloc::vec::spec_from_iter::SpecFromIter<T,I>>::from_iter",
+            "my_app::load::rows",
+            "my_app::main",
+        ],
+        5000,
+        1000,
+    );
+    PROFILER.inject_synthetic_stack(
+        &[
+            "<alloc::string::String as core::clone::Clone>::clone",
+            "my_app::load::rows",
+            "my_app::serve",
+        ],
+        2000,
+        1500,
+    );
+    PROFILER.inject_synthetic_stack(&CACHE_STACK, 4000, 3000);
+    PROFILER.inject_synthetic_stack(&["std::thread::spawn"], 100, 100);
+
+    let groups = PROFILER.top_k_by_user_frame(10, Measurement::AllocatedBytes);
+    assert_eq!(groups.len(), 3);
+    assert_eq!(groups[0].frame, "my_app::load::rows");
+    assert_eq!(groups[0].num_stacks, 2);
+    assert_eq!(groups[0].allocated_bytes, 7000);
+    assert_eq!(groups[0].retained_bytes, 2500);
+    assert_eq!(groups[1].frame, "my_app::cache::insert");
+    assert_eq!(groups[2].frame, "<unknown>");
+
+    let groups = PROFILER.top_k_by_user_frame(1, Measurement::RetainedBytes);
+    assert_eq!(groups.len(), 1);
+    assert_eq!(groups[0].frame, "my_app::cache::insert");
+    assert!(groups[0]
+        .to_string()
+        .contains("from 1 stacks: my_app::cache::insert"));
+}
+
+#[test]
+#[serial]
+fn nested_session_deltas_test() {
+    PROFILER.reset_state_for_testing_only();
+
+    let cache = PROFILER.inject_synthetic_stack(&["my_app::cache::insert"], 1000, 500);
+    let outer = PROFILER.begin_session();
+    let parse = PROFILER.inject_synthetic_stack(&["my_app::parse::tokens"], 1500, 1500);
+    let inner = PROFILER.begin_session();
+    PROFILER.inject_synthetic_stack(&["my_app::cache::insert"], 3000, 1000);
+
+    // The inner session only sees the growth of the cache stack since it started
+    let inner_report = inner.finish();
+    assert_eq!(inner_report.stacks.len(), 1);
+    let cache_delta = inner_report.stack(cache).unwrap();
+    assert_eq!(cache_delta.allocated_bytes, 2000);
+    assert_eq!(cache_delta.freed_bytes, 1500);
+    assert_eq!(cache_delta.retained_bytes_delta(), 500);
+    assert!(inner_report.stack(parse).is_none());
+
+    // The outer session sees both stacks, largest first
+    let outer_report = outer.finish();
+    assert_eq!(outer_report.stacks.len(), 2);
+    assert_eq!(outer_report.stacks[0].stats.stack_hash(), cache);
+    assert_eq!(outer_report.stack(parse).unwrap().allocated_bytes, 1500);
+    assert_eq!(outer_report.allocated_bytes(), 3500);
+    assert_eq!(outer_report.retained_bytes_delta(), 2000);
+    assert!(outer_report.to_string().contains("over 2 stacks"));
+}
+
+#[test]
+#[serial]
+fn write_stacks_jsonl_test() {
+    PROFILER.reset_state_for_testing_only();
+
+    let cache = PROFILER.inject_synthetic_stack(&CACHE_STACK, 4000, 3000);
+    PROFILER.inject_synthetic_stack(&["my_app::parse::tokens"], 9000, 100);
+
+    let mut out = Vec::new();
+    assert_eq!(PROFILER.write_stacks_jsonl(&mut out).unwrap(), 2);
+    let out = String::from_utf8(out).unwrap();
+    let lines: Vec<&str> = out.lines().collect();
+    assert_eq!(lines.len(), 3);
+    assert!(lines[0].starts_with("{\"config\":{\"version\":"));
+    assert!(lines[0].contains(&format!(
+        "\"sampling_ratio\":{},",
+        PROFILER.sampling_ratio()
+    )));
+    let lines = &lines[1..];
+    assert!(lines
+        .iter()
+        .all(|line| line.starts_with('{') && line.ends_with('}')));
+
+    let cache_line = lines
+        .iter()
+        .find(|line| line.contains(&format!("\"stack_hash\":\"{:#x}\"", cache)))
+        .unwrap();
+    assert!(cache_line.contains("\"allocated_bytes\":4000,"));
+    assert!(cache_line.contains("\"retained_bytes\":3000,"));
+    assert!(cache_line.ends_with("\"frames\":[\"my_app::cache::insert\",\"my_app::main\"]}"));
+}
+
+#[test]
+#[serial]
+fn symbol_map_export_import_test() {
+    static OFFLINE: YingProfiler = YingProfiler::new(10, 64 * 1024 * 1024 * 1024);
+    PROFILER.inject_synthetic_stack(&["my_app::export::me", "<T as my_app::Trait>\tx"], 10, 10);
+
+    let mut exported = Vec::new();
+    let num_ips = PROFILER.export_symbol_map(&mut exported).unwrap();
+    assert_eq!(num_ips, PROFILER.symbol_map_size());
+    assert!(num_ips >= 2);
+
+    assert_eq!(OFFLINE.import_symbol_map(&exported[..]).unwrap(), num_ips);
+    assert_eq!(OFFLINE.symbol_map_size(), num_ips);
+    // Already known instruction pointers are not imported again
+    assert_eq!(OFFLINE.import_symbol_map(&exported[..]).unwrap(), 0);
+
+    let mut reexported = Vec::new();
+    OFFLINE.export_symbol_map(&mut reexported).unwrap();
+    let sorted_lines = |bytes: &[u8]| {
+        let mut lines: Vec<String> = String::from_utf8(bytes.to_vec())
+            .unwrap()
+            .lines()
+            .map(String::from)
+            .collect();
+        lines.sort();
+        lines
+    };
+    assert_eq!(sorted_lines(&exported), sorted_lines(&reexported));
+    assert!(sorted_lines(&exported)
+        .iter()
+        .any(|line| line.ends_with("\t0\t<synthetic>\t<T as my_app::Trait>\tx")));
+
+    assert!(OFFLINE.import_symbol_map(&b"not a symbol\n"[..]).is_err());
+}
+
+#[test]
+#[serial]
+fn new_stacks_since_snapshot_test() {
+    PROFILER.reset_state_for_testing_only();
+
+    let old = PROFILER.inject_synthetic_stack(&["my_app::old::path"], 1000, 1000);
+    let baseline = PROFILER.snapshot();
+    assert_eq!(baseline.num_stacks(), 1);
+
+    // An old stack which grew is not new
+    PROFILER.inject_synthetic_stack(&["my_app::old::path"], 5000, 5000);
+    let small = PROFILER.inject_synthetic_stack(&["my_app::new::small"], 100, 100);
+    let big = PROFILER.inject_synthetic_stack(&["my_app::new::big"], 900, 800);
+
+    let after = PROFILER.snapshot();
+    let new_stacks = after.new_stacks_since(&baseline);
+    let new_hashes: Vec<u64> = new_stacks.iter().map(|s| s.stack_hash()).collect();
+    assert_eq!(new_hashes, vec![big, small]);
+
+    // Snapshots don't change when the profiler does
+    assert_eq!(baseline.get(old).unwrap().allocated_bytes(), 1000);
+    assert_eq!(after.get(old).unwrap().allocated_bytes(), 5000);
+    assert!(baseline.new_stacks_since(&after).is_empty());
+}
+
+#[test]
+#[serial]
+fn free_without_alloc_does_not_wrap_test() {
+    let layout = Layout::from_size_align(4096, 8).unwrap();
+    let total_start = YingProfiler::total_retained_bytes();
+    let freed_without_alloc_start = YingProfiler::freed_without_alloc_bytes();
+    unsafe {
+        let counted = PROFILER.alloc(layout);
+        // Allocated behind the profiler's back, like memory allocated before it was installed
+        let uncounted = System.alloc(layout);
+        assert_eq!(YingProfiler::total_retained_bytes(), total_start + 4096);
+
+        PROFILER.dealloc(counted, layout);
+        assert_eq!(YingProfiler::total_retained_bytes(), total_start);
+        PROFILER.dealloc(uncounted, layout);
+    }
+    // Whatever of the uncounted free isn't covered by bytes still retained is counted apart, rather than wrapping
+    let covered = total_start.min(4096);
+    assert_eq!(YingProfiler::total_retained_bytes(), total_start - covered);
+    assert_eq!(
+        YingProfiler::freed_without_alloc_bytes() - freed_without_alloc_start,
+        4096 - covered
+    );
+}
+
+// Every allocation is counted in the total retained bytes, even before the profiler's state is set up on the first
+// sample, so one made before and freed after leaves the total balanced without any per-allocation bookkeeping
+#[test]
+#[serial]
+fn free_after_state_init_test() {
+    static LATE: YingProfiler = YingProfiler::new(1_000_000, 64 * 1024 * 1024 * 1024);
+    let layout = Layout::from_size_align(4096, 8).unwrap();
+    let total_start = YingProfiler::total_retained_bytes();
+    let freed_without_alloc_start = YingProfiler::freed_without_alloc_bytes();
+
+    // Not sampled, so the state is still uninitialized
+    let early = unsafe { LATE.alloc(layout) };
+    assert_eq!(YingProfiler::total_retained_bytes(), total_start + 4096);
+    // Any query sets the state up
+    assert_eq!(LATE.num_outstanding_allocs(), 0);
+
+    unsafe { LATE.dealloc(early, layout) };
+    assert_eq!(YingProfiler::total_retained_bytes(), total_start);
+    assert_eq!(
+        YingProfiler::freed_without_alloc_bytes(),
+        freed_without_alloc_start
+    );
+}
+
+// Grows and frees buffers with realloc() and dealloc() from several threads.  Profiled retained bytes must follow
+// exactly what is still outstanding, rather than drifting away from it.
+#[test]
+#[serial]
+fn realloc_dealloc_retained_drift_test() {
+    static DRIFT: YingProfiler = YingProfiler::new(1, 64 * 1024 * 1024 * 1024);
+    let retained_start = YingProfiler::profiled_bytes_retained();
+
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            std::thread::spawn(|| {
+                let mut kept = Vec::new();
+                for n in 0..500 {
+                    // Doubling one step at a time goes through a chain of reallocs, like a growing Vec
+                    let mut layout = Layout::from_size_align(8, 8).unwrap();
+                    let mut ptr = unsafe { DRIFT.alloc(layout) };
+                    while layout.size() < 512 {
+                        ptr = unsafe { DRIFT.realloc(ptr, layout, layout.size() * 2) };
+                        layout = Layout::from_size_align(layout.size() * 2, 8).unwrap();
+                    }
+                    if n % 10 == 0 {
+                        kept.push((ptr as usize, layout));
+                    } else {
+                        unsafe { DRIFT.dealloc(ptr, layout) };
+                    }
+                }
+                kept
+            })
+        })
+        .collect();
+    let kept: Vec<_> = handles
+        .into_iter()
+        .flat_map(|h| h.join().expect("Cannot wait for thread"))
+        .collect();
+
+    let mut outstanding_bytes = 0;
+    DRIFT.for_each_outstanding(|alloc| outstanding_bytes += alloc.info.size() as usize);
+    assert!(outstanding_bytes > 0);
+    assert_eq!(
+        YingProfiler::profiled_bytes_retained() - retained_start,
+        outstanding_bytes
+    );
+
+    for (ptr, layout) in kept {
+        unsafe { DRIFT.dealloc(ptr as *mut u8, layout) };
+    }
+    assert_eq!(DRIFT.num_outstanding_allocs(), 0);
+    assert_eq!(YingProfiler::profiled_bytes_retained(), retained_start);
+}
+
+// A sampled allocation freed with profiling locked out, here by a for_each_outstanding callback, is taken off the
+// outstanding allocations once profiling is unlocked, so a later allocation at its address can't be mistaken for it
+#[test]
+#[serial]
+fn free_while_locked_out_test() {
+    static LOCKED: YingProfiler = YingProfiler::new(1, 64 * 1024 * 1024 * 1024);
+    let layout = Layout::from_size_align(256, 8).unwrap();
+    let retained_start = YingProfiler::profiled_bytes_retained();
+    let ptr = unsafe { LOCKED.alloc(layout) };
+    assert!(LOCKED.lookup_allocation(ptr).is_some());
+    assert_eq!(
+        YingProfiler::profiled_bytes_retained() - retained_start,
+        256
+    );
+
+    LOCKED.for_each_outstanding(|alloc| unsafe { LOCKED.dealloc(alloc.ptr as *mut u8, layout) });
+    assert!(LOCKED.lookup_allocation(ptr).is_none());
+    assert_eq!(LOCKED.num_outstanding_allocs(), 0);
+    assert_eq!(YingProfiler::profiled_bytes_retained(), retained_start);
+}
+
+// With latency timing, every sampled allocation is timed into the latency histogram
+#[test]
+#[serial]
+fn sampling_latency_histogram_test() {
+    static TIMED: YingProfiler =
+        YingProfiler::new(1, 64 * 1024 * 1024 * 1024).with_sampling_latency_timing(true);
+    let count_start = YingProfiler::sampling_latency_histogram().count();
+    let layout = Layout::from_size_align(128, 8).unwrap();
+    let ptrs: Vec<_> = (0..100).map(|_| unsafe { TIMED.alloc(layout) }).collect();
+
+    let hist = YingProfiler::sampling_latency_histogram();
+    assert_eq!(hist.count() - count_start, 100);
+    assert_eq!(hist.counts().iter().sum::<u64>(), hist.count());
+    assert!(hist.average_nanos() > 0.0);
+
+    for ptr in ptrs {
+        unsafe { TIMED.dealloc(ptr, layout) };
+    }
+}
+
+// Once adaptive sampling tightens the ratio, sampling decisions follow the tightened ratio
+#[test]
+#[serial]
+fn adaptive_sampling_ratio_test() {
+    static SPIKING: YingProfiler =
+        YingProfiler::new(1000, 64 * 1024 * 1024 * 1024).with_adaptive_sampling(1);
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    let mut ptrs: Vec<_> = (0..100).map(|_| unsafe { SPIKING.alloc(layout) }).collect();
+    assert_eq!(SPIKING.stack_cardinality_report().outstanding_allocs, 0);
+
+    SPIKING.testing_only_set_adaptive_ratio(1);
+    ptrs.extend((0..100).map(|_| unsafe { SPIKING.alloc(layout) }));
+    assert_eq!(SPIKING.stack_cardinality_report().outstanding_allocs, 100);
+
+    for ptr in ptrs {
+        unsafe { SPIKING.dealloc(ptr, layout) };
+    }
+    assert_eq!(SPIKING.stack_cardinality_report().outstanding_allocs, 0);
+}
+
+// While adaptive sampling samples more densely, each sample stands for fewer allocations
+#[test]
+#[serial]
+fn summary_with_adaptive_sampling_test() {
+    static ADAPTIVE: YingProfiler =
+        YingProfiler::new(100, 64 * 1024 * 1024 * 1024).with_adaptive_sampling(10);
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    let ptrs: Vec<_> = (0..100)
+        .map(|_| unsafe { ADAPTIVE.alloc(layout) })
+        .collect();
+    assert!(YingProfiler::profiled_bytes_retained() >= 64);
+
+    ADAPTIVE.testing_only_set_adaptive_ratio(25);
+    assert_eq!(ADAPTIVE.effective_sampling_ratio(), 25);
+    assert_eq!(
+        ADAPTIVE.summary().estimated_profiled_retained_bytes,
+        YingProfiler::profiled_bytes_retained() * 25
+    );
+
+    for ptr in ptrs {
+        unsafe { ADAPTIVE.dealloc(ptr, layout) };
+    }
+}
+
+#[test]
+#[serial]
+fn recent_alloc_rate_test() {
+    static RATED: YingProfiler = YingProfiler::new(1, 64 * 1024 * 1024 * 1024);
+    let layout = Layout::from_size_align(256, 8).unwrap();
+    let ptrs: Vec<_> = (0..20).map(|_| unsafe { RATED.alloc(layout) }).collect();
+
+    // All 20 sampled allocations come from one stack, well within the 10 second window
+    let top = RATED.top_k_stacks_by_allocated(1);
+    assert_eq!(top[0].num_allocations(), 20);
+    assert_eq!(top[0].recent_alloc_rate(), 2.0);
+    assert!(top[0]
+        .rich_report(&RATED, false, false)
+        .contains("2.00 sampled allocations/sec over the last 10s"));
+
+    for ptr in ptrs {
+        unsafe { RATED.dealloc(ptr, layout) };
+    }
+}
+
+// A loop allocating three sizes in turn, with a sampling ratio of 3, returns the size classes sampled
+fn sampled_size_classes(profiler: &YingProfiler) -> Vec<u64> {
+    let layouts: Vec<Layout> = [16, 256, 4096]
+        .iter()
+        .map(|&size| Layout::from_size_align(size, 8).unwrap())
+        .collect();
+    let mut ptrs = Vec::new();
+    for _ in 0..300 {
+        for &layout in &layouts {
+            ptrs.push((unsafe { profiler.alloc(layout) }, layout));
+        }
+    }
+    let classes = profiler
+        .retained_by_size_class()
+        .iter()
+        .map(|class| class.max_size)
+        .collect();
+    for (ptr, layout) in ptrs {
+        unsafe { profiler.dealloc(ptr, layout) };
+    }
+    classes
+}
+
+#[test]
+#[serial]
+fn sampling_jitter_avoids_lockstep_test() {
+    static LOCKSTEP: YingProfiler = YingProfiler::new(3, 64 * 1024 * 1024 * 1024);
+    static JITTERED: YingProfiler =
+        YingProfiler::new(3, 64 * 1024 * 1024 * 1024).with_sampling_jitter(true);
+
+    // Every third allocation is always the same size
+    assert_eq!(sampled_size_classes(&LOCKSTEP).len(), 1);
+    assert_eq!(sampled_size_classes(&JITTERED).len(), 3);
+}
+
+// Which of 200 allocations in a row were sampled
+fn sampled_indices(profiler: &YingProfiler) -> Vec<usize> {
+    let layout = Layout::from_size_align(48, 8).unwrap();
+    let ptrs: Vec<_> = (0..200)
+        .map(|_| unsafe { profiler.alloc(layout) })
+        .collect();
+    let sampled = (0..ptrs.len())
+        .filter(|&i| profiler.lookup_allocation(ptrs[i]).is_some())
+        .collect();
+    for ptr in ptrs {
+        unsafe { profiler.dealloc(ptr, layout) };
+    }
+    sampled
+}
+
+#[test]
+#[serial]
+fn sampling_seed_reproducible_test() {
+    static SEEDED: YingProfiler = YingProfiler::new(10, 64 * 1024 * 1024 * 1024)
+        .with_sampling_jitter(true)
+        .with_sampling_seed(1234);
+    static SAME_SEED: YingProfiler = YingProfiler::new(10, 64 * 1024 * 1024 * 1024)
+        .with_sampling_jitter(true)
+        .with_sampling_seed(1234);
+    static OTHER_SEED: YingProfiler = YingProfiler::new(10, 64 * 1024 * 1024 * 1024)
+        .with_sampling_jitter(true)
+        .with_sampling_seed(4321);
+
+    let seeded = sampled_indices(&SEEDED);
+    assert!(seeded.len() > 5);
+    // Even from another thread, the same seed samples the same allocations
+    let same = std::thread::spawn(|| sampled_indices(&SAME_SEED))
+        .join()
+        .unwrap();
+    assert_eq!(seeded, same);
+    assert_ne!(seeded, sampled_indices(&OTHER_SEED));
+}
+
+#[test]
+#[serial]
+fn memory_pressure_callback_test() {
+    static PRESSURED: YingProfiler = YingProfiler::new(1000, 64 * 1024 * 1024 * 1024);
+    static NUM_CALLS: AtomicUsize = AtomicUsize::new(0);
+    PRESSURED.on_memory_pressure(1024 * 1024, 0.5, |total_retained| {
+        assert!(total_retained >= 512 * 1024);
+        NUM_CALLS.fetch_add(1, Ordering::SeqCst);
+    });
+
+    let small = Layout::from_size_align(256 * 1024, 8).unwrap();
+    let big = Layout::from_size_align(512 * 1024, 8).unwrap();
+    unsafe {
+        let first = PRESSURED.alloc(small);
+        assert_eq!(NUM_CALLS.load(Ordering::SeqCst), 0);
+        let second = PRESSURED.alloc(big);
+        assert_eq!(NUM_CALLS.load(Ordering::SeqCst), 1);
+        // Still over the threshold, but only called once per crossing
+        let third = PRESSURED.alloc(small);
+        assert_eq!(NUM_CALLS.load(Ordering::SeqCst), 1);
+
+        // Dropping well below the threshold re-arms it
+        PRESSURED.dealloc(second, big);
+        PRESSURED.dealloc(third, small);
+        let fourth = PRESSURED.alloc(big);
+        assert_eq!(NUM_CALLS.load(Ordering::SeqCst), 2);
+
+        PRESSURED.dealloc(first, small);
+        PRESSURED.dealloc(fourth, big);
+    }
+}
+
+#[test]
+#[serial]
+fn collision_check_test() {
+    static CHECKED: YingProfiler =
+        YingProfiler::new(1, 64 * 1024 * 1024 * 1024).with_collision_check(true);
+
+    // The same stack sampled over and over is never a collision
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    for _ in 0..100 {
+        unsafe { CHECKED.dealloc(CHECKED.alloc(layout), layout) };
+    }
+    assert_eq!(
+        CHECKED.top_k_stacks_by_allocated(1)[0].num_allocations(),
+        100
+    );
+    assert_eq!(YingProfiler::stack_hash_collisions(), 0);
+}
+
+#[test]
+#[serial]
+fn stack_hash_seed_test() {
+    static SEEDED: YingProfiler =
+        YingProfiler::new(10, 64 * 1024 * 1024 * 1024).with_stack_hash_seed(42);
+    PROFILER.reset_state_for_testing_only();
+    let default_hash = PROFILER.inject_synthetic_stack(&CACHE_STACK, 4000, 3000);
+    let seeded_hash = SEEDED.inject_synthetic_stack(&CACHE_STACK, 4000, 3000);
+    assert_ne!(default_hash, seeded_hash);
+
+    // Stats and reports carry the seeded hash, but the symbol based key is the same
+    let seeded = &SEEDED.top_k_stacks_by_allocated(1)[0];
+    let default = &PROFILER.top_k_stacks_by_allocated(1)[0];
+    assert_eq!(seeded.stack_hash(), seeded_hash);
+    assert_eq!(default.stack_hash(), default_hash);
+    assert!(seeded
+        .rich_report(&SEEDED, false, false)
+        .contains(&format!("hash = 0x{:x}", seeded_hash)));
+    assert_eq!(
+        seeded.canonical_key(&SEEDED),
+        default.canonical_key(&PROFILER)
+    );
+}
+
+#[test]
+#[serial]
+fn top_movers_test() {
+    static TRACKED: YingProfiler = YingProfiler::new(10, 64 * 1024 * 1024 * 1024);
+    assert!(TRACKED.top_movers(5).is_empty());
+    TRACKED.roll_top_movers_interval();
+    assert!(TRACKED.top_movers(5).is_empty());
+
+    // During the first interval: one stack grows a lot, one a little, and one frees all it allocates
+    let grower = TRACKED.inject_synthetic_stack(&CACHE_STACK, 9000, 8000);
+    let slow = TRACKED.inject_synthetic_stack(&["my_app::log::append", "my_app::main"], 1000, 500);
+    TRACKED.inject_synthetic_stack(&PARSE_STACK, 5000, 0);
+    TRACKED.roll_top_movers_interval();
+
+    let movers = TRACKED.top_movers(5);
+    assert_eq!(movers.len(), 2);
+    assert_eq!(movers[0].stats.stack_hash(), grower);
+    assert_eq!(movers[0].retained_bytes_delta(), 8000);
+    assert_eq!(movers[1].stats.stack_hash(), slow);
+    assert_eq!(TRACKED.top_movers(1).len(), 1);
+}
+
+#[test]
+#[serial]
+fn heat_decay_test() {
+    static DECAYED: YingProfiler = YingProfiler::new(10, 64 * 1024 * 1024 * 1024);
+    let startup =
+        DECAYED.inject_synthetic_stack(&["my_app::load_config", "my_app::main"], 90000, 1000);
+    // Six half lives
+    DECAYED.decay_heat(Duration::from_secs(6), Duration::from_secs(1));
+    let now = DECAYED.inject_synthetic_stack(&["my_app::serve", "my_app::main"], 2000, 1000);
+
+    // Cumulative totals still favor startup, but its heat has decayed away
+    assert_eq!(
+        DECAYED.top_k_stacks_by_allocated(1)[0].stack_hash(),
+        startup
+    );
+    let recent = DECAYED.top_k_by_recent_allocation(2);
+    assert_eq!(recent[0].stack_hash(), now);
+    assert_eq!(recent[1].allocation_heat(), 90000 / 64);
+    assert_eq!(recent[1].allocated_bytes(), 90000);
+}
+
+#[test]
+#[serial]
+fn arm_disarm_test() {
+    static ARMED: YingProfiler =
+        YingProfiler::new(1, 64 * 1024 * 1024 * 1024).with_explicit_arming();
+    let layout = Layout::from_size_align(256, 8).unwrap();
+
+    // Dormant until armed
+    assert!(!ARMED.is_armed());
+    let before = unsafe { ARMED.alloc(layout) };
+    assert_eq!(ARMED.stack_cardinality_report().num_stacks, 0);
+
+    // Arming samples from a clean slate, and frees of allocations from before arming are ignored
+    ARMED.arm();
+    let during = unsafe { ARMED.alloc(layout) };
+    unsafe { ARMED.dealloc(before, layout) };
+    let stats = &ARMED.top_k_stacks_by_allocated(1)[0];
+    assert_eq!(stats.num_allocations(), 1);
+    assert_eq!(stats.num_frees(), 0);
+
+    // Disarming freezes the profile: later allocations and frees don't change it
+    ARMED.disarm();
+    unsafe { ARMED.dealloc(during, layout) };
+    let after = unsafe { ARMED.alloc(layout) };
+    let stats = &ARMED.top_k_stacks_by_allocated(1)[0];
+    assert_eq!(stats.num_allocations(), 1);
+    assert_eq!(stats.retained_profiled_bytes(), 256);
+
+    // Re-arming starts a new window, dropping the frozen allocation
+    ARMED.arm();
+    assert_eq!(ARMED.stack_cardinality_report().num_stacks, 0);
+    assert_eq!(ARMED.stack_cardinality_report().outstanding_allocs, 0);
+    unsafe { ARMED.dealloc(after, layout) };
+    ARMED.disarm();
+}
+
+/// Fails every allocation of 1 MiB or more, as if out of memory
+struct FailingAlloc;
+
+unsafe impl GlobalAlloc for FailingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if layout.size() >= 1024 * 1024 {
+            std::ptr::null_mut()
+        } else {
+            System.alloc(layout)
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+static LAST_FAILED_SIZE: AtomicUsize = AtomicUsize::new(0);
+
+fn record_failure(layout: Layout) {
+    LAST_FAILED_SIZE.store(layout.size(), Ordering::SeqCst);
+}
+
+#[test]
+#[serial]
+fn alloc_failure_test() {
+    static FAILING: YingProfiler<FailingAlloc> =
+        YingProfiler::new_with_allocator(FailingAlloc, 1, 64 * 1024 * 1024 * 1024);
+    FAILING.on_alloc_failure(record_failure);
+    let failed_before = YingProfiler::failed_allocs();
+
+    let small = Layout::from_size_align(64, 8).unwrap();
+    let huge = Layout::from_size_align(2 * 1024 * 1024, 8).unwrap();
+    let ptr = unsafe { FAILING.alloc(small) };
+    assert!(!ptr.is_null());
+    assert_eq!(YingProfiler::failed_allocs(), failed_before);
+
+    // Both a plain allocation and a realloc which the inner allocator can't satisfy are counted
+    assert!(unsafe { FAILING.alloc(huge) }.is_null());
+    assert_eq!(LAST_FAILED_SIZE.load(Ordering::SeqCst), 2 * 1024 * 1024);
+    assert!(unsafe { FAILING.realloc(ptr, small, 3 * 1024 * 1024) }.is_null());
+    assert_eq!(LAST_FAILED_SIZE.load(Ordering::SeqCst), 3 * 1024 * 1024);
+    assert_eq!(YingProfiler::failed_allocs(), failed_before + 2);
+    unsafe { FAILING.dealloc(ptr, small) };
+}
+
+/// Log output captured from a tracing subscriber
+#[cfg(feature = "tracing-logs")]
+#[derive(Clone, Default)]
+struct CapturedLogs(Arc<Mutex<Vec<u8>>>);
+
+#[cfg(feature = "tracing-logs")]
+impl Write for CapturedLogs {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(feature = "tracing-logs")]
+#[test]
+#[serial]
+fn giant_alloc_tracing_event_test() {
+    use tracing_subscriber::util::SubscriberInitExt;
+
+    static LIMITED: YingProfiler = YingProfiler::new(1, 1024 * 1024);
+    // Giant allocations are only checked once the state is initialized
+    LIMITED.stack_cardinality_report();
+    let logs = CapturedLogs::default();
+    let writer = logs.clone();
+    let _subscriber = tracing_subscriber::fmt()
+        .with_writer(move || writer.clone())
+        .with_ansi(false)
+        .finish()
+        .set_default();
+
+    let denied_before = YingProfiler::denied_giant_allocs();
+    let huge = Layout::from_size_align(2 * 1024 * 1024, 8).unwrap();
+    assert!(unsafe { LIMITED.alloc(huge) }.is_null());
+    assert_eq!(YingProfiler::denied_giant_allocs(), denied_before + 1);
+
+    let output = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+    assert!(output.contains("WARN"), "{}", output);
+    assert!(output.contains("Huge memory allocation denied by Ying profiler"));
+    assert!(output.contains("size=2097152"));
+    assert!(output.contains("giant_alloc_tracing_event_test"));
+}
+
+#[test]
+#[serial]
+fn untracked_realloc_sampling_test() {
+    static SAMPLED: YingProfiler = YingProfiler::new(1, 64 * 1024 * 1024 * 1024)
+        .with_explicit_arming()
+        .with_untracked_realloc_sampling(true);
+    static DEFAULT: YingProfiler =
+        YingProfiler::new(1, 64 * 1024 * 1024 * 1024).with_explicit_arming();
+    let layout = Layout::from_size_align(64, 8).unwrap();
+
+    for profiler in [&SAMPLED, &DEFAULT] {
+        // Allocated while disarmed, so not tracked
+        let ptr = unsafe { profiler.alloc(layout) };
+        profiler.arm();
+        assert!(profiler.lookup_allocation(ptr).is_none());
+        let grown = unsafe { profiler.realloc(ptr, layout, 4096) };
+        let grown_layout = Layout::from_size_align(4096, 8).unwrap();
+
+        let info = profiler.lookup_allocation(grown);
+        if std::ptr::eq(profiler, &SAMPLED) {
+            // Counted as a new allocation of the new size
+            assert_eq!(info.unwrap().size(), 4096);
+            let stats = &profiler.top_k_stacks_by_allocated(1)[0];
+            assert_eq!(stats.num_allocations(), 1);
+            assert_eq!(stats.allocated_bytes(), 4096);
+            unsafe { profiler.dealloc(grown, grown_layout) };
+            assert_eq!(
+                profiler.top_k_stacks_by_allocated(1)[0].retained_profiled_bytes(),
+                0
+            );
+        } else {
+            assert!(info.is_none());
+            assert_eq!(profiler.stack_cardinality_report().num_stacks, 0);
+            unsafe { profiler.dealloc(grown, grown_layout) };
+        }
+        profiler.disarm();
+    }
+}
+
+#[test]
+#[serial]
+fn write_pprof_test() {
+    reset_to_cache_and_parse();
+
+    let mut buf = Vec::new();
+    assert_eq!(PROFILER.write_pprof(&mut buf).unwrap(), 2);
+    // Starts with the first sample type, and has symbol names in the string table
+    assert_eq!(buf[0], 0x0a);
+    assert!(contains(&buf, b"inuse_space"));
+    assert!(contains(&buf, b"my_app::cache::insert"));
+    assert!(contains(&buf, b"my_app::parse::tokens"));
+    // With the config as comments
+    assert!(contains(&buf, b"ying.sampling_ratio=10"));
+}
+
+// Keeps every write, and fails once it has one, like a pipe closed by the reader
+struct OneShotWriter(Arc<Mutex<Vec<u8>>>);
+
+impl Write for OneShotWriter {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(data);
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Err(std::io::ErrorKind::BrokenPipe.into())
+    }
+}
+
+#[test]
+#[serial]
+fn stream_pprof_deltas_test() {
+    PROFILER.reset_state_for_testing_only();
+    PROFILER.inject_synthetic_stack(&["my_app::before_stream", "my_app::main"], 4000, 3000);
+
+    let written = Arc::new(Mutex::new(Vec::new()));
+    let handle =
+        PROFILER.stream_pprof_to(OneShotWriter(written.clone()), Duration::from_millis(300));
+    std::thread::sleep(Duration::from_millis(100));
+    PROFILER.inject_synthetic_stack(&["my_app::during_stream", "my_app::main"], 9000, 100);
+
+    let err = handle.join().unwrap().unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::BrokenPipe);
+    let written = written.lock().unwrap();
+    // One length prefixed profile, with only the stack which allocated during the interval
+    let (len, prefix_len) = if written[0] < 0x80 {
+        (written[0] as usize, 1)
+    } else {
+        ((written[0] & 0x7f) as usize | (written[1] as usize) << 7, 2)
+    };
+    assert_eq!(written.len(), prefix_len + len);
+    assert!(contains(&written, b"my_app::during_stream"));
+    assert!(!contains(&written, b"my_app::before_stream"));
+}
+
+#[test]
+#[serial]
+fn pprof_pusher_retries_test() {
+    PROFILER.reset_state_for_testing_only();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/ingest", listener.local_addr().unwrap());
+    PROFILER
+        .spawn_pprof_pusher(
+            &url,
+            Duration::from_millis(200),
+            &[("service", "my app"), ("instance", "a1")],
+        )
+        .unwrap();
+    std::thread::sleep(Duration::from_millis(50));
+    PROFILER.inject_synthetic_stack(&["my_app::pushed", "my_app::main"], 5000, 500);
+
+    // The first push is rejected, and the same profile is retried
+    let first = read_http_request(&listener, "503 Service Unavailable");
+    let second = read_http_request(&listener, "200 OK");
+    assert!(first.starts_with(b"POST /ingest?service=my%20app&instance=a1 HTTP/1.1\r\n"));
+    assert_eq!(first, second);
+    assert!(contains(&second, b"my_app::pushed"));
+}
+
+// Accepts one HTTP request, answers it with `status` and returns the whole request
+fn read_http_request(listener: &TcpListener, status: &str) -> Vec<u8> {
+    let (mut stream, _) = listener.accept().unwrap();
+    let mut request = Vec::new();
+    let mut buf = [0u8; 4096];
+    let body_start = loop {
+        let n = stream.read(&mut buf).unwrap();
+        request.extend_from_slice(&buf[..n]);
+        if let Some(pos) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+            break pos + 4;
+        }
+    };
+    let headers = String::from_utf8_lossy(&request[..body_start]).to_string();
+    let content_length: usize = headers
+        .lines()
+        .find_map(|line| line.strip_prefix("Content-Length: "))
+        .unwrap()
+        .parse()
+        .unwrap();
+    while request.len() < body_start + content_length {
+        let n = stream.read(&mut buf).unwrap();
+        request.extend_from_slice(&buf[..n]);
+    }
+    write!(stream, "HTTP/1.1 {}\r\nContent-Length: 0\r\n\r\n", status).unwrap();
+    request
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack
+        .windows(needle.len())
+        .any(|window| window == needle)
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+#[test]
+#[serial]
+fn alloc_size_stddev_test() {
+    static SIZES: YingProfiler = YingProfiler::new(1, 64 * 1024 * 1024 * 1024);
+    let layouts: Vec<_> = [100, 200, 300, 400]
+        .iter()
+        .map(|&size| Layout::from_size_align(size, 8).unwrap())
+        .collect();
+    let ptrs: Vec<_> = layouts
+        .iter()
+        .map(|&layout| unsafe { SIZES.alloc(layout) })
+        .collect();
+
+    let stats = &SIZES.top_k_stacks_by_allocated(1)[0];
+    assert_eq!(stats.num_allocations(), 4);
+    assert_eq!(stats.mean_alloc_size(), 250.0);
+    assert!((stats.stddev_alloc_size() - 12500f64.sqrt()).abs() < 1e-9);
+
+    for (ptr, layout) in ptrs.into_iter().zip(layouts) {
+        unsafe { SIZES.dealloc(ptr, layout) };
+    }
+}
+
+#[test]
+#[serial]
+fn stacks_with_allocations_above_test() {
+    static BIG: YingProfiler = YingProfiler::new(1, 64 * 1024 * 1024 * 1024);
+    let small = BIG.inject_synthetic_stack(&["my_app::small", "my_app::main"], 4096, 0);
+    let medium = BIG.inject_synthetic_stack(&["my_app::medium", "my_app::main"], 2 << 20, 0);
+    let large = BIG.inject_synthetic_stack(&["my_app::large", "my_app::main"], 8 << 20, 0);
+
+    let big_stacks: Vec<_> = BIG
+        .stacks_with_allocations_above(1 << 20)
+        .iter()
+        .map(|stats| (stats.stack_hash(), stats.max_alloc_size()))
+        .collect();
+    assert_eq!(big_stacks, vec![(large, 8 << 20), (medium, 2 << 20)]);
+    assert_eq!(BIG.stacks_with_allocations_above(4096).len(), 2);
+    let all = BIG.stacks_with_allocations_above(4095);
+    assert_eq!(all.len(), 3);
+    assert_eq!(all[2].stack_hash(), small);
+}
+
+// Frames shared by both "library" functions, which fill the frames skipped as the profiler's own
+#[inline(never)]
+fn shared_alloc(profiler: &YingProfiler, layout: Layout) -> *mut u8 {
+    shared_alloc_inner(profiler, layout)
+}
+
+#[inline(never)]
+fn shared_alloc_inner(profiler: &YingProfiler, layout: Layout) -> *mut u8 {
+    unsafe { profiler.alloc(layout) }
+}
+
+#[inline(never)]
+fn noisy_lib_alloc_a(profiler: &YingProfiler, layout: Layout) -> *mut u8 {
+    shared_alloc(profiler, layout)
+}
+
+#[inline(never)]
+fn noisy_lib_alloc_b(profiler: &YingProfiler, layout: Layout) -> *mut u8 {
+    shared_alloc(profiler, layout)
+}
+
+fn not_noisy_lib(name: &str) -> bool {
+    !name.contains("noisy_lib")
+}
+
+// Allocates through both "library" functions from the same call site, so the stacks differ only in them
+fn alloc_through_library(profiler: &YingProfiler) -> Vec<String> {
+    let layout = Layout::from_size_align(128, 8).unwrap();
+    let allocs: [fn(&YingProfiler, Layout) -> *mut u8; 2] = [noisy_lib_alloc_a, noisy_lib_alloc_b];
+    let ptrs: Vec<_> = allocs.iter().map(|alloc| alloc(profiler, layout)).collect();
+    let reports = profiler
+        .top_k_stacks_by_allocated(10)
+        .iter()
+        .map(|stats| stats.rich_report(profiler, false, false))
+        .collect();
+    for ptr in ptrs {
+        unsafe { profiler.dealloc(ptr, layout) };
+    }
+    reports
+}
+
+#[test]
+#[serial]
+fn hash_and_display_frame_filters_test() {
+    static UNFILTERED: YingProfiler = YingProfiler::new(1, 64 * 1024 * 1024 * 1024);
+    static HASH_FILTERED: YingProfiler = YingProfiler::new(1, 64 * 1024 * 1024 * 1024)
+        .with_hash_frame_filter(not_noisy_lib)
+        .with_collision_check(true);
+    static DISPLAY_FILTERED: YingProfiler =
+        YingProfiler::new(1, 64 * 1024 * 1024 * 1024).with_display_frame_filter(not_noisy_lib);
+
+    assert_eq!(alloc_through_library(&UNFILTERED).len(), 2);
+
+    // Grouped by the call site, but the library frame is still displayed
+    let reports = alloc_through_library(&HASH_FILTERED);
+    assert_eq!(reports.len(), 1);
+    assert!(reports[0].contains("noisy_lib_alloc_a"));
+    assert_eq!(
+        HASH_FILTERED.top_k_stacks_by_allocated(1)[0].num_allocations(),
+        2
+    );
+    assert_eq!(YingProfiler::stack_hash_collisions(), 0);
+
+    // Still two stacks, but neither shows the library frame
+    let reports = alloc_through_library(&DISPLAY_FILTERED);
+    assert_eq!(reports.len(), 2);
+    assert!(reports.iter().all(|report| !report.contains("noisy_lib")));
+    assert!(reports[0].contains("alloc_through_library"));
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+#[test]
+#[serial]
+fn module_offsets_test() {
+    static MODULES: YingProfiler =
+        YingProfiler::new(1, 64 * 1024 * 1024 * 1024).with_module_offsets(true);
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    let ptr = unsafe { MODULES.alloc(layout) };
+    let report = MODULES.top_k_stacks_by_allocated(1)[0].rich_report(&MODULES, false, false);
+    unsafe { MODULES.dealloc(ptr, layout) };
+
+    // Frames of the test itself are in the test executable
+    let test_frame = report
+        .lines()
+        .find(|line| line.contains("synthetic_tests::module_offsets_test"))
+        .unwrap();
+    assert!(test_frame.contains(" [synthetic_tests-"), "{}", test_frame);
+    assert!(test_frame.ends_with(']'));
+}
+
+#[test]
+#[serial]
+fn memory_budget_shedding_test() {
+    static BUDGETED: YingProfiler =
+        YingProfiler::new(1, 64 * 1024 * 1024 * 1024).with_max_profiler_memory_bytes(16 * 1024);
+    let names: Vec<String> = (0..50).map(|n| format!("my_app::cold_{}", n)).collect();
+    for name in &names {
+        BUDGETED.inject_synthetic_stack(&[name, "my_app::main"], 100, 0);
+    }
+    let hot = BUDGETED.inject_synthetic_stack(&["my_app::hot", "my_app::main"], 100, 100);
+    assert!(BUDGETED.profiler_memory_estimate() > 16 * 1024);
+    assert!(!BUDGETED.is_shedding());
+
+    // The next sample notices the budget is exceeded
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    let untracked_before = YingProfiler::untracked_outstanding_allocs();
+    let ptr = unsafe { BUDGETED.alloc(layout) };
+    assert!(BUDGETED.is_shedding());
+    assert!(BUDGETED.lookup_allocation(ptr).is_none());
+    assert_eq!(
+        YingProfiler::untracked_outstanding_allocs(),
+        untracked_before + 1
+    );
+    unsafe { BUDGETED.dealloc(ptr, layout) };
+
+    // Cold stacks were evicted, but not the one still retaining memory
+    assert!(BUDGETED.evicted_stacks() > 0);
+    let num_stacks = BUDGETED.stack_cardinality_report().num_stacks;
+    assert_eq!(num_stacks, 52 - BUDGETED.evicted_stacks());
+    assert!(BUDGETED
+        .top_k_stacks_by_retained(1)
+        .iter()
+        .any(|stats| stats.stack_hash() == hot));
+}
+
+// Symbols and interned frames can't be shed, so they don't keep the profiler shedding once stacks are evicted
+#[test]
+#[serial]
+fn memory_budget_symbols_test() {
+    static SYMBOL_HEAVY: YingProfiler =
+        YingProfiler::new(1, 64 * 1024 * 1024 * 1024).with_max_profiler_memory_bytes(16 * 1024);
+    let names: Vec<Vec<String>> = (0..100)
+        .map(|n| {
+            (0..20)
+                .map(|f| format!("my_app::cold_{}::f{}", n, f))
+                .collect()
+        })
+        .collect();
+    let layout = Layout::from_size_align(64, 8).unwrap();
+
+    // The budget is checked on samples 0, 64 and 128.  All allocations come from this one call site, so their
+    // stack already exists when the budget is exceeded, and isn't added after cold stacks are evicted.
+    let mut shedding = Vec::with_capacity(129);
+    for n in 0..129 {
+        if n == 1 {
+            for frames in &names {
+                let frames: Vec<&str> = frames.iter().map(String::as_str).collect();
+                SYMBOL_HEAVY.inject_synthetic_stack(&frames, 100, 0);
+            }
+            assert!(SYMBOL_HEAVY.profiler_memory_estimate() > 10 * 16 * 1024);
+        }
+        let ptr = unsafe { SYMBOL_HEAVY.alloc(layout) };
+        unsafe { SYMBOL_HEAVY.dealloc(ptr, layout) };
+        shedding.push(SYMBOL_HEAVY.is_shedding());
+    }
+    assert!(!shedding[63]);
+    assert!(shedding[64]);
+    assert!(SYMBOL_HEAVY.evicted_stacks() > 0);
+
+    // Evicting cold stacks brought the budgeted estimate under 80%, though symbols still exceed the budget
+    assert!(!shedding[128]);
+    assert!(SYMBOL_HEAVY.profiler_memory_estimate() > 16 * 1024);
+}
+
+// The count of outstanding allocations the cap and the memory budget use stays in step with the map
+#[test]
+#[serial]
+fn outstanding_count_test() {
+    static CAPPED: YingProfiler =
+        YingProfiler::new(1, 64 * 1024 * 1024 * 1024).with_max_outstanding_allocs(50);
+    let counts = || {
+        let num_outstanding = CAPPED.num_outstanding_allocs();
+        assert_eq!(
+            num_outstanding,
+            CAPPED.stack_cardinality_report().outstanding_allocs
+        );
+        num_outstanding
+    };
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    let mut ptrs: Vec<_> = (0..80).map(|_| unsafe { CAPPED.alloc(layout) }).collect();
+    assert_eq!(counts(), 50);
+
+    // Reallocs move tracked allocations, and frees of tracked ones make room under the cap
+    for ptr in &mut ptrs[..10] {
+        *ptr = unsafe { CAPPED.realloc(*ptr, layout, 128) };
+    }
+    assert_eq!(counts(), 50);
+    let bigger = Layout::from_size_align(128, 8).unwrap();
+    for ptr in ptrs.drain(..10) {
+        unsafe { CAPPED.dealloc(ptr, bigger) };
+    }
+    assert_eq!(counts(), 40);
+    ptrs.extend((0..20).map(|_| unsafe { CAPPED.alloc(layout) }));
+    assert_eq!(counts(), 50);
+
+    CAPPED.clear_stats();
+    assert_eq!(counts(), 0);
+    for ptr in ptrs {
+        unsafe { CAPPED.dealloc(ptr, layout) };
+    }
+    assert_eq!(counts(), 0);
+}
+
+#[test]
+#[serial]
+fn max_samples_per_sec_test() {
+    static THROTTLED: YingProfiler =
+        YingProfiler::new(1, 64 * 1024 * 1024 * 1024).with_max_samples_per_sec(10);
+    let layout = Layout::from_size_align(32, 8).unwrap();
+    let throttled_before = YingProfiler::throttled_samples();
+    let ptrs: Vec<_> = (0..100)
+        .map(|_| unsafe { THROTTLED.alloc(layout) })
+        .collect();
+
+    // A burst of a second's worth of samples, then a new token every 100ms
+    let sampled = ptrs
+        .iter()
+        .filter(|&&ptr| THROTTLED.lookup_allocation(ptr).is_some())
+        .count();
+    assert!((10..=12).contains(&sampled), "{}", sampled);
+    assert_eq!(
+        YingProfiler::throttled_samples() - throttled_before,
+        100 - sampled
+    );
+    for ptr in ptrs {
+        unsafe { THROTTLED.dealloc(ptr, layout) };
+    }
+}
+
+#[test]
+#[serial]
+fn config_test() {
+    static CONFIGURED: YingProfiler = YingProfiler::new(100, 1024 * 1024)
+        .with_adaptive_sampling(10)
+        .with_max_samples_per_sec(1000)
+        .with_cpu_budget_ppm(10_000);
+
+    let config = CONFIGURED.config();
+    assert_eq!(config.sampling_ratio, 100);
+    assert_eq!(config.effective_sampling_ratio, 100);
+    assert_eq!(config.adaptive_min_ratio, Some(10));
+    assert_eq!(config.single_alloc_limit, 1024 * 1024);
+    assert_eq!(config.max_samples_per_sec, Some(1000));
+    assert_eq!(config.max_outstanding_allocs, None);
+    assert_eq!(config.max_profiling_cpu_ppm, Some(10_000));
+    assert_eq!(config.features.contains(&"tokio"), cfg!(feature = "tokio"));
+    let line = config.to_string();
+    assert!(line.contains(" sampling_ratio=100 "));
+    assert!(line.contains(" max_outstanding_allocs=null "));
+}
+
+#[test]
+#[serial]
+fn retained_by_generation_test() {
+    static GENERATIONS: YingProfiler = YingProfiler::new(1, 64 * 1024 * 1024 * 1024);
+    let startup_layout = Layout::from_size_align(1000, 8).unwrap();
+    let later_layout = Layout::from_size_align(3000, 8).unwrap();
+    assert_eq!(GENERATIONS.generation(), 0);
+    let startup = unsafe { GENERATIONS.alloc(startup_layout) };
+    assert_eq!(GENERATIONS.next_generation(), 1);
+    let later = unsafe { GENERATIONS.alloc(later_layout) };
+    assert_eq!(
+        GENERATIONS
+            .lookup_allocation(later)
+            .unwrap()
+            .profile_generation(),
+        1
+    );
+
+    // Resetting a stack also starts a new generation
+    let stack_hash = GENERATIONS.top_k_stacks_by_allocated(1)[0].stack_hash();
+    assert!(GENERATIONS.reset_stack(stack_hash));
+    assert_eq!(GENERATIONS.generation(), 2);
+
+    let generations = GENERATIONS.retained_by_generation();
+    assert_eq!(generations.len(), 2);
+    assert_eq!(generations[0].generation, 0);
+    assert_eq!(generations[0].num_allocs, 1);
+    assert_eq!(generations[0].sampled_bytes, 1000);
+    assert_eq!(generations[1].generation, 1);
+    assert_eq!(generations[1].sampled_bytes, 3000);
+
+    unsafe { GENERATIONS.dealloc(startup, startup_layout) };
+    unsafe { GENERATIONS.dealloc(later, later_layout) };
+    assert!(GENERATIONS.retained_by_generation().is_empty());
+}
+
+#[test]
+#[serial]
+fn top_k_by_allocation_site_test() {
+    static PASS_THROUGH: YingProfiler = YingProfiler::new(10, 64 * 1024 * 1024 * 1024)
+        .with_pass_through_frames(&["bytes::bytes_mut::"]);
+    let vec_push = [
+        "alloc::raw_vec::finish_grow",
+        "alloc::raw_vec::RawVec<T,A>::grow_one",
+        "alloc::vec::Vec<T,A>::push",
+        "my_app::index::add",
+        "my_app::main",
+    ];
+    let map_insert = [
+        "hashbrown::raw::RawTable<T,A>::reserve_rehash",
+        "std::collections::hash::map::HashMap<K,V,S>::insert",
+        "my_app::index::add",
+        "my_app::main",
+    ];
+    let boxed = [
+        "alloc::alloc::exchange_malloc",
+        "alloc::boxed::Box<T>::new",
+        "my_app::main",
+    ];
+    let bytes = [
+        "bytes::bytes_mut::BytesMut::reserve_inner",
+        "my_app::net::read",
+    ];
+    PASS_THROUGH.inject_synthetic_stack(&vec_push, 4000, 4000);
+    PASS_THROUGH.inject_synthetic_stack(&map_insert, 1000, 1000);
+    PASS_THROUGH.inject_synthetic_stack(&boxed, 3000, 0);
+    PASS_THROUGH.inject_synthetic_stack(&bytes, 2000, 0);
+
+    let sites = PASS_THROUGH.top_k_by_allocation_site(10, Measurement::AllocatedBytes);
+    let frames: Vec<&str> = sites.iter().map(|group| group.frame.as_str()).collect();
+    // Collection growth is attributed to the caller, other allocations keep their innermost frame
+    assert_eq!(
+        frames,
+        [
+            "my_app::index::add",
+            "alloc::boxed::Box<T>::new",
+            "my_app::net::read"
+        ]
+    );
+    assert_eq!(sites[0].allocated_bytes, 5000);
+    assert_eq!(sites[0].num_stacks, 2);
+}
+
+#[test]
+#[serial]
+fn assert_no_growth_test() {
+    static LEAK_CHECKED: YingProfiler = YingProfiler::new(1, 64 * 1024 * 1024 * 1024);
+    let layout = Layout::from_size_align(4096, 8).unwrap();
+
+    // Freed before the closure returns, so no growth
+    LEAK_CHECKED.assert_no_growth(|| {
+        let ptr = unsafe { LEAK_CHECKED.alloc(layout) };
+        unsafe { LEAK_CHECKED.dealloc(ptr, layout) };
+    });
+
+    let mut leaked = std::ptr::null_mut();
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        LEAK_CHECKED.assert_no_growth(|| leaked = unsafe { LEAK_CHECKED.alloc(layout) });
+    }));
+    let message = *result.unwrap_err().downcast::<String>().unwrap();
+    assert!(
+        message.starts_with("Retained memory grew by 4096 bytes"),
+        "{}",
+        message
+    );
+    assert!(
+        message.contains("retained +4096 sampled bytes"),
+        "{}",
+        message
+    );
+
+    // Within the tolerance
+    LEAK_CHECKED.assert_no_growth_within(4096, || unsafe { LEAK_CHECKED.dealloc(leaked, layout) });
+}
+
+#[test]
+#[serial]
+fn ignored_allocations_test() {
+    const ARENA_BYTES: usize = 1024 * 1024;
+    static ARENA_IGNORED: YingProfiler = YingProfiler::new(1, 64 * 1024 * 1024 * 1024)
+        .with_ignored_allocations(|_ptr, size| size == ARENA_BYTES);
+    let arena_layout = Layout::from_size_align(ARENA_BYTES, 8).unwrap();
+    let small_layout = Layout::from_size_align(64, 8).unwrap();
+    let ignored_before = YingProfiler::ignored_samples();
+
+    let arena = unsafe { ARENA_IGNORED.alloc(arena_layout) };
+    let small = unsafe { ARENA_IGNORED.alloc(small_layout) };
+    assert!(ARENA_IGNORED.lookup_allocation(arena).is_none());
+    assert!(ARENA_IGNORED.lookup_allocation(small).is_some());
+    assert_eq!(YingProfiler::ignored_samples(), ignored_before + 1);
+
+    unsafe { ARENA_IGNORED.dealloc(arena, arena_layout) };
+    unsafe { ARENA_IGNORED.dealloc(small, small_layout) };
+}
+
+#[test]
+#[serial]
+fn churn_ratio_and_retention_test() {
+    PROFILER.reset_state_for_testing_only();
+    let leak =
+        PROFILER.inject_synthetic_stack(&["my_app::registry::add", "my_app::main"], 10_000, 9_000);
+    let temps = PROFILER.inject_synthetic_stack(
+        &["my_app::parse::scratch", "my_app::main"],
+        1_000_000,
+        10_000,
+    );
+
+    let by_retained = PROFILER.top_k_stacks_by_retained(2);
+    assert_eq!(by_retained[0].stack_hash(), temps);
+    assert!((by_retained[0].churn_ratio() - 0.99).abs() < 1e-9);
+    assert!((by_retained[1].churn_ratio() - 0.1).abs() < 1e-9);
+
+    // The stack keeping nearly all it allocates ranks first, although it retains less
+    let by_retention = PROFILER.top_k_by_retention(2);
+    assert_eq!(by_retention[0].stack_hash(), leak);
+    assert_eq!(by_retention[1].stack_hash(), temps);
+}
+
+#[test]
+#[serial]
+fn flamegraph_weights_test() {
+    PROFILER.reset_state_for_testing_only();
+    let big = PROFILER.inject_synthetic_stack(&["my_app::load_blob", "my_app::main"], 1_000_000, 0);
+    let small = PROFILER.inject_synthetic_stack(&["my_app::small", "my_app::main"], 100, 100);
+    let stacks = PROFILER.snapshot();
+
+    let big = stacks.get(big).unwrap();
+    assert!(big
+        .dtrace_report(&PROFILER, Measurement::AllocatedBytes)
+        .ends_with("  1000000\n"));
+    assert!(big
+        .dtrace_report(&PROFILER, Measurement::NumAllocations)
+        .ends_with("  1\n"));
+    assert!(big
+        .dtrace_report(&PROFILER, Measurement::LiveAllocations)
+        .ends_with("  0\n"));
+    assert_eq!(stacks.get(small).unwrap().live_allocations(), 1);
+
+    let groups = PROFILER.top_k_by_user_frame(1, Measurement::LiveAllocations);
+    assert_eq!(groups[0].frame, "my_app::small");
+
+    let path = std::env::temp_dir().join(format!("ying_count_flame_{}.svg", std::process::id()));
+    gen_flamegraph(&PROFILER, Measurement::NumAllocations, &path).unwrap();
+    let svg = std::fs::read_to_string(&path).unwrap();
+    assert!(svg.contains("my_app::load_blob"));
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+#[serial]
+fn realloc_to_zero_test() {
+    static REALLOC_ZERO: YingProfiler = YingProfiler::new(1, 64 * 1024 * 1024 * 1024);
+    let layout = Layout::from_size_align(256, 8).unwrap();
+    let ptr = unsafe { REALLOC_ZERO.alloc(layout) };
+    let retained = YingProfiler::total_retained_bytes();
+    let profiled_retained = YingProfiler::profiled_bytes_retained();
+
+    // Violates the realloc contract, so it fails, which leaves the block valid and still tracked
+    assert!(unsafe { REALLOC_ZERO.realloc(ptr, layout, 0) }.is_null());
+    assert_eq!(REALLOC_ZERO.lookup_allocation(ptr).unwrap().size(), 256);
+    assert_eq!(YingProfiler::total_retained_bytes(), retained);
+    assert_eq!(YingProfiler::profiled_bytes_retained(), profiled_retained);
+
+    unsafe { REALLOC_ZERO.dealloc(ptr, layout) };
+    assert!(REALLOC_ZERO.lookup_allocation(ptr).is_none());
+}
+
+struct JitSymbolizer;
+
+impl Symbolizer for JitSymbolizer {
+    fn symbolize(&self, ip: u64) -> Vec<FriendlySymbol> {
+        vec![FriendlySymbol::new(
+            &format!("jit::code_{:x}", ip),
+            "<jit>",
+            0,
+        )]
+    }
+}
+
+#[test]
+#[serial]
+fn custom_symbolizer_test() {
+    static JIT_SYMBOLIZED: YingProfiler =
+        YingProfiler::new(1, 64 * 1024 * 1024 * 1024).with_symbolizer(&JitSymbolizer);
+    let layout = Layout::from_size_align(128, 8).unwrap();
+
+    let ptr = unsafe { JIT_SYMBOLIZED.alloc(layout) };
+    let stack_hash = JIT_SYMBOLIZED.lookup_allocation(ptr).unwrap().stack_hash();
+    let matching = JIT_SYMBOLIZED.stacks_matching("jit::code_");
+    assert_eq!(matching.len(), 1);
+    assert_eq!(matching[0].stack_hash(), stack_hash);
+    assert!(matching[0]
+        .rich_report(&JIT_SYMBOLIZED, true, false)
+        .contains("<jit>"));
+    unsafe { JIT_SYMBOLIZED.dealloc(ptr, layout) };
+}
+
+// Resolves nothing, like the debug info of a stripped binary
+struct StrippedSymbolizer;
+
+impl Symbolizer for StrippedSymbolizer {
+    fn symbolize(&self, _ip: u64) -> Vec<FriendlySymbol> {
+        Vec::new()
+    }
+}
+
+#[test]
+#[serial]
+fn unresolved_frames_test() {
+    static STRIPPED: YingProfiler =
+        YingProfiler::new(1, 64 * 1024 * 1024 * 1024).with_symbolizer(&StrippedSymbolizer);
+    let layout = Layout::from_size_align(128, 8).unwrap();
+
+    let ptr = unsafe { STRIPPED.alloc(layout) };
+    let stack_hash = STRIPPED.lookup_allocation(ptr).unwrap().stack_hash();
+    // Frames are named after their IP and offset within this test binary, eg 0x55d0c3a1b2c4 (synthetic_tests-…+0x1b2c4)
+    let matching = STRIPPED.stacks_matching("(synthetic_tests-");
+    assert_eq!(matching.len(), 1);
+    assert_eq!(matching[0].stack_hash(), stack_hash);
+    let report = matching[0].rich_report(&STRIPPED, true, false);
+    assert!(report.contains("+0x"), "{}", report);
+    assert!(report.contains("loaded at 0x"), "{}", report);
+    assert!(!report.contains("unresolved_frames_test"), "{}", report);
+    unsafe { STRIPPED.dealloc(ptr, layout) };
+}
+
+#[test]
+#[serial]
+fn lazy_symbolization_test() {
+    static LAZY: YingProfiler =
+        YingProfiler::new(1, 64 * 1024 * 1024 * 1024).with_lazy_symbolization(5);
+    let layout = Layout::from_size_align(512, 8).unwrap();
+
+    let ptr = unsafe { LAZY.alloc(layout) };
+    assert!(LAZY.lookup_allocation(ptr).is_some());
+    assert_eq!(LAZY.symbol_map_size(), 0);
+
+    // The top stack is resolved in the background
+    LAZY.spawn_symbol_resolver(Duration::from_millis(10));
+    let start = std::time::Instant::now();
+    while LAZY.symbol_map_size() == 0 && start.elapsed() < Duration::from_secs(10) {
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    assert!(LAZY.symbol_map_size() > 0);
+    assert_eq!(LAZY.stacks_matching("lazy_symbolization_test").len(), 1);
+    unsafe { LAZY.dealloc(ptr, layout) };
+}
+
+#[cfg(unix)]
+#[test]
+#[serial]
+fn unix_socket_commands_test() {
+    use std::os::unix::net::UnixStream;
+
+    PROFILER.reset_state_for_testing_only();
+    PROFILER.inject_synthetic_stack(&["my_app::socket::served", "my_app::main"], 2048, 1024);
+    let path = std::env::temp_dir().join(format!("ying_control_{}.sock", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    PROFILER.serve_unix_socket(&path).unwrap();
+
+    let mut stream = UnixStream::connect(&path).unwrap();
+    stream.write_all(b"retained\ntop 1\ndump\nbogus\n").unwrap();
+    stream.shutdown(std::net::Shutdown::Write).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    let _ = std::fs::remove_file(&path);
+
+    assert!(response.starts_with("Ying: "), "{}", response);
+    assert!(response.contains("my_app::socket::served"), "{}", response);
+    assert!(response.contains("\"retained_bytes\":1024"), "{}", response);
+    assert!(response.contains("Unknown command bogus"), "{}", response);
+}
+
+#[test]
+#[serial]
+fn mark_intentional_test() {
+    static INTENTIONAL: YingProfiler = YingProfiler::new(1, 64 * 1024 * 1024 * 1024);
+    let layout = Layout::from_size_align(1024, 8).unwrap();
+    let stats_of = |stack_hash| INTENTIONAL.snapshot().get(stack_hash).cloned().unwrap();
+
+    let config = unsafe { INTENTIONAL.alloc(layout) };
+    let stack_hash = INTENTIONAL.lookup_allocation(config).unwrap().stack_hash();
+    assert_eq!(stats_of(stack_hash).leak_suspect_bytes(), 1024);
+
+    assert!(INTENTIONAL.mark_intentional(config));
+    assert!(INTENTIONAL.mark_intentional(config));
+    assert!(INTENTIONAL
+        .lookup_allocation(config)
+        .unwrap()
+        .is_intentional());
+    let stats = stats_of(stack_hash);
+    assert_eq!(stats.retained_profiled_bytes(), 1024);
+    assert_eq!(stats.intentionally_retained_bytes(), 1024);
+    assert_eq!(stats.leak_suspect_bytes(), 0);
+
+    unsafe { INTENTIONAL.dealloc(config, layout) };
+    assert_eq!(stats_of(stack_hash).intentionally_retained_bytes(), 0);
+    assert!(!INTENTIONAL.mark_intentional(config));
+}
+
+#[test]
+#[serial]
+fn owned_stack_stats_test() {
+    PROFILER.reset_state_for_testing_only();
+    let hash =
+        PROFILER.inject_synthetic_stack(&["my_app::owned::leaf", "my_app::main"], 4096, 1024);
+    let stats = PROFILER.snapshot().get(hash).cloned().unwrap();
+    let key = stats.canonical_key(&PROFILER);
+
+    let owned = stats.into_owned(&PROFILER);
+    // Still complete after the profiler forgets the stack
+    PROFILER.reset_state_for_testing_only();
+    assert_eq!(owned.stack_hash, hash);
+    assert_eq!(owned.frames, vec!["my_app::owned::leaf", "my_app::main"]);
+    assert_eq!(owned.canonical_key(), key);
+    assert_eq!(owned.allocated_bytes, 4096);
+    assert_eq!(owned.retained_bytes, 1024);
+    assert_eq!(owned.freed_bytes, 3072);
+    assert_eq!(owned.clone(), owned);
+}
+
+#[test]
+#[serial]
+fn top_movers_vs_baseline_test() {
+    const BASELINE_PATH: &str = concat!(env!("CARGO_TARGET_TMPDIR"), "/ying_baseline.jsonl");
+    static RELEASED: YingProfiler = YingProfiler::new(10, 64 * 1024 * 1024 * 1024);
+    static DEPLOYED: YingProfiler =
+        YingProfiler::new(10, 64 * 1024 * 1024 * 1024).with_baseline(BASELINE_PATH);
+    static NO_BASELINE: YingProfiler = YingProfiler::new(10, 64 * 1024 * 1024 * 1024)
+        .with_baseline(concat!(env!("CARGO_TARGET_TMPDIR"), "/ying_missing.jsonl"));
+
+    RELEASED.inject_synthetic_stack(&CACHE_STACK, 4000, 1000);
+    RELEASED.inject_synthetic_stack(&PARSE_STACK, 2000, 500);
+    let file = std::fs::File::create(BASELINE_PATH).unwrap();
+    RELEASED
+        .write_stacks_jsonl(std::io::BufWriter::new(file))
+        .unwrap();
+
+    let cache = DEPLOYED.inject_synthetic_stack(&CACHE_STACK, 5000, 3000);
+    DEPLOYED.inject_synthetic_stack(&PARSE_STACK, 2000, 400);
+    let index =
+        DEPLOYED.inject_synthetic_stack(&["my_app::index::build", "my_app::main"], 300, 200);
+
+    let movers = DEPLOYED.top_movers_vs_baseline(10);
+    let hashes: Vec<u64> = movers.iter().map(|m| m.stats.stack_hash()).collect();
+    assert_eq!(hashes, vec![cache, index]);
+    assert_eq!(movers[0].retained_bytes_delta(), 2000);
+    assert_eq!(movers[0].allocated_bytes_delta(), 1000);
+    assert_eq!(movers[0].baseline_retained_bytes, 1000);
+    // Not in the baseline, so counted from zero
+    assert_eq!(movers[1].retained_bytes_delta(), 200);
+    assert_eq!(DEPLOYED.top_movers_vs_baseline(1).len(), 1);
+
+    NO_BASELINE.inject_synthetic_stack(&CACHE_STACK, 5000, 3000);
+    assert!(NO_BASELINE.top_movers_vs_baseline(10).is_empty());
+}
+
+#[test]
+#[serial]
+fn sampling_stats_test() {
+    static COUNTED: YingProfiler = YingProfiler::new(10, 64 * 1024 * 1024 * 1024);
+    let layout = Layout::from_size_align(32, 8).unwrap();
+    let before = YingProfiler::sampling_stats();
+    let ptrs: Vec<_> = (0..1000)
+        .map(|_| unsafe { COUNTED.alloc(layout) })
+        .collect();
+    let after = YingProfiler::sampling_stats();
+
+    assert_eq!(after.alloc_calls - before.alloc_calls, 1000);
+    let sampled = after.sampled - before.sampled;
+    assert_eq!(
+        sampled + after.skipped_by_ratio - before.skipped_by_ratio,
+        1000
+    );
+    assert!((95..=105).contains(&sampled), "{}", sampled);
+    // Not the global allocator, so the profiler's own allocations don't come back through it
+    assert_eq!(after.skipped_reentrant, before.skipped_reentrant);
+    assert!(after.observed_ratio() > 0.0);
+    assert!(after.to_string().contains("allocs seen"));
+    for ptr in ptrs {
+        unsafe { COUNTED.dealloc(ptr, layout) };
+    }
+}
+
+#[test]
+#[serial]
+fn reset_state_for_testing_only_test() {
+    static RESET: YingProfiler = YingProfiler::new(1, 64 * 1024 * 1024 * 1024);
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    let retained_before = YingProfiler::profiled_bytes_retained();
+    let ptrs: Vec<_> = (0..10).map(|_| unsafe { RESET.alloc(layout) }).collect();
+    assert_eq!(
+        YingProfiler::profiled_bytes_retained(),
+        retained_before + 10 * 64
+    );
+
+    RESET.reset_state_for_testing_only();
+    let cardinality = RESET.stack_cardinality_report();
+    assert_eq!(cardinality.num_stacks, 0);
+    assert_eq!(cardinality.outstanding_allocs, 0);
+    // The forgotten allocations no longer count as profiled retained bytes, and freeing them changes nothing
+    assert_eq!(YingProfiler::profiled_bytes_retained(), retained_before);
+    for ptr in ptrs {
+        unsafe { RESET.dealloc(ptr, layout) };
+    }
+    assert_eq!(YingProfiler::profiled_bytes_retained(), retained_before);
+}
+
+#[test]
+#[serial]
+fn clear_stats_test() {
+    static CLEARED: YingProfiler = YingProfiler::new(1, 64 * 1024 * 1024 * 1024);
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    let retained_before = YingProfiler::profiled_bytes_retained();
+    let ptrs: Vec<_> = (0..10).map(|_| unsafe { CLEARED.alloc(layout) }).collect();
+    let allocated_before = YingProfiler::profiled_bytes_allocated();
+    let sampled_before = YingProfiler::sampling_stats().sampled;
+
+    // Clearing one profiler leaves the counters shared with other profilers alone
+    CLEARED.clear_stats();
+    assert_eq!(CLEARED.stack_cardinality_report().num_stacks, 0);
+    assert_eq!(CLEARED.stack_cardinality_report().outstanding_allocs, 0);
+    assert_eq!(YingProfiler::profiled_bytes_allocated(), allocated_before);
+    assert_eq!(YingProfiler::profiled_bytes_retained(), retained_before);
+    assert_eq!(YingProfiler::sampling_stats().sampled, sampled_before);
+
+    // Sampling carries on after clearing
+    let ptr = unsafe { CLEARED.alloc(layout) };
+    assert!(CLEARED.lookup_allocation(ptr).is_some());
+    assert_eq!(YingProfiler::sampling_stats().sampled, sampled_before + 1);
+    assert_eq!(
+        YingProfiler::profiled_bytes_allocated(),
+        allocated_before + 64
+    );
+    for ptr in ptrs.into_iter().chain([ptr]) {
+        unsafe { CLEARED.dealloc(ptr, layout) };
+    }
+}
+
+#[test]
+#[serial]
+fn reset_global_counters_test() {
+    static COUNTED_GLOBALLY: YingProfiler = YingProfiler::new(1, 64 * 1024 * 1024 * 1024);
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    let ptr = unsafe { COUNTED_GLOBALLY.alloc(layout) };
+    assert!(YingProfiler::sampling_stats().sampled > 0);
+
+    YingProfiler::reset_global_counters();
+    assert_eq!(YingProfiler::profiled_bytes_allocated(), 0);
+    let stats = YingProfiler::sampling_stats();
+    assert_eq!(
+        (stats.alloc_calls, stats.sampled, stats.skipped_by_ratio),
+        (0, 0, 0)
+    );
+    // The profile itself and the retained bytes of live allocations are kept
+    assert!(COUNTED_GLOBALLY.lookup_allocation(ptr).is_some());
+    assert_eq!(COUNTED_GLOBALLY.stack_cardinality_report().num_stacks, 1);
+    unsafe { COUNTED_GLOBALLY.dealloc(ptr, layout) };
+}
+
+#[test]
+#[serial]
+fn frame_pointer_unwinding_test() {
+    static WALKED: YingProfiler =
+        YingProfiler::new(1, 64 * 1024 * 1024 * 1024).with_frame_pointer_unwinding(true);
+    assert!(WALKED.config().frame_pointer_unwinding);
+    assert!(WALKED
+        .config()
+        .to_string()
+        .contains(" frame_pointer_unwinding=true "));
+
+    // Whether the walk is used or falls back to a backtrace depends on how the test was built, but either way
+    // the allocation is recorded against a stack with resolved frames
+    let layout = Layout::from_size_align(128, 8).unwrap();
+    let ptr = unsafe { WALKED.alloc(layout) };
+    let info = WALKED.lookup_allocation(ptr).unwrap();
+    let stats = WALKED.top_k_stacks_by_allocated(1);
+    assert_eq!(stats.len(), 1);
+    assert_eq!(stats[0].stack_hash(), info.stack_hash());
+    assert!(!stats[0].rich_report(&WALKED, false, false).is_empty());
+    unsafe { WALKED.dealloc(ptr, layout) };
+}
+
+#[test]
+#[serial]
+fn confidence_test() {
+    static CONFIDENT: YingProfiler = YingProfiler::new(10, 64 * 1024 * 1024 * 1024);
+    let layout = Layout::from_size_align(48, 8).unwrap();
+    let mut ptrs = Vec::new();
+    // All from the same call site, so the same stack gains samples
+    for n in 1..=2000 {
+        ptrs.push(unsafe { CONFIDENT.alloc(layout) });
+        if n == 50 {
+            let stats = CONFIDENT.top_k_stacks_by_allocated(1).remove(0);
+            assert_eq!(stats.confidence(10), Confidence::Low);
+            assert_eq!(stats.confidence(1), Confidence::High);
+            let report = stats.rich_report(&CONFIDENT, false, false);
+            assert!(report.contains("  low confidence, from "), "{}", report);
+            assert!(report.contains(" samples at 1 in 10\n"), "{}", report);
+        }
+    }
+
+    let stats = CONFIDENT.top_k_stacks_by_allocated(1).remove(0);
+    assert_eq!(stats.confidence(10), Confidence::High);
+    assert!(stats
+        .rich_report(&CONFIDENT, false, false)
+        .contains("  high confidence, from "));
+    for ptr in ptrs {
+        unsafe { CONFIDENT.dealloc(ptr, layout) };
+    }
+}
+
+#[test]
+#[serial]
+fn depth_budget_test() {
+    static BUDGETED: YingProfiler =
+        YingProfiler::new(1, 64 * 1024 * 1024 * 1024).with_depth_budget(2, 1024);
+    let small_layout = Layout::from_size_align(64, 8).unwrap();
+    let big_layout = Layout::from_size_align(4096, 8).unwrap();
+    let small = unsafe { BUDGETED.alloc(small_layout) };
+    let big = unsafe { BUDGETED.alloc(big_layout) };
+
+    let frames_of = |ptr| {
+        let stack_hash = BUDGETED.lookup_allocation(ptr).unwrap().stack_hash();
+        let stats = BUDGETED.snapshot().get(stack_hash).cloned().unwrap();
+        stats.into_owned(&BUDGETED).frames
+    };
+    let small_frames = frames_of(small);
+    let big_frames = frames_of(big);
+    // Symbols inlined into a frame still show, so a shallow stack can have a few more names than frames
+    assert!(small_frames.len() < big_frames.len(), "{:?}", small_frames);
+    assert!(big_frames
+        .iter()
+        .any(|frame| frame.contains("depth_budget_test")));
+    unsafe { BUDGETED.dealloc(small, small_layout) };
+    unsafe { BUDGETED.dealloc(big, big_layout) };
+}
+
+#[test]
+#[serial]
+fn log_record_test() {
+    PROFILER.reset_state_for_testing_only();
+    let hash =
+        PROFILER.inject_synthetic_stack(&["my_app::log::record", "my_app::main"], 4000, 1000);
+    let stats = PROFILER.snapshot().get(hash).cloned().unwrap();
+
+    let record = stats.log_record().to_string();
+    assert!(!record.contains('\n'));
+    assert!(
+        record.starts_with(&format!("stack_hash={:#x} ", hash)),
+        "{}",
+        record
+    );
+    assert!(
+        record.contains(" allocated_bytes=4000 retained_bytes=1000 "),
+        "{}",
+        record
+    );
+    assert!(record.ends_with(" churn_ratio=0.750"), "{}", record);
+    let fields: Vec<(&str, &str)> = record
+        .split(' ')
+        .map(|pair| pair.split_once('=').unwrap())
+        .collect();
+    assert_eq!(fields.len(), 6);
+}
+
+// Every allocation is counted by size, even those which are not sampled
+#[test]
+#[serial]
+fn global_size_histogram_test() {
+    static RARELY_SAMPLED: YingProfiler = YingProfiler::new(1_000_000, 64 * 1024 * 1024 * 1024);
+    let before = YingProfiler::global_size_histogram();
+
+    // 3000 bytes is in the bucket for 2048 up to 4095 bytes, and 1 byte in the bucket for 1 byte
+    let large = Layout::from_size_align(3000, 8).unwrap();
+    let tiny = Layout::from_size_align(1, 1).unwrap();
+    let ptrs: Vec<_> = (0..10)
+        .map(|_| unsafe { RARELY_SAMPLED.alloc(large) })
+        .collect();
+    let tiny_ptr = unsafe { RARELY_SAMPLED.alloc(tiny) };
+
+    let after = YingProfiler::global_size_histogram();
+    let deltas: Vec<u64> = after.iter().zip(before).map(|(a, b)| a - b).collect();
+    assert_eq!(deltas[12], 10);
+    assert_eq!(deltas[1], 1);
+    assert_eq!(deltas.iter().sum::<u64>(), 11);
+
+    // Frees don't change the histogram
+    for ptr in ptrs {
+        unsafe { RARELY_SAMPLED.dealloc(ptr, large) };
+    }
+    unsafe { RARELY_SAMPLED.dealloc(tiny_ptr, tiny) };
+    assert_eq!(YingProfiler::global_size_histogram(), after);
+}
+
+#[test]
+#[serial]
+fn category_breakdown_test() {
+    static CATEGORIZED: YingProfiler = YingProfiler::new(1, 64 * 1024 * 1024 * 1024);
+    let category = |category: SizeCategory| YingProfiler::category_breakdown()[category as usize];
+    let small_before = category(SizeCategory::Small);
+    let large_before = category(SizeCategory::Large);
+
+    let small_layout = Layout::from_size_align(200, 8).unwrap();
+    let small = unsafe { CATEGORIZED.alloc(small_layout) };
+    let large_layout = Layout::from_size_align(100 * 1024, 8).unwrap();
+    let large = unsafe { CATEGORIZED.alloc(large_layout) };
+    let small_after = category(SizeCategory::Small);
+    assert_eq!(small_after.category, SizeCategory::Small);
+    assert_eq!(
+        small_after.num_allocations,
+        small_before.num_allocations + 1
+    );
+    assert_eq!(
+        small_after.allocated_bytes,
+        small_before.allocated_bytes + 200
+    );
+    assert_eq!(
+        small_after.retained_bytes,
+        small_before.retained_bytes + 200
+    );
+    assert_eq!(
+        category(SizeCategory::Large).retained_bytes,
+        large_before.retained_bytes + 100 * 1024
+    );
+
+    // Growing the small allocation moves its retained bytes to the medium category
+    let medium_before = category(SizeCategory::Medium);
+    let medium = unsafe { CATEGORIZED.realloc(small, small_layout, 4000) };
+    let medium_layout = Layout::from_size_align(4000, 8).unwrap();
+    assert_eq!(
+        category(SizeCategory::Small).retained_bytes,
+        small_before.retained_bytes
+    );
+    assert_eq!(
+        category(SizeCategory::Medium).retained_bytes,
+        medium_before.retained_bytes + 4000
+    );
+
+    unsafe {
+        CATEGORIZED.dealloc(medium, medium_layout);
+        CATEGORIZED.dealloc(large, large_layout);
+    }
+    assert_eq!(
+        category(SizeCategory::Medium).retained_bytes,
+        medium_before.retained_bytes
+    );
+    assert_eq!(
+        category(SizeCategory::Large).retained_bytes,
+        large_before.retained_bytes
+    );
+    assert!(category(SizeCategory::Large).allocated_bytes >= 100 * 1024);
+}
+
+#[inline(never)]
+fn alloc_survivor(profiler: &YingProfiler, layout: Layout) -> *mut u8 {
+    unsafe { profiler.alloc(layout) }
+}
+
+#[test]
+#[serial]
+fn survivors_test() {
+    static SURVIVING: YingProfiler = YingProfiler::new(1, 64 * 1024 * 1024 * 1024);
+    let layout = Layout::from_size_align(512, 8).unwrap();
+    let kept: Vec<_> = (0..2).map(|_| alloc_survivor(&SURVIVING, layout)).collect();
+    let freed = unsafe { SURVIVING.alloc(layout) };
+    let earlier = SURVIVING.capture_live();
+    assert_eq!(earlier.num_allocs(), 3);
+
+    unsafe { SURVIVING.dealloc(freed, layout) };
+    // Likely at the freed address, from the same stack, but a different allocation
+    let late = unsafe { SURVIVING.alloc(layout) };
+    let later = SURVIVING.capture_live();
+    assert!(later.taken_at() >= earlier.taken_at());
+
+    let survivors = SURVIVING.survivors(&earlier, &later);
+    assert_eq!(survivors.len(), 1, "{:?}", survivors);
+    assert_eq!(survivors[0].num_allocs, 2);
+    assert_eq!(survivors[0].retained_bytes, 1024);
+    assert!(survivors[0]
+        .stack
+        .as_ref()
+        .unwrap()
+        .contains("survivors_test"));
+    assert!(survivors[0]
+        .to_string()
+        .starts_with("1024 bytes in 2 surviving allocations"));
+
+    for ptr in kept.into_iter().chain([late]) {
+        unsafe { SURVIVING.dealloc(ptr, layout) };
+    }
+    assert!(SURVIVING
+        .survivors(&earlier, &SURVIVING.capture_live())
+        .is_empty());
+}
+
+/// Keeps the callbacks of the u64 observable instruments registered with it, so a test can collect them
+#[cfg(feature = "otel")]
+#[derive(Default)]
+struct CollectingProvider {
+    callbacks: Mutex<Vec<(String, Callback<u64>)>>,
+}
+
+#[cfg(feature = "otel")]
+impl InstrumentProvider for CollectingProvider {
+    fn u64_observable_counter(
+        &self,
+        builder: AsyncInstrumentBuilder<'_, ObservableCounter<u64>, u64>,
+    ) -> ObservableCounter<u64> {
+        let name = builder.name.to_string();
+        let mut callbacks = self.callbacks.lock().unwrap();
+        callbacks.extend(builder.callbacks.into_iter().map(|cb| (name.clone(), cb)));
+        ObservableCounter::new()
+    }
+
+    fn u64_observable_gauge(
+        &self,
+        builder: AsyncInstrumentBuilder<'_, ObservableGauge<u64>, u64>,
+    ) -> ObservableGauge<u64> {
+        let name = builder.name.to_string();
+        let mut callbacks = self.callbacks.lock().unwrap();
+        callbacks.extend(builder.callbacks.into_iter().map(|cb| (name.clone(), cb)));
+        ObservableGauge::new()
+    }
+}
+
+#[cfg(feature = "otel")]
+impl CollectingProvider {
+    /// Runs every callback, returning (instrument, value, attributes) for each observation
+    fn collect(&self) -> Vec<(String, u64, Vec<KeyValue>)> {
+        struct Observations(Mutex<Vec<(u64, Vec<KeyValue>)>>);
+        impl AsyncInstrument<u64> for Observations {
+            fn observe(&self, value: u64, attributes: &[KeyValue]) {
+                self.0.lock().unwrap().push((value, attributes.to_vec()));
+            }
+        }
+
+        let mut collected = Vec::new();
+        for (name, callback) in self.callbacks.lock().unwrap().iter() {
+            let observations = Observations(Mutex::new(Vec::new()));
+            callback(&observations);
+            for (value, attributes) in observations.0.into_inner().unwrap() {
+                collected.push((name.clone(), value, attributes));
+            }
+        }
+        collected
+    }
+}
+
+#[cfg(feature = "otel")]
+#[test]
+#[serial]
+fn register_otel_test() {
+    reset_to_cache_and_parse();
+
+    let provider = Arc::new(CollectingProvider::default());
+    PROFILER.register_otel(&Meter::new(provider.clone()));
+    let collected = provider.collect();
+    let values = |instrument: &str| -> Vec<u64> {
+        collected
+            .iter()
+            .filter(|(name, _, _)| name == instrument)
+            .map(|(_, value, _)| *value)
+            .collect()
+    };
+
+    assert_eq!(values("ying.stacks"), vec![2]);
+    assert_eq!(
+        values("ying.retained_bytes"),
+        vec![YingProfiler::total_retained_bytes() as u64]
+    );
+    assert_eq!(
+        values("ying.profiled.retained_bytes"),
+        vec![YingProfiler::profiled_bytes_retained() as u64]
+    );
+
+    // Top frames are scaled by the sampling ratio of 10, and labelled with the frame
+    let top_frames: Vec<(u64, String)> = collected
+        .iter()
+        .filter(|(name, _, _)| name == "ying.top_frame.retained_bytes")
+        .map(|(_, value, attributes)| {
+            assert_eq!(attributes.len(), 1);
+            assert_eq!(attributes[0].key.as_str(), "frame");
+            (*value, attributes[0].value.as_str().into_owned())
+        })
+        .collect();
+    assert_eq!(
+        top_frames,
+        vec![
+            (30000, "my_app::cache::insert".to_string()),
+            (1000, "my_app::parse::tokens".to_string()),
+        ]
+    );
+}
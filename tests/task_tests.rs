@@ -0,0 +1,69 @@
+// Tests for attributing allocations to Tokio tasks, which need the tokio feature and every allocation sampled
+#![cfg(all(
+    feature = "tokio",
+    not(feature = "disabled"),
+    any(target_os = "linux", target_os = "macos")
+))]
+
+use serial_test::serial;
+use ying_profiler::YingProfiler;
+
+#[global_allocator]
+static YING_ALLOC: YingProfiler = YingProfiler::new(1, 64 * 1024 * 1024 * 1024);
+
+const NUM_ALLOCS: usize = 1000;
+
+#[tokio::test]
+#[serial]
+async fn top_k_tasks_by_retained_test() {
+    // Memory allocated by a task stays attributed to it after the task finishes
+    let items = tokio::spawn(async {
+        (0..NUM_ALLOCS)
+            .map(|_n| Box::new([0u64; 64]))
+            .collect::<Vec<_>>()
+    })
+    .await
+    .unwrap();
+    assert_eq!(items.len(), NUM_ALLOCS);
+
+    let top_task = YING_ALLOC.top_k_tasks_by_retained(1)[0];
+    assert!(top_task.retained_bytes >= (NUM_ALLOCS * 512) as u64);
+    assert!(top_task.allocated_bytes >= top_task.retained_bytes);
+    assert!(top_task.num_allocations >= NUM_ALLOCS as u64);
+
+    // Once all its memory is freed, the task is forgotten
+    drop(items);
+    assert!(YING_ALLOC
+        .top_k_tasks_by_retained(10)
+        .iter()
+        .all(|task| task.task_id != top_task.task_id));
+}
+
+#[allow(clippy::vec_box)]
+#[inline(never)]
+fn spawn_blocking_allocator() -> tokio::task::JoinHandle<Vec<Box<[u64; 64]>>> {
+    let handle = YING_ALLOC.spawn_blocking(|| {
+        (0..NUM_ALLOCS)
+            .map(|_n| Box::new([0u64; 64]))
+            .collect::<Vec<_>>()
+    });
+    // Not a tail call, so this function keeps its frame in release builds
+    std::hint::black_box(handle)
+}
+
+#[tokio::test]
+#[serial]
+async fn spawn_blocking_stitches_spawner_stack_test() {
+    let items = spawn_blocking_allocator().await.unwrap();
+    assert_eq!(items.len(), NUM_ALLOCS);
+
+    // The blocking task's allocations carry the spawner's frames after the marker
+    let stitched = YING_ALLOC.stacks_matching("<spawn_blocking>");
+    let stack = stitched
+        .iter()
+        .find(|stats| stats.num_allocations() >= NUM_ALLOCS as u64)
+        .expect("No stitched stack for the blocking task's allocations");
+    let report = stack.rich_report(&YING_ALLOC, false, false);
+    let (_task_frames, spawner_frames) = report.split_once("<spawn_blocking>").unwrap();
+    assert!(spawner_frames.contains("spawn_blocking_allocator"));
+}
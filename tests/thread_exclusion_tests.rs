@@ -0,0 +1,42 @@
+// Tests for excluding threads from profiling by name, which needs its own global allocator configuration
+#![cfg(not(feature = "disabled"))]
+#![cfg(any(target_os = "linux", target_os = "macos"))]
+
+use ying_profiler::YingProfiler;
+
+#[global_allocator]
+static YING_ALLOC: YingProfiler =
+    YingProfiler::new(1, 64 * 1024 * 1024 * 1024).with_excluded_threads(&["ying-noise"]);
+
+#[inline(never)]
+fn allocate_on_noisy_thread() -> Vec<[u64; 32]> {
+    (0..500).map(|_n| [0u64; 32]).collect()
+}
+
+#[inline(never)]
+fn allocate_on_kept_thread() -> Vec<[u64; 32]> {
+    (0..500).map(|_n| [1u64; 32]).collect()
+}
+
+#[test]
+fn excluded_threads_are_not_sampled_test() {
+    let noisy = std::thread::Builder::new()
+        .name("ying-noise-1".to_string())
+        .spawn(allocate_on_noisy_thread)
+        .unwrap();
+    let kept = std::thread::Builder::new()
+        .name("ying-kept".to_string())
+        .spawn(allocate_on_kept_thread)
+        .unwrap();
+    assert_eq!(
+        noisy.join().unwrap().len() + kept.join().unwrap().len(),
+        1000
+    );
+
+    assert!(YING_ALLOC
+        .stacks_matching("allocate_on_noisy_thread")
+        .is_empty());
+    assert!(!YING_ALLOC
+        .stacks_matching("allocate_on_kept_thread")
+        .is_empty());
+}